@@ -0,0 +1,186 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2014-2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::io::Result;
+use gather_unit::GatherUnit;
+use output::ColourSpace;
+use plot_unit::PlotUnit;
+use scene::Scene;
+use tonemap_unit::TonemapUnit;
+use trace_unit::TraceUnit;
+
+/// Renders a sequence of frames at evenly spaced times, for an animation.
+/// Every frame is rendered from scratch, with its own seed, so the noise
+/// does not stay in place from one frame to the next.
+pub struct Animation {
+    /// The number of frames to render.
+    pub frames: u32,
+
+    /// The time passed to `get_camera_at_time` at the start of the first
+    /// frame.
+    pub start_time: f32,
+
+    /// The time at the end of the last frame. Frame `i` starts at
+    /// `start_time + (end_time - start_time) * i / frames`.
+    pub end_time: f32,
+
+    /// The fraction of the frame duration that the shutter is open. It is
+    /// 0.5 by default, a 180 degree shutter; with 0.0, motion freezes.
+    pub shutter: f32,
+
+    /// The number of batches to trace for every frame.
+    pub batches_per_frame: u32,
+
+    /// The number of photons to trace per batch.
+    pub batch_size: usize,
+
+    /// The seed of the first frame. Frame `i` uses `seed + i`.
+    pub seed: u32
+}
+
+impl Animation {
+    /// Creates an animation of `frames` frames between `start_time` and
+    /// `end_time`, with 16 batches of the default size per frame.
+    pub fn new(frames: u32, start_time: f32, end_time: f32) -> Animation {
+        assert!(frames > 0, "an animation needs at least one frame");
+        Animation {
+            frames: frames,
+            start_time: start_time,
+            end_time: end_time,
+            shutter: 0.5,
+            batches_per_frame: 16,
+            batch_size: ::app::DEFAULT_BATCH_SIZE,
+            seed: 0
+        }
+    }
+
+    /// Returns the duration of a single frame.
+    pub fn frame_duration(&self) -> f32 {
+        (self.end_time - self.start_time) / self.frames as f32
+    }
+
+    /// Returns the time at which the specified frame starts.
+    pub fn frame_time(&self, frame: u32) -> f32 {
+        self.start_time + self.frame_duration() * frame as f32
+    }
+
+    /// Renders every frame of `scene` to a canvas of the specified size,
+    /// and calls `on_frame` with the index and the sRGB image of the frame.
+    /// The frames are rendered in order, on the current thread.
+    pub fn render<F>(&self, scene: &Scene, width: u32, height: u32, mut on_frame: F)
+        where F: FnMut(u32, &[u8]) {
+        let mut trace_unit = TraceUnit::new(0, width, height, self.batch_size);
+        let mut plot_unit = PlotUnit::new(0, width, height);
+        let mut tonemap_unit = TonemapUnit::new(width, height);
+
+        for frame in 0 .. self.frames {
+            // The trace unit is reused, but every frame is a fresh start.
+            trace_unit.frame_time = self.frame_time(frame);
+            trace_unit.shutter_open = 0.0;
+            trace_unit.shutter_close = self.frame_duration() * self.shutter;
+            trace_unit.frame_seed = self.seed.wrapping_add(frame);
+            let mut gather_unit = GatherUnit::new_empty(width, height);
+
+            for _ in 0 .. self.batches_per_frame {
+                trace_unit.render(scene);
+                plot_unit.plot(&trace_unit.mapped_photons);
+                gather_unit.accumulate(&plot_unit.tristimulus_buffer);
+                plot_unit.clear();
+            }
+
+            tonemap_unit.tonemap(&gather_unit.display_buffer());
+            on_frame(frame, &tonemap_unit.rgb_buffer);
+        }
+    }
+
+    /// Renders every frame like `render`, and saves frame `i` to
+    /// `<path_prefix><i>.png`, with the index padded to four digits.
+    pub fn render_to_files(&self, scene: &Scene, width: u32, height: u32, path_prefix: &str)
+                           -> Result<()> {
+        let mut result = Ok(());
+        self.render(scene, width, height, |frame, rgb| {
+            // Do not bother writing more frames after a failure.
+            if result.is_err() { return; }
+            let path = format!("{}{:04}.png", path_prefix, frame);
+            result = ::output::save_png(&path, rgb, width, height, ColourSpace::Srgb);
+            if result.is_ok() { println!("wrote frame {} to {}", frame, path); }
+        });
+        result
+    }
+}
+
+#[test]
+fn frames_show_motion() {
+    use std::env;
+    use std::f32::consts::PI;
+    use std::fs;
+    use camera::{Camera, Projection};
+    use geometry::Sphere;
+    use image;
+    use image::GenericImage;
+    use material::BlackBodyMaterial;
+    use object::MaterialBox::Emissive;
+    use object::Object;
+    use quaternion::Quaternion;
+    use vector3::Vector3;
+
+    // A camera that pans to the right, so a fixed light moves to the left.
+    fn panning_camera(t: f32) -> Camera {
+        Camera {
+            position: Vector3::new(9.0 * t - 3.0, 0.0, 0.0),
+            field_of_view: PI * 0.5,
+            focal_distance: 1.0,
+            depth_of_field: 1.0e6,
+            chromatic_abberation: 0.0,
+            horizontal_shift: 0.0,
+            vertical_shift: 0.0,
+            aperture_blades: 0,
+            projection: Projection::Perspective,
+            orientation: Quaternion::new(0.0, 0.0, 0.0, 1.0)
+        }
+    }
+
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(0.0, 10.0, 0.0), 2.0)),
+                            Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![light], panning_camera);
+
+    let dir = env::temp_dir().join(format!("robigo-animation-{}", ::rand::random::<u32>()));
+    fs::create_dir(&dir).unwrap();
+    let prefix = dir.join("frame").to_str().unwrap().to_string();
+
+    let mut animation = Animation::new(3, 0.0, 1.0);
+    animation.shutter = 0.0;
+    animation.batches_per_frame = 8;
+    animation.batch_size = ::app::TEST_BATCH_SIZE;
+    animation.render_to_files(&scene, 32, 16, &prefix).unwrap();
+
+    // Find the brightest column of every frame.
+    let brightest_column = |frame: u32| {
+        let img = image::open(format!("{}{:04}.png", prefix, frame)).unwrap();
+        let (width, height) = img.dimensions();
+        let column_sum = |x: u32| {
+            (0 .. height).map(|y| {
+                let px = img.get_pixel(x, y);
+                px.data[0] as u32 + px.data[1] as u32 + px.data[2] as u32
+            }).sum::<u32>()
+        };
+        (0 .. width).max_by_key(|&x| column_sum(x)).unwrap()
+    };
+    let columns: Vec<u32> = (0 .. 3).map(brightest_column).collect();
+    assert!(columns[0] > columns[1] && columns[1] > columns[2], "columns {:?}", columns);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
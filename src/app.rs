@@ -14,14 +14,15 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-extern crate num_cpus;
-
-use std::sync::mpsc::{Sender, Receiver, channel};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError, channel};
 use std::f32::consts::PI;
-use std::sync::{Arc, Mutex};
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::time;
-use camera::Camera;
+use camera::{Camera, Projection};
 use constants::GOLDEN_RATIO;
 use gather_unit::GatherUnit;
 use geometry::{Circle, Paraboloid, Plane, Sphere, Surface, new_hexagonal_prism};
@@ -32,85 +33,278 @@ use material::{BlackBodyMaterial,
                Sf10GlassMaterial,
                SoapBubbleMaterial};
 use object::Object;
+use output::PixelFormat;
 use plot_unit::PlotUnit;
 use quaternion::Quaternion;
 use ray::Ray;
 use scene::Scene;
-use task_scheduler::{Task, TaskScheduler};
+use task_scheduler::{Progress, Task, TaskScheduler};
 use tonemap_unit::TonemapUnit;
 use trace_unit::TraceUnit;
 use vector3::Vector3;
 
 pub type Image = Vec<u8>;
 
+/// The width of the canvas (in pixels) when no other size is requested.
+pub const DEFAULT_WIDTH: u32 = 1280;
+
+/// The height of the canvas (in pixels) when no other size is requested.
+pub const DEFAULT_HEIGHT: u32 = 720;
+
+/// The number of tiles horizontally and vertically over which photons are
+/// distributed when sampling adaptively.
+pub const ADAPTIVE_TILES: (u32, u32) = (16, 9);
+
+/// The number of photons that a trace unit traces in one batch when no
+/// other size is requested. Larger batches have less overhead, smaller
+/// batches show progress sooner.
+pub const DEFAULT_BATCH_SIZE: usize = 1024 * 512;
+
+/// The number of photons per batch in tests, small so that they run quickly.
+#[cfg(test)]
+pub const TEST_BATCH_SIZE: usize = 1024;
+
 pub struct App {
     /// Channel that produces a rendered image periodically.
-    pub images: Receiver<Image>
+    pub images: Receiver<Image>,
+
+    /// Channel that produces the final image, once a stop criterion is met.
+    /// Nothing more is rendered after that.
+    pub done: Receiver<Image>,
+
+    /// Channel that reports progress after every gather.
+    pub progress: Receiver<Progress>,
+
+    /// The task scheduler that the workers share.
+    task_scheduler: Arc<Mutex<TaskScheduler>>,
+
+    /// The thread that restarts workers that panicked. It returns once all
+    /// workers have stopped after the render is done.
+    supervisor: Option<thread::JoinHandle<()>>
+}
+
+/// Holds the task of a worker thread. When the thread stops, it notifies the
+/// supervisor, and whether it panicked. If it did, the units of its task are
+/// returned to the task scheduler, so they are not lost.
+struct ExitGuard {
+    /// The ID of the worker.
+    id: usize,
+
+    /// The task that the worker is executing.
+    task: Task,
+
+    /// The task scheduler to return the units to.
+    task_scheduler: Arc<Mutex<TaskScheduler>>,
+
+    /// The channel to the supervisor.
+    exit_tx: Sender<(usize, bool)>
+}
+
+/// Locks the task scheduler. If a worker panicked while it held the lock,
+/// the mutex is poisoned, but the scheduler is still usable: the units of
+/// the task of that worker are returned by its `ExitGuard`, and other tasks
+/// are not affected. So the other workers continue, instead of panicking one
+/// after another.
+fn lock_scheduler<'a>(task_scheduler: &'a Mutex<TaskScheduler>) -> MutexGuard<'a, TaskScheduler> {
+    match task_scheduler.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        let panicked = thread::panicking();
+        if panicked {
+            let task = mem::replace(&mut self.task, Task::Sleep);
+            lock_scheduler(&self.task_scheduler).recover_task(task);
+        }
+
+        // If the supervisor is gone, there is nobody left to tell.
+        let _ = self.exit_tx.send((self.id, panicked));
+    }
 }
 
 impl App {
-    /// Constructs and starts a new path tracer that renders to a canvas of
-    /// the specified size.
-    pub fn new(image_width: u32, image_height: u32) -> App {
-        let concurrency = num_cpus::get();
-        let ts = TaskScheduler::new(concurrency, image_width, image_height);
+    /// Renders `scene` without a user interface, with the tasks of `ts`,
+    /// until one of its stop criteria is met (so at least one must be set),
+    /// writes the final image as a PNG file in the specified format to
+    /// `path`, and stops all workers. Progress is printed while rendering.
+    /// Returns the gather unit, which holds everything that was gathered.
+    ///
+    /// Unlike the interactive renderer, this should not continue a previous
+    /// render, nor save progress, so that every run starts afresh. Create
+    /// the task scheduler with `TaskScheduler::new_empty` for that.
+    pub fn render_to_file<P: AsRef<Path>>(concurrency: usize, ts: TaskScheduler, scene: Scene,
+                                          path: P, format: PixelFormat)
+                                          -> io::Result<Box<GatherUnit>> {
+        let app = App::start(concurrency, ts, scene);
+        let stopped = || {
+            io::Error::new(io::ErrorKind::Other, "the render stopped without a final image")
+        };
+
+        loop {
+            match app.done.recv_timeout(time::Duration::from_secs(1)) {
+                Ok(_) => break,
+                Err(RecvTimeoutError::Timeout) => { },
+                Err(RecvTimeoutError::Disconnected) => return Err(stopped())
+            }
+            if let Some(progress) = app.progress.try_iter().last() {
+                println!("traced {} batches ({} photons), {:.1} photons per pixel, in {} s",
+                         progress.batches_traced, progress.photons_traced,
+                         progress.photons_per_pixel, progress.elapsed.num_seconds());
+            }
+        }
+
+        let (tonemap_unit, gather_unit) = try!(app.join().ok_or_else(stopped));
+        try!(tonemap_unit.write_png(path, format));
+        Ok(gather_unit)
+    }
+
+    /// Starts `concurrency` workers that execute the tasks of `ts`, which
+    /// render `scene`. This allows the task scheduler to be configured
+    /// beyond what the other constructors do.
+    pub fn start(concurrency: usize, mut ts: TaskScheduler, scene: Scene) -> App {
+        let (progress_tx, progress_rx) = channel();
+        ts.progress_tx = Some(progress_tx);
         let task_scheduler = Arc::new(Mutex::new(ts));
 
-        // Channel for communicating back to the main task.
+        // Channels for communicating back to the main task.
         let (img_tx, img_rx) = channel();
+        let (done_tx, done_rx) = channel();
 
-        // Set up the scene that will be rendered.
-        let scene = Arc::new(App::set_up_scene());
+        // The scene is shared by all workers, so everything in it must be
+        // thread-safe. It does not change any more, so it can be accelerated.
+        ::scene::assert_shareable::<Scene>();
+        let mut scene = scene;
+        scene.build_bvh(concurrency);
+        scene.build_light_distribution();
+        let scene = Arc::new(scene);
+
+        // Keep the task scheduler, to take the final units from it once the
+        // workers have stopped.
+        let scheduler = task_scheduler.clone();
+
+        // Workers report over this channel when they stop unexpectedly.
+        let (exit_tx, exit_rx) = channel();
 
         // Spawn as many workers as cores.
-        for _ in 0 .. concurrency {
-            App::start_worker(task_scheduler.clone(),
+        for id in 0 .. concurrency {
+            App::start_worker(id,
+                              task_scheduler.clone(),
                               scene.clone(),
-                              img_tx.clone());
+                              img_tx.clone(),
+                              done_tx.clone(),
+                              exit_tx.clone());
         }
 
-        App { images: img_rx }
+        // If a worker panics, the work of the task it was executing is lost,
+        // but its units are returned, and the other workers can continue.
+        // Replace the worker, so that the render does not slow down. Workers
+        // that stop normally are done.
+        let supervisor = thread::spawn(move || {
+            let mut running = concurrency;
+            for (id, panicked) in exit_rx.iter() {
+                if !panicked {
+                    running -= 1;
+                    if running == 0 { break; }
+                    continue;
+                }
+                println!("worker {} stopped unexpectedly, restarting it", id);
+                App::start_worker(id,
+                                  task_scheduler.clone(),
+                                  scene.clone(),
+                                  img_tx.clone(),
+                                  done_tx.clone(),
+                                  exit_tx.clone());
+            }
+        });
+
+        App {
+            images: img_rx,
+            done: done_rx,
+            progress: progress_rx,
+            task_scheduler: scheduler,
+            supervisor: Some(supervisor)
+        }
+    }
+
+    /// Waits until all workers have stopped. They only stop once the final
+    /// image has been rendered, so this blocks forever if there are no stop
+    /// criteria. Returns the tonemap unit with the final image and the
+    /// gather unit, or `None` if the render did not finish.
+    pub fn join(mut self) -> Option<(Box<TonemapUnit>, Box<GatherUnit>)> {
+        if let Some(supervisor) = self.supervisor.take() {
+            // A panic in the supervisor would have been reported already.
+            let _ = supervisor.join();
+        }
+        lock_scheduler(&self.task_scheduler).take_final_units()
     }
 
     #[cfg(test)]
     pub fn new_test(image_width: u32, image_height: u32) -> App {
         // Set up a task scheduler and scene with no concurrency.
-        let mut ts = TaskScheduler::new(1, image_width, image_height);
+        let mut ts = TaskScheduler::new_empty(1, image_width, image_height,
+                                              TEST_BATCH_SIZE, None);
         let (mut img_tx, img_rx) = channel();
+        let (mut done_tx, done_rx) = channel();
+        let (progress_tx, progress_rx) = channel();
+        ts.progress_tx = Some(progress_tx);
         let scene = Arc::new(App::set_up_scene());
 
         // Run 5 tasks serially, on this thread.
         let mut task = Task::Sleep;
         for _ in 0u8 .. 5 {
-            task = ts.get_new_task(task);
-            App::execute_task(&mut task, &scene, &mut img_tx);
+            task = ts.get_new_task(0, task);
+            App::execute_task(&mut task, &scene, &mut img_tx, &mut done_tx);
         }
 
-        App { images: img_rx }
+        App {
+            images: img_rx,
+            done: done_rx,
+            progress: progress_rx,
+            task_scheduler: Arc::new(Mutex::new(ts)),
+            supervisor: None
+        }
     }
 
-    fn start_worker(task_scheduler: Arc<Mutex<TaskScheduler>>,
+    fn start_worker(id: usize,
+                    task_scheduler: Arc<Mutex<TaskScheduler>>,
                     scene: Arc<Scene>,
-                    img_tx: Sender<Image>) {
+                    img_tx: Sender<Image>,
+                    done_tx: Sender<Image>,
+                    exit_tx: Sender<(usize, bool)>) {
         thread::spawn(move || {
-            // Move img_tx into the proc.
+            // Move the channels into the proc.
             let mut owned_img_tx = img_tx;
+            let mut owned_done_tx = done_tx;
 
             // There is no task yet, but the task scheduler expects
             // a completed task. Therefore, this worker is done sleeping.
-            let mut task = Task::Sleep;
+            // The guard reports to the supervisor when the worker unwinds.
+            let mut guard = ExitGuard {
+                id: id,
+                task: Task::Sleep,
+                task_scheduler: task_scheduler,
+                exit_tx: exit_tx
+            };
 
-            // Continue rendering forever, unless the application is terminated.
+            // Continue rendering until the render is done, or forever if it
+            // has no stop criteria.
             loop {
                 // Ask the task scheduler for a new task, complete the old one.
                 // Then execute it.
-                task = task_scheduler.lock().unwrap().get_new_task(task);
-                App::execute_task(&mut task, &scene, &mut owned_img_tx);
+                let completed = mem::replace(&mut guard.task, Task::Sleep);
+                guard.task = lock_scheduler(&guard.task_scheduler).get_new_task(id, completed);
+                if let Task::Exit = guard.task { break; }
+                App::execute_task(&mut guard.task, &scene,
+                                  &mut owned_img_tx, &mut owned_done_tx);
             }
         });
     }
 
-    fn execute_task(task: &mut Task, scene: &Scene, img_tx: &mut Sender<Image>) {
+    fn execute_task(task: &mut Task, scene: &Scene, img_tx: &mut Sender<Image>,
+                    done_tx: &mut Sender<Image>) {
         match *task {
             Task::Sleep =>
                 App::execute_sleep_task(),
@@ -121,7 +315,10 @@ impl App {
             Task::Gather(ref mut gather_unit, ref mut units) =>
                 App::execute_gather_task(gather_unit, &mut units[..]),
             Task::Tonemap(ref mut tonemap_unit, ref mut gather_unit) =>
-                App::execute_tonemap_task(img_tx, tonemap_unit, gather_unit)
+                App::execute_tonemap_task(img_tx, tonemap_unit, gather_unit),
+            Task::Finish(ref mut tonemap_unit, ref mut gather_unit) =>
+                App::execute_tonemap_task(done_tx, tonemap_unit, gather_unit),
+            Task::Exit => { }
         }
     }
 
@@ -143,18 +340,22 @@ impl App {
     fn execute_gather_task(gather_unit: &mut GatherUnit,
                            units: &mut[Box<PlotUnit>]) {
         for unit in units {
-            gather_unit.accumulate(&unit.tristimulus_buffer);
+            gather_unit.accumulate_counted(&unit.tristimulus_buffer, &unit.photon_counts);
             unit.clear();
         }
 
         // Save the gather state, so that rendering can be continued later.
-        gather_unit.save();
+        if gather_unit.save_progress {
+            if let Err(err) = gather_unit.save(::gather_unit::CHECKPOINT_PATH) {
+                println!("failed to save the render: {}", err);
+            }
+        }
     }
 
     fn execute_tonemap_task(img_tx: &mut Sender<Image>,
                             tonemap_unit: &mut TonemapUnit,
                             gather_unit: &mut GatherUnit) {
-        tonemap_unit.tonemap(&gather_unit.tristimulus_buffer);
+        tonemap_unit.tonemap(&gather_unit.display_buffer());
 
         // Copy the rendered image.
         let img = tonemap_unit.rgb_buffer.clone();
@@ -163,7 +364,7 @@ impl App {
         img_tx.send(img).unwrap();
     }
 
-    fn set_up_scene() -> Scene {
+    pub fn set_up_scene() -> Scene {
         use object::MaterialBox::{Emissive, Reflective};
 
         let mut objects = Vec::new();
@@ -227,7 +428,9 @@ impl App {
         let ceiling_position = Vector3::new(0.0, 0.0, sky_height * 2.0);
         let ceiling_plane = Box::new(Plane::new(floor_normal, ceiling_position));
         let blue = Box::new(DiffuseColouredMaterial::new(0.5, 470.0, 25.0));
-        let ceiling = Object::new(ceiling_plane, Reflective(blue));
+        let mut ceiling = Object::new(ceiling_plane, Reflective(blue));
+        // A ray can never hit a plane twice in a row.
+        ceiling.exclude_self_hits = true;
         objects.push(ceiling);
 
         // Spiral sunflower seeds.
@@ -239,16 +442,14 @@ impl App {
         for i in first_seed .. first_seed + seeds {
             let phi = i as f32 * gamma;
             let r = (i as f32).sqrt() * seed_scale;
-            let position = Vector3 {
-                x: phi.cos() * r,
-                y: phi.sin() * r,
-                z: (r - sun_radius) * -0.5
-            } + sun_position;
-            let sphere = Box::new(Sphere::new(position, seed_size));
             let mat = Box::new(DiffuseColouredMaterial::new(0.9,
                               (i - first_seed) as f32 / seeds as f32
                               * 130.0 + 600.0, 60.0));
-            let object = Object::new(sphere, Reflective(mat));
+            let object = Object::sphere(seed_size)
+                .translate(r, 0.0, (r - sun_radius) * -0.5)
+                .rotate(Quaternion::rotation(0.0, 0.0, 1.0, phi))
+                .translate(sun_position.x, sun_position.y, sun_position.z)
+                .with_material(Reflective(mat));
             objects.push(object);
         }
 
@@ -289,10 +490,8 @@ impl App {
         let prism_radius: f32 = 17.0;
         let prism_height: f32 = 8.0;
         for i in 0 .. prisms {
-            for &(ofs, radius, phi_ofs, h) in vec!(
-                    (0.0f32, 1.0f32, 0.0f32, 1.0f32),
-                    (0.5 * prism_angle, 1.2, PI * 0.5, 1.5)
-                ).iter() {
+            for &(ofs, radius, phi_ofs, h) in &[(0.0f32, 1.0f32, 0.0f32, 1.0f32),
+                                                (0.5 * prism_angle, 1.2, PI * 0.5, 1.5)] {
                 let phi = i as f32 * prism_angle + ofs;
                 // Get an initial position.
                 let mut position = Vector3 {
@@ -352,13 +551,320 @@ impl App {
                 depth_of_field: 2.0,
                 // A subtle amount of chromatic abberation.
                 chromatic_abberation: 0.012,
+                horizontal_shift: 0.0,
+                vertical_shift: 0.0,
+                aperture_blades: 0,
+                projection: Projection::Perspective,
                 orientation: orientation
             }
         }
 
-        Scene {
-            objects: objects,
-            get_camera_at_time: make_camera
+        Scene::new(objects, make_camera)
+    }
+}
+
+/// Executes the tasks of the scheduler one after another on this thread,
+/// until `inspect` returns a result. `inspect` sees every task after it was
+/// executed, and the final image once there is one.
+#[cfg(test)]
+fn render_serially<T, F>(ts: &mut TaskScheduler, scene: &Scene, mut inspect: F) -> T
+    where F: FnMut(&Task, Option<Image>) -> Option<T> {
+    let (mut img_tx, _img_rx) = channel();
+    let (mut done_tx, done_rx) = channel();
+    let mut task = Task::Sleep;
+    loop {
+        task = ts.get_new_task(0, task);
+        App::execute_task(&mut task, scene, &mut img_tx, &mut done_tx);
+        if let Some(result) = inspect(&task, done_rx.try_recv().ok()) {
+            return result;
         }
     }
 }
+
+#[test]
+fn worker_panic_does_not_stop_render() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use intersection::Intersection;
+    use material::Material;
+    use object::MaterialBox::{Emissive, Reflective};
+
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+
+    // A material that panics on the first bounce only.
+    struct FaultyMaterial;
+    impl Material for FaultyMaterial {
+        fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+            if !PANICKED.swap(true, Ordering::SeqCst) { panic!("faulty material"); }
+            DiffuseGreyMaterial::new(0.8).get_new_ray(incoming_ray, intersection)
+        }
+    }
+
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let wall = Object::new(Box::new(Plane::new(forward, forward * 10.0)),
+                           Reflective(Box::new(FaultyMaterial)));
+    let light = Object::new(Box::new(Plane::new(forward, forward * -10.0)),
+                            Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![wall, light], ::camera::test_camera);
+
+    // With a single worker, the render only continues if it is replaced, and
+    // the trace unit that it held is returned.
+    let mut ts = TaskScheduler::new_empty(1, 16, 16, TEST_BATCH_SIZE, None);
+    ts.stop_criteria.max_batches = Some(6);
+    let app = App::start(1, ts, scene);
+    let image = app.done.recv().unwrap();
+    assert!(PANICKED.load(Ordering::SeqCst));
+    assert!(image.iter().any(|&c| c > 0));
+    app.join();
+}
+
+#[test]
+fn workers_exit_when_done() {
+    let mut ts = TaskScheduler::new_empty(3, 32, 18, TEST_BATCH_SIZE, Some(4));
+    ts.stop_criteria.max_batches = Some(4);
+    let app = App::start(3, ts, App::set_up_scene());
+    let image = app.done.recv().unwrap();
+    assert_eq!(image.len(), 32 * 18 * 3);
+
+    // Once the final image is done, all workers stop, so joining returns.
+    let (joined_tx, joined_rx) = channel();
+    thread::spawn(move || {
+        app.join();
+        joined_tx.send(()).unwrap();
+    });
+    let joined = joined_rx.recv_timeout(time::Duration::from_secs(30));
+    assert!(joined.is_ok(), "workers kept running after finishing");
+}
+
+#[test]
+fn poisoned_scheduler_remains_usable() {
+    let ts = TaskScheduler::new_empty(2, 8, 8, TEST_BATCH_SIZE, None);
+    let task_scheduler = Arc::new(Mutex::new(ts));
+
+    // A worker panics while it holds the lock.
+    let ts = task_scheduler.clone();
+    let result = thread::spawn(move || {
+        let _guard = ts.lock().unwrap();
+        panic!("worker panicked while scheduling");
+    }).join();
+    assert!(result.is_err());
+    assert!(task_scheduler.is_poisoned());
+
+    // The other worker can still get tasks, on another thread.
+    let ts = task_scheduler.clone();
+    let traced = thread::spawn(move || {
+        let mut task = Task::Sleep;
+        for _ in 0 .. 3 {
+            task = lock_scheduler(&ts).get_new_task(1, task);
+            if let Task::Trace(_) = task { return true; }
+        }
+        false
+    }).join();
+    assert!(traced.unwrap());
+}
+
+#[test]
+fn seeded_renders_are_reproducible() {
+    // Render serially until the first gather, and tonemap the gathered
+    // batches right away.
+    let render_first_image = |seed: u32| {
+        let (width, height) = (32, 18);
+        let mut ts = TaskScheduler::new_empty(1, width, height, TEST_BATCH_SIZE, Some(seed));
+        render_serially(&mut ts, &App::set_up_scene(), |task, _| match *task {
+            Task::Gather(ref gather_unit, _) => {
+                let mut tonemap_unit = TonemapUnit::new(width, height);
+                tonemap_unit.tonemap(&gather_unit.display_buffer());
+                Some(tonemap_unit.rgb_buffer.clone())
+            },
+            _ => None
+        })
+    };
+
+    let image = render_first_image(7);
+    assert!(image.iter().any(|&c| c > 0));
+    assert!(image == render_first_image(7));
+    assert!(image != render_first_image(8));
+}
+
+#[test]
+fn adaptive_sampling_concentrates_photons_on_noise() {
+    use object::MaterialBox::Emissive;
+
+    // Only the left half of the image sees a light; the right half is black
+    // and therefore free of noise.
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(-4.0, 5.0, 0.0), 2.0)),
+                            Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![light], ::camera::test_camera);
+
+    // Render serially, and inspect the gather unit when the render is done.
+    let mut ts = TaskScheduler::new_empty(1, 16, 8, TEST_BATCH_SIZE, Some(5));
+    ts.adaptive_tiles = Some((2, 1));
+    ts.stop_criteria.max_batches = Some(40);
+    let image = render_serially(&mut ts, &scene, |task, _| match *task {
+        Task::Finish(_, ref gather_unit) => Some(gather_unit.sample_count_image()),
+        _ => None
+    });
+
+    // After the first gathers, the right half only gets its share of the
+    // uniform fraction of the photons, and that shows in the sample counts.
+    let (mut left, mut right) = (0u32, 0u32);
+    for (i, px) in image.chunks(3).enumerate() {
+        if i % 16 < 8 { left += px[0] as u32; } else { right += px[0] as u32; }
+    }
+    assert!(left > right * 3);
+}
+
+#[test]
+fn capped_pixels_stop_the_render() {
+    use object::MaterialBox::Emissive;
+
+    // The edge of the light never converges, because it is a hard edge.
+    let light = Object::new(Box::new(Sphere::new(Vector3::new(-4.0, 5.0, 0.0), 2.0)),
+                            Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![light], ::camera::test_camera);
+
+    let max_batches = 400;
+    let mut ts = TaskScheduler::with_gather_unit(1, 16, 8, TEST_BATCH_SIZE, Some(6), {
+        let mut gather_unit = GatherUnit::new_empty(16, 8);
+        gather_unit.max_pixel_photons = Some(100.0);
+        gather_unit
+    });
+    ts.adaptive_tiles = Some((4, 2));
+    ts.stop_criteria.max_batches = Some(max_batches);
+    let mut traced = 0;
+    render_serially(&mut ts, &scene, |task, _| match *task {
+        Task::Trace(_) => { traced += 1; None },
+        Task::Finish(_, ref gather_unit) => {
+            // No pixel got more photons than the cap, yet all reached it.
+            assert!(gather_unit.all_pixels_capped());
+            for y in 0 .. 8 {
+                for x in 0 .. 16 {
+                    let n = gather_unit.photon_count(x, y);
+                    assert!(n > 50.0 && n <= 100.0, "pixel ({}, {}) got {} photons", x, y, n);
+                }
+            }
+            Some(())
+        },
+        _ => None
+    });
+    assert!(traced < max_batches);
+}
+
+#[test]
+fn render_stops_when_converged() {
+    use task_scheduler::StopCriteria;
+
+    let max_batches = 40;
+
+    // Render serially until the render is done, and count the batches.
+    let render_until_done = |max_relative_error: f32| {
+        let mut ts = TaskScheduler::new_empty(1, 32, 18, TEST_BATCH_SIZE, Some(3));
+        ts.stop_criteria = StopCriteria {
+            max_relative_error: Some(max_relative_error),
+            max_time: None,
+            max_batches: Some(max_batches)
+        };
+        let mut traced = 0;
+        render_serially(&mut ts, &App::set_up_scene(), |task, image| {
+            if let Task::Trace(_) = *task { traced += 1; }
+            image.map(|image| {
+                assert!(image.iter().any(|&c| c > 0));
+                traced
+            })
+        })
+    };
+
+    // The relative error never exceeds 1, so a threshold of 2 is met as
+    // soon as it can be estimated. The error never drops below 0.
+    assert!(render_until_done(2.0) < max_batches);
+    assert!(render_until_done(0.0) >= max_batches);
+}
+
+#[test]
+fn shared_scene_traces_concurrently() {
+    // The same units, traced serially and on two threads at once with a
+    // single shared scene, must trace the same photons.
+    let mut scene = App::set_up_scene();
+    scene.build_bvh(2);
+    let scene = Arc::new(scene);
+
+    let trace = |scene: &Scene, id: usize| {
+        let mut unit = TraceUnit::new(id, 32, 18, TEST_BATCH_SIZE);
+        unit.frame_seed = 5;
+        unit.render(scene);
+        unit.mapped_photons.iter().map(|p| (p.x, p.y, p.probability)).collect::<Vec<_>>()
+    };
+    let serial: Vec<_> = (0 .. 2).map(|id| trace(&scene, id)).collect();
+
+    let threads: Vec<_> = (0 .. 2).map(|id| {
+        let scene = scene.clone();
+        thread::spawn(move || {
+            let mut unit = TraceUnit::new(id, 32, 18, TEST_BATCH_SIZE);
+            unit.frame_seed = 5;
+            unit.render(&scene);
+            unit.mapped_photons.iter().map(|p| (p.x, p.y, p.probability)).collect::<Vec<_>>()
+        })
+    }).collect();
+    let concurrent: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+    assert!(serial == concurrent);
+}
+
+#[test]
+fn images_have_requested_size() {
+    let (width, height) = (640, 480);
+    let mut ts = TaskScheduler::new_empty(1, width, height, TEST_BATCH_SIZE, Some(1));
+    ts.stop_criteria.max_batches = Some(1);
+
+    // Render serially until the final image.
+    let image = render_serially(&mut ts, &App::set_up_scene(), |_, image| image);
+    assert_eq!(image.len(), 640 * 480 * 3);
+}
+
+#[test]
+fn render_to_file_writes_final_image() {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    let name = format!("robigo-luculenta-headless-test-{}.png", process::id());
+    let path = env::temp_dir().join(name);
+    let _ = fs::remove_file(&path);
+
+    let mut ts = TaskScheduler::new_empty(2, 32, 18, TEST_BATCH_SIZE, Some(1));
+    ts.stop_criteria.max_batches = Some(2);
+    let format = ::output::RGB8;
+    let gather_unit = App::render_to_file(2, ts, App::set_up_scene(), &path, format).unwrap();
+    assert!(gather_unit.sample_count(0, 0) > 0);
+
+    // The workers have stopped by now, so nothing writes to the file.
+    let size = fs::metadata(&path).unwrap().len();
+    fs::remove_file(&path).unwrap();
+    assert!(size > 0);
+}
+
+#[test]
+fn progress_is_reported_after_gathering() {
+    let mut ts = TaskScheduler::new_empty(1, 32, 18, TEST_BATCH_SIZE, Some(2));
+    let (progress_tx, progress_rx) = channel();
+    ts.progress_tx = Some(progress_tx);
+
+    // Render serially until a few progress reports arrived.
+    let mut reports: Vec<Progress> = Vec::new();
+    render_serially(&mut ts, &App::set_up_scene(), |_, _| {
+        reports.extend(progress_rx.try_iter());
+        if reports.len() >= 3 { Some(()) } else { None }
+    });
+
+    // The counters never decrease, and they do increase over a few gathers.
+    for pair in reports.windows(2) {
+        assert!(pair[1].batches_traced >= pair[0].batches_traced);
+        assert!(pair[1].photons_traced >= pair[0].photons_traced);
+        assert!(pair[1].photons_per_pixel >= pair[0].photons_per_pixel);
+        assert!(pair[1].elapsed >= pair[0].elapsed);
+    }
+    let (first, last) = (reports[0], reports[reports.len() - 1]);
+    assert!(first.batches_traced > 0);
+    assert!(last.batches_traced > first.batches_traced);
+    assert!(last.photons_traced > first.photons_traced);
+    assert_eq!(last.photons_per_pixel, last.photons_traced as f32 / (32.0 * 18.0));
+}
@@ -0,0 +1,253 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::thread;
+use geometry::BoundingBox;
+use ray::Ray;
+
+/// The maximum number of items in a leaf node.
+const LEAF_SIZE: usize = 4;
+
+/// Subtrees with fewer items than this are built on the current thread,
+/// because spawning a thread would cost more than it saves.
+const MIN_PARALLEL_SIZE: usize = 4096;
+
+enum Node {
+    /// A node with the indices of the items that it contains.
+    Leaf(BoundingBox, Vec<usize>),
+
+    /// A node with two children, the indices of the child nodes.
+    Interior(BoundingBox, usize, usize)
+}
+
+/// A bounding volume hierarchy over items with bounds, such as the objects
+/// of a scene, so that a ray need only be intersected with the items whose
+/// bounds it passes.
+pub struct Bvh {
+    /// The nodes of the tree; the first node is the root.
+    nodes: Vec<Node>,
+
+    /// The items that have no bounds, and must always be intersected.
+    unbounded: Vec<usize>
+}
+
+impl Bvh {
+    /// Builds a hierarchy over the items with the specified bounds, by
+    /// splitting them at the median along the axis in which their centres
+    /// are spread the most. Items without bounds are always visited, and so
+    /// are items with bounds that are not finite, which cannot be sorted.
+    ///
+    /// The two halves of a split are built on separate threads, until
+    /// `threads` threads are busy. The tree does not depend on the number
+    /// of threads.
+    pub fn new(bounds: &[Option<BoundingBox>], threads: usize) -> Bvh {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (i, b) in bounds.iter().enumerate() {
+            match *b {
+                Some(b) if b.is_finite() => bounded.push((i, b)),
+                _ => unbounded.push(i)
+            }
+        }
+
+        Bvh {
+            nodes: if bounded.is_empty() { Vec::new() }
+                   else { build(&mut bounded[..], threads) },
+            unbounded: unbounded
+        }
+    }
+
+    /// Calls `visit` with the indices of all items that the ray might hit
+    /// before `max_distance`. `visit` returns the distance to the nearest
+    /// hit found so far, so that subtrees further away are skipped.
+    pub fn traverse<F>(&self, ray: &Ray, max_distance: f32, mut visit: F)
+        where F: FnMut(usize) -> f32 {
+        let mut distance = max_distance;
+        for &i in &self.unbounded {
+            distance = visit(i);
+        }
+
+        if self.nodes.is_empty() { return; }
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            match self.nodes[node] {
+                Node::Leaf(ref bounds, ref items) => {
+                    match bounds.entry_distance(ray) {
+                        Some(t) if t < distance => {
+                            for &i in items {
+                                distance = visit(i);
+                            }
+                        },
+                        _ => { }
+                    }
+                },
+                Node::Interior(ref bounds, left, right) => {
+                    match bounds.entry_distance(ray) {
+                        Some(t) if t < distance => {
+                            stack.push(right);
+                            stack.push(left);
+                        },
+                        _ => { }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds the nodes of a subtree over the items, with the root first, and
+/// every node followed by its left and then its right subtree. The indices
+/// of child nodes are relative to the root of the subtree.
+fn build(items: &mut [(usize, BoundingBox)], threads: usize) -> Vec<Node> {
+    let bounds = items[1 ..].iter().fold(items[0].1, |acc, &(_, b)| acc.union(b));
+
+    if items.len() <= LEAF_SIZE {
+        return vec![Node::Leaf(bounds, items.iter().map(|&(i, _)| i).collect())];
+    }
+
+    // Sort along the axis with the largest spread of centres.
+    let centres: Vec<_> = items.iter().map(|&(_, b)| b.centre()).collect();
+    let spread = BoundingBox::around(&centres[..]);
+    let extent = spread.max - spread.min;
+    let axis = if extent.x >= extent.y && extent.x >= extent.z { 0 }
+               else if extent.y >= extent.z { 1 }
+               else { 2 };
+    let key = |b: &BoundingBox| {
+        let c = b.centre();
+        match axis { 0 => c.x, 1 => c.y, _ => c.z }
+    };
+    items.sort_by(|a, b| key(&a.1).partial_cmp(&key(&b.1)).unwrap());
+
+    // Build the left half on a new thread if there are threads to spare,
+    // and give each half its share of the remaining threads.
+    let mid = items.len() / 2;
+    let (left, right) = items.split_at_mut(mid);
+    let (left, right) = if threads > 1 && left.len() >= MIN_PARALLEL_SIZE {
+        let mut owned_left = left.to_vec();
+        let left_threads = threads / 2;
+        let handle = thread::spawn(move || build(&mut owned_left[..], left_threads));
+        let right = build(right, threads - left_threads);
+        (handle.join().unwrap(), right)
+    } else {
+        (build(left, 1), build(right, 1))
+    };
+
+    // The left subtree follows this node, and the right one follows that.
+    let mut nodes = Vec::with_capacity(1 + left.len() + right.len());
+    nodes.push(Node::Interior(bounds, 1, 1 + left.len()));
+    append_subtree(&mut nodes, left);
+    append_subtree(&mut nodes, right);
+    nodes
+}
+
+/// Appends the nodes of a subtree, offsetting the indices of its children.
+fn append_subtree(nodes: &mut Vec<Node>, subtree: Vec<Node>) {
+    let offset = nodes.len();
+    nodes.extend(subtree.into_iter().map(|node| match node {
+        Node::Interior(bounds, left, right) => {
+            Node::Interior(bounds, left + offset, right + offset)
+        },
+        leaf => leaf
+    }));
+}
+
+#[test]
+fn parallel_build_matches_serial_build() {
+    use geometry::{Surface, Triangle};
+    use vector3::Vector3;
+
+    // Enough small triangles scattered through a cube that the build is
+    // split over several threads.
+    ::monte_carlo::seed([2, 7, 1, 8]);
+    let random_point = || Vector3::new(::monte_carlo::get_bi_unit(),
+                                       ::monte_carlo::get_bi_unit(),
+                                       ::monte_carlo::get_bi_unit()) * 20.0;
+    let triangles: Vec<Triangle> = (0 .. 5 * MIN_PARALLEL_SIZE).map(|_| {
+        let p = random_point();
+        Triangle::new(p, p + random_point() * 0.05, p + random_point() * 0.05)
+    }).collect();
+    let bounds: Vec<_> = triangles.iter().map(|t| t.bounding_box()).collect();
+
+    let serial = Bvh::new(&bounds, 1);
+    let parallel = Bvh::new(&bounds, 4);
+
+    // The trees are identical, node for node.
+    let flatten = |bvh: &Bvh| bvh.nodes.iter().map(|node| match *node {
+        Node::Leaf(b, ref items) => (vec![b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z],
+                                     true, items.clone()),
+        Node::Interior(b, l, r) => (vec![b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z],
+                                    false, vec![l, r])
+    }).collect::<Vec<_>>();
+    assert!(flatten(&serial) == flatten(&parallel));
+
+    // And so are the nearest hits.
+    let nearest_hit = |bvh: &Bvh, ray: &Ray| {
+        let mut nearest = None;
+        let mut distance = 1.0e12;
+        bvh.traverse(ray, distance, |i| {
+            if let Some(isect) = triangles[i].intersect(ray) {
+                if isect.distance < distance {
+                    distance = isect.distance;
+                    nearest = Some(i);
+                }
+            }
+            distance
+        });
+        nearest.map(|i| (i, distance))
+    };
+    let mut hits = 0;
+    for _ in 0 .. 2000 {
+        let ray = Ray {
+            origin: random_point() * 1.5,
+            direction: random_point().normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let hit = nearest_hit(&serial, &ray);
+        assert!(hit == nearest_hit(&parallel, &ray));
+        if hit.is_some() { hits += 1; }
+    }
+    assert!(hits > 100);
+}
+
+#[test]
+fn items_with_non_finite_bounds_are_always_visited() {
+    use std::f32::{INFINITY, NAN};
+    use vector3::Vector3;
+
+    // Enough boxes along the x-axis to be sorted, two of them broken.
+    let unit_box = |x: f32| BoundingBox {
+        min: Vector3::new(x, -0.5, -0.5),
+        max: Vector3::new(x + 1.0, 0.5, 0.5)
+    };
+    let mut bounds: Vec<_> = (0 .. 12).map(|i| Some(unit_box(i as f32 * 2.0))).collect();
+    bounds[3] = Some(unit_box(NAN));
+    bounds[7] = Some(BoundingBox { max: Vector3::new(INFINITY, 0.5, 0.5), .. unit_box(0.0) });
+    let bvh = Bvh::new(&bounds, 1);
+
+    // A ray along the y-axis through the first box visits it, and the
+    // broken boxes, but not the boxes far away.
+    let ray = Ray {
+        origin: Vector3::new(0.5, -10.0, 0.0),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let mut visited = Vec::new();
+    bvh.traverse(&ray, INFINITY, |i| { visited.push(i); INFINITY });
+    assert!(visited.contains(&0) && visited.contains(&3) && visited.contains(&7));
+    assert!(!visited.contains(&11));
+}
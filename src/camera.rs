@@ -14,10 +14,26 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::f32::consts::PI;
 use quaternion::Quaternion;
 use ray::Ray;
 use vector3::Vector3;
 
+/// How a camera projects the scene onto the screen.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Projection {
+    /// Rays diverge from the lens, so distant objects appear smaller. The
+    /// field of view determines how much of the scene is visible.
+    Perspective,
+
+    /// All rays are parallel to the optical axis, so objects keep their size
+    /// regardless of distance, as in technical drawings. The value is the
+    /// distance in the scene that maps to one screen unit, so the view is
+    /// twice as wide. There is no depth of field.
+    Orthographic(f32)
+}
+
+#[derive(Copy, Clone)]
 pub struct Camera {
     /// Location of the camera in the scene.
     pub position: Vector3,
@@ -26,6 +42,8 @@ pub struct Camera {
     pub field_of_view: f32,
 
     /// The distance along the optical axis that is perfectly in focus.
+    /// Points on the plane perpendicular to the axis at this distance are
+    /// sharp, regardless of what geometry is there.
     pub focal_distance: f32,
 
     /// The amount of depth of field. A large value indicates that all
@@ -37,6 +55,26 @@ pub struct Camera {
     /// abberation, larger values result in more chromatic abberation.
     pub chromatic_abberation: f32,
 
+    /// Horizontal shift of the lens relative to the centre of the image, in
+    /// the same units as screen coordinates. Positive values shift the view
+    /// to the right, without rotating the camera.
+    pub horizontal_shift: f32,
+
+    /// Vertical shift of the lens relative to the centre of the image, in
+    /// the same units as screen coordinates. Positive values shift the view
+    /// upwards, which keeps vertical lines parallel when looking up at
+    /// buildings.
+    pub vertical_shift: f32,
+
+    /// The number of blades of the aperture. With three or more blades, the
+    /// lens is a regular polygon, which gives polygonal bokeh. Otherwise the
+    /// lens is a disk.
+    pub aperture_blades: u32,
+
+    /// How the scene is projected onto the screen. The field of view, focal
+    /// distance and depth of field only apply to a perspective projection.
+    pub projection: Projection,
+
     /// The direction in which the camera is looking.
     pub orientation: Quaternion
 }
@@ -48,13 +86,17 @@ impl Camera {
                       x: f32,
                       y: f32,
                       chromatic_abberation_factor: f32,
-                      dof_angle: f32,
-                      dof_radius: f32)
+                      lens_point: Vector3)
                       -> Ray {
         // The smaller the FOV, the further the screen is away;
         // the larger the FOV, the closer the screen is.
         let screen_distance = 1.0 / (self.field_of_view * 0.5).tan();
         
+        // Shifting the lens moves the optical axis away from the centre of
+        // the image, but the image plane stays parallel to the lens.
+        let x = x + self.horizontal_shift;
+        let y = y - self.vertical_shift;
+
         // Then apply some wavelength dependent zoom to create chromatic
         // abberation. Please note, this is not a physically correct model of
         // chromatic abberation, for a correct response, you can place a lens
@@ -68,15 +110,6 @@ impl Camera {
         // long as the ray has not been transformed yet).
         let focus_point = direction * (self.focal_distance / direction.y);
 
-        // Then take a new point on the camera 'lens' (this is of course not
-        // accurate, but then again, the pinhole camera does not have depth of
-        // field at all, so it is a hack anyway).
-        let lens_point = Vector3 {
-            x: dof_angle.cos() * dof_radius,
-            y: 0.0,
-            z: dof_angle.sin() * dof_radius
-        };
-
         // Then construct the new ray, from the lens point,
         // through the focus point.
         Ray {
@@ -89,12 +122,43 @@ impl Camera {
         }
     }
 
+    /// Returns a ray through the screen at the specified position for an
+    /// orthographic projection, where `scale` is the distance in the scene
+    /// that corresponds to one screen unit.
+    fn get_orthographic_ray(&self,
+                            x: f32,
+                            y: f32,
+                            chromatic_abberation_factor: f32,
+                            scale: f32)
+                            -> Ray {
+        // All rays point along the optical axis, only their origins are
+        // spread over the image plane, so the shift simply moves the view.
+        let x = (x + self.horizontal_shift) * chromatic_abberation_factor;
+        let y = (y - self.vertical_shift) * chromatic_abberation_factor;
+        let offset = Vector3::new(x * scale, 0.0, -y * scale);
+
+        Ray {
+            origin: self.position + offset.rotate(self.orientation),
+            direction: Vector3::new(0.0, 1.0, 0.0).rotate(self.orientation),
+            wavelength: 0.0,
+            probability: 1.0
+        }
+    }
+
     /// Returns a camera ray through the screen at the specified position,
     /// where -1.0 is left and 1.0 is right, with square units.
     pub fn get_ray(&self, x: f32, y: f32, wavelength: f32) -> Ray {
         // Pick depth of field coordinates randomly.
-        let dof_angle = ::monte_carlo::get_longitude();
-        let dof_radius = ::monte_carlo::get_unit() / self.depth_of_field;
+        let lens = (::monte_carlo::get_unit(), ::monte_carlo::get_unit());
+        self.get_ray_through_lens(x, y, wavelength, lens)
+    }
+
+    /// Returns a camera ray like `get_ray`, but through the point on the lens
+    /// given by `lens`, with both coordinates in the range [0, 1], instead of
+    /// a random point. This allows sampling the lens and screen jointly.
+    pub fn get_ray_through_lens(&self, x: f32, y: f32, wavelength: f32,
+                                lens: (f32, f32)) -> Ray {
+        let lens_point = self.get_lens_point(lens);
 
         // Calculate a zoom factor based on the wavelength
         // to simulate chromatic abberation of the lens.
@@ -102,8 +166,208 @@ impl Camera {
         let chromatic_zoom = 1.0 + d * self.chromatic_abberation;
 
         // Then retrieve a ray through the screen.
-        let mut r = self.get_screen_ray(x, y, chromatic_zoom, dof_angle, dof_radius);
+        let mut r = match self.projection {
+            Projection::Perspective => self.get_screen_ray(x, y, chromatic_zoom, lens_point),
+            Projection::Orthographic(scale) => {
+                self.get_orthographic_ray(x, y, chromatic_zoom, scale)
+            }
+        };
         r.wavelength = wavelength;
         r
     }
-}       
+
+    /// Returns the camera at `t` on the way from this camera to `other`,
+    /// where 0.0 returns this camera and 1.0 returns `other`. The position and
+    /// the lens are interpolated linearly, and the orientation turns with a
+    /// constant angular velocity. The projection and the aperture blades are
+    /// those of this camera.
+    pub fn interpolate(&self, other: &Camera, t: f32) -> Camera {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        Camera {
+            position: self.position + (other.position - self.position) * t,
+            field_of_view: lerp(self.field_of_view, other.field_of_view),
+            focal_distance: lerp(self.focal_distance, other.focal_distance),
+            depth_of_field: lerp(self.depth_of_field, other.depth_of_field),
+            chromatic_abberation: lerp(self.chromatic_abberation, other.chromatic_abberation),
+            horizontal_shift: lerp(self.horizontal_shift, other.horizontal_shift),
+            vertical_shift: lerp(self.vertical_shift, other.vertical_shift),
+            orientation: Quaternion::slerp(self.orientation, other.orientation, t),
+            .. *self
+        }
+    }
+
+    /// Maps `lens`, with both coordinates in the range [0, 1], to a point
+    /// on the camera 'lens' (this is of course not accurate, but then again,
+    /// the pinhole camera does not have depth of field at all, so it is a
+    /// hack anyway). The lens lies in the xz-plane, before rotation.
+    fn get_lens_point(&self, lens: (f32, f32)) -> Vector3 {
+        let (u, v) = lens;
+        let radius = 1.0 / self.depth_of_field;
+
+        if self.aperture_blades < 3 {
+            let dof_angle = u * PI * 2.0;
+            let dof_radius = v * radius;
+            return Vector3 {
+                x: dof_angle.cos() * dof_radius,
+                y: 0.0,
+                z: dof_angle.sin() * dof_radius
+            };
+        }
+
+        // The polygon consists of one triangle per blade, between the centre
+        // and two adjacent corners. All triangles have the same area, so pick
+        // one uniformly, and reuse the remainder of `u` to pick a point in it.
+        let n = self.aperture_blades as f32;
+        let blade = (u * n).floor().min(n - 1.0);
+        let t = u * n - blade;
+        let corner = |i: f32| {
+            let angle = i / n * PI * 2.0;
+            Vector3::new(angle.cos() * radius, 0.0, angle.sin() * radius)
+        };
+
+        // Fold the unit square onto the triangle, uniformly.
+        let s = v.sqrt();
+        corner(blade) * (s * (1.0 - t)) + corner(blade + 1.0) * (s * t)
+    }
+}
+
+/// Returns a camera at the origin that looks along the positive y-axis,
+/// nearly without depth of field. Tests can use it as `get_camera_at_time`.
+#[cfg(test)]
+pub fn test_camera(_: f32) -> Camera {
+    Camera {
+        position: Vector3::zero(),
+        field_of_view: PI * 0.5,
+        focal_distance: 1.0,
+        depth_of_field: 1.0e6,
+        chromatic_abberation: 0.0,
+        horizontal_shift: 0.0,
+        vertical_shift: 0.0,
+        aperture_blades: 0,
+        projection: Projection::Perspective,
+        orientation: Quaternion::new(0.0, 0.0, 0.0, 1.0)
+    }
+}
+
+#[test]
+fn interpolated_camera_turns_between_keyframes() {
+    let start = test_camera(0.0);
+    let end = Camera {
+        position: Vector3::new(10.0, 0.0, 0.0),
+        field_of_view: PI * 0.25,
+        orientation: Quaternion::rotation(0.0, 0.0, 1.0, PI * 0.5),
+        .. start
+    };
+
+    // The ends are the keyframes themselves.
+    let forward = |camera: &Camera| camera.get_ray(0.0, 0.0, 550.0).direction;
+    assert!((forward(&start.interpolate(&end, 0.0)) - forward(&start)).magnitude() < 1.0e-5);
+    assert!((forward(&start.interpolate(&end, 1.0)) - forward(&end)).magnitude() < 1.0e-5);
+
+    // Halfway, the camera has turned an eighth of a turn, and it is halfway
+    // along the straight line between the keyframes.
+    let half = start.interpolate(&end, 0.5);
+    let direction = forward(&half);
+    let expected = Vector3::new(-(PI * 0.25).sin(), (PI * 0.25).cos(), 0.0);
+    assert!((direction - expected).magnitude() < 1.0e-4);
+    assert!((half.position - Vector3::new(5.0, 0.0, 0.0)).magnitude() < 1.0e-5);
+    assert!((half.field_of_view - PI * 0.375).abs() < 1.0e-5);
+}
+
+#[test]
+fn vertical_shift_moves_horizon_without_keystone() {
+    let mut camera = test_camera(0.0);
+
+    // Without shift, the horizon is in the middle of the frame.
+    assert!(camera.get_ray(0.3, 0.0, 550.0).direction.z.abs() < 1.0e-4);
+
+    // Shifting the view upwards moves the horizon down in the frame.
+    camera.vertical_shift = 0.4;
+    assert!(camera.get_ray(0.3, 0.0, 550.0).direction.z > 0.1);
+    assert!(camera.get_ray(0.3, 0.4, 550.0).direction.z.abs() < 1.0e-4);
+
+    // A vertical edge on a wall parallel to the image plane must remain
+    // vertical: all rays in a column hit the wall at the same x.
+    let wall_x = |y: f32| {
+        let ray = camera.get_ray(0.7, y, 550.0);
+        ray.origin.x + ray.direction.x * (10.0 - ray.origin.y) / ray.direction.y
+    };
+    for &y in [-1.0f32, -0.5, 0.5, 1.0].iter() {
+        assert!((wall_x(y) - wall_x(0.0)).abs() < 1.0e-3);
+    }
+}
+
+#[test]
+fn focal_distance_determines_focus_plane() {
+    use geometry::{Plane, Surface};
+
+    let mut camera = test_camera(0.0);
+    camera.focal_distance = 10.0;
+    camera.depth_of_field = 0.5;
+
+    // Put a plane in the focus plane, and one beyond it.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let in_focus = Plane::new(forward, forward * 10.0);
+    let out_of_focus = Plane::new(forward, forward * 20.0);
+
+    // Rays through one screen position start at different points on the
+    // lens, but they must all meet at the focus plane.
+    let spread = |surface: &Plane| {
+        let hits: Vec<Vector3> = (0 .. 100).map(|_| {
+            surface.intersect(&camera.get_ray(0.3, -0.2, 550.0)).unwrap().position
+        }).collect();
+        hits.iter().fold(0.0f32, |acc, &p| acc.max((p - hits[0]).magnitude()))
+    };
+
+    assert!(spread(&in_focus) < 1.0e-3);
+    assert!(spread(&out_of_focus) > 0.1);
+}
+
+#[test]
+fn orthographic_rays_are_parallel() {
+    let mut camera = test_camera(0.0);
+    camera.projection = Projection::Orthographic(5.0);
+
+    // Rays through different screen positions share their direction, and
+    // their origins are offset across the image plane by the scale.
+    let a = camera.get_ray(-0.5, 0.2, 550.0);
+    let b = camera.get_ray(0.8, -0.6, 550.0);
+    assert!((a.direction - b.direction).magnitude() < 1.0e-6);
+    assert!((a.direction - Vector3::new(0.0, 1.0, 0.0)).magnitude() < 1.0e-6);
+    assert!((b.origin - a.origin - Vector3::new(6.5, 0.0, 4.0)).magnitude() < 1.0e-4);
+
+    // The perspective projection spreads the rays instead.
+    camera.projection = Projection::Perspective;
+    let a = camera.get_ray(-0.5, 0.2, 550.0);
+    let b = camera.get_ray(0.8, -0.6, 550.0);
+    assert!((a.direction - b.direction).magnitude() > 0.5);
+}
+
+#[test]
+fn lens_points_lie_inside_aperture_polygon() {
+    let mut camera = test_camera(0.0);
+    camera.depth_of_field = 0.5;
+    camera.aperture_blades = 6;
+
+    // The corners of the hexagon lie on a circle with this radius, and the
+    // edges at this distance from the centre.
+    let radius = 1.0 / camera.depth_of_field;
+    let apothem = radius * (PI / 6.0).cos();
+
+    let mut max_distance = 0.0f32;
+    for _ in 0 .. 1000 {
+        let p = camera.get_ray(0.1, 0.2, 550.0).origin;
+        assert!(p.y.abs() < 1.0e-5);
+
+        // Inside the polygon, the projection onto the normal of every edge is
+        // at most the apothem.
+        for i in 0 .. 6 {
+            let angle = (i as f32 + 0.5) / 6.0 * PI * 2.0;
+            assert!(p.x * angle.cos() + p.z * angle.sin() <= apothem + 1.0e-5);
+        }
+        max_distance = max_distance.max(p.magnitude());
+    }
+
+    // The samples must also fill the polygon, not only a small disk inside.
+    assert!(max_distance > apothem * 0.95);
+}
@@ -0,0 +1,130 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use vector3::Vector3;
+
+/// A joint bilateral filter that blurs chromaticity, but not luminance.
+///
+/// Every photon carries a single wavelength, so neighbouring pixels often
+/// sampled different parts of the spectrum, which shows up as colour noise.
+/// The eye is much less sensitive to blurred colour than to blurred detail,
+/// so the colour can be averaged over a neighbourhood, guided by luminance
+/// to avoid bleeding across edges, while luminance is kept as is.
+pub struct ChromaFilter {
+    /// The radius of the filter window, in pixels.
+    pub radius: u32,
+
+    /// The standard deviation of the spatial weight, in pixels.
+    pub spatial_sigma: f32,
+
+    /// The standard deviation of the luminance weight, in terms of the
+    /// relative luminance difference (Y1 - Y2) / (Y1 + Y2).
+    pub luminance_sigma: f32
+}
+
+impl ChromaFilter {
+    /// Creates a filter with reasonable default parameters.
+    pub fn new() -> ChromaFilter {
+        ChromaFilter {
+            radius: 3,
+            spatial_sigma: 2.0,
+            luminance_sigma: 0.2
+        }
+    }
+
+    /// Filters the CIE XYZ buffer of an image of the specified size,
+    /// and returns the filtered buffer.
+    pub fn apply(&self, tristimuli: &[Vector3], width: u32, height: u32) -> Vec<Vector3> {
+        let r = self.radius as i32;
+        let (w, h) = (width as i32, height as i32);
+        let inv_spatial = -0.5 / (self.spatial_sigma * self.spatial_sigma);
+        let inv_luminance = -0.5 / (self.luminance_sigma * self.luminance_sigma);
+
+        let mut result = Vec::with_capacity(tristimuli.len());
+        for y in 0 .. h {
+            for x in 0 .. w {
+                let centre = tristimuli[(y * w + x) as usize];
+
+                // Black pixels have no colour to filter.
+                if centre.y <= 0.0 {
+                    result.push(centre);
+                    continue;
+                }
+
+                // Sum the neighbours with luminance weighting, so the ratio
+                // of the sums is the weighted average chromaticity.
+                let mut sum = Vector3::zero();
+                for j in (y - r).max(0) .. (y + r + 1).min(h) {
+                    for i in (x - r).max(0) .. (x + r + 1).min(w) {
+                        let px = tristimuli[(j * w + i) as usize];
+                        if px.y <= 0.0 { continue; }
+
+                        let dist_sqr = ((i - x) * (i - x) + (j - y) * (j - y)) as f32;
+                        let rel_diff = (px.y - centre.y) / (px.y + centre.y);
+                        let weight = (dist_sqr * inv_spatial
+                                      + rel_diff * rel_diff * inv_luminance).exp();
+                        sum = sum + px * weight;
+                    }
+                }
+
+                // Keep the luminance of the centre pixel, and take the colour
+                // of the neighbourhood.
+                result.push(Vector3 {
+                    x: sum.x / sum.y * centre.y,
+                    y: centre.y,
+                    z: sum.z / sum.y * centre.y
+                });
+            }
+        }
+
+        result
+    }
+}
+
+#[test]
+fn chroma_filter_reduces_chroma_noise_more_than_luminance_noise() {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    // A grey image with noise in both luminance and colour.
+    let (width, height) = (32, 32);
+    let mut rng = XorShiftRng::from_seed([29, 31, 37, 41]);
+    let image: Vec<Vector3> = (0 .. width * height).map(|_| {
+        let lum = 1.0 + 0.1 * (rng.gen::<f32>() - 0.5);
+        let x = 1.0 + 0.5 * (rng.gen::<f32>() - 0.5);
+        let z = 1.0 + 0.5 * (rng.gen::<f32>() - 0.5);
+        Vector3::new(x, 1.0, z) * lum
+    }).collect();
+
+    fn variances(image: &[Vector3]) -> (f32, f32) {
+        let variance = |values: &Vec<f32>| {
+            let n = values.len() as f32;
+            let mean = values.iter().fold(0.0, |a, &v| a + v) / n;
+            values.iter().fold(0.0, |a, &v| a + (v - mean) * (v - mean)) / n
+        };
+        let lum = image.iter().map(|px| px.y).collect();
+        let chroma = image.iter().map(|px| px.x / (px.x + px.y + px.z)).collect();
+        (variance(&lum), variance(&chroma))
+    }
+
+    let filtered = ChromaFilter::new().apply(&image, width, height);
+    let (lum_before, chroma_before) = variances(&image);
+    let (lum_after, chroma_after) = variances(&filtered);
+
+    let lum_reduction = lum_after / lum_before;
+    let chroma_reduction = chroma_after / chroma_before;
+    assert!(chroma_reduction < 0.2);
+    assert!(chroma_reduction < lum_reduction * 0.5);
+}
@@ -0,0 +1,104 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2014-2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use vector3::Vector3;
+
+/// A perceptually ordered colour map for scientific visualisation, that maps
+/// a scalar in [0, 1] to a display colour. These are not physically based,
+/// they are meant for showing data, not for photorealism.
+#[derive(Copy, Clone)]
+pub enum ColourMap {
+    /// The default colour map of Matplotlib, from dark purple to yellow.
+    Viridis,
+
+    /// The rainbow-like colour map by Google, from dark blue through green
+    /// to dark red.
+    Turbo
+}
+
+/// Viridis, sampled at 0.0, 0.1, ..., 1.0, as sRGB bytes.
+const VIRIDIS: [[u8; 3]; 11] = [[0x44, 0x01, 0x54], [0x48, 0x24, 0x75], [0x41, 0x44, 0x87],
+                                [0x35, 0x5f, 0x8d], [0x2a, 0x78, 0x8e], [0x21, 0x91, 0x8c],
+                                [0x22, 0xa8, 0x84], [0x44, 0xbf, 0x70], [0x7a, 0xd1, 0x51],
+                                [0xbd, 0xdf, 0x26], [0xfd, 0xe7, 0x25]];
+
+/// Evaluates the polynomial with the coefficients `c`, lowest degree first.
+fn polynomial(c: &[f32; 6], t: f32) -> f32 {
+    c.iter().rev().fold(0.0, |acc, &ci| acc * t + ci)
+}
+
+impl ColourMap {
+    /// Maps `t` to a colour with sRGB-encoded components in [0, 1]. Values
+    /// outside of [0, 1] are clamped.
+    pub fn map(&self, t: f32) -> Vector3 {
+        let t = t.max(0.0).min(1.0);
+        match *self {
+            ColourMap::Viridis => {
+                // Interpolate linearly between the samples.
+                let last = VIRIDIS.len() - 1;
+                let indexf = t * last as f32;
+                let index = (indexf.floor() as usize).min(last - 1);
+                let remainder = indexf - index as f32;
+                let (a, b) = (VIRIDIS[index], VIRIDIS[index + 1]);
+                let channel = |c: usize| {
+                    (a[c] as f32 * (1.0 - remainder) + b[c] as f32 * remainder) / 255.0
+                };
+                Vector3::new(channel(0), channel(1), channel(2))
+            },
+            ColourMap::Turbo => {
+                // This is the polynomial approximation by Google, which is
+                // within a few percent of the reference table.
+                const RED: [f32; 6] = [0.13572138, 4.61539260, -42.66032258,
+                                       132.13108234, -152.94239396, 59.28637943];
+                const GREEN: [f32; 6] = [0.09140261, 2.19418839, 4.84296658,
+                                         -14.18503333, 4.27729857, 2.82956604];
+                const BLUE: [f32; 6] = [0.10667330, 12.64194608, -60.58204836,
+                                        110.36276771, -89.90310912, 27.34824973];
+                let clamp = |x: f32| x.max(0.0).min(1.0);
+                Vector3::new(clamp(polynomial(&RED, t)),
+                             clamp(polynomial(&GREEN, t)),
+                             clamp(polynomial(&BLUE, t)))
+            }
+        }
+    }
+
+    /// Maps a wavelength (in nm) in the visible range of the tracer,
+    /// [380, 780], to a colour.
+    pub fn map_wavelength(&self, wavelength: f32) -> Vector3 {
+        self.map((wavelength - 380.0) / 400.0)
+    }
+}
+
+#[test]
+fn colour_maps_match_reference_entries() {
+    let close = |c: Vector3, rgb: [u8; 3]| {
+        (c.x * 255.0 - rgb[0] as f32).abs() < 4.0 &&
+        (c.y * 255.0 - rgb[1] as f32).abs() < 4.0 &&
+        (c.z * 255.0 - rgb[2] as f32).abs() < 4.0
+    };
+
+    assert!(close(ColourMap::Viridis.map(0.0), [0x44, 0x01, 0x54]));
+    assert!(close(ColourMap::Viridis.map(0.5), [0x21, 0x91, 0x8c]));
+    assert!(close(ColourMap::Viridis.map(1.0), [0xfd, 0xe7, 0x25]));
+
+    // Turbo is blue near the start, green in the middle, and ends dark red.
+    let start = ColourMap::Turbo.map(0.1);
+    let middle = ColourMap::Turbo.map(0.5);
+    let end = ColourMap::Turbo.map(1.0);
+    assert!(start.z > start.x && start.z > start.y);
+    assert!(middle.y > middle.x && middle.y > middle.z);
+    assert!(end.x > end.y && end.x > end.z);
+}
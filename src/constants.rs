@@ -23,3 +23,5 @@ pub const BOLTZMANNS_CONSTANT: f64 = 1.3806488e-23;
 pub const SPEED_OF_LIGHT: f64 = 299792458.0;
 
 pub const WIENS_CONSTANT: f64 = 2.897772126e-3;
+
+pub const LUMINOUS_EFFICACY: f64 = 683.0;
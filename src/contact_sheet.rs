@@ -0,0 +1,144 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use output::ColourSpace;
+use tonemap_unit::TonemapUnit;
+use vector3::Vector3;
+
+/// Saves the image at increasing numbers of gathered batches, so that the
+/// convergence of a render can be shown as a montage.
+pub struct ContactSheet {
+    /// The numbers of gathered batches at which to save, in increasing order.
+    milestones: Vec<u32>,
+
+    /// The index of the next milestone to save.
+    next_milestone: usize,
+
+    /// The path of the files, to which the number of batches and ".png"
+    /// is appended.
+    path_prefix: String,
+
+    /// The tonemap unit for the saved images, separate from the one that
+    /// produces the displayed images.
+    tonemap_unit: TonemapUnit,
+
+    /// The width of the canvas (in pixels).
+    image_width: u32,
+
+    /// The height of the canvas (in pixels).
+    image_height: u32
+}
+
+impl ContactSheet {
+    /// Creates a contact sheet for a canvas of the specified size, that
+    /// saves an image to `<path_prefix><batches>.png` at every milestone.
+    pub fn new(width: u32, height: u32, milestones: Vec<u32>, path_prefix: String)
+               -> ContactSheet {
+        assert!(milestones.windows(2).all(|w| w[0] < w[1]),
+                "milestones must be increasing");
+        ContactSheet {
+            milestones: milestones,
+            next_milestone: 0,
+            path_prefix: path_prefix,
+            tonemap_unit: TonemapUnit::new(width, height),
+            image_width: width,
+            image_height: height
+        }
+    }
+
+    /// Returns the milestones 1, 4, 16, ... up to and including `max`.
+    pub fn powers_of_four(max: u32) -> Vec<u32> {
+        let mut milestones = Vec::new();
+        let mut n = 1;
+        while n <= max {
+            milestones.push(n);
+            n = n * 4;
+        }
+        milestones
+    }
+
+    /// Saves the tristimulus buffer if `batches` is the next milestone.
+    /// Milestones that were passed already, for instance because the render
+    /// was resumed, are skipped.
+    pub fn record(&mut self, batches: u32, tristimuli: &[Vector3]) {
+        let mut passed = None;
+        while self.next_milestone < self.milestones.len()
+            && self.milestones[self.next_milestone] <= batches {
+            passed = Some(self.milestones[self.next_milestone]);
+            self.next_milestone += 1;
+        }
+        if passed != Some(batches) { return; }
+
+        self.tonemap_unit.tonemap(tristimuli);
+        let path = format!("{}{:04}.png", self.path_prefix, batches);
+        match ::output::save_png(&path, &self.tonemap_unit.rgb_buffer,
+                                 self.image_width, self.image_height, ColourSpace::Srgb) {
+            Ok(_) => println!("wrote contact sheet image to {}", path),
+            Err(reason) => println!("failed to write contact sheet image: {}", reason)
+        }
+    }
+}
+
+#[test]
+fn contact_sheet_saves_milestones() {
+    use std::env;
+    use std::fs;
+    use gather_unit::GatherUnit;
+
+    let dir = env::temp_dir().join(format!("robigo-contact-sheet-{}", ::rand::random::<u32>()));
+    fs::create_dir(&dir).unwrap();
+    let prefix = dir.join("frame").to_str().unwrap().to_string();
+
+    let mut unit = GatherUnit::new_empty(4, 4);
+    unit.contact_sheet = Some(ContactSheet::new(4, 4, ContactSheet::powers_of_four(64), prefix));
+    let batch: Vec<Vector3> = (0 .. 16).map(|i| Vector3::new(1.0, i as f32, 1.0)).collect();
+    for _ in 0 .. 64 {
+        unit.accumulate(&batch);
+    }
+
+    let mut files: Vec<String> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_str().unwrap().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["frame0001.png", "frame0004.png", "frame0016.png", "frame0064.png"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn contact_sheet_skips_passed_milestones() {
+    use std::env;
+    use std::fs;
+
+    let dir = env::temp_dir().join(format!("robigo-contact-sheet-{}", ::rand::random::<u32>()));
+    fs::create_dir(&dir).unwrap();
+    let prefix = dir.join("frame").to_str().unwrap().to_string();
+
+    // A render that resumes after 10 batches has passed milestones 1 and 4.
+    let mut sheet = ContactSheet::new(2, 2, ContactSheet::powers_of_four(64), prefix);
+    let batch: Vec<Vector3> = (0 .. 4).map(|_| Vector3::new(1.0, 1.0, 1.0)).collect();
+    for batches in 11 .. 65 {
+        sheet.record(batches, &batch);
+    }
+
+    let mut files: Vec<String> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().file_name().to_str().unwrap().to_string())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["frame0016.png", "frame0064.png"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,83 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use sky::SkyModel;
+use vector3::Vector3;
+
+/// Determines what rays see when they escape the scene.
+pub enum Environment {
+    /// The utter darkness of The Void.
+    Void,
+
+    /// A fixed image behind the scene, seen by camera rays only.
+    Plate(BackgroundPlate),
+
+    /// A daylight sky, that illuminates the scene.
+    Sky(SkyModel),
+
+    /// A radiance that depends on the (unit) direction of the ray and on the
+    /// wavelength, such as a uniform sky or a gradient towards the horizon.
+    /// It illuminates the scene, like the sky.
+    Background(Box<Fn(Vector3, f32) -> f32 + Sync + Send>)
+}
+
+/// An image that is shown where camera rays escape, for compositing the
+/// render onto a photograph. It is looked up by screen coordinate, not by
+/// direction, and it does not illuminate the scene.
+pub struct BackgroundPlate {
+    /// The width of the image (in pixels).
+    width: u32,
+
+    /// The height of the image (in pixels).
+    height: u32,
+
+    /// The linear RGB values of the pixels, row by row.
+    pixels: Vec<Vector3>
+}
+
+impl BackgroundPlate {
+    /// Creates a plate from linear RGB pixels, stored row by row. The plate
+    /// should have the same aspect ratio as the rendered image.
+    pub fn new(width: u32, height: u32, pixels: Vec<Vector3>) -> BackgroundPlate {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        BackgroundPlate {
+            width: width,
+            height: height,
+            pixels: pixels
+        }
+    }
+
+    /// Returns the pixel at the screen position, where x ranges from -1.0
+    /// (left) to 1.0 (right), and y has the same units.
+    fn get_pixel(&self, x: f32, y: f32) -> Vector3 {
+        let aspect_ratio = ::screen::aspect_ratio(self.width, self.height);
+        let u = (x + 1.0) * 0.5;
+        let v = (y * aspect_ratio + 1.0) * 0.5;
+        let i = ((u * self.width as f32) as i32).max(0).min(self.width as i32 - 1);
+        let j = ((v * self.height as f32) as i32).max(0).min(self.height as i32 - 1);
+        self.pixels[(j * self.width as i32 + i) as usize]
+    }
+
+    /// Returns the intensity of the plate at the specified screen position
+    /// and wavelength. The RGB value is turned into a spectrum crudely, by
+    /// splitting the visible range into a blue, green and red band.
+    pub fn get_intensity(&self, x: f32, y: f32, wavelength: f32) -> f32 {
+        let rgb = self.get_pixel(x, y);
+        if wavelength < 490.0 { rgb.z }
+        else if wavelength < 580.0 { rgb.y }
+        else { rgb.x }
+    }
+}
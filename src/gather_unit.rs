@@ -14,45 +14,170 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::f32::consts::PI;
 use std::fs::File;
-use std::io::{Write, BufReader, BufWriter};
+use std::io::{self, Error, ErrorKind, Read, Write, BufReader, BufWriter};
 use std::iter::repeat;
-use std::mem::transmute;
+use std::path::Path;
+use constants::LUMINOUS_EFFICACY;
+use contact_sheet::ContactSheet;
+use importance_map::ImportanceMap;
 use read;
 use vector3::Vector3;
 
+/// The file that the gather unit is saved to after every gather, and that
+/// a render is resumed from.
+pub const CHECKPOINT_PATH: &str = "buffer.raw";
+
+/// Identifies a file written by `GatherUnit::save`, and its version.
+const CHECKPOINT_MAGIC: &[u8; 8] = b"RLGATHR2";
+
 pub struct GatherUnit {
+    /// The width of the canvas (in pixels).
+    image_width: u32,
+
     /// The buffer of tristimulus values.
     pub tristimulus_buffer: Vec<Vector3>,
 
     /// A buffer that contains compensation for rounding errors in summing.
-    compensation_buffer: Vec<Vector3>
+    compensation_buffer: Vec<Vector3>,
+
+    /// The per-pixel sum of the luminance of all batches accumulated so
+    /// far, for estimating the variance.
+    luminance_sum: Vec<f64>,
+
+    /// The per-pixel sum of the squared luminance of all batches
+    /// accumulated so far.
+    luminance_sq_sum: Vec<f64>,
+
+    /// The number of batches accumulated so far, including those of the
+    /// render that was resumed, if any.
+    batches: u32,
+
+    /// The number of batches accumulated so far, per pixel. This
+    /// differs from `batches` only for pixels in cleared regions.
+    pixel_batches: Vec<u32>,
+
+    /// The number of photons that every pixel received so far. Unlike
+    /// `pixel_batches`, this reflects adaptive sampling, which concentrates
+    /// photons on the noisy parts of the image.
+    photon_counts: Vec<f64>,
+
+    /// Whether a pixel reached `max_pixel_photons`, per pixel. Such pixels
+    /// ignore all further batches.
+    capped: Vec<bool>,
+
+    /// The maximum number of photons that a pixel accumulates, if any. A
+    /// batch that would take a pixel over it is not accumulated into that
+    /// pixel, and the pixel is not sampled adaptively any more. This bounds
+    /// the work spent on pixels whose noise never converges, such as pixels
+    /// on a hard edge. It only applies to batches accumulated with
+    /// `accumulate_counted`.
+    pub max_pixel_photons: Option<f64>,
+
+    /// Two independent half images, when enabled with `split_halves`.
+    halves: Option<HalfBuffers>,
+
+    /// A cheap estimate of the image that is shown while there are only
+    /// few batches, when set with `seed_preview`.
+    preview: Option<Preview>,
+
+    /// Saves the image at milestones of the number of batches, if set.
+    pub contact_sheet: Option<ContactSheet>,
+
+    /// Whether the buffer is saved after every gather, so that rendering
+    /// can be continued later.
+    pub save_progress: bool
+}
+
+/// Two images that receive alternating batches, so that their difference
+/// can be used as an estimate of the noise without a reference image.
+struct HalfBuffers {
+    /// The sum of the even and odd batches respectively.
+    buffers: [Vec<Vector3>; 2],
+
+    /// The number of batches accumulated into each buffer.
+    batches: [u32; 2]
+}
+
+/// A preview image that is blended with the accumulated batches, as if it
+/// were `weight` batches. Its influence fades as real batches accumulate.
+struct Preview {
+    /// The preview image, in the units of a single batch.
+    buffer: Vec<Vector3>,
+
+    /// The number of batches that the preview counts as.
+    weight: f32
 }
 
 impl GatherUnit {
     /// Constructs a new GatherUnit that will gather a canvas
-    /// of the specified size.
+    /// of the specified size, continuing a previous render if there is one.
     pub fn new(width: u32, height: u32) -> GatherUnit {
-        let sz = (width * height) as usize;
-        let mut unit = GatherUnit {
-            tristimulus_buffer: repeat(Vector3::zero()).take(sz).collect(),
-            compensation_buffer: repeat(Vector3::zero()).take(sz).collect()
-        };
+        let mut unit = GatherUnit::new_empty(width, height);
+        unit.save_progress = true;
 
         // Try to continue a previous render.
-        unit.read();
+        match unit.load(CHECKPOINT_PATH) {
+            Ok(()) => println!("resuming the render from {}", CHECKPOINT_PATH),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => { },
+            Err(err) => println!("not resuming from {}: {}", CHECKPOINT_PATH, err)
+        }
 
         unit
     }
 
+    /// Constructs a GatherUnit with an empty canvas, that does not continue
+    /// a previous render, nor save its progress.
+    pub fn new_empty(width: u32, height: u32) -> GatherUnit {
+        let sz = (width * height) as usize;
+        GatherUnit {
+            image_width: width,
+            tristimulus_buffer: repeat(Vector3::zero()).take(sz).collect(),
+            compensation_buffer: repeat(Vector3::zero()).take(sz).collect(),
+            luminance_sum: repeat(0.0).take(sz).collect(),
+            luminance_sq_sum: repeat(0.0).take(sz).collect(),
+            batches: 0,
+            pixel_batches: repeat(0).take(sz).collect(),
+            photon_counts: repeat(0.0).take(sz).collect(),
+            capped: repeat(false).take(sz).collect(),
+            max_pixel_photons: None,
+            halves: None,
+            preview: None,
+            contact_sheet: None,
+            save_progress: false
+        }
+    }
+
     /// Add the results of the PlotUnit to the canvas.
     pub fn accumulate(&mut self, tristimuli: &[Vector3]) {
+        self.accumulate_pixels(tristimuli, None);
+    }
+
+    /// Adds the results of a PlotUnit to the canvas like `accumulate`, and
+    /// the number of photons that it plotted per pixel to the counts that
+    /// `sample_count_image` shows. Pixels that would receive more than
+    /// `max_pixel_photons` in total are capped instead.
+    pub fn accumulate_counted(&mut self, tristimuli: &[Vector3], photon_counts: &[f32]) {
+        self.accumulate_pixels(tristimuli, Some(photon_counts));
+    }
+
+    fn accumulate_pixels(&mut self, tristimuli: &[Vector3], photon_counts: Option<&[f32]>) {
+        if let (Some(counts), Some(max)) = (photon_counts, self.max_pixel_photons) {
+            for (i, &n) in counts.iter().enumerate() {
+                if self.photon_counts[i] + n as f64 > max { self.capped[i] = true; }
+            }
+        }
+        let capped = &self.capped;
+
         let accs = self.tristimulus_buffer.iter_mut();
         let comps = self.compensation_buffer.iter_mut();
         let pixels = tristimuli.iter();
 
         // Loop through all the pixels, and add the values.
-        for ((comp, acc), px) in comps.zip(accs).zip(pixels) {
+        for (i, ((comp, acc), px)) in comps.zip(accs).zip(pixels).enumerate() {
+            if capped[i] { continue; }
+
             // What we want to add, is the real value to add (px),
             // minus compensation for previous errors.
             let extra = *px - *comp;
@@ -61,34 +186,759 @@ impl GatherUnit {
             *comp = (sum - *acc) - extra;
             *acc = sum;
         }
+
+        // Keep track of the luminance moments for the convergence metric.
+        let sums = self.luminance_sum.iter_mut();
+        let sq_sums = self.luminance_sq_sum.iter_mut();
+        for (i, ((sum, sq_sum), px)) in sums.zip(sq_sums).zip(tristimuli.iter()).enumerate() {
+            if capped[i] { continue; }
+            let y = px.y as f64;
+            *sum += y;
+            *sq_sum += y * y;
+        }
+        // Route the batch into the half buffer whose turn it is.
+        if let Some(ref mut halves) = self.halves {
+            let i = (halves.batches[0] > halves.batches[1]) as usize;
+            for (acc, px) in halves.buffers[i].iter_mut().zip(tristimuli.iter()) {
+                *acc = *acc + *px;
+            }
+            halves.batches[i] += 1;
+        }
+
+        for (i, n) in self.pixel_batches.iter_mut().enumerate() {
+            if !capped[i] { *n += 1; }
+        }
+        if let Some(counts) = photon_counts {
+            for (i, (acc, &n)) in self.photon_counts.iter_mut().zip(counts.iter()).enumerate() {
+                if !capped[i] { *acc += n as f64; }
+            }
+        }
+        self.batches += 1;
+
+        if let Some(ref mut sheet) = self.contact_sheet {
+            sheet.record(self.batches, &self.tristimulus_buffer);
+        }
+    }
+
+    /// Returns whether every pixel reached `max_pixel_photons`, so that
+    /// further batches change nothing.
+    pub fn all_pixels_capped(&self) -> bool {
+        self.capped.iter().all(|&capped| capped)
+    }
+
+    /// Starts routing alternating batches into two separate half images,
+    /// in addition to the full image.
+    pub fn split_halves(&mut self) {
+        let sz = self.tristimulus_buffer.len();
+        self.halves = Some(HalfBuffers {
+            buffers: [repeat(Vector3::zero()).take(sz).collect(),
+                      repeat(Vector3::zero()).take(sz).collect()],
+            batches: [0, 0]
+        });
     }
 
-    /// Saves the tristimulus buffer to a file, so that rendering
-    /// can be resumed later.
-    pub fn save(&self) {
-        let file = File::create("buffer.raw").ok()
-                       .expect("failed to open file");
+    /// Returns the root mean square difference in luminance between the two
+    /// half images, relative to their mean luminance. This approximates the
+    /// noise in the half images. Returns `None` when the halves are not
+    /// enabled or when either half is still empty.
+    pub fn half_difference(&self) -> Option<f32> {
+        let halves = match self.halves {
+            Some(ref halves) => halves,
+            None => return None
+        };
+        if halves.batches[0] == 0 || halves.batches[1] == 0 { return None; }
+
+        let n_even = halves.batches[0] as f64;
+        let n_odd = halves.batches[1] as f64;
+        let mut sum_sq_diff = 0.0;
+        let mut sum_mean = 0.0;
+        for (even, odd) in halves.buffers[0].iter().zip(halves.buffers[1].iter()) {
+            let y_even = even.y as f64 / n_even;
+            let y_odd = odd.y as f64 / n_odd;
+            sum_sq_diff += (y_even - y_odd) * (y_even - y_odd);
+            sum_mean += (y_even + y_odd) * 0.5;
+        }
+
+        if sum_mean <= 0.0 { return None; }
+        let n = halves.buffers[0].len() as f64;
+        Some(((sum_sq_diff / n).sqrt() / (sum_mean / n)) as f32)
+    }
+
+    /// Returns the root mean square of the relative standard error of the
+    /// luminance of all lit pixels, estimated from the variance between the
+    /// batches accumulated so far. The render can be considered converged
+    /// when this falls below a threshold. Returns `None` when there are not
+    /// enough batches yet to estimate the variance.
+    pub fn relative_error(&self) -> Option<f32> {
+        let mut sum_sq_rel_error = 0.0;
+        let mut lit_pixels = 0u32;
+        for i in 0 .. self.pixel_batches.len() {
+            if let Some((mean, variance)) = self.luminance_statistics(i) {
+                if mean <= 0.0 { continue; }
+                sum_sq_rel_error += variance / (mean * mean);
+                lit_pixels += 1;
+            }
+        }
+
+        if lit_pixels == 0 { return None; }
+        Some((sum_sq_rel_error / lit_pixels as f64).sqrt() as f32)
+    }
+
+    /// Returns the mean luminance of a batch in pixel `i`, and the variance
+    /// of that mean, or `None` if fewer than two batches were accumulated.
+    fn luminance_statistics(&self, i: usize) -> Option<(f64, f64)> {
+        let n = self.pixel_batches[i];
+        if n < 2 { return None; }
+        let n = n as f64;
+        let mean = self.luminance_sum[i] / n;
+
+        // The unbiased variance of a batch, divided by the number of
+        // batches, is the variance of the mean.
+        let sq_mean = self.luminance_sq_sum[i] / n;
+        let variance = (sq_mean - mean * mean).max(0.0) * n / (n - 1.0);
+        Some((mean, variance / n))
+    }
+
+    /// Returns the estimated variance of the mean luminance of a batch, per
+    /// pixel. It is the square of the noise in the image (in the units of a
+    /// single batch), and decreases as more batches are accumulated. Pixels
+    /// with fewer than two batches have a variance of 0.
+    pub fn variance_buffer(&self) -> Vec<f32> {
+        (0 .. self.pixel_batches.len()).map(|i| {
+            self.luminance_statistics(i).map_or(0.0, |(_, variance)| variance as f32)
+        }).collect()
+    }
+
+    /// Returns the root mean square of the standard error of the mean
+    /// luminance over all pixels, in the units of a single batch. Unlike
+    /// `relative_error`, it is not relative to the brightness of the pixels.
+    /// Returns `None` when there are not enough batches yet.
+    pub fn rms_noise(&self) -> Option<f32> {
+        let mut sum_variance = 0.0;
+        let mut pixels = 0u32;
+        for i in 0 .. self.pixel_batches.len() {
+            if let Some((_, variance)) = self.luminance_statistics(i) {
+                sum_variance += variance;
+                pixels += 1;
+            }
+        }
+
+        if pixels == 0 { return None; }
+        Some((sum_variance / pixels as f64).sqrt() as f32)
+    }
+
+    /// Divides the image into `tiles_x` by `tiles_y` tiles, and returns a
+    /// map that samples every tile in proportion to its noise: the root
+    /// mean square of the standard error of the pixels in the tile. Capped
+    /// pixels do not count, and tiles of only capped pixels are skipped.
+    pub fn importance_map(&self, tiles_x: u32, tiles_y: u32) -> ImportanceMap {
+        let w = self.image_width;
+        let h = self.image_height();
+        let variance = self.variance_buffer();
+        let n = (tiles_x * tiles_y) as usize;
+        let tile = |x: u32, y: u32| ((y * tiles_y / h) * tiles_x + x * tiles_x / w) as usize;
+        let mut sums = vec![0.0f32; n];
+        let mut counts = vec![0u32; n];
+        let mut reachable = vec![false; n];
+        for y in 0 .. h {
+            for x in 0 .. w {
+                let i = (y * w + x) as usize;
+                if self.capped[i] { continue; }
+                sums[tile(x, y)] += variance[i];
+                counts[tile(x, y)] += 1;
+
+                // Photons are spread over neighbouring pixels, so a tile
+                // must be sampled while pixels near it are not capped.
+                for ny in y.saturating_sub(2) .. (y + 3).min(h) {
+                    for nx in x.saturating_sub(2) .. (x + 3).min(w) {
+                        reachable[tile(nx, ny)] = true;
+                    }
+                }
+            }
+        }
+
+        let noise: Vec<Option<f32>> = (0 .. n).map(|t| {
+            if !reachable[t] { None }
+            else if counts[t] == 0 { Some(0.0) }
+            else { Some((sums[t] / counts[t] as f32).sqrt()) }
+        }).collect();
+        ImportanceMap::new(tiles_x, tiles_y, &noise)
+    }
+
+    /// Discards everything gathered for the pixels in the rectangle from
+    /// (`x0`, `y0`) up to but excluding (`x1`, `y1`), so that the region can
+    /// be rendered again after the scene changed. The rest of the image is
+    /// preserved. Note that a change usually affects the indirect light
+    /// elsewhere as well, so the cleared region must include all pixels that
+    /// the change can affect for the result to be correct.
+    pub fn clear_region(&mut self, x0: u32, y0: u32, x1: u32, y1: u32) {
+        let w = self.image_width;
+        let h = self.tristimulus_buffer.len() as u32 / w;
+        for y in y0 .. y1.min(h) {
+            for x in x0 .. x1.min(w) {
+                let i = (y * w + x) as usize;
+                self.tristimulus_buffer[i] = Vector3::zero();
+                self.compensation_buffer[i] = Vector3::zero();
+                self.luminance_sum[i] = 0.0;
+                self.luminance_sq_sum[i] = 0.0;
+                self.pixel_batches[i] = 0;
+                self.photon_counts[i] = 0.0;
+                self.capped[i] = false;
+            }
+        }
+
+        // The half images count batches for the image as a whole, so they
+        // cannot be cleared per pixel. Start them afresh instead.
+        if self.halves.is_some() {
+            self.split_halves();
+        }
+    }
+
+    /// Returns the number of batches accumulated for the specified pixel.
+    pub fn sample_count(&self, x: u32, y: u32) -> u32 {
+        self.pixel_batches[(y * self.image_width + x) as usize]
+    }
+
+    /// Returns the number of photons accumulated for the specified pixel, as
+    /// counted by `accumulate_counted`.
+    pub fn photon_count(&self, x: u32, y: u32) -> f64 {
+        self.photon_counts[(y * self.image_width + x) as usize]
+    }
+
+    /// Returns the luminance of the pixel in cd/m^2. The image must have
+    /// been rendered uniformly with `photon_count` photons in total, and an
+    /// emissive intensity of 1.0 is taken to be a spectral radiance of
+    /// `radiance_scale` W/(sr m^2 nm).
+    pub fn luminance(&self, x: u32, y: u32, photon_count: u64, radiance_scale: f32) -> f32 {
+        // Plot units map the screen onto the pixel centres, so the edges of
+        // the screen fall on the centres of the outer pixels. An inner pixel
+        // therefore receives the photons of one (width - 1) * (height - 1)th
+        // of the screen.
+        let w = self.image_width as f64;
+        let h = (self.tristimulus_buffer.len() as u32 / self.image_width) as f64;
+        let photons_per_pixel = photon_count as f64 / ((w - 1.0) * (h - 1.0));
+
+        // The CIE Y value is the integral of the radiance against the
+        // luminous efficiency function, estimated by averaging over
+        // wavelengths that are uniformly distributed over 400 nm.
+        let cie_y = self.tristimulus_buffer[(y * self.image_width + x) as usize].y as f64;
+        let integral = cie_y / photons_per_pixel * 400.0;
+        (LUMINOUS_EFFICACY * integral * radiance_scale as f64) as f32
+    }
+
+    /// Returns the illuminance in lux on the surface seen in the pixel,
+    /// assuming that the surface is diffuse with the specified reflectance.
+    /// See `luminance` for the other parameters.
+    pub fn illuminance(&self, x: u32, y: u32, photon_count: u64, radiance_scale: f32,
+                       reflectance: f32) -> f32 {
+        // A diffuse surface reflects a radiance of reflectance * E / pi.
+        PI * self.luminance(x, y, photon_count, radiance_scale) / reflectance
+    }
+
+    /// Returns an 8-bit RGB image of the number of photons per pixel, for
+    /// inspecting where samples went. The most sampled pixels are white;
+    /// the values are linear, not gamma encoded.
+    pub fn sample_count_image(&self) -> Vec<u8> {
+        let max_count = self.photon_counts.iter().fold(0.0f64, |a, &n| a.max(n));
+        self.photon_counts.iter().flat_map(|&n| {
+            let grey = if max_count > 0.0 { (n * 255.0 / max_count) as u8 } else { 0 };
+            vec![grey, grey, grey].into_iter()
+        }).collect()
+    }
+
+    /// Sets a cheap estimate of the image, such as one produced by
+    /// `TraceUnit::render_preview`, to display before the first batches are
+    /// in. The preview is in the units of a single batch, and it counts as
+    /// `weight` batches. It only affects `display_buffer`, and because its
+    /// weight stays the same, real batches wash it out over time.
+    pub fn seed_preview(&mut self, preview: &[Vector3], weight: f32) {
+        self.preview = Some(Preview {
+            buffer: preview.to_vec(),
+            weight: weight
+        });
+    }
+
+    /// Returns the tristimulus buffer, where pixels in cleared regions are
+    /// scaled to compensate for the batches that they missed, so that the
+    /// image has a consistent brightness. If there is a preview, it is
+    /// blended in with its weight.
+    pub fn display_buffer(&self) -> Vec<Vector3> {
+        let batches = self.batches as f32;
+        let pixels = self.tristimulus_buffer.iter().zip(self.pixel_batches.iter());
+        match self.preview {
+            None => pixels.map(|(&px, &n)| {
+                if n == 0 || n == self.batches { px } else { px * (batches / n as f32) }
+            }).collect(),
+            Some(ref preview) => pixels.zip(preview.buffer.iter()).map(|((&px, &n), &pre)| {
+                // Average the batches and the preview, and scale the mean
+                // like the sum of the batches, but at least like one batch.
+                let mean = (px + pre * preview.weight) * (1.0 / (n as f32 + preview.weight));
+                mean * batches.max(1.0)
+            }).collect()
+        }
+    }
+
+    /// Saves everything that was accumulated to a file, so that rendering
+    /// can be resumed later with `load`. The file is in native byte order:
+    /// the magic bytes, the width, height and number of batches, and then
+    /// per pixel the tristimulus value and its compensation, the luminance
+    /// sums, the number of batches and the number of photons.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = try!(File::create(path));
         let mut file = BufWriter::new(file);
-        let data = self.tristimulus_buffer.iter()
-                       .chain(self.compensation_buffer.iter());
-        for trist in data {
-            let xyz: &[u8; 12] = unsafe { transmute(trist) };
-            file.write_all(xyz).ok().expect("failed to write raw buffer");
-        }
-    }
-
-    /// Reads the tristimulus buffer from a file, to resume rendering.
-    fn read(&mut self) {
-        if let Ok(ref file) = File::open("buffer.raw") {
-            let mut file = BufReader::new(file);
-            let data = self.tristimulus_buffer.iter_mut()
-                           .chain(self.compensation_buffer.iter_mut());
-            for trist in data {
-                let xyz: &mut [u8; 12] = unsafe { transmute(trist) };
-                read::read_into(&mut file, xyz).ok()
-                     .expect("failed to read from raw buffer");
+        try!(file.write_all(CHECKPOINT_MAGIC));
+        try!(write_u32(&mut file, self.image_width));
+        try!(write_u32(&mut file, self.image_height()));
+        try!(write_u32(&mut file, self.batches));
+
+        for i in 0 .. self.tristimulus_buffer.len() {
+            try!(write_vector3(&mut file, self.tristimulus_buffer[i]));
+            try!(write_vector3(&mut file, self.compensation_buffer[i]));
+            try!(write_f64(&mut file, self.luminance_sum[i]));
+            try!(write_f64(&mut file, self.luminance_sq_sum[i]));
+            try!(write_u32(&mut file, self.pixel_batches[i]));
+            try!(write_f64(&mut file, self.photon_counts[i]));
+        }
+
+        file.flush()
+    }
+
+    /// Replaces everything that was accumulated with the contents of a file
+    /// written by `save`, to resume rendering. The file must be for an image
+    /// of the same size. If it cannot be loaded, the unit is not changed.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let file = try!(File::open(path));
+        let mut file = BufReader::new(file);
+
+        let mut magic = [0u8; 8];
+        try!(read::read_into(&mut file, &mut magic));
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a saved gather unit"));
+        }
+
+        let width = try!(read_u32(&mut file));
+        let height = try!(read_u32(&mut file));
+        if width != self.image_width || height != self.image_height() {
+            let msg = format!("the saved image is {}x{}, but the image is {}x{}",
+                              width, height, self.image_width, self.image_height());
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let batches = try!(read_u32(&mut file));
+
+        // Read into new buffers first, so that a truncated file does not
+        // leave a partially loaded image behind.
+        let sz = self.tristimulus_buffer.len();
+        let mut tristimulus_buffer = Vec::with_capacity(sz);
+        let mut compensation_buffer = Vec::with_capacity(sz);
+        let mut luminance_sum = Vec::with_capacity(sz);
+        let mut luminance_sq_sum = Vec::with_capacity(sz);
+        let mut pixel_batches = Vec::with_capacity(sz);
+        let mut photon_counts = Vec::with_capacity(sz);
+        for _ in 0 .. sz {
+            tristimulus_buffer.push(try!(read_vector3(&mut file)));
+            compensation_buffer.push(try!(read_vector3(&mut file)));
+            luminance_sum.push(try!(read_f64(&mut file)));
+            luminance_sq_sum.push(try!(read_f64(&mut file)));
+            pixel_batches.push(try!(read_u32(&mut file)));
+            photon_counts.push(try!(read_f64(&mut file)));
+        }
+
+        self.tristimulus_buffer = tristimulus_buffer;
+        self.compensation_buffer = compensation_buffer;
+        self.luminance_sum = luminance_sum;
+        self.luminance_sq_sum = luminance_sq_sum;
+        self.pixel_batches = pixel_batches;
+        self.photon_counts = photon_counts;
+        self.capped = repeat(false).take(sz).collect();
+        self.batches = batches;
+        Ok(())
+    }
+
+    /// Writes the image as a Radiance HDR file with CIE XYZ values, so that
+    /// it can be tonemapped again later without rendering it again. The
+    /// values are the mean of a batch, like those of `display_buffer`
+    /// divided by the number of batches, so they do not grow as the render
+    /// continues.
+    pub fn save_hdr<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let scale = 1.0 / self.batches.max(1) as f32;
+        let xyz: Vec<f32> = self.display_buffer().iter().flat_map(|&px| {
+            vec![px.x * scale, px.y * scale, px.z * scale].into_iter()
+        }).collect();
+        ::output::save_hdr(path, &xyz, self.image_width, self.image_height())
+    }
+
+    /// Returns the height of the canvas (in pixels).
+    fn image_height(&self) -> u32 {
+        self.tristimulus_buffer.len() as u32 / self.image_width
+    }
+}
+
+fn write_u32<W: Write>(writer: &mut W, x: u32) -> io::Result<()> {
+    writer.write_all(&x.to_ne_bytes())
+}
+
+fn write_f64<W: Write>(writer: &mut W, x: f64) -> io::Result<()> {
+    writer.write_all(&x.to_ne_bytes())
+}
+
+fn write_vector3<W: Write>(writer: &mut W, v: Vector3) -> io::Result<()> {
+    try!(writer.write_all(&v.x.to_ne_bytes()));
+    try!(writer.write_all(&v.y.to_ne_bytes()));
+    writer.write_all(&v.z.to_ne_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    try!(read::read_into(reader, &mut bytes));
+    Ok(u32::from_ne_bytes(bytes))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    try!(read::read_into(reader, &mut bytes));
+    Ok(f32::from_ne_bytes(bytes))
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> io::Result<f64> {
+    let mut bytes = [0u8; 8];
+    try!(read::read_into(reader, &mut bytes));
+    Ok(f64::from_ne_bytes(bytes))
+}
+
+fn read_vector3<R: Read>(reader: &mut R) -> io::Result<Vector3> {
+    let x = try!(read_f32(reader));
+    let y = try!(read_f32(reader));
+    let z = try!(read_f32(reader));
+    Ok(Vector3::new(x, y, z))
+}
+
+#[test]
+fn relative_error_decreases_with_batches() {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([3, 5, 7, 11]);
+    let mut unit = GatherUnit::new_empty(4, 4);
+    assert_eq!(unit.relative_error(), None);
+
+    let mut errors = Vec::new();
+    for i in 1 .. 129 {
+        let batch: Vec<Vector3> = (0 .. 16).map(|_| {
+            let y = rng.gen::<f32>() * 2.0;
+            Vector3::new(y, y, y)
+        }).collect();
+        unit.accumulate(&batch);
+        if i == 8 || i == 32 || i == 128 {
+            errors.push(unit.relative_error().unwrap());
+        }
+    }
+
+    assert!(errors[0] > errors[1]);
+    assert!(errors[1] > errors[2]);
+
+    // The standard error should roughly halve for every four times as many
+    // batches.
+    assert!(errors[1] / errors[2] > 1.5 && errors[1] / errors[2] < 2.5);
+}
+
+#[test]
+fn variance_decreases_with_batches() {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([29, 31, 37, 41]);
+    let mut unit = GatherUnit::new_empty(4, 4);
+    assert_eq!(unit.rms_noise(), None);
+
+    let mut noise = Vec::new();
+    for i in 1 .. 129 {
+        let batch: Vec<Vector3> = (0 .. 16).map(|_| {
+            let y = rng.gen::<f32>() * 2.0;
+            Vector3::new(y, y, y)
+        }).collect();
+        unit.accumulate(&batch);
+        if i == 8 || i == 32 || i == 128 {
+            let variances = unit.variance_buffer();
+            assert!(variances.iter().all(|&v| v > 0.0));
+            noise.push(unit.rms_noise().unwrap());
+        }
+    }
+
+    assert!(noise[0] > noise[1]);
+    assert!(noise[1] > noise[2]);
+
+    // A batch is uniform on [0, 2), with a variance of 1/3, so the variance
+    // of the mean of 128 batches is 1/384.
+    let expected = (1.0f32 / 384.0).sqrt();
+    assert!((noise[2] - expected).abs() < expected * 0.25);
+
+    // Clearing a region discards its variance along with the image.
+    unit.clear_region(0, 0, 2, 2);
+    assert_eq!(unit.variance_buffer()[0], 0.0);
+    assert!(unit.variance_buffer()[15] > 0.0);
+}
+
+#[test]
+fn half_buffers_converge() {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([13, 17, 19, 23]);
+    let mut unit = GatherUnit::new_empty(4, 4);
+    assert_eq!(unit.half_difference(), None);
+    unit.split_halves();
+
+    let mut differences = Vec::new();
+    for i in 1 .. 257 {
+        let batch: Vec<Vector3> = (0 .. 16).map(|_| {
+            let y = rng.gen::<f32>() * 2.0;
+            Vector3::new(y, y, y)
+        }).collect();
+        unit.accumulate(&batch);
+        if i == 1 { assert_eq!(unit.half_difference(), None); }
+        if i == 16 || i == 64 || i == 256 {
+            differences.push(unit.half_difference().unwrap());
+        }
+    }
+
+    assert!(differences[0] > differences[1]);
+    assert!(differences[1] > differences[2]);
+}
+
+#[test]
+fn clear_region_preserves_the_rest() {
+    let mut unit = GatherUnit::new_empty(4, 4);
+    unit.split_halves();
+    let batch: Vec<Vector3> = (0 .. 16).map(|_| Vector3::new(1.0, 2.0, 3.0)).collect();
+    for _ in 0 .. 3 {
+        unit.accumulate(&batch);
+    }
+    assert_eq!(unit.half_difference(), Some(0.0));
+
+    // Clear the 2x2 pixels at the top right.
+    unit.clear_region(2, 0, 4, 2);
+    for y in 0 .. 4 {
+        for x in 0 .. 4 {
+            let px = unit.tristimulus_buffer[(y * 4 + x) as usize];
+            if x >= 2 && y < 2 {
+                assert_eq!(unit.sample_count(x, y), 0);
+                assert_eq!(px.y, 0.0);
+            } else {
+                assert_eq!(unit.sample_count(x, y), 3);
+                assert_eq!(px.y, 6.0);
             }
         }
     }
 
+    // The half images start over, so they need two new batches to compare.
+    assert_eq!(unit.half_difference(), None);
+
+    // After accumulating again, the display buffer has a consistent
+    // brightness, although the region has fewer samples.
+    unit.accumulate(&batch);
+    assert_eq!(unit.half_difference(), None);
+    unit.accumulate(&batch);
+    assert_eq!(unit.half_difference(), Some(0.0));
+    assert_eq!(unit.sample_count(3, 0), 2);
+    assert_eq!(unit.sample_count(0, 3), 5);
+    let display = unit.display_buffer();
+    assert!(display.iter().all(|px| (px.y - 10.0).abs() < 1.0e-5));
+}
+
+#[test]
+fn sample_count_image_shows_sampling_density() {
+    let mut unit = GatherUnit::new_empty(4, 4);
+    let batch: Vec<Vector3> = (0 .. 16).map(|_| Vector3::new(1.0, 2.0, 3.0)).collect();
+    let counts = [1.0f32; 16];
+    for _ in 0 .. 3 {
+        unit.accumulate_counted(&batch, &counts);
+    }
+
+    // After clearing the left half, it has fewer samples than the right.
+    unit.clear_region(0, 0, 2, 4);
+    unit.accumulate_counted(&batch, &counts);
+    let image = unit.sample_count_image();
+    assert_eq!(image.len(), 4 * 4 * 3);
+    for y in 0 .. 4 {
+        for x in 0 .. 4 {
+            let i = ((y * 4 + x) * 3) as usize;
+            assert!(image[i] == image[i + 1] && image[i] == image[i + 2]);
+            assert_eq!(image[i], if x < 2 { 255 / 4 } else { 255 });
+        }
+    }
+}
+
+#[test]
+fn illuminance_matches_uniform_sky() {
+    use geometry::{Plane, SpacePartitioning};
+    use material::{DiffuseGreyMaterial, EmissiveMaterial};
+    use object::{MaterialBox, Object};
+    use plot_unit::PlotUnit;
+    use scene::Scene;
+    use trace_unit::TraceUnit;
+
+    /// Emits a spectral radiance of one unit at every wavelength.
+    struct FlatEmitter;
+    impl EmissiveMaterial for FlatEmitter {
+        fn get_intensity(&self, _wavelength: f32) -> f32 { 1.0 }
+    }
+
+    // The camera looks at a diffuse wall. Behind the camera is an infinite
+    // emitting plane that covers the hemisphere above the wall, so the
+    // illuminance on the wall is pi times the luminance of the emitter.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let wall = Object::new(Box::new(Plane::new(forward, forward * 5.0)),
+                           MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.5))));
+    let light = Object::new(Box::new(SpacePartitioning::new(forward, forward * -10.0)),
+                            MaterialBox::Emissive(Box::new(FlatEmitter)));
+    let scene = Scene::new(vec![wall, light], ::camera::test_camera);
+
+    let (width, height) = (16, 9);
+    let mut trace_unit = TraceUnit::new(0, width, height, 1024);
+    let mut plot_unit = PlotUnit::new(0, width, height);
+    let mut gather_unit = GatherUnit::new_empty(width, height);
+    let batches = 100;
+    for _ in 0 .. batches {
+        trace_unit.render(&scene);
+        plot_unit.plot(&trace_unit.mapped_photons);
+    }
+    gather_unit.accumulate(&plot_unit.tristimulus_buffer);
+    let photon_count = (batches * trace_unit.mapped_photons.len()) as u64;
+
+    // With a radiance of 0.01 W/(sr m^2 nm), the luminance of the emitter
+    // is 683 lm/W times the integral of the luminous efficiency function.
+    let radiance_scale = 0.01;
+    let efficiency_integral = (380 .. 780).map(|w| ::cie1931::get_tristimulus(w as f32).y)
+                                          .sum::<f32>();
+    let expected = PI * 683.0 * efficiency_integral * radiance_scale;
+
+    // Average over the inner pixels to reduce noise.
+    let mut sum = 0.0;
+    for y in 1 .. height - 1 {
+        for x in 1 .. width - 1 {
+            sum += gather_unit.illuminance(x, y, photon_count, radiance_scale, 0.5);
+        }
+    }
+    let mean = sum / ((width - 2) * (height - 2)) as f32;
+    assert!((mean - expected).abs() < expected * 0.03, "{} lux instead of {}", mean, expected);
+}
+
+#[test]
+fn preview_is_washed_out_by_batches() {
+    use geometry::Plane;
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::{MaterialBox, Object};
+    use plot_unit::PlotUnit;
+    use scene::Scene;
+    use trace_unit::TraceUnit;
+
+    // A diffuse wall in front of the camera, lit by a light behind it. The
+    // preview includes only a single bounce, so it is too dark.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let wall = Object::new(Box::new(Plane::new(forward, forward * 5.0)),
+                           MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let light = Object::new(Box::new(Plane::new(forward, forward * -10.0)),
+                            MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![wall, light], ::camera::test_camera);
+
+    let (width, height) = (8, 8);
+    let mut trace_unit = TraceUnit::new(0, width, height, 1024);
+    let mut plot_unit = PlotUnit::new(0, width, height);
+    let mut seeded = GatherUnit::new_empty(width, height);
+    let mut reference = GatherUnit::new_empty(width, height);
+
+    trace_unit.render_preview(&scene);
+    plot_unit.plot(&trace_unit.mapped_photons);
+    seeded.seed_preview(&plot_unit.tristimulus_buffer, 4.0);
+    plot_unit.clear();
+
+    // Before any batches, the preview is shown instead of black.
+    assert!(seeded.display_buffer().iter().all(|px| px.y > 0.0));
+    assert!(reference.display_buffer().iter().all(|px| px.y == 0.0));
+
+    // Returns the mean relative difference in luminance to the reference.
+    let difference = |seeded: &GatherUnit, reference: &GatherUnit| {
+        let (a, b) = (seeded.display_buffer(), reference.display_buffer());
+        a.iter().zip(b.iter()).map(|(p, q)| (p.y - q.y).abs() / q.y).sum::<f32>()
+            / a.len() as f32
+    };
+
+    let mut differences = Vec::new();
+    for i in 1 .. 401 {
+        trace_unit.render(&scene);
+        plot_unit.plot(&trace_unit.mapped_photons);
+        seeded.accumulate(&plot_unit.tristimulus_buffer);
+        reference.accumulate(&plot_unit.tristimulus_buffer);
+        plot_unit.clear();
+        if i == 4 || i == 400 {
+            differences.push(difference(&seeded, &reference));
+        }
+    }
+
+    // The preview biases the image, but less and less so.
+    assert!(differences[0] > differences[1]);
+    assert!(differences[1] < 0.02, "difference is {}", differences[1]);
+}
+
+#[test]
+fn save_and_load_round_trip() {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    let mut rng = XorShiftRng::from_seed([43, 47, 53, 59]);
+    let mut unit = GatherUnit::new_empty(4, 3);
+    for _ in 0 .. 5 {
+        let batch: Vec<Vector3> = (0 .. 12).map(|_| {
+            Vector3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>())
+        }).collect();
+        unit.accumulate(&batch);
+    }
+    unit.clear_region(0, 0, 1, 1);
+
+    let name = format!("robigo-luculenta-gather-test-{}.raw", process::id());
+    let path = env::temp_dir().join(name);
+    unit.save(&path).unwrap();
+
+    // The loaded unit continues exactly where the saved one stopped.
+    let mut loaded = GatherUnit::new_empty(4, 3);
+    loaded.load(&path).unwrap();
+    for (a, b) in unit.tristimulus_buffer.iter().zip(loaded.tristimulus_buffer.iter()) {
+        assert!(a.x == b.x && a.y == b.y && a.z == b.z);
+    }
+    assert_eq!(loaded.batches, 5);
+    assert_eq!(loaded.sample_count(0, 0), 0);
+    assert_eq!(loaded.sample_count(3, 2), 5);
+    assert_eq!(loaded.relative_error(), unit.relative_error());
+
+    // An image of a different size is not loaded, and it is left intact.
+    let mut other = GatherUnit::new_empty(3, 4);
+    other.accumulate(&[Vector3::new(1.0, 1.0, 1.0); 12]);
+    let err = other.load(&path).err().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(other.batches, 1);
+    assert!(other.tristimulus_buffer.iter().all(|px| px.y == 1.0));
+}
+
+#[test]
+fn save_hdr_preserves_the_range() {
+    use std::env;
+    use std::fs;
+    use std::io::BufReader;
+    use std::process;
+
+    let mut unit = GatherUnit::new_empty(3, 2);
+    let batch: Vec<Vector3> = (0 .. 6).map(|i| Vector3::new(1.0, 100.0 * i as f32, 0.5)).collect();
+    unit.accumulate(&batch);
+    unit.accumulate(&batch);
+
+    let name = format!("robigo-luculenta-gather-test-{}.hdr", process::id());
+    let path = env::temp_dir().join(name);
+    unit.save_hdr(&path).unwrap();
+    let image = ::output::read_hdr(&mut BufReader::new(File::open(&path).unwrap())).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // The values are the mean of a batch, far beyond 1.0 for bright pixels.
+    assert_eq!((image.width, image.height), (3, 2));
+    assert!((image.xyz[16] - 500.0).abs() < 5.0);
+    assert!((image.xyz[4] - 100.0).abs() < 1.0);
 }
@@ -15,14 +15,124 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::f32::consts::PI;
+use std::sync::Arc;
 use intersection::Intersection;
+use quaternion::Quaternion;
 use ray::Ray;
 use vector3::{Vector3, cross, dot};
 
-/// Represents a surface that can be intersected with a ray.
-pub trait Surface {
+/// Represents a surface that can be intersected with a ray. Surfaces are
+/// shared between render threads, so they must be `Send + Sync`.
+pub trait Surface: Send + Sync {
     /// Returns whether the surface was intersected, and if so, where.
     fn intersect(&self, ray: &Ray) -> Option<Intersection>;
+
+    /// Like `intersect`, but ignores hits on the back side of the surface,
+    /// or on the front side if `reversed` is true. Two-sided surfaces whose
+    /// intersections always face the ray, like triangles, must override
+    /// this to test their own orientation. Surfaces made of parts, like
+    /// meshes, override it to find the nearest part that is not culled.
+    fn intersect_culled(&self, ray: &Ray, reversed: bool) -> Option<Intersection> {
+        self.intersect(ray).filter(|isect| faces_ray(ray, isect.normal, reversed))
+    }
+
+    /// Returns a box that contains the surface, or `None` if the surface is
+    /// unbounded. Surfaces without bounds are always intersected.
+    fn bounding_box(&self) -> Option<BoundingBox> { None }
+
+    /// Returns the area of the surface, or `None` if the surface is
+    /// unbounded, or if its area is not known.
+    fn area(&self) -> Option<f32> { None }
+
+    /// Returns a point distributed uniformly over the surface, together with
+    /// the surface normal there. Surfaces with an area must implement this,
+    /// so that they can be sampled as lights.
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> { None }
+}
+
+/// An axis-aligned box that bounds a surface.
+#[derive(Copy, Clone)]
+pub struct BoundingBox {
+    /// The corner with the smallest coordinates.
+    pub min: Vector3,
+
+    /// The corner with the largest coordinates.
+    pub max: Vector3
+}
+
+impl BoundingBox {
+    /// Returns the smallest box that contains all of the points.
+    pub fn around(points: &[Vector3]) -> BoundingBox {
+        let mut bounds = BoundingBox { min: points[0], max: points[0] };
+        for p in &points[1 ..] {
+            bounds.min = Vector3::new(bounds.min.x.min(p.x), bounds.min.y.min(p.y),
+                                      bounds.min.z.min(p.z));
+            bounds.max = Vector3::new(bounds.max.x.max(p.x), bounds.max.y.max(p.y),
+                                      bounds.max.z.max(p.z));
+        }
+        bounds
+    }
+
+    /// Returns the smallest box that contains both boxes.
+    pub fn union(self, other: BoundingBox) -> BoundingBox {
+        BoundingBox::around(&[self.min, self.max, other.min, other.max])
+    }
+
+    /// Returns the box that is contained in both boxes. It may be empty.
+    pub fn intersection(self, other: BoundingBox) -> BoundingBox {
+        BoundingBox {
+            min: Vector3::new(self.min.x.max(other.min.x), self.min.y.max(other.min.y),
+                              self.min.z.max(other.min.z)),
+            max: Vector3::new(self.max.x.min(other.max.x), self.max.y.min(other.max.y),
+                              self.max.z.min(other.max.z))
+        }
+    }
+
+    /// Returns the eight corners of the box.
+    pub fn corners(&self) -> [Vector3; 8] {
+        let (a, b) = (self.min, self.max);
+        [Vector3::new(a.x, a.y, a.z), Vector3::new(b.x, a.y, a.z),
+         Vector3::new(a.x, b.y, a.z), Vector3::new(b.x, b.y, a.z),
+         Vector3::new(a.x, a.y, b.z), Vector3::new(b.x, a.y, b.z),
+         Vector3::new(a.x, b.y, b.z), Vector3::new(b.x, b.y, b.z)]
+    }
+
+    /// Returns the centre of the box.
+    pub fn centre(&self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Returns whether all coordinates of the corners are finite, and not
+    /// NaN.
+    pub fn is_finite(&self) -> bool {
+        [self.min.x, self.min.y, self.min.z, self.max.x, self.max.y, self.max.z]
+            .iter().all(|c| c.is_finite())
+    }
+
+    /// Returns the distance along the ray at which it enters the box, if it
+    /// hits the box at all. For a ray that starts inside, this is 0.0.
+    pub fn entry_distance(&self, ray: &Ray) -> Option<f32> {
+        let mut t_min = 0.0f32;
+        let mut t_max = ::std::f32::INFINITY;
+        let axes = [(ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+                    (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+                    (ray.origin.z, ray.direction.z, self.min.z, self.max.z)];
+
+        // This is the slab method: intersect the intervals in which the ray
+        // lies between the two planes of every axis.
+        for &(origin, direction, min, max) in axes.iter() {
+            if direction == 0.0 {
+                if origin < min || origin > max { return None; }
+            } else {
+                let t1 = (min - origin) / direction;
+                let t2 = (max - origin) / direction;
+                t_min = t_min.max(t1.min(t2));
+                t_max = t_max.min(t1.max(t2));
+            }
+        }
+
+        if t_min <= t_max { Some(t_min) } else { None }
+    }
 }
 
 /// Represents a part of space.
@@ -127,6 +237,9 @@ impl Volume for SpacePartitioning {
     }
 }
 
+/// A flat disk: the part of a plane within a radius of the centre. Like
+/// planes, circles are two-sided. With an emissive material, it is a round
+/// area light.
 pub struct Circle {
     /// A unit vector perpendicular to the circle.
     normal: Vector3,
@@ -148,19 +261,10 @@ impl Circle {
     }
 }
 
-// Filter(ed) is implemented manually, because it is deprecated in the standard
-// library, but it allows for some elegant code, so I wanted to keep it.
-trait Filter<T> {
-    fn filter<F>(self, condition: F) -> Self where F: Fn(&T) -> bool;
-}
-
-impl<T> Filter<T> for Option<T> {
-    fn filter<F>(self, condition: F) -> Option<T> where F: Fn(&T) -> bool {
-        match self {
-            Some(x) => if condition(&x) { Some(x) } else { None },
-            None => None
-        }
-    }
+/// Returns whether a surface with the specified normal faces the ray, or
+/// faces away from it if `reversed` is true.
+pub fn faces_ray(ray: &Ray, normal: Vector3, reversed: bool) -> bool {
+    (dot(ray.direction, normal) <= 0.0) != reversed
 }
 
 impl Surface for Circle {
@@ -181,6 +285,228 @@ impl Surface for Circle {
             }
         })
     }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        // The box of the sphere that contains the circle is good enough.
+        let r = self.radius_squared.sqrt();
+        let extent = Vector3::new(r, r, r);
+        Some(BoundingBox { min: self.position - extent, max: self.position + extent })
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(PI * self.radius_squared)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // The square root makes the distribution uniform over the area.
+        let r = (::monte_carlo::get_unit() * self.radius_squared).sqrt();
+        let phi = ::monte_carlo::get_longitude();
+        let offset = Vector3::new(phi.cos() * r, phi.sin() * r, 0.0).rotate_towards(self.normal);
+        Some((self.position + offset, self.normal))
+    }
+}
+
+/// A flat parallelogram, spanned by two edges from a corner, such as a
+/// rectangular ceiling light. Like planes, quads are two-sided.
+pub struct Quad {
+    /// The corner from which the edges start.
+    origin: Vector3,
+
+    /// The first edge.
+    edge_u: Vector3,
+
+    /// The second edge.
+    edge_v: Vector3,
+
+    /// The unit normal, the cross product of the edges.
+    normal: Vector3,
+
+    /// The cross product of the edges divided by its squared magnitude,
+    /// for finding the coordinates of a point along the edges.
+    w: Vector3
+}
+
+impl Quad {
+    /// Creates the parallelogram with corners `origin`, `origin + edge_u`,
+    /// `origin + edge_u + edge_v` and `origin + edge_v`.
+    pub fn new(origin: Vector3, edge_u: Vector3, edge_v: Vector3) -> Quad {
+        let n = cross(edge_u, edge_v);
+        Quad {
+            origin: origin,
+            edge_u: edge_u,
+            edge_v: edge_v,
+            normal: n.normalise(),
+            w: n * (1.0 / n.magnitude_squared())
+        }
+    }
+}
+
+impl Surface for Quad {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        intersect_plane(&self.normal, &self.origin, ray)
+        .filter(|&(pos, _, _)| {
+            // Allow only intersections with both coordinates along the
+            // edges in [0, 1].
+            let p = pos - self.origin;
+            let a = dot(self.w, cross(p, self.edge_v));
+            let b = dot(self.w, cross(self.edge_u, p));
+            a >= 0.0 && a <= 1.0 && b >= 0.0 && b <= 1.0
+        })
+        .map(|(pos, t, d)| {
+            Intersection {
+                position: pos,
+                normal: if d < 0.0 { self.normal } else { -self.normal },
+                tangent: self.edge_u.normalise(),
+                distance: t
+            }
+        })
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(BoundingBox::around(&[self.origin,
+                                   self.origin + self.edge_u,
+                                   self.origin + self.edge_v,
+                                   self.origin + self.edge_u + self.edge_v]))
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(cross(self.edge_u, self.edge_v).magnitude())
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // Uniform coordinates along the edges are uniform over the area, so
+        // the probability density is one over the area.
+        let a = ::monte_carlo::get_unit();
+        let b = ::monte_carlo::get_unit();
+        Some((self.origin + self.edge_u * a + self.edge_v * b, self.normal))
+    }
+}
+
+/// A flat triangle. Like planes, triangles are two-sided.
+pub struct Triangle {
+    /// The first vertex.
+    v0: Vector3,
+
+    /// The second vertex.
+    v1: Vector3,
+
+    /// The third vertex.
+    v2: Vector3,
+
+    /// The unit face normal, by the right-hand rule on the vertex order.
+    normal: Vector3
+}
+
+impl Triangle {
+    /// Creates the triangle with vertices `v0`, `v1` and `v2`.
+    pub fn new(v0: Vector3, v1: Vector3, v2: Vector3) -> Triangle {
+        Triangle {
+            v0: v0,
+            v1: v1,
+            v2: v2,
+            normal: cross(v1 - v0, v2 - v0).normalise()
+        }
+    }
+}
+
+/// Returns the x, y or z component of `v`, for an index 0, 1 or 2.
+fn component(v: Vector3, i: usize) -> f32 {
+    match i {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z
+    }
+}
+
+impl Surface for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        // This is the watertight algorithm of Woop, Benthin and Wald. The
+        // vertices are transformed into a space where the ray starts at the
+        // origin and points along the z-axis, so the hit test is a 2D test
+        // of whether the origin lies inside the projected triangle. Adjacent
+        // triangles compute the test for a shared edge identically, so a ray
+        // cannot slip through the seam between them.
+        let d = ray.direction;
+
+        // Use the largest component of the direction as the z-axis.
+        let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+        let kz = if ax > ay && ax > az { 0 } else if ay > az { 1 } else { 2 };
+        let kx = (kz + 1) % 3;
+        let ky = (kx + 1) % 3;
+
+        // Shear the vertices, so that the direction becomes (0, 0, 1).
+        let dz = component(d, kz);
+        let sx = component(d, kx) / dz;
+        let sy = component(d, ky) / dz;
+        let sz = 1.0 / dz;
+        let shear = |v: Vector3| {
+            let p = v - ray.origin;
+            let pz = component(p, kz);
+            (component(p, kx) - sx * pz, component(p, ky) - sy * pz, sz * pz)
+        };
+        let (x0, y0, z0) = shear(self.v0);
+        let (x1, y1, z1) = shear(self.v1);
+        let (x2, y2, z2) = shear(self.v2);
+
+        // The scaled barycentric coordinates are edge functions.
+        let mut u = x2 * y1 - y2 * x1;
+        let mut v = x0 * y2 - y0 * x2;
+        let mut w = x1 * y0 - y1 * x0;
+
+        // Exactly on an edge, the sign is decided in double precision.
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            let (x0, y0) = (x0 as f64, y0 as f64);
+            let (x1, y1) = (x1 as f64, y1 as f64);
+            let (x2, y2) = (x2 as f64, y2 as f64);
+            u = (x2 * y1 - y2 * x1) as f32;
+            v = (x0 * y2 - y0 * x2) as f32;
+            w = (x1 * y0 - y1 * x0) as f32;
+        }
+
+        // Triangles are two-sided, so all signs must be equal, either way.
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+
+        // The ray is parallel to the plane of the triangle.
+        let det = u + v + w;
+        if det == 0.0 { return None; }
+
+        // A ray has one direction, do not hit backwards.
+        let t = (u * z0 + v * z1 + w * z2) / det;
+        if t <= 0.0 { return None; }
+
+        let d = dot(self.normal, ray.direction);
+        Some(Intersection {
+            position: ray.origin + ray.direction * t,
+            // Triangles are two-sided, like planes.
+            normal: if d < 0.0 { self.normal } else { -self.normal },
+            tangent: (self.v1 - self.v0).normalise(),
+            distance: t
+        })
+    }
+
+    fn intersect_culled(&self, ray: &Ray, reversed: bool) -> Option<Intersection> {
+        // The intersection always faces the ray, so test the vertex order.
+        if !faces_ray(ray, self.normal, reversed) { return None; }
+        self.intersect(ray)
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(BoundingBox::around(&[self.v0, self.v1, self.v2]))
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(cross(self.v1 - self.v0, self.v2 - self.v0).magnitude() * 0.5)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // Fold the unit square onto the triangle, uniformly.
+        let s = ::monte_carlo::get_unit().sqrt();
+        let t = ::monte_carlo::get_unit();
+        let p = self.v0 + (self.v1 - self.v0) * (s * (1.0 - t)) + (self.v2 - self.v0) * (s * t);
+        Some((p, self.normal))
+    }
 }
 
 pub struct Sphere {
@@ -258,6 +584,25 @@ impl Surface for Sphere {
         };
         Some(intersection)
     }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        let r = self.radius_squared.sqrt();
+        let extent = Vector3::new(r, r, r);
+        Some(BoundingBox { min: self.position - extent, max: self.position + extent })
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(4.0 * PI * self.radius_squared)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // A uniform z and longitude give a uniform point on the sphere.
+        let z = ::monte_carlo::get_bi_unit();
+        let phi = ::monte_carlo::get_longitude();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        let normal = Vector3::new(phi.cos() * r, phi.sin() * r, z);
+        Some((self.position + normal * self.radius_squared.sqrt(), normal))
+    }
 }
 
 impl Volume for Sphere {
@@ -266,6 +611,138 @@ impl Volume for Sphere {
     }
 }
 
+/// A box with faces parallel to the coordinate planes, for rooms and for
+/// Cornell box scenes. The normals point outward.
+pub struct AxisAlignedBox {
+    /// The corner with the smallest coordinates.
+    min: Vector3,
+
+    /// The corner with the largest coordinates.
+    max: Vector3
+}
+
+impl AxisAlignedBox {
+    /// Creates the box between the corners `min` and `max`; every
+    /// coordinate of `min` must be less than that of `max`.
+    pub fn new(min: Vector3, max: Vector3) -> AxisAlignedBox {
+        assert!(min.x < max.x && min.y < max.y && min.z < max.z,
+                "the minimum corner of a box must be below the maximum corner");
+        AxisAlignedBox {
+            min: min,
+            max: max
+        }
+    }
+
+    /// Returns the unit vector along axis 0, 1 or 2, times `sign`.
+    fn axis(i: usize, sign: f32) -> Vector3 {
+        match i {
+            0 => Vector3::new(sign, 0.0, 0.0),
+            1 => Vector3::new(0.0, sign, 0.0),
+            _ => Vector3::new(0.0, 0.0, sign)
+        }
+    }
+}
+
+impl Surface for AxisAlignedBox {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        // This is the slab method, like `BoundingBox::entry_distance`, but
+        // it also tracks through which face the ray enters and leaves.
+        let mut t_near = -::std::f32::INFINITY;
+        let mut t_far = ::std::f32::INFINITY;
+        let mut near_normal = Vector3::zero();
+        let mut far_normal = Vector3::zero();
+        for i in 0 .. 3 {
+            let origin = component(ray.origin, i);
+            let direction = component(ray.direction, i);
+            let (min, max) = (component(self.min, i), component(self.max, i));
+            if direction == 0.0 {
+                // A ray parallel to the slab, also a ray that grazes a face,
+                // never crosses its planes.
+                if origin < min || origin > max { return None; }
+                continue;
+            }
+
+            let sign = if direction > 0.0 { 1.0 } else { -1.0 };
+            let (t1, t2) = if direction > 0.0 {
+                ((min - origin) / direction, (max - origin) / direction)
+            } else {
+                ((max - origin) / direction, (min - origin) / direction)
+            };
+            if t1 > t_near {
+                t_near = t1;
+                near_normal = AxisAlignedBox::axis(i, -sign);
+            }
+            if t2 < t_far {
+                t_far = t2;
+                far_normal = AxisAlignedBox::axis(i, sign);
+            }
+        }
+
+        if t_near > t_far { return None; }
+
+        // A ray that starts inside the box hits it where it leaves.
+        let (t, normal) = if t_near > 0.0 {
+            (t_near, near_normal)
+        } else if t_far > 0.0 {
+            (t_far, far_normal)
+        } else {
+            return None;
+        };
+
+        // Any axis perpendicular to the normal will do as tangent.
+        let tangent = if normal.x == 0.0 {
+            Vector3::new(1.0, 0.0, 0.0)
+        } else {
+            Vector3::new(0.0, 1.0, 0.0)
+        };
+
+        Some(Intersection {
+            position: ray.origin + ray.direction * t,
+            normal: normal,
+            tangent: tangent,
+            distance: t
+        })
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        Some(BoundingBox { min: self.min, max: self.max })
+    }
+
+    fn area(&self) -> Option<f32> {
+        let d = self.max - self.min;
+        Some(2.0 * (d.x * d.y + d.y * d.z + d.z * d.x))
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // Pick a pair of opposite faces in proportion to their area, and
+        // then one of the two, and a uniform point on it.
+        let d = self.max - self.min;
+        let areas = [d.y * d.z, d.z * d.x, d.x * d.y];
+        let u = ::monte_carlo::get_unit() * (areas[0] + areas[1] + areas[2]);
+        let i = if u < areas[0] { 0 } else if u < areas[0] + areas[1] { 1 } else { 2 };
+        let far = ::monte_carlo::get_unit() < 0.5;
+
+        let mut p = Vector3::new(self.min.x + ::monte_carlo::get_unit() * d.x,
+                                 self.min.y + ::monte_carlo::get_unit() * d.y,
+                                 self.min.z + ::monte_carlo::get_unit() * d.z);
+        let face = if far { component(self.max, i) } else { component(self.min, i) };
+        match i {
+            0 => p.x = face,
+            1 => p.y = face,
+            _ => p.z = face
+        }
+        Some((p, AxisAlignedBox::axis(i, if far { 1.0 } else { -1.0 })))
+    }
+}
+
+impl Volume for AxisAlignedBox {
+    fn lies_inside(&self, p: Vector3) -> bool {
+        p.x > self.min.x && p.x < self.max.x &&
+        p.y > self.min.y && p.y < self.max.y &&
+        p.z > self.min.z && p.z < self.max.z
+    }
+}
+
 #[derive(Clone)]
 pub struct Paraboloid {
     /// The position of the focal point projected onto the plane.
@@ -388,15 +865,18 @@ impl<T1, T2> Surface for Compound<T1, T2>
         let i2 = i2.filter(|i| { self.surface1.lies_inside(i.position) });
 
         // If both intersections are valid, pick the closest one.
-        if i1.is_some() && i2.is_some() {
-            if i1.unwrap().distance < i2.unwrap().distance {
-                return i1;
-            } else {
-                return i2;
-            }
+        match (i1, i2) {
+            (Some(a), Some(b)) => if a.distance < b.distance { i1 } else { i2 },
+            _ => i1.or(i2)
         }
+    }
 
-        i1.or(i2)
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        // The compound is the intersection of the two volumes.
+        match (self.surface1.bounding_box(), self.surface2.bounding_box()) {
+            (Some(b1), Some(b2)) => Some(b1.intersection(b2)),
+            (b1, b2) => b1.or(b2)
+        }
     }
 }
 
@@ -406,6 +886,133 @@ impl<T1, T2> Volume for Compound<T1, T2> where T1: Volume, T2: Volume {
     }
 }
 
+/// A surface that is scaled uniformly, then rotated, and then translated.
+pub struct Transformed<S> {
+    /// The untransformed surface.
+    surface: S,
+
+    /// The uniform scale to apply, first.
+    scale: f32,
+
+    /// The rotation to apply after scaling.
+    rotation: Quaternion,
+
+    /// The translation to apply after rotating.
+    translation: Vector3
+}
+
+impl<S> Transformed<S> {
+    /// Creates a surface that is `surface` rotated by `rotation`
+    /// (a unit quaternion) and then translated by `translation`.
+    pub fn new(surface: S, rotation: Quaternion, translation: Vector3) -> Transformed<S> {
+        Transformed::scaled(surface, 1.0, rotation, translation)
+    }
+
+    /// Creates a surface that is `surface` scaled by `scale`, then rotated by
+    /// `rotation` (a unit quaternion), and then translated by `translation`.
+    pub fn scaled(surface: S, scale: f32, rotation: Quaternion, translation: Vector3)
+                  -> Transformed<S> {
+        assert!(scale > 0.0, "the scale of a transformed surface must be positive");
+        Transformed {
+            surface: surface,
+            scale: scale,
+            rotation: rotation,
+            translation: translation
+        }
+    }
+
+    /// Transforms a point from world space into the space of the surface.
+    fn to_local(&self, p: Vector3) -> Vector3 {
+        (p - self.translation).rotate(self.rotation.conjugate()) * (1.0 / self.scale)
+    }
+
+    /// Transforms a point from the space of the surface into world space.
+    fn to_world(&self, p: Vector3) -> Vector3 {
+        (p * self.scale).rotate(self.rotation) + self.translation
+    }
+
+    /// Transforms a ray into the space of the surface. The direction is
+    /// only rotated, so it stays normalised, but distances are scaled.
+    fn to_local_ray(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: self.to_local(ray.origin),
+            direction: ray.direction.rotate(self.rotation.conjugate()),
+            wavelength: ray.wavelength,
+            probability: ray.probability
+        }
+    }
+
+    /// Transforms an intersection in the space of the surface into world
+    /// space. The scale is uniform, so normals need only be rotated.
+    fn to_world_hit(&self, isect: Intersection) -> Intersection {
+        Intersection {
+            position: self.to_world(isect.position),
+            normal: isect.normal.rotate(self.rotation),
+            tangent: isect.tangent.rotate(self.rotation),
+            distance: isect.distance * self.scale
+        }
+    }
+}
+
+impl<S> Surface for Transformed<S> where S: Surface {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.surface.intersect(&self.to_local_ray(ray)).map(|isect| self.to_world_hit(isect))
+    }
+
+    fn intersect_culled(&self, ray: &Ray, reversed: bool) -> Option<Intersection> {
+        self.surface.intersect_culled(&self.to_local_ray(ray), reversed)
+            .map(|isect| self.to_world_hit(isect))
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        // Bound the transformed corners of the untransformed box.
+        self.surface.bounding_box().map(|bounds| {
+            let corners = bounds.corners();
+            let transformed: Vec<Vector3> = corners.iter().map(|&p| self.to_world(p)).collect();
+            BoundingBox::around(&transformed)
+        })
+    }
+
+    fn area(&self) -> Option<f32> {
+        // Rotation and translation preserve the area, scaling does not.
+        self.surface.area().map(|area| area * self.scale * self.scale)
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        self.surface.sample_point().map(|(p, n)| (self.to_world(p), n.rotate(self.rotation)))
+    }
+}
+
+impl<S> Volume for Transformed<S> where S: Volume {
+    fn lies_inside(&self, p: Vector3) -> bool {
+        self.surface.lies_inside(self.to_local(p))
+    }
+}
+
+/// A shared surface is a surface too, so that one surface, such as a large
+/// mesh, can be placed many times without duplicating it.
+impl<S: ?Sized> Surface for Arc<S> where S: Surface {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        (**self).intersect(ray)
+    }
+
+    fn intersect_culled(&self, ray: &Ray, reversed: bool) -> Option<Intersection> {
+        (**self).intersect_culled(ray, reversed)
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        (**self).bounding_box()
+    }
+
+    fn area(&self) -> Option<f32> {
+        (**self).area()
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        (**self).sample_point()
+    }
+}
+
 pub type InfinitePrism = Compound<Compound<SpacePartitioning, SpacePartitioning>,
                               SpacePartitioning>;
 
@@ -513,3 +1120,185 @@ pub fn new_hexagonal_prism(axis: Vector3,
 
     Compound::new(iprism, prism)
 }
+
+#[test]
+fn triangle_intersection() {
+    let triangle = Triangle::new(Vector3::zero(),
+                                 Vector3::new(1.0, 0.0, 0.0),
+                                 Vector3::new(0.0, 1.0, 0.0));
+    let ray = |x: f32, y: f32, direction: Vector3| Ray {
+        origin: Vector3::new(x, y, 0.0) - direction,
+        direction: direction,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let up = Vector3::new(0.0, 0.0, 1.0);
+
+    // A hit in the interior, from either side, with the normal facing the ray.
+    let isect = triangle.intersect(&ray(0.25, 0.25, up)).unwrap();
+    assert!((isect.distance - 1.0).abs() < 1.0e-6);
+    assert!((isect.position - Vector3::new(0.25, 0.25, 0.0)).magnitude() < 1.0e-6);
+    assert!((isect.normal + up).magnitude() < 1.0e-6);
+    let isect = triangle.intersect(&ray(0.25, 0.25, -up)).unwrap();
+    assert!((isect.normal - up).magnitude() < 1.0e-6);
+
+    // Rays through a vertex or an edge hit, just outside they miss.
+    assert!(triangle.intersect(&ray(0.0, 0.0, up)).is_some());
+    assert!(triangle.intersect(&ray(1.0, 0.0, up)).is_some());
+    assert!(triangle.intersect(&ray(0.5, 0.0, up)).is_some());
+    assert!(triangle.intersect(&ray(0.5, 0.5, up)).is_some());
+    assert!(triangle.intersect(&ray(-0.01, 0.5, up)).is_none());
+    assert!(triangle.intersect(&ray(0.5, -0.01, up)).is_none());
+    assert!(triangle.intersect(&ray(0.51, 0.51, up)).is_none());
+
+    // A ray parallel to the plane of the triangle never hits, not even
+    // when it lies in the plane.
+    let along = Vector3::new(1.0, 0.0, 0.0);
+    assert!(triangle.intersect(&ray(0.25, 0.25, along)).is_none());
+
+    // The triangle is not hit backwards.
+    let behind = Ray {
+        origin: Vector3::new(0.25, 0.25, 1.0),
+        direction: up,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    assert!(triangle.intersect(&behind).is_none());
+}
+
+#[test]
+fn triangles_are_watertight() {
+    ::monte_carlo::seed([9, 8, 7, 6]);
+    let random_point = || Vector3::new(::monte_carlo::get_bi_unit(),
+                                       ::monte_carlo::get_bi_unit(),
+                                       ::monte_carlo::get_bi_unit());
+
+    // Two triangles that share the edge from a to b, forming a flat quad in
+    // an arbitrary orientation, with coordinates that are not round.
+    let axis = Vector3::new(0.3, -0.7, 0.2).normalise();
+    let q = Quaternion::rotation(axis.x, axis.y, axis.z, 1.234);
+    let offset = Vector3::new(0.1, 0.3, 0.7);
+    let corner = |x: f32, y: f32| Vector3::new(x, y, 0.0).rotate(q) + offset;
+    let (a, b) = (corner(0.13, -0.71), corner(-0.37, 0.53));
+    let (c, d) = (corner(-0.91, -0.27), corner(0.83, 0.19));
+    let triangles = [Triangle::new(a, b, c), Triangle::new(b, a, d)];
+
+    // Fire rays from all around at points on the shared edge.
+    for i in 0 .. 10000 {
+        let target = a + (b - a) * (0.05 + 0.9 * ::monte_carlo::get_unit());
+        let origin = random_point().normalise() * 5.0 + target;
+        let ray = Ray {
+            origin: origin,
+            direction: (target - origin).normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let hit = triangles.iter().any(|t| t.intersect(&ray).is_some());
+        assert!(hit, "ray {} slipped through the edge", i);
+    }
+}
+
+#[test]
+fn axis_aligned_box_intersection() {
+    let aabb = AxisAlignedBox::new(Vector3::new(-1.0, 2.0, -1.0), Vector3::new(1.0, 4.0, 1.0));
+    let ray = |origin: Vector3, direction: Vector3| Ray {
+        origin: origin,
+        direction: direction.normalise(),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+
+    // A ray that enters the front face.
+    let isect = aabb.intersect(&ray(Vector3::new(0.3, 0.0, -0.2), forward)).unwrap();
+    assert!((isect.distance - 2.0).abs() < 1.0e-6);
+    assert_eq!(isect.normal.y, -1.0);
+    assert!((isect.position.x - 0.3).abs() < 1.0e-6);
+
+    // A ray that starts inside exits through the back face.
+    let isect = aabb.intersect(&ray(Vector3::new(0.0, 3.0, 0.5), forward)).unwrap();
+    assert!((isect.distance - 1.0).abs() < 1.0e-6);
+    assert_eq!(isect.normal.y, 1.0);
+
+    // An oblique ray from inside leaves through a side face.
+    let isect = aabb.intersect(&ray(Vector3::new(0.0, 3.0, 0.0), Vector3::new(1.0, 0.2, 0.0)))
+                    .unwrap();
+    assert_eq!(isect.normal.x, 1.0);
+    assert!((isect.position.x - 1.0).abs() < 1.0e-6);
+
+    // A ray that grazes the top face still hits the back face, without NaNs.
+    let isect = aabb.intersect(&ray(Vector3::new(0.0, 0.0, 1.0), forward)).unwrap();
+    assert!((isect.distance - 2.0).abs() < 1.0e-6);
+
+    // Rays that pass beside or point away from the box miss it.
+    assert!(aabb.intersect(&ray(Vector3::new(1.5, 0.0, 0.0), forward)).is_none());
+    assert!(aabb.intersect(&ray(Vector3::zero(), -forward)).is_none());
+    assert!(aabb.intersect(&ray(Vector3::new(0.0, 5.0, 0.0), forward)).is_none());
+}
+
+#[test]
+fn circle_intersection_is_bounded() {
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let circle = Circle::new(up, Vector3::new(1.0, 2.0, 0.0), 2.0);
+    let ray = |origin: Vector3, direction: Vector3| Ray {
+        origin: origin,
+        direction: direction,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // A ray that hits the plane inside the radius hits the circle.
+    let isect = circle.intersect(&ray(Vector3::new(2.9, 2.0, 5.0), -up)).unwrap();
+    assert!((isect.distance - 5.0).abs() < 1.0e-5);
+    assert!((isect.position.x - 2.9).abs() < 1.0e-5);
+    assert_eq!(isect.normal.z, 1.0);
+
+    // From below, the normal faces the ray.
+    let isect = circle.intersect(&ray(Vector3::new(1.0, 1.0, -1.0), up)).unwrap();
+    assert_eq!(isect.normal.z, -1.0);
+
+    // Just outside of the radius, the ray misses.
+    assert!(circle.intersect(&ray(Vector3::new(3.01, 2.0, 5.0), -up)).is_none());
+
+    // A ray parallel to the circle misses it.
+    let parallel = Vector3::new(1.0, 0.0, 0.0);
+    assert!(circle.intersect(&ray(Vector3::new(-5.0, 2.0, 0.5), parallel)).is_none());
+}
+
+#[test]
+fn quad_intersection_and_sampling() {
+    let origin = Vector3::new(-1.0, 3.0, 2.0);
+    let edge_u = Vector3::new(2.0, 0.0, 0.0);
+    let edge_v = Vector3::new(0.5, 1.0, 0.0);
+    let quad = Quad::new(origin, edge_u, edge_v);
+    let down = Vector3::new(0.0, 0.0, -1.0);
+    let ray = |x: f32, y: f32, direction: Vector3| Ray {
+        origin: Vector3::new(x, y, 2.0) - direction * 4.0,
+        direction: direction,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // A hit inside, from either side, with the normal toward the ray.
+    let isect = quad.intersect(&ray(0.5, 3.5, down)).unwrap();
+    assert!((isect.distance - 4.0).abs() < 1.0e-5);
+    assert_eq!(isect.normal.z, 1.0);
+    assert_eq!(quad.intersect(&ray(0.5, 3.5, -down)).unwrap().normal.z, -1.0);
+
+    // Outside of the parallelogram, although inside its bounding box.
+    assert!(quad.intersect(&ray(-0.9, 3.9, down)).is_none());
+    assert!(quad.intersect(&ray(0.5, 4.1, down)).is_none());
+
+    assert!((quad.area().unwrap() - 2.0).abs() < 1.0e-5);
+
+    // Sampled points lie within the parallelogram.
+    for _ in 0 .. 100 {
+        let (p, normal) = quad.sample_point().unwrap();
+        assert_eq!(normal.z, 1.0);
+        assert!((p.z - 2.0).abs() < 1.0e-5);
+        let b = p.y - 3.0;
+        let a = (p.x + 1.0 - 0.5 * b) / 2.0;
+        assert!(a > -1.0e-5 && a < 1.0 + 1.0e-5);
+        assert!(b > -1.0e-5 && b < 1.0 + 1.0e-5);
+    }
+}
@@ -0,0 +1,185 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::f32::EPSILON;
+
+/// The fraction of the samples that is spread uniformly over the image,
+/// regardless of the importance, so that tiles which merely look converged
+/// are still sampled, and the weight of a sample is at most 1 / 0.25.
+const UNIFORM_FRACTION: f32 = 0.25;
+
+/// A coarse grid of tiles over the image, with a probability for every tile
+/// to place a photon in it. It warps uniform screen samples, so that noisy
+/// tiles receive more photons than tiles that have converged already.
+///
+/// The grid is sampled row by row: first a row from the marginal
+/// distribution, then a tile within the row. This preserves the
+/// stratification of the uniform samples.
+#[derive(Clone)]
+pub struct ImportanceMap {
+    /// The number of tiles along the width of the image.
+    tiles_x: u32,
+
+    /// The number of tiles along the height of the image.
+    tiles_y: u32,
+
+    /// The probability of sampling every tile, row by row.
+    probabilities: Vec<f32>,
+
+    /// The cumulative distribution of the rows.
+    row_cdf: Vec<f32>,
+
+    /// The cumulative distribution of the tiles within every row.
+    tile_cdfs: Vec<Vec<f32>>
+}
+
+/// Returns the index of the interval of `cdf` that contains `u`, and `u`
+/// rescaled to the range [0, 1) within that interval. Empty intervals are
+/// never returned.
+fn invert_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    // Rounding can leave the total slightly below 1; then take the last
+    // interval that is not empty.
+    let i = cdf.iter().position(|&c| u < c).unwrap_or_else(|| {
+        let last = cdf[cdf.len() - 1];
+        cdf.iter().position(|&c| c == last).unwrap_or(cdf.len() - 1)
+    });
+    let low = if i == 0 { 0.0 } else { cdf[i - 1] };
+    let width = cdf[i] - low;
+    let t = if width > 0.0 { (u - low) / width } else { 0.5 };
+    (i, t.max(0.0).min(0.99999994))
+}
+
+/// Returns the normalised cumulative sum of the weights.
+fn cumulative(weights: &[f32]) -> Vec<f32> {
+    let total = weights.iter().fold(0.0, |a, &w| a + w);
+    let mut sum = 0.0;
+    weights.iter().map(|&w| { sum += w; sum / total }).collect()
+}
+
+impl ImportanceMap {
+    /// Creates a map of `tiles_x` by `tiles_y` tiles, where `importance`
+    /// holds a non-negative value per tile, row by row, in proportion to
+    /// which the tiles should be sampled. Part of the samples is always
+    /// spread uniformly over the tiles. Tiles with an importance of `None`
+    /// are not sampled at all, unless no tile is to be sampled.
+    pub fn new(tiles_x: u32, tiles_y: u32, importance: &[Option<f32>]) -> ImportanceMap {
+        let n = (tiles_x * tiles_y) as usize;
+        assert_eq!(importance.len(), n);
+
+        // Without any importance, sample uniformly.
+        let sampled = importance.iter().filter(|x| x.is_some()).count();
+        let total = importance.iter().fold(0.0f32, |a, x| a + x.unwrap_or(0.0).max(0.0));
+        let probabilities: Vec<f32> = importance.iter().map(|&x| {
+            match x {
+                _ if sampled == 0 => 1.0 / n as f32,
+                None => 0.0,
+                Some(x) if total > 0.0 && total.is_finite() => {
+                    UNIFORM_FRACTION / sampled as f32
+                        + (1.0 - UNIFORM_FRACTION) * x.max(0.0) / total
+                },
+                Some(_) => 1.0 / sampled as f32
+            }
+        }).collect();
+
+        let rows: Vec<&[f32]> = probabilities.chunks(tiles_x as usize).collect();
+        let row_sums: Vec<f32> = rows.iter()
+            .map(|row| row.iter().fold(0.0, |a, &p| a + p))
+            .collect();
+        let tile_cdfs = rows.iter().map(|row| cumulative(row)).collect();
+
+        ImportanceMap {
+            tiles_x: tiles_x,
+            tiles_y: tiles_y,
+            row_cdf: cumulative(&row_sums),
+            tile_cdfs: tile_cdfs,
+            probabilities: probabilities
+        }
+    }
+
+    /// Maps the point (`u`, `v`) in the unit square, where uniform points
+    /// would cover the image evenly, to a point that covers the tiles in
+    /// proportion to their importance. Returns the point, and the weight
+    /// by which the photon must be multiplied to compensate for the change
+    /// in density. Like the uniform point, the point is strictly inside
+    /// the unit square.
+    pub fn sample(&self, u: f32, v: f32) -> (f32, f32, f32) {
+        let (row, v) = invert_cdf(&self.row_cdf, v);
+        let (column, u) = invert_cdf(&self.tile_cdfs[row], u);
+        let p = self.probabilities[row * self.tiles_x as usize + column];
+        let n = (self.tiles_x * self.tiles_y) as f32;
+        let x = (column as f32 + u) / self.tiles_x as f32;
+        let y = (row as f32 + v) / self.tiles_y as f32;
+        let (x, y) = (x.max(EPSILON).min(1.0 - EPSILON), y.max(EPSILON).min(1.0 - EPSILON));
+        (x, y, 1.0 / (p * n))
+    }
+}
+
+#[test]
+fn importance_map_weights_compensate_density() {
+    // One tile is nine times as important as the other three.
+    let map = ImportanceMap::new(2, 2, &[Some(9.0), Some(1.0), Some(1.0), Some(1.0)]);
+
+    let n = 100;
+    let mut counts = [0u32; 4];
+    let mut weight_sum = 0.0;
+    for i in 0 .. n {
+        for j in 0 .. n {
+            let u = (i as f32 + 0.5) / n as f32;
+            let v = (j as f32 + 0.5) / n as f32;
+            let (x, y, weight) = map.sample(u, v);
+            assert!(x > 0.0 && x < 1.0 && y > 0.0 && y < 1.0);
+            let tile = (y * 2.0) as usize * 2 + (x * 2.0) as usize;
+            counts[tile] += 1;
+            weight_sum += weight;
+        }
+    }
+
+    // The important tile gets 0.25 / 4 + 0.75 * 9 / 12 of the samples.
+    let expected = 0.0625 + 0.5625;
+    assert!((counts[0] as f32 / (n * n) as f32 - expected).abs() < 0.01);
+    assert!(counts[1] < counts[0] / 4);
+
+    // Weighted, every tile counts as much as with uniform sampling, so the
+    // mean weight is one.
+    assert!((weight_sum / (n * n) as f32 - 1.0).abs() < 0.01);
+
+    // Even the corners of the unit square map strictly inside it.
+    for &(u, v) in &[(0.0, 0.0), (0.99999994, 0.99999994)] {
+        let (x, y, _) = map.sample(u, v);
+        assert!(x > 0.0 && x < 1.0 && y > 0.0 && y < 1.0);
+    }
+}
+
+#[test]
+fn importance_map_skips_excluded_tiles() {
+    // The bottom row and the top left tile are not sampled.
+    let map = ImportanceMap::new(2, 2, &[None, Some(1.0), None, None]);
+    let n = 50;
+    let mut weight_sum = 0.0;
+    for i in 0 .. n {
+        for j in 0 .. n {
+            let u = (i as f32 + 0.5) / n as f32;
+            let v = (j as f32 + 0.5) / n as f32;
+            let (x, y, weight) = map.sample(u, v);
+            assert!(x > 0.5 && y < 0.5);
+            weight_sum += weight;
+        }
+    }
+
+    // The remaining tile is sampled four times as densely as it would be
+    // uniformly, so its photons count for a quarter.
+    assert!((weight_sum / (n * n) as f32 - 0.25).abs() < 1.0e-5);
+}
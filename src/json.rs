@@ -0,0 +1,219 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Result};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The members of a JSON object, by key.
+pub type Object = BTreeMap<String, Json>;
+
+/// A JSON value. Only what scene files need is supported: numbers are
+/// read as `f64`, and strings may contain the simple escapes, but not
+/// `\u` escapes.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Json {
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Object)
+}
+
+impl Json {
+    /// Reads a single JSON value from the reader.
+    pub fn from_reader<R: Read>(reader: &mut R) -> Result<Json> {
+        let mut text = String::new();
+        try!(reader.read_to_string(&mut text));
+        Json::parse(&text)
+    }
+
+    /// Parses a single JSON value, which may be surrounded by whitespace.
+    pub fn parse(text: &str) -> Result<Json> {
+        let mut parser = Parser { chars: text.chars().peekable() };
+        let value = try!(parser.parse_value());
+        parser.skip_whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some(c) => Err(invalid(format!("unexpected '{}' after the value", c)))
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&Object> {
+        match *self { Json::Object(ref obj) => Some(obj), _ => None }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match *self { Json::Array(ref array) => Some(array), _ => None }
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match *self { Json::String(ref s) => Some(s), _ => None }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self { Json::Number(x) => Some(x), _ => None }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self { Json::Boolean(b) => Some(b), _ => None }
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() { break; }
+            self.chars.next();
+        }
+    }
+
+    /// Consumes the next character, which must be `expected`.
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(invalid(format!("expected '{}' but found '{}'", expected, c))),
+            None => Err(invalid(format!("expected '{}' but the input ended", expected)))
+        }
+    }
+
+    /// Consumes the keyword `word`, of which the first character has been
+    /// peeked, and returns `value`.
+    fn parse_keyword(&mut self, word: &str, value: Json) -> Result<Json> {
+        for c in word.chars() {
+            try!(self.expect(c));
+        }
+        Ok(value)
+    }
+
+    fn parse_value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => self.parse_string().map(Json::String),
+            Some('t') => self.parse_keyword("true", Json::Boolean(true)),
+            Some('f') => self.parse_keyword("false", Json::Boolean(false)),
+            Some('n') => self.parse_keyword("null", Json::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) => Err(invalid(format!("unexpected '{}'", c))),
+            None => Err(invalid("expected a value but the input ended".to_string()))
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json> {
+        let mut text = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_digit() && !"-+.eE".contains(c) { break; }
+            text.push(c);
+            self.chars.next();
+        }
+        text.parse().map(Json::Number)
+            .map_err(|_| invalid(format!("'{}' is not a number", text)))
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        try!(self.expect('"'));
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => s.push(match self.chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => c,
+                    Some(c) => return Err(invalid(format!("unsupported escape '\\{}'", c))),
+                    None => break
+                }),
+                Some(c) => s.push(c),
+                None => break
+            }
+        }
+        Err(invalid("a string was not terminated".to_string()))
+    }
+
+    fn parse_array(&mut self) -> Result<Json> {
+        try!(self.expect('['));
+        let mut array = Vec::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Json::Array(array));
+        }
+        loop {
+            array.push(try!(self.parse_value()));
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(array)),
+                _ => return Err(invalid("expected ',' or ']' in an array".to_string()))
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Json> {
+        try!(self.expect('{'));
+        let mut obj = Object::new();
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Json::Object(obj));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = try!(self.parse_string());
+            self.skip_whitespace();
+            try!(self.expect(':'));
+            let value = try!(self.parse_value());
+            obj.insert(key, value);
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(obj)),
+                _ => return Err(invalid("expected ',' or '}' in an object".to_string()))
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_nested_values() {
+    let json = Json::parse(" { \"a\": [1, -2.5e1, true, null], \"b\": \"x\\\"y\", \"c\": {} } ")
+        .unwrap();
+    let obj = json.as_object().unwrap();
+    let a = obj.get("a").unwrap().as_array().unwrap();
+    assert_eq!(a[0].as_f64(), Some(1.0));
+    assert_eq!(a[1].as_f64(), Some(-25.0));
+    assert_eq!(a[2], Json::Boolean(true));
+    assert_eq!(a[3], Json::Null);
+    assert_eq!(obj.get("b").unwrap().as_string(), Some("x\"y"));
+    assert_eq!(obj.get("c").unwrap().as_object().map(|c| c.len()), Some(0));
+
+    for bad in &["", "[1, 2", "{\"a\" 1}", "[1] 2", "tru", "\"open"] {
+        assert_eq!(Json::parse(bad).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
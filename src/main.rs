@@ -14,40 +14,483 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+// The code is written in the Rust of its time: `try!`, trait objects without
+// `dyn`, explicit field initialisers, `repeat(x).take(n)`, and comparisons
+// like `!(x > 0.0)` that reject NaN. It is kept consistent, rather than
+// mixing in newer idioms piecemeal.
+#![allow(deprecated, bare_trait_objects)]
+#![allow(clippy::redundant_field_names,
+         clippy::excessive_precision,
+         clippy::legacy_numeric_constants,
+         clippy::manual_clamp,
+         clippy::manual_range_contains,
+         clippy::manual_repeat_n,
+         clippy::manual_strip,
+         clippy::needless_borrowed_reference,
+         clippy::neg_cmp_op_on_partial_ord,
+         clippy::assign_op_pattern,
+         clippy::io_other_error,
+         clippy::match_like_matches_macro,
+         clippy::question_mark,
+         clippy::unnecessary_map_or)]
+
 extern crate image;
+extern crate num_cpus;
 extern crate rand;
 extern crate time;
 
+use std::env;
+use std::process;
+use std::str::FromStr;
+use animation::Animation;
 use app::App;
+use chroma_filter::ChromaFilter;
+use colour_map::ColourMap;
+use contact_sheet::ContactSheet;
+use object::MaterialBox::Reflective;
+use output::{ColourSpace, PixelFormat};
+use plot_unit::{PlotMode, PlotUnit};
+use srgb::Encoding;
+use task_scheduler::TaskScheduler;
+use tonemap_unit::{FilmicCurve, ToneCurve, TonemapOperator};
+use trace_unit::{Integrator, ScreenSampling, TraceUnit};
+use vector3::Vector3;
 
+mod animation;
 mod app;
+mod bvh;
 mod camera;
+mod chroma_filter;
 mod cie1931;
+mod colour_map;
 mod constants;
+mod contact_sheet;
+mod environment;
 mod gather_unit;
 mod geometry;
+mod importance_map;
 mod intersection;
+mod json;
 mod material;
+mod mesh;
 mod monte_carlo;
 mod object;
+mod output;
 mod plot_unit;
 mod pop_iter;
 mod quaternion;
 mod ray;
 mod read;
 mod scene;
+mod screen;
+mod sky;
+mod spectrum;
 mod srgb;
 mod task_scheduler;
 mod tonemap_unit;
 mod trace_unit;
 mod vector3;
+mod white_balance;
+
+/// The number of batches that the preview of --preview counts as.
+const PREVIEW_WEIGHT: f32 = 4.0;
+
+/// Prints `message` and the usage to the standard error, and exits with a
+/// non-zero status. Mistakes on the command line are not bugs, so they are
+/// reported like this rather than with a panic.
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("usage: robigo-luculenta [<batches>] [<options>]");
+    process::exit(2)
+}
+
+/// Returns the argument after `option`, which should be `what`.
+fn next_arg<I>(args: &mut I, option: &str, what: &str) -> String
+    where I: Iterator<Item = String> {
+    match args.next() {
+        Some(x) => x,
+        None => usage_error(&format!("expected {} after {}", what, option))
+    }
+}
+
+/// Parses the argument after `option`, which should be `what`.
+fn parse_next<T, I>(args: &mut I, option: &str, what: &str) -> T
+    where T: FromStr, I: Iterator<Item = String> {
+    let arg = next_arg(args, option, what);
+    match arg.parse() {
+        Ok(x) => x,
+        Err(_) => usage_error(&format!("expected {} after {}, not '{}'", what, option, arg))
+    }
+}
+
+/// Prints the path of the camera ray through the screen position (`x`, `y`)
+/// at 550 nm, and the contribution of the ray across the visible spectrum.
+fn print_debug_ray(scene: &scene::Scene, x: f32, y: f32) {
+    let show = |v: Vector3| format!("({:.3}, {:.3}, {:.3})", v.x, v.y, v.z);
+    let path = TraceUnit::trace_debug_ray(scene, x, y, 550.0);
+    println!("camera ray {} at 550 nm contributes {}",
+             show(path.camera_direction), path.contribution);
+    for vertex in &path.vertices {
+        let outgoing = vertex.outgoing_direction.map(&show).unwrap_or("none".to_string());
+        println!("  object {} at {}, normal {}, emissive: {}, continues {}",
+                 vertex.object, show(vertex.position), show(vertex.normal),
+                 vertex.emissive, outgoing);
+    }
+
+    let camera = scene.get_camera(0.0);
+    for wavelength in (400 .. 701).step_by(50) {
+        let ray = camera.get_ray(x, y, wavelength as f32);
+        let sample = TraceUnit::trace_single_ray(scene, ray);
+        println!("at {} nm the ray contributes {}, and it ends going {}",
+                 sample.wavelength, sample.contribution, show(sample.final_direction));
+    }
+}
 
 fn main() {
+    let width = app::DEFAULT_WIDTH;
+    let height = app::DEFAULT_HEIGHT;
+    let concurrency = num_cpus::get();
+
+    // The arguments are an optional number of batches to render, and
+    // options. With --contact-sheet <prefix>, the image is also saved after
+    // 1, 4, 16, ... batches, to <prefix><batches>.png. With --scene <file>,
+    // the scene is loaded from a JSON file instead of the built-in scene.
+    // With --adaptive, more photons go to the noisy parts of the image.
+    // With --batch-size <n>, every batch traces n photons. With
+    // --max-pixel-photons <n>, pixels stop accumulating after n photons, and
+    // the render stops when all pixels did.
+    //
+    // Sampling:
+    //   --seed <n>                 trace the same photons in every run
+    //   --jittered                 place photons on a jittered grid, not a Halton sequence
+    //   --guide-wavelengths <i>    sample wavelengths by the material of object i
+    //   --integrator path|direct   trace full paths, which is the default, or direct light
+    //   --integrator ao <d>        render the ambient occlusion within distance d
+    //   --spectral-samples <n>     trace n wavelengths per path, for less colour noise
+    //   --preview                  show a cheap estimate until the first batches are in
+    //
+    // Tonemapping:
+    //   --tonemap logarithmic      tonemap logarithmically, which is the default
+    //   --tonemap filmic           tonemap with a filmic curve
+    //   --tonemap reinhard         tonemap with the operator of Reinhard et al.
+    //   --tonemap aces             tonemap with the ACES filmic curve
+    //   --tonemap viridis|turbo    show the mean wavelength in false colour
+    //   --tone-curve <v0,v1,...>   tonemap by mapping [0, 1] onto the values
+    //   --gamma <g>                encode with a power curve instead of sRGB
+    //   --linear                   encode linearly, and tag images as linear
+    //   --white-balance <K>        make light of K Kelvin white
+    //   --chroma-filter            reduce colour noise before tonemapping
+    //
+    // Diagnostics:
+    //   --split-halves             report the difference between two half images
+    //   --clear-region <x0> <y0> <x1> <y1>
+    //                              discard a region of the resumed render
+    //   --debug-ray <x> <y>        print the path through a screen position, and exit
+    //   --animate <frames> <duration>
+    //                              render frames to frame0000.png and so on, with the
+    //                              number of batches per frame, and exit
+    //
+    // With a number of batches:
+    //   --png-format <format>      write output.png in a format such as rgb16
+    //   --hdr <file>               also write the image in the Radiance format
+    //   --sample-counts <file>     write the photons per pixel as an image
+    //   --measure <x> <y> <r>      print the luminance of a pixel, and the illuminance
+    //                              on the diffuse surface of reflectance r that it shows
+    let mut batches = None;
+    let mut contact_sheet_prefix = None;
+    let mut scene_path = None;
+    let mut adaptive = false;
+    let mut batch_size = app::DEFAULT_BATCH_SIZE;
+    let mut max_pixel_photons = None;
+    let mut seed = None;
+    let mut jittered = false;
+    let mut guide_wavelengths = None;
+    let mut integrator = Integrator::Path;
+    let mut spectral_samples = 1;
+    let mut operator = None;
+    let mut encoding = None;
+    let mut white_balance = None;
+    let mut chroma_filter = false;
+    let mut split_halves = false;
+    let mut clear_region = None;
+    let mut preview = false;
+    let mut debug_ray = None;
+    let mut animate = None;
+    let mut png_format = output::RGB8;
+    let mut hdr_path = None;
+    let mut sample_counts_path = None;
+    let mut measure = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--contact-sheet" => {
+                contact_sheet_prefix = Some(next_arg(&mut args, &arg, "a path prefix"));
+            },
+            "--adaptive" => adaptive = true,
+            "--batch-size" => {
+                batch_size = parse_next(&mut args, &arg, "the number of photons");
+            },
+            "--max-pixel-photons" => {
+                max_pixel_photons = Some(parse_next(&mut args, &arg, "a number"));
+            },
+            "--scene" => scene_path = Some(next_arg(&mut args, &arg, "a path")),
+            "--seed" => seed = Some(parse_next(&mut args, &arg, "a number")),
+            "--jittered" => jittered = true,
+            "--guide-wavelengths" => {
+                guide_wavelengths = Some(parse_next(&mut args, &arg, "the index of an object"));
+            },
+            "--integrator" => {
+                let name = next_arg(&mut args, &arg, "an integrator");
+                integrator = match &name[..] {
+                    "path" => Integrator::Path,
+                    "direct" => Integrator::Direct,
+                    "ao" => {
+                        Integrator::AmbientOcclusion(parse_next(&mut args, "--integrator ao",
+                                                                "a distance"))
+                    },
+                    _ => usage_error(&format!("unknown integrator '{}'", name))
+                };
+            },
+            "--spectral-samples" => {
+                spectral_samples = parse_next(&mut args, &arg, "the number of wavelengths");
+                if spectral_samples == 0 {
+                    usage_error("expected at least one wavelength after --spectral-samples");
+                }
+            },
+            "--tonemap" => {
+                let name = next_arg(&mut args, &arg, "an operator");
+                operator = Some(match &name[..] {
+                    "logarithmic" => TonemapOperator::Logarithmic,
+                    "filmic" => TonemapOperator::Filmic(FilmicCurve::new()),
+                    "reinhard" => TonemapOperator::Reinhard(None),
+                    "aces" => TonemapOperator::Aces,
+                    "viridis" => TonemapOperator::FalseColour(ColourMap::Viridis),
+                    "turbo" => TonemapOperator::FalseColour(ColourMap::Turbo),
+                    _ => usage_error(&format!("unknown tonemap operator '{}'", name))
+                });
+            },
+            "--tone-curve" => {
+                let list = next_arg(&mut args, &arg, "comma-separated values");
+                let entries = list.split(',').map(|x| x.parse().ok())
+                    .collect::<Option<Vec<f32>>>()
+                    .filter(|entries| entries.len() >= 2)
+                    .unwrap_or_else(|| {
+                        usage_error("expected at least two comma-separated values after \
+                                     --tone-curve")
+                    });
+                operator = Some(TonemapOperator::Curve(ToneCurve::new(0.0, 1.0, entries)));
+            },
+            "--gamma" => encoding = Some(Encoding::Gamma(parse_next(&mut args, &arg, "a gamma"))),
+            "--linear" => encoding = Some(Encoding::Gamma(1.0)),
+            "--white-balance" => {
+                white_balance = Some(parse_next(&mut args, &arg, "a temperature in Kelvin"));
+            },
+            "--chroma-filter" => chroma_filter = true,
+            "--split-halves" => split_halves = true,
+            "--clear-region" => {
+                let x0 = parse_next(&mut args, &arg, "a rectangle");
+                let y0 = parse_next(&mut args, &arg, "a rectangle");
+                let x1 = parse_next(&mut args, &arg, "a rectangle");
+                let y1 = parse_next(&mut args, &arg, "a rectangle");
+                clear_region = Some((x0, y0, x1, y1));
+            },
+            "--preview" => preview = true,
+            "--debug-ray" => {
+                let x = parse_next(&mut args, &arg, "a screen position");
+                let y = parse_next(&mut args, &arg, "a screen position");
+                debug_ray = Some((x, y));
+            },
+            "--animate" => {
+                let frames = parse_next(&mut args, &arg, "the number of frames");
+                let duration = parse_next(&mut args, &arg, "the number of frames and a duration");
+                animate = Some((frames, duration));
+            },
+            "--png-format" => {
+                let name = next_arg(&mut args, &arg, "a pixel format");
+                png_format = match PixelFormat::parse(&name) {
+                    Some(format) => format,
+                    None => usage_error(&format!("unknown pixel format '{}'", name))
+                };
+            },
+            "--hdr" => hdr_path = Some(next_arg(&mut args, &arg, "a path")),
+            "--sample-counts" => sample_counts_path = Some(next_arg(&mut args, &arg, "a path")),
+            "--measure" => {
+                let x = parse_next(&mut args, &arg, "a pixel");
+                let y = parse_next(&mut args, &arg, "a pixel");
+                let reflectance = parse_next(&mut args, &arg, "a pixel and a reflectance");
+                measure = Some((x, y, reflectance));
+            },
+            _ => match arg.parse() {
+                Ok(n) => batches = Some(n),
+                Err(_) => usage_error(&format!("unknown option '{}'", arg))
+            }
+        }
+    }
+
+    let mut scene = match scene_path {
+        Some(path) => match scene::load_json(&path) {
+            Ok(scene) => scene,
+            Err(reason) => {
+                eprintln!("failed to load scene from {}: {}", path, reason);
+                process::exit(1);
+            }
+        },
+        None => App::set_up_scene()
+    };
+
+    // The render threads seed their own samplers, but the debug ray and the
+    // preview are traced on this thread.
+    if let Some(seed) = seed {
+        monte_carlo::seed([seed, 0, 0, 0]);
+    }
+
+    if let Some((x, y)) = debug_ray {
+        scene.build_bvh(concurrency);
+        scene.build_light_distribution();
+        print_debug_ray(&scene, x, y);
+        return;
+    }
+
+    if let Some((frames, duration)) = animate {
+        let mut animation = Animation::new(frames, 0.0, duration);
+        animation.batches_per_frame = batches.unwrap_or(animation.batches_per_frame);
+        animation.batch_size = batch_size;
+        animation.seed = seed.unwrap_or(0);
+        scene.build_bvh(concurrency);
+        scene.build_light_distribution();
+        if let Err(reason) = animation.render_to_files(&scene, width, height, "frame") {
+            println!("failed to render the animation: {}", reason);
+        }
+        return;
+    }
+
+    // A render with a number of batches starts afresh, an interactive render
+    // continues the previous one, if there is one.
+    let mut ts = match batches {
+        Some(_) => TaskScheduler::new_empty(concurrency, width, height, batch_size, seed),
+        None => TaskScheduler::new(concurrency, width, height, batch_size, seed)
+    };
+    if adaptive {
+        ts.adaptive_tiles = Some(app::ADAPTIVE_TILES);
+    }
+
+    {
+        let gather_unit = ts.gather_unit();
+        gather_unit.max_pixel_photons = max_pixel_photons;
+        if let Some(prefix) = contact_sheet_prefix {
+            let milestones = ContactSheet::powers_of_four(batches.unwrap_or(1 << 20));
+            gather_unit.contact_sheet = Some(ContactSheet::new(width, height, milestones, prefix));
+        }
+        if split_halves {
+            gather_unit.split_halves();
+        }
+        if let Some((x0, y0, x1, y1)) = clear_region {
+            gather_unit.clear_region(x0, y0, x1, y1);
+        }
+    }
+
+    // False colour images need the mean wavelength of every pixel, rather
+    // than its tristimulus value.
+    let plot_mode = match operator {
+        Some(TonemapOperator::FalseColour(_)) => PlotMode::Wavelength,
+        _ => PlotMode::Tristimulus
+    };
+    for plot_unit in ts.plot_units() {
+        plot_unit.mode = plot_mode;
+    }
+    {
+        let tonemap_unit = ts.tonemap_unit();
+        if let Some(operator) = operator {
+            tonemap_unit.operator = operator;
+        }
+        if let Some(encoding) = encoding {
+            tonemap_unit.encoding = encoding;
+        }
+        tonemap_unit.white_balance = white_balance;
+        if chroma_filter {
+            tonemap_unit.chroma_filter = Some(ChromaFilter::new());
+        }
+    }
+
+    {
+        let guide_material = guide_wavelengths.map(|i: usize| {
+            match scene.objects.get(i).map(|obj| &obj.material) {
+                Some(&Reflective(ref material)) => material,
+                _ => usage_error(&format!("object {} does not exist, or it is not reflective", i))
+            }
+        });
+        for trace_unit in ts.trace_units() {
+            trace_unit.integrator = integrator;
+            trace_unit.set_spectral_samples(spectral_samples);
+            if jittered {
+                trace_unit.screen_sampling = ScreenSampling::Jittered;
+            }
+            if let Some(material) = guide_material {
+                trace_unit.guide_wavelengths(&**material);
+            }
+        }
+    }
+
+    if preview {
+        scene.build_bvh(concurrency);
+        scene.build_light_distribution();
+        let mut trace_unit = TraceUnit::new(0, width, height, batch_size);
+        let mut plot_unit = PlotUnit::new(0, width, height);
+        plot_unit.mode = plot_mode;
+        trace_unit.render_preview(&scene);
+        plot_unit.plot(&trace_unit.mapped_photons);
+        ts.gather_unit().seed_preview(&plot_unit.tristimulus_buffer, PREVIEW_WEIGHT);
+    }
+
+    // With a number of batches, render that many batches without
+    // interruption, write the image, and exit.
+    if let Some(batches) = batches {
+        ts.stop_criteria.max_batches = Some(batches);
+        let gather_unit = match App::render_to_file(concurrency, ts, scene, "output.png",
+                                                    png_format) {
+            Ok(gather_unit) => gather_unit,
+            Err(reason) => {
+                println!("failed to render output png: {}", reason);
+                return;
+            }
+        };
+        println!("wrote image to output.png");
+
+        if let Some(path) = hdr_path {
+            match gather_unit.save_hdr(&path) {
+                Ok(()) => println!("wrote hdr image to {}", path),
+                Err(reason) => println!("failed to write hdr image: {}", reason)
+            }
+        }
+        if let Some(path) = sample_counts_path {
+            let image = gather_unit.sample_count_image();
+            match output::save_png(&path, &image, width, height, ColourSpace::LinearSrgb) {
+                Ok(()) => println!("wrote sample counts to {}", path),
+                Err(reason) => println!("failed to write sample counts: {}", reason)
+            }
+        }
+        if let Some((x, y, reflectance)) = measure {
+            // Every batch that the pixel received had the same number of
+            // photons.
+            let batches = gather_unit.sample_count(x, y);
+            let photons = batches as u64 * batch_size as u64;
+            println!("pixel ({}, {}) received {} batches ({} photons counted)",
+                     x, y, batches, gather_unit.photon_count(x, y));
+            println!("luminance: {} cd/m^2, illuminance: {} lux",
+                     gather_unit.luminance(x, y, photons, 1.0),
+                     gather_unit.illuminance(x, y, photons, 1.0, reflectance));
+        }
+        return;
+    }
+
     // Start up the path tracer. It begins rendering immediately.
-    let width = 1280u32;
-    let height = 720u32;
-    let app = App::new(width, height);
+    let app = App::start(concurrency, ts, scene);
     let images = app.images;
+    let colour_space = if encoding == Some(Encoding::Gamma(1.0)) {
+        ColourSpace::LinearSrgb
+    } else {
+        ColourSpace::Srgb
+    };
 
     println!("press ctrl+c to stop rendering");
 
@@ -58,8 +501,7 @@ fn main() {
         let img = images.recv().unwrap();
 
         // Write the image to output.png.
-        match image::save_buffer("output.png", &img,
-                                 width, height, image::RGB(8)) {
+        match output::save_png("output.png", &img, width, height, colour_space) {
             Ok(_) => println!("wrote image to output.png"),
             Err(reason) => println!("failed to write output png: {}", reason)
         }
@@ -68,7 +510,7 @@ fn main() {
 
 #[test]
 fn simulate_main() {
-    let width = 1280u32;
-    let height = 720u32;
+    let width = app::DEFAULT_WIDTH;
+    let height = app::DEFAULT_HEIGHT;
     App::new_test(width, height);
 }
@@ -17,26 +17,51 @@
 use std::f32::consts::PI;
 use intersection::Intersection;
 use ray::Ray;
-use vector3::dot;
+use spectrum::SampledSpectrum;
+use vector3::{Vector3, dot};
 use constants::{BOLTZMANNS_CONSTANT, SPEED_OF_LIGHT, PLANCKS_CONSTANT, WIENS_CONSTANT};
 
 /// Models the behaviour of a ray when it bounces off a surface.
-pub trait Material {
+///
+/// Scenes are shared between render threads, so materials must be
+/// `Send + Sync`; a material that holds non-thread-safe state, such as a
+/// `Cell`, is rejected when its `impl` is compiled.
+pub trait Material: Send + Sync {
     /// Returns the ray that continues the light path, backwards from the
     /// camera to the light source.
     fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray;
+
+    /// Returns the reflectance at the specified wavelength if the material
+    /// is perfectly diffuse, with `get_new_ray` sampling the cosine-weighted
+    /// hemisphere. Light sources are then sampled directly at every bounce,
+    /// and the path continues with this reflectance, without calling
+    /// `get_new_ray` (see `Shading`). Other materials return `None`.
+    fn get_diffuse_reflectance(&self, _wavelength: f32) -> Option<f32> { None }
+
+    /// Returns how important the specified wavelength is for this material,
+    /// for sampling wavelengths with a `WavelengthDistribution`. Saturated
+    /// materials reflect little light at most wavelengths, so sampling
+    /// those is wasteful. By default this is the diffuse reflectance.
+    fn get_wavelength_importance(&self, wavelength: f32) -> Option<f32> {
+        self.get_diffuse_reflectance(wavelength)
+    }
 }
 
 /// Models the behavior of a light-emitting surface. Light-emitting surfaces
 /// are handled independently of reflecting surfaces.
-pub trait EmissiveMaterial {
+/// Like `Material`, emissive materials must be `Send + Sync`.
+pub trait EmissiveMaterial: Send + Sync {
     /// Returns the light intensity at the specified `wavelength`.
     fn get_intensity(&self, wavelength: f32) -> f32;
 }
 
 /// Returns a ray as if reflected by a perfectly diffuse white material.
+/// The direction is cosine-weighted about the normal, so the density of the
+/// samples cancels the cosine in the rendering equation, and a Lambertian
+/// material only has to weight the ray by its reflectance.
 fn get_diffuse_ray(incoming_ray: &Ray, intersection: &Intersection) -> Ray {
-    // Generate a ray in a random direction,
+    // Generate a ray in a random direction (cosine-weighted by projecting
+    // a uniform point on the disk onto the hemisphere, Malley's method),
     // originating from the intersection.
     let hemi_vec = ::monte_carlo::get_hemisphere_vector();
 
@@ -57,6 +82,40 @@ fn get_diffuse_ray(incoming_ray: &Ray, intersection: &Intersection) -> Ray {
     }
 }
 
+/// The terms of a material at a single interaction. They are evaluated once,
+/// so that sampling the lights directly and continuing the path share them.
+pub struct Shading<'a> {
+    material: &'a Material,
+
+    /// The diffuse reflectance at the wavelength of the interaction, if the
+    /// material is diffuse.
+    pub diffuse_reflectance: Option<f32>
+}
+
+impl<'a> Shading<'a> {
+    /// Evaluates the terms of `material` for light of the specified wavelength.
+    pub fn new(material: &'a Material, wavelength: f32) -> Shading<'a> {
+        Shading {
+            material: material,
+            diffuse_reflectance: material.get_diffuse_reflectance(wavelength)
+        }
+    }
+
+    /// Returns the ray that continues the light path, like
+    /// `Material::get_new_ray`, but for a diffuse material, the reflectance
+    /// is not evaluated again.
+    pub fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+        match self.diffuse_reflectance {
+            Some(reflectance) => {
+                let mut ray = get_diffuse_ray(incoming_ray, intersection);
+                ray.probability = reflectance;
+                ray
+            },
+            None => self.material.get_new_ray(incoming_ray, intersection)
+        }
+    }
+}
+
 /// The Boltzmann distribution.
 fn boltzmann(wavelength: f64, temperature: f64) -> f64 {
     // Use double precision here, the numbers are quite large/small,
@@ -104,6 +163,30 @@ impl EmissiveMaterial for BlackBodyMaterial {
     }
 }
 
+/// A light with an arbitrary spectrum, such as a fluorescent lamp or a laser,
+/// given by intensities at a number of wavelengths that are interpolated
+/// linearly. Outside of the sampled wavelengths, it emits nothing.
+pub struct TabulatedEmissiveMaterial {
+    /// The intensity per wavelength.
+    spectrum: SampledSpectrum
+}
+
+impl TabulatedEmissiveMaterial {
+    pub fn new(spectrum: SampledSpectrum) -> TabulatedEmissiveMaterial {
+        TabulatedEmissiveMaterial {
+            spectrum: spectrum
+        }
+    }
+}
+
+impl EmissiveMaterial for TabulatedEmissiveMaterial {
+    fn get_intensity(&self, wavelength: f32) -> f32 {
+        let (min, max) = self.spectrum.range();
+        if wavelength < min || wavelength > max { return 0.0; }
+        self.spectrum.evaluate(wavelength)
+    }
+}
+
 /// A perfectly diffuse material that reflects all wavelengths perfectly,
 /// but absorbes some energy.
 pub struct DiffuseGreyMaterial {
@@ -127,6 +210,10 @@ impl Material for DiffuseGreyMaterial {
         ray.probability = self.reflectance;
         ray
     }
+
+    fn get_diffuse_reflectance(&self, _wavelength: f32) -> Option<f32> {
+        Some(self.reflectance)
+    }
 }
 
 /// Reflects light of a certain wavelength better than others,
@@ -150,21 +237,56 @@ impl DiffuseColouredMaterial {
             deviation: dev
         }
     }
-}
 
-impl Material for DiffuseColouredMaterial {
-    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+    /// Returns the reflectance at the specified wavelength.
+    fn get_reflectance(&self, wavelength: f32) -> f32 {
         // Compute the probability using Gaussian falloff.
-        let p = (self.wavelength - incoming_ray.wavelength) / self.deviation;
+        let p = (self.wavelength - wavelength) / self.deviation;
         let q = (-0.5 * p * p).exp();
+        self.reflectance * q
+    }
+}
 
+impl Material for DiffuseColouredMaterial {
+    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
         let mut ray = get_diffuse_ray(incoming_ray, intersection);
         
         // The probablity is a combination of reflectance, and the probability
         // based on the wavelength.
-        ray.probability = self.reflectance * q;
+        ray.probability = self.get_reflectance(incoming_ray.wavelength);
         ray
     }
+
+    fn get_diffuse_reflectance(&self, wavelength: f32) -> Option<f32> {
+        Some(self.get_reflectance(wavelength))
+    }
+}
+
+/// A diffuse material with a reflectance that varies with wavelength as
+/// given by a sampled spectrum, such as a measured reflectance curve.
+pub struct SpectralDiffuseMaterial {
+    /// The reflectance per wavelength; values should lie in [0, 1].
+    reflectance: SampledSpectrum
+}
+
+impl SpectralDiffuseMaterial {
+    pub fn new(reflectance: SampledSpectrum) -> SpectralDiffuseMaterial {
+        SpectralDiffuseMaterial {
+            reflectance: reflectance
+        }
+    }
+}
+
+impl Material for SpectralDiffuseMaterial {
+    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+        let mut ray = get_diffuse_ray(incoming_ray, intersection);
+        ray.probability = self.reflectance.evaluate(incoming_ray.wavelength);
+        ray
+    }
+
+    fn get_diffuse_reflectance(&self, wavelength: f32) -> Option<f32> {
+        Some(self.reflectance.evaluate(wavelength))
+    }
 }
 
 /// Blends between perfect reflection and diffuse.
@@ -195,6 +317,156 @@ impl Material for GlossyMirrorMaterial {
     }
 }
 
+/// A perfect mirror that reflects all wavelengths equally well.
+pub struct SpecularMaterial {
+    /// How much the mirror reflects; 1.0 is a perfect mirror, metals are
+    /// usually somewhat lower.
+    reflectance: f32
+}
+
+impl SpecularMaterial {
+    pub fn new(refl: f32) -> SpecularMaterial {
+        SpecularMaterial {
+            reflectance: refl
+        }
+    }
+}
+
+impl Material for SpecularMaterial {
+    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+        // There is only one direction in which the ray can be reflected,
+        // so the probability is just the reflectance.
+        Ray {
+            origin: intersection.position,
+            direction: incoming_ray.direction.reflect(intersection.normal),
+            wavelength: incoming_ray.wavelength,
+            probability: self.reflectance
+        }
+    }
+}
+
+/// Returns the Smith masking function for the GGX distribution with
+/// roughness `alpha`, for a direction at `cos_v` with the normal.
+fn smith_ggx(cos_v: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2.0 * cos_v / (cos_v + (a2 + (1.0 - a2) * cos_v * cos_v).sqrt())
+}
+
+/// A glossy reflector with rough microfacets that have the GGX
+/// (Trowbridge-Reitz) distribution of normals.
+pub struct GlossyMaterial {
+    /// The width of the distribution of microfacet normals, which is the
+    /// square of the perceptual roughness.
+    alpha: f32
+}
+
+impl GlossyMaterial {
+    /// Creates a glossy material with the specified roughness, where 0.0
+    /// is a perfect mirror, and 1.0 spreads the reflection broadly.
+    pub fn new(roughness: f32) -> GlossyMaterial {
+        GlossyMaterial {
+            alpha: roughness * roughness
+        }
+    }
+}
+
+impl Material for GlossyMaterial {
+    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+        // Use the normal on the side of the incoming ray.
+        let normal = if dot(incoming_ray.direction, intersection.normal) < 0.0 {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+
+        // Sample a microfacet normal proportional to its projected area,
+        // D(h) cos(theta_h), and mirror the ray in it.
+        let u = ::monte_carlo::get_unit().min(0.9999);
+        let phi = ::monte_carlo::get_longitude();
+        let tan_theta = self.alpha * (u / (1.0 - u)).sqrt();
+        let cos_theta = 1.0 / (1.0 + tan_theta * tan_theta).sqrt();
+        let sin_theta = tan_theta * cos_theta;
+        let micro_normal = Vector3::new(phi.cos() * sin_theta,
+                                        phi.sin() * sin_theta,
+                                        cos_theta).rotate_towards(normal);
+        let direction = incoming_ray.direction.reflect(micro_normal);
+
+        // With this sampling, the BRDF times the cosine over the pdf is
+        // G (i . h) / ((i . n) (h . n)). A reflection below the surface is
+        // absorbed.
+        let cos_i = -dot(incoming_ray.direction, normal);
+        let cos_o = dot(direction, normal);
+        let probability = if cos_o <= 0.0 || cos_i <= 0.0 {
+            0.0
+        } else {
+            let g = smith_ggx(cos_i, self.alpha) * smith_ggx(cos_o, self.alpha);
+            let cos_ih = -dot(incoming_ray.direction, micro_normal);
+            (g * cos_ih / (cos_i * cos_theta)).min(1.0)
+        };
+
+        Ray {
+            origin: intersection.position,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: probability
+        }
+    }
+}
+
+/// Returns Schlick's approximation of the Fresnel reflectance, for the cosine
+/// of the angle of incidence `cos_i`, and the reflectance at normal
+/// incidence `f0`.
+fn fresnel_schlick(cos_i: f32, f0: f32) -> f32 {
+    let c = 1.0 - cos_i.abs().min(1.0);
+    f0 + (1.0 - f0) * c * c * c * c * c
+}
+
+/// A diffuse base under a clear dielectric coating, like plastic or paint.
+/// The coating reflects specularly, more so at grazing angles.
+pub struct PlasticMaterial {
+    /// How much the diffuse base reflects; 0.0 is black, 1.0 is white.
+    reflectance: f32,
+
+    /// The reflectance of the coating at normal incidence.
+    specular_reflectance: f32
+}
+
+impl PlasticMaterial {
+    /// Creates a plastic material with the specified diffuse reflectance, and
+    /// a coating with the specified index of refraction (around 1.5).
+    pub fn new(refl: f32, ior: f32) -> PlasticMaterial {
+        let r = (ior - 1.0) / (ior + 1.0);
+        PlasticMaterial {
+            reflectance: refl,
+            specular_reflectance: r * r
+        }
+    }
+}
+
+impl Material for PlasticMaterial {
+    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+        let cos_i = dot(incoming_ray.direction, intersection.normal);
+        let fresnel = fresnel_schlick(cos_i, self.specular_reflectance);
+
+        // Pick the specular lobe with the probability that the coating
+        // reflects. Because the lobe is chosen with exactly its own weight,
+        // the weights cancel, and a specular reflection carries everything.
+        if ::monte_carlo::get_unit() < fresnel {
+            Ray {
+                origin: intersection.position,
+                direction: incoming_ray.direction.reflect(intersection.normal),
+                wavelength: incoming_ray.wavelength,
+                probability: 1.0
+            }
+        } else {
+            // The light that passed the coating is reflected by the base.
+            let mut ray = get_diffuse_ray(incoming_ray, intersection);
+            ray.probability = self.reflectance;
+            ray
+        }
+    }
+}
+
 /// Refractive glass.
 pub struct Sf10GlassMaterial;
 
@@ -215,7 +487,7 @@ impl Sf10GlassMaterial {
 
 impl Material for Sf10GlassMaterial {
     fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
-        let mut cos_i = -dot(incoming_ray.direction, intersection.normal);
+        let cos_i = -dot(incoming_ray.direction, intersection.normal);
 
         // Retrieve the index of refraction to be used,
         // which can be wavelength-dependent.
@@ -231,19 +503,13 @@ impl Material for Sf10GlassMaterial {
             // The formula below assumes the normal to be at the same side as
             // the incident ray. If this is not the case, reverse the normal.
             normal = -normal;
-            cos_i = -cos_i;
         }
 
-        let sin_t_sqr = ior * ior * (1.0 - cos_i * cos_i);
-
-        let dir = if sin_t_sqr > 1.0 {
+        let dir = match incoming_ray.direction.refract(normal, ior) {
+            Some(refracted) => refracted,
             // When refraction is impossible, total internal reflection must
             // have occurred.
-            incoming_ray.direction.reflect(normal)
-        } else {
-            // Otherwise compute the reflected ray.
-            let cos_t = (1.0 - sin_t_sqr).sqrt();
-            incoming_ray.direction * ior + normal * (ior * cos_i - cos_t)
+            None => incoming_ray.direction.reflect(normal)
         };
 
         // There is only one way in which the ray can be refracted,
@@ -260,6 +526,155 @@ impl Material for Sf10GlassMaterial {
     }
 }
 
+/// A wavelength-dependent index of refraction for dielectric materials.
+pub trait RefractiveIndex: Send + Sync {
+    /// Returns the index of refraction at the specified wavelength in nm.
+    fn get_index_of_refraction(&self, wavelength: f32) -> f32;
+}
+
+impl<F> RefractiveIndex for F where F: Fn(f32) -> f32 + Send + Sync {
+    fn get_index_of_refraction(&self, wavelength: f32) -> f32 {
+        self(wavelength)
+    }
+}
+
+/// An index of refraction given by the Sellmeier equation,
+/// n^2 = 1 + sum of B_i w^2 / (w^2 - C_i), with the wavelength w in um.
+pub struct SellmeierGlass {
+    /// The coefficients B1, B2 and B3.
+    b: [f64; 3],
+
+    /// The coefficients C1, C2 and C3, in um^2.
+    c: [f64; 3]
+}
+
+impl SellmeierGlass {
+    /// Creates glass with the specified Sellmeier coefficients, as they are
+    /// listed in glass catalogues.
+    pub fn new(b: [f64; 3], c: [f64; 3]) -> SellmeierGlass {
+        SellmeierGlass {
+            b: b,
+            c: c
+        }
+    }
+
+    /// Returns Schott N-BK7, a common crown glass.
+    pub fn bk7() -> SellmeierGlass {
+        SellmeierGlass::new([1.03961212, 0.231792344, 1.01046945],
+                            [0.00600069867, 0.0200179144, 103.560653])
+    }
+}
+
+impl RefractiveIndex for SellmeierGlass {
+    fn get_index_of_refraction(&self, wavelength: f32) -> f32 {
+        // Square and convert nanometer to micrometer.
+        let w2 = (wavelength * wavelength * 1.0e-6) as f64;
+        let n2 = self.b.iter().zip(self.c.iter())
+                     .fold(1.0, |acc, (&b, &c)| acc + b * w2 / (w2 - c));
+        n2.sqrt() as f32
+    }
+}
+
+/// An index of refraction given by Cauchy's equation, n = A + B / w^2,
+/// specified by the index of refraction at the Fraunhofer d line (587.6 nm)
+/// and the Abbe number. A low Abbe number means strong dispersion, like
+/// flint glass; a high number means weak dispersion, like crown glass.
+pub struct CauchyGlass {
+    /// The constant term A.
+    a: f32,
+
+    /// The coefficient B, in nm^2.
+    b: f32
+}
+
+impl CauchyGlass {
+    /// Creates glass with index of refraction `ior` at 587.6 nm and the
+    /// specified Abbe number, (n_d - 1) / (n_F - n_C).
+    pub fn new(ior: f32, abbe_number: f32) -> CauchyGlass {
+        // The Fraunhofer F, C and d lines, in nm.
+        let (lambda_f, lambda_c, lambda_d) = (486.1f32, 656.3f32, 587.6f32);
+
+        // With Cauchy's equation, n_F - n_C = B (1 / F^2 - 1 / C^2).
+        let b = (ior - 1.0) / (abbe_number
+                               * (1.0 / (lambda_f * lambda_f) - 1.0 / (lambda_c * lambda_c)));
+        CauchyGlass {
+            a: ior - b / (lambda_d * lambda_d),
+            b: b
+        }
+    }
+}
+
+impl RefractiveIndex for CauchyGlass {
+    fn get_index_of_refraction(&self, wavelength: f32) -> f32 {
+        self.a + self.b / (wavelength * wavelength)
+    }
+}
+
+/// Returns the Fresnel reflectance for unpolarised light that arrives at
+/// an interface where the ratio of indices of refraction is `eta` (n1 / n2),
+/// at the cosines of the incident and transmitted angles.
+fn fresnel_dielectric(cos_i: f32, cos_t: f32, eta: f32) -> f32 {
+    let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_s * r_s + r_p * r_p) * 0.5
+}
+
+/// A transparent material like glass or water, that either reflects or
+/// refracts a ray, with the probabilities of the Fresnel equations.
+pub struct DielectricMaterial {
+    /// The index of refraction of the material, relative to air.
+    ior: Box<RefractiveIndex>
+}
+
+impl DielectricMaterial {
+    /// Creates a dielectric with the specified, possibly
+    /// wavelength-dependent, index of refraction.
+    pub fn new(ior: Box<RefractiveIndex>) -> DielectricMaterial {
+        DielectricMaterial {
+            ior: ior
+        }
+    }
+}
+
+impl Material for DielectricMaterial {
+    fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+        let mut cos_i = -dot(incoming_ray.direction, intersection.normal);
+        let mut eta = 1.0 / self.ior.get_index_of_refraction(incoming_ray.wavelength);
+        let mut normal = intersection.normal;
+
+        // When the ray leaves the material, the indices swap, and the
+        // normal must be flipped to be on the side of the incident ray.
+        if cos_i < 0.0 {
+            eta = 1.0 / eta;
+            normal = -normal;
+            cos_i = -cos_i;
+        }
+
+        // Beyond the critical angle there is total internal reflection.
+        // Otherwise, reflect with the probability given by the Fresnel
+        // equations. Because the choice is made with exactly the weight of
+        // either event, the weights cancel, and the probability is 1.
+        let direction = match incoming_ray.direction.refract(normal, eta) {
+            None => incoming_ray.direction.reflect(normal),
+            Some(refracted) => {
+                let cos_t = -dot(refracted, normal);
+                if ::monte_carlo::get_unit() < fresnel_dielectric(cos_i, cos_t, eta) {
+                    incoming_ray.direction.reflect(normal)
+                } else {
+                    refracted
+                }
+            }
+        };
+
+        Ray {
+            origin: intersection.position,
+            direction: direction,
+            wavelength: incoming_ray.wavelength,
+            probability: 1.0
+        }
+    }
+}
+
 /// Not a physically accurate thin-film material, but still an aesthetically
 /// pleasing soap bubble material.
 pub struct SoapBubbleMaterial;
@@ -304,3 +719,388 @@ impl Material for SoapBubbleMaterial {
         }
     }
 }
+
+#[test]
+fn non_sync_material_is_rejected() {
+    use std::cell::Cell;
+    use std::marker::PhantomData;
+
+    // A material that counts its bounces in a `Cell`. Writing
+    // `impl Material for CountingMaterial` would not compile.
+    #[allow(dead_code)]
+    struct CountingMaterial {
+        bounces: Cell<u32>
+    }
+
+    // Inherent methods take precedence over trait methods, but the inherent
+    // one only exists for `Send + Sync` types. This detects the bounds that
+    // `Material` requires without failing to compile.
+    struct Probe<T>(PhantomData<T>);
+    trait NotShareable { fn is_shareable(&self) -> bool { false } }
+    impl<T> NotShareable for Probe<T> { }
+    impl<T: Send + Sync> Probe<T> { fn is_shareable(&self) -> bool { true } }
+
+    assert!(!Probe::<CountingMaterial>(PhantomData).is_shareable());
+    assert!(Probe::<DiffuseGreyMaterial>(PhantomData).is_shareable());
+    assert!(Probe::<BlackBodyMaterial>(PhantomData).is_shareable());
+}
+
+#[test]
+fn plastic_is_specular_at_grazing_angles() {
+    let plastic = PlasticMaterial::new(0.8, 1.5);
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: up,
+        tangent: Vector3::new(1.0, 0.0, 0.0),
+        distance: 1.0
+    };
+
+    // Returns the fraction of bounces that are mirror reflections.
+    let specular_fraction = |direction: Vector3| {
+        let ray = Ray {
+            origin: -direction,
+            direction: direction,
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let mirror = direction.reflect(up);
+        let n = 10000;
+        let specular = (0 .. n).filter(|_| {
+            let new_ray = plastic.get_new_ray(&ray, &intersection);
+            (new_ray.direction - mirror).magnitude() < 1.0e-5
+        }).count();
+        specular as f32 / n as f32
+    };
+
+    let normal = specular_fraction(-up);
+    let oblique = specular_fraction(Vector3::new(0.966, 0.0, -0.259).normalise());
+    let grazing = specular_fraction(Vector3::new(0.995, 0.0, -0.1).normalise());
+
+    // At normal incidence, 4% is reflected by the coating.
+    assert!((normal - 0.04).abs() < 0.01);
+    assert!(oblique > normal);
+    assert!(grazing > 0.5);
+}
+
+#[test]
+fn low_abbe_number_disperses_more() {
+    let flint = CauchyGlass::new(1.62, 36.0);
+    let crown = CauchyGlass::new(1.52, 64.0);
+
+    // The index at the d line and the Abbe number are reproduced.
+    let abbe_number = |glass: &CauchyGlass| {
+        let n = |wavelength: f32| glass.get_index_of_refraction(wavelength);
+        (n(587.6) - 1.0) / (n(486.1) - n(656.3))
+    };
+    assert!((flint.get_index_of_refraction(587.6) - 1.62).abs() < 1.0e-5);
+    assert!((abbe_number(&flint) - 36.0).abs() < 1.0e-2);
+    assert!((abbe_number(&crown) - 64.0).abs() < 1.0e-2);
+
+    // Blue light is refracted more than red light, more so by flint glass.
+    let spread = |glass: &CauchyGlass| {
+        glass.get_index_of_refraction(450.0) - glass.get_index_of_refraction(650.0)
+    };
+    assert!(spread(&crown) > 0.0);
+    assert!(spread(&flint) > spread(&crown) * 1.5);
+}
+
+#[test]
+fn dielectric_refracts_by_snells_law() {
+    let glass = DielectricMaterial::new(Box::new(|_| 1.5));
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: up,
+        tangent: Vector3::new(1.0, 0.0, 0.0),
+        distance: 1.0
+    };
+    let ray = |direction: Vector3| Ray {
+        origin: -direction,
+        direction: direction,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // Enter the glass at 30 degrees from the normal. Most rays refract,
+    // with sin(t) = sin(i) / 1.5; the others are mirrored.
+    let incoming = ray(Vector3::new(0.5, 0.0, -0.75f32.sqrt()));
+    let n = 10000;
+    let mut reflected = 0;
+    for _ in 0 .. n {
+        let new_ray = glass.get_new_ray(&incoming, &intersection);
+        assert_eq!(new_ray.probability, 1.0);
+        if new_ray.direction.z > 0.0 {
+            reflected += 1;
+            assert!((new_ray.direction - incoming.direction.reflect(up)).magnitude() < 1.0e-5);
+        } else {
+            assert!((new_ray.direction.magnitude() - 1.0).abs() < 1.0e-5);
+            assert!((new_ray.direction.x - 0.5 / 1.5).abs() < 1.0e-5);
+            assert!(new_ray.direction.y.abs() < 1.0e-5);
+        }
+    }
+
+    // The Fresnel reflectance at 30 degrees is about 4.2%.
+    let fraction = reflected as f32 / n as f32;
+    assert!((fraction - 0.042).abs() < 0.01);
+
+    // Leaving the glass at 60 degrees exceeds the critical angle of 41.8
+    // degrees, so the ray is always reflected.
+    let leaving = ray(Vector3::new(0.75f32.sqrt(), 0.0, 0.5));
+    for _ in 0 .. 100 {
+        let new_ray = glass.get_new_ray(&leaving, &intersection);
+        assert!(new_ray.direction.z < 0.0);
+    }
+}
+
+#[test]
+fn specular_reflects_about_the_normal() {
+    let mirror = SpecularMaterial::new(0.9);
+    let intersection = Intersection {
+        position: Vector3::new(1.0, 2.0, 0.0),
+        normal: Vector3::new(0.0, 0.0, 1.0),
+        tangent: Vector3::new(1.0, 0.0, 0.0),
+        distance: 1.0
+    };
+
+    // Arrive at 45 degrees, and leave at 45 degrees on the other side.
+    let d = 0.5f32.sqrt();
+    let incoming = Ray {
+        origin: Vector3::new(0.0, 2.0, 1.0),
+        direction: Vector3::new(d, 0.0, -d),
+        wavelength: 430.0,
+        probability: 1.0
+    };
+    let ray = mirror.get_new_ray(&incoming, &intersection);
+
+    assert!((ray.direction - Vector3::new(d, 0.0, d)).magnitude() < 1.0e-6);
+    assert!((ray.origin - intersection.position).magnitude() < 1.0e-6);
+    assert_eq!(ray.wavelength, 430.0);
+    assert_eq!(ray.probability, 0.9);
+}
+
+#[test]
+fn sellmeier_glass_bends_blue_more_than_red() {
+    let bk7 = SellmeierGlass::bk7();
+    assert!((bk7.get_index_of_refraction(587.6) - 1.5168).abs() < 1.0e-4);
+
+    let glass = DielectricMaterial::new(Box::new(SellmeierGlass::bk7()));
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: Vector3::new(0.0, 0.0, 1.0),
+        tangent: Vector3::new(1.0, 0.0, 0.0),
+        distance: 1.0
+    };
+
+    // Returns the sine of the refraction angle for a ray that enters the
+    // glass at 45 degrees. Some rays are reflected, those are skipped.
+    let sin_refracted = |wavelength: f32| {
+        let d = 0.5f32.sqrt();
+        let incoming = Ray {
+            origin: Vector3::new(-d, 0.0, d),
+            direction: Vector3::new(d, 0.0, -d),
+            wavelength: wavelength,
+            probability: 1.0
+        };
+        loop {
+            let ray = glass.get_new_ray(&incoming, &intersection);
+            if ray.direction.z < 0.0 {
+                return ray.direction.x;
+            }
+        }
+    };
+
+    // A smaller angle with the normal means that the ray was bent more.
+    let (blue, red) = (sin_refracted(400.0), sin_refracted(700.0));
+    assert!(blue < red);
+    let n_blue = bk7.get_index_of_refraction(400.0);
+    assert!((blue - 0.5f32.sqrt() / n_blue).abs() < 1.0e-5);
+}
+
+#[test]
+fn glossy_spreads_with_roughness() {
+    ::monte_carlo::seed([2, 7, 1, 8]);
+
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: up,
+        tangent: Vector3::new(1.0, 0.0, 0.0),
+        distance: 1.0
+    };
+    let d = 0.5f32.sqrt();
+    let incoming = Ray {
+        origin: Vector3::new(-d, 0.0, d),
+        direction: Vector3::new(d, 0.0, -d),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let mirror = incoming.direction.reflect(up);
+
+    // Returns the mean angle between the reflected rays and the mirror
+    // direction, and the mean probability.
+    let spread = |roughness: f32| {
+        let glossy = GlossyMaterial::new(roughness);
+        let n = 10000;
+        let (mut angle, mut probability) = (0.0, 0.0);
+        for _ in 0 .. n {
+            let ray = glossy.get_new_ray(&incoming, &intersection);
+            assert!(ray.probability >= 0.0 && ray.probability <= 1.0);
+            angle += dot(ray.direction, mirror).max(-1.0).min(1.0).acos();
+            probability += ray.probability;
+        }
+        (angle / n as f32, probability / n as f32)
+    };
+
+    // Without roughness, it is a perfect mirror.
+    let (a0, p0) = spread(0.0);
+    assert!(a0 < 1.0e-3);
+    assert!((p0 - 1.0).abs() < 1.0e-5);
+
+    let (a1, _) = spread(0.2);
+    let (a2, _) = spread(0.5);
+    let (a3, p3) = spread(1.0);
+    assert!(a0 < a1 && a1 < a2 && a2 < a3);
+    assert!(a3 > 0.5);
+
+    // Rough surfaces lose some energy to masking and shadowing.
+    assert!(p3 < 1.0 && p3 > 0.3);
+}
+
+#[test]
+fn tabulated_spectrum_emits_a_line() {
+    // A narrow line at 532 nm, like a green laser pointer.
+    let line = SampledSpectrum::new(&[(530.0, 0.0), (532.0, 5.0), (534.0, 0.0)]).unwrap();
+    let laser = TabulatedEmissiveMaterial::new(line);
+
+    assert_eq!(laser.get_intensity(532.0), 5.0);
+    assert!((laser.get_intensity(531.0) - 2.5).abs() < 1.0e-5);
+    assert_eq!(laser.get_intensity(530.0), 0.0);
+
+    // Away from the line, and outside the table, there is no light.
+    assert_eq!(laser.get_intensity(450.0), 0.0);
+    assert_eq!(laser.get_intensity(534.5), 0.0);
+    assert_eq!(laser.get_intensity(700.0), 0.0);
+}
+
+#[test]
+fn spectral_diffuse_uses_the_reflectance_curve() {
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: up,
+        tangent: Vector3::new(1.0, 0.0, 0.0),
+        distance: 1.0
+    };
+    let ray = |wavelength: f32| Ray {
+        origin: up,
+        direction: -up,
+        wavelength: wavelength,
+        probability: 1.0
+    };
+
+    // A flat curve reflects half of the light at every wavelength.
+    let flat = SampledSpectrum::new(&[(380.0, 0.5), (780.0, 0.5)]).unwrap();
+    let grey = SpectralDiffuseMaterial::new(flat);
+    for w in (380 .. 781).filter(|w| w % 20 == 0) {
+        let new_ray = grey.get_new_ray(&ray(w as f32), &intersection);
+        assert!((new_ray.probability - 0.5).abs() < 1.0e-6);
+        assert!(dot(new_ray.direction, up) > 0.0);
+        assert_eq!(grey.get_diffuse_reflectance(w as f32), Some(0.5));
+    }
+
+    // A measured curve is interpolated between the samples.
+    let red = SampledSpectrum::new(&[(400.0, 0.05), (580.0, 0.1), (620.0, 0.8)]).unwrap();
+    let red = SpectralDiffuseMaterial::new(red);
+    assert!((red.get_new_ray(&ray(600.0), &intersection).probability - 0.45).abs() < 1.0e-6);
+    assert!((red.get_new_ray(&ray(450.0), &intersection).probability - 0.0639).abs() < 1.0e-3);
+}
+
+#[test]
+fn diffuse_directions_follow_a_cosine_lobe() {
+    use vector3::cross;
+
+    ::monte_carlo::seed([3, 1, 4, 1]);
+
+    // A tilted normal, so that the rotation towards it is tested too.
+    let normal = Vector3::new(1.0, 2.0, 2.0).normalise();
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: normal,
+        tangent: cross(normal, Vector3::new(0.0, 0.0, 1.0)).normalise(),
+        distance: 1.0
+    };
+    let incoming = Ray {
+        origin: normal,
+        direction: -normal,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // For a cosine lobe, the density of cos(theta) is 2 cos(theta), so the
+    // squared cosine is distributed uniformly on [0, 1].
+    let material = DiffuseColouredMaterial::new(0.8, 550.0, 60.0);
+    let n = 20000;
+    let mut bins = [0u32; 10];
+    let mut mean = Vector3::zero();
+    for _ in 0 .. n {
+        let ray = material.get_new_ray(&incoming, &intersection);
+        assert!((ray.probability - 0.8).abs() < 1.0e-6);
+        let cos_theta = dot(ray.direction, normal);
+        assert!(cos_theta >= -1.0e-5);
+        let bin = ((cos_theta * cos_theta * 10.0) as usize).min(9);
+        bins[bin] += 1;
+        mean = mean + ray.direction * (1.0 / n as f32);
+    }
+
+    // Every bin is within five standard deviations of the expected count.
+    let expected = n as f32 / 10.0;
+    let sigma = (n as f32 * 0.1 * 0.9).sqrt();
+    for &count in bins.iter() {
+        assert!((count as f32 - expected).abs() < 5.0 * sigma, "{:?}", bins);
+    }
+
+    // The lobe is symmetric about the normal, with a mean cosine of 2/3.
+    assert!((mean - normal * (2.0 / 3.0)).magnitude() < 0.02);
+}
+
+#[test]
+fn shading_continues_paths_like_the_material() {
+    use vector3::cross;
+
+    let normal = Vector3::new(0.0, 0.0, 1.0);
+    let intersection = Intersection {
+        position: Vector3::zero(),
+        normal: normal,
+        tangent: cross(normal, Vector3::new(1.0, 0.0, 0.0)),
+        distance: 1.0
+    };
+    let spectrum = SampledSpectrum::new(&[(400.0, 0.2), (700.0, 0.9)]).unwrap();
+    let materials: Vec<Box<Material>> = vec![
+        Box::new(DiffuseGreyMaterial::new(0.7)),
+        Box::new(DiffuseColouredMaterial::new(0.9, 550.0, 40.0)),
+        Box::new(SpectralDiffuseMaterial::new(spectrum)),
+        Box::new(GlossyMirrorMaterial::new(0.3))
+    ];
+
+    // With the same random numbers, the cached terms give the same rays.
+    for (i, material) in materials.iter().enumerate() {
+        for &wavelength in &[420.0, 550.0, 680.0] {
+            let incoming = Ray {
+                origin: normal,
+                direction: Vector3::new(0.3, 0.0, -1.0).normalise(),
+                wavelength: wavelength,
+                probability: 1.0
+            };
+            let seed = [i as u32 + 1, 2, 3, 4];
+            ::monte_carlo::seed(seed);
+            let uncached = material.get_new_ray(&incoming, &intersection);
+            ::monte_carlo::seed(seed);
+            let shading = Shading::new(&**material, wavelength);
+            let cached = shading.get_new_ray(&incoming, &intersection);
+            assert_eq!(uncached.probability, cached.probability);
+            let (a, b) = (uncached.direction, cached.direction);
+            assert!(a.x == b.x && a.y == b.y && a.z == b.z);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::path::Path;
+use bvh::Bvh;
+use geometry::{BoundingBox, Surface, Triangle};
+use intersection::Intersection;
+use ray::Ray;
+use vector3::Vector3;
+
+/// A collection of triangles that act as a single surface, so that one
+/// material applies to all of them.
+///
+/// The mesh has its own hierarchy over the triangles, in the space of the
+/// mesh. When the mesh is shared by several instances, the hierarchy of the
+/// scene contains the instances, and rays that enter an instance are
+/// transformed into the space of the mesh before they descend into this
+/// hierarchy. That way the hierarchy is built once, however many instances
+/// there are.
+pub struct Mesh {
+    triangles: Vec<Triangle>,
+    bvh: Bvh,
+
+    /// The total area of the triangles up to and including every triangle,
+    /// so that a triangle can be picked by area with a binary search.
+    cumulative_areas: Vec<f32>
+}
+
+impl Mesh {
+    /// Creates a mesh of the triangles, and builds the hierarchy over them
+    /// on all cores.
+    pub fn new(triangles: Vec<Triangle>) -> Mesh {
+        let bounds: Vec<_> = triangles.iter().map(|t| t.bounding_box()).collect();
+        let mut total = 0.0;
+        let cumulative_areas = triangles.iter().map(|t| {
+            total += t.area().unwrap_or(0.0);
+            total
+        }).collect();
+        Mesh {
+            bvh: Bvh::new(&bounds, ::num_cpus::get()),
+            triangles: triangles,
+            cumulative_areas: cumulative_areas
+        }
+    }
+
+    /// Returns the nearest of the intersections that `intersect` finds with
+    /// the triangles that the ray might hit.
+    fn nearest_hit<F>(&self, ray: &Ray, intersect: F) -> Option<Intersection>
+        where F: Fn(&Triangle) -> Option<Intersection> {
+        let mut result: Option<Intersection> = None;
+        let mut distance = ::std::f32::INFINITY;
+        self.bvh.traverse(ray, distance, |i| {
+            if let Some(isect) = intersect(&self.triangles[i]) {
+                if isect.distance < distance {
+                    distance = isect.distance;
+                    result = Some(isect);
+                }
+            }
+            distance
+        });
+        result
+    }
+}
+
+impl Surface for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        self.nearest_hit(ray, |triangle| triangle.intersect(ray))
+    }
+
+    fn intersect_culled(&self, ray: &Ray, reversed: bool) -> Option<Intersection> {
+        // Cull every triangle by itself, so that a ray can pass through
+        // the back of one triangle and hit the front of another.
+        self.nearest_hit(ray, |triangle| triangle.intersect_culled(ray, reversed))
+    }
+
+    fn bounding_box(&self) -> Option<BoundingBox> {
+        let mut boxes = self.triangles.iter().filter_map(|t| t.bounding_box());
+        boxes.next().map(|first| boxes.fold(first, |acc, b| acc.union(b)))
+    }
+
+    fn area(&self) -> Option<f32> {
+        Some(self.cumulative_areas.last().cloned().unwrap_or(0.0))
+    }
+
+    fn sample_point(&self) -> Option<(Vector3, Vector3)> {
+        // Pick a triangle with a probability proportional to its area: the
+        // first one whose cumulative area exceeds the target.
+        let total = match self.cumulative_areas.last() {
+            Some(&total) => total,
+            None => return None
+        };
+        let target = ::monte_carlo::get_unit() * total;
+        let (mut i, mut last) = (0, self.triangles.len() - 1);
+        while i < last {
+            let mid = (i + last) / 2;
+            if target < self.cumulative_areas[mid] { last = mid; } else { i = mid + 1; }
+        }
+        self.triangles[i].sample_point()
+    }
+}
+
+/// Returns an error for the specified line of an OBJ file.
+fn invalid_line(line_number: usize, message: &str) -> Error {
+    let msg = format!("line {}: {}", line_number, message);
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Parses the coordinates of a 'v' line.
+fn parse_vertex<'a, I>(fields: I, line_number: usize) -> Result<Vector3>
+    where I: Iterator<Item = &'a str> {
+    let coords: Vec<f32> = fields.take(3).filter_map(|f| f.parse().ok()).collect();
+    if coords.len() != 3 {
+        return Err(invalid_line(line_number, "a vertex needs three coordinates"));
+    }
+    Ok(Vector3::new(coords[0], coords[1], coords[2]))
+}
+
+/// Resolves a vertex reference of an 'f' line, such as "3", "3/1" or
+/// "-1//2", to an index into the vertices read so far.
+fn parse_index(field: &str, vertex_count: usize, line_number: usize) -> Result<usize> {
+    // Only the position index is used; texture coordinates and normals
+    // are ignored.
+    let position = field.split('/').next().unwrap();
+    let index = match position.parse::<isize>() {
+        Ok(index) => index,
+        Err(_) => return Err(invalid_line(line_number, "malformed face index"))
+    };
+
+    // Indices start at 1, negative indices count back from the last vertex.
+    let resolved = if index < 0 { vertex_count as isize + index } else { index - 1 };
+    if resolved < 0 || resolved >= vertex_count as isize {
+        return Err(invalid_line(line_number, "face refers to a missing vertex"));
+    }
+    Ok(resolved as usize)
+}
+
+/// Reads the triangles from Wavefront OBJ data. Faces with more than three
+/// vertices are triangulated as a fan, which is correct for convex faces.
+/// Directives other than vertices and faces are ignored.
+pub fn read_obj<R: BufRead>(reader: R) -> Result<Vec<Triangle>> {
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("v") => vertices.push(try!(parse_vertex(fields, i + 1))),
+            Some("f") => {
+                let mut indices = Vec::new();
+                for field in fields {
+                    indices.push(try!(parse_index(field, vertices.len(), i + 1)));
+                }
+                if indices.len() < 3 {
+                    return Err(invalid_line(i + 1, "a face needs at least three vertices"));
+                }
+                for j in 1 .. indices.len() - 1 {
+                    triangles.push(Triangle::new(vertices[indices[0]],
+                                                 vertices[indices[j]],
+                                                 vertices[indices[j + 1]]));
+                }
+            },
+            _ => { }
+        }
+    }
+
+    Ok(triangles)
+}
+
+/// Loads the triangles from a Wavefront OBJ file.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<Vec<Triangle>> {
+    let file = try!(File::open(path));
+    read_obj(BufReader::new(file))
+}
+
+#[test]
+fn read_obj_cube() {
+    use material::DiffuseGreyMaterial;
+    use object::{MaterialBox, Object};
+    use scene::Scene;
+
+    // A cube with quad faces, four units in front of the camera.
+    let cube = "# A cube\n\
+                o cube\n\
+                v -1 4 -1\nv 1 4 -1\nv 1 4 1\nv -1 4 1\n\
+                v -1 6 -1\nv 1 6 -1\nv 1 6 1\nv -1 6 1\n\
+                vn 0 -1 0\n\
+                usemtl grey\n\
+                f 1 2 3 4\nf 8 7 6 5\nf 1//1 5//1 6//1 2//1\n\
+                f 2 6 7 3\nf 3/1 7/1 8/1 4/1\nf -8 -4 -1 -5\n";
+    let triangles = read_obj(cube.as_bytes()).unwrap();
+    assert_eq!(triangles.len(), 12);
+
+    let mesh = Mesh::new(triangles);
+    assert_eq!(mesh.triangles.len(), 12);
+    let cube = Object::new(Box::new(mesh),
+                           MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec![cube], ::camera::test_camera);
+
+    // A camera ray through the centre of the screen hits the near face.
+    let ray = ::camera::test_camera(0.0).get_ray(0.0, 0.0, 550.0);
+    let (isect, _) = scene.intersect(&ray).unwrap();
+    assert!((isect.position.y - 4.0).abs() < 1.0e-3);
+}
+
+#[test]
+fn read_obj_rejects_bad_faces() {
+    assert!(read_obj("v 0 0 0\nv 1 0 0\nf 1 2 3\n".as_bytes()).is_err());
+    assert!(read_obj("v 0 0 0\nv 1 0 0\nf 1 2\n".as_bytes()).is_err());
+    assert!(read_obj("v 0 0\n".as_bytes()).is_err());
+}
+
+#[test]
+fn backface_culling_skips_back_facing_triangles() {
+    use material::DiffuseGreyMaterial;
+    use object::{MaterialBox, Object};
+    use scene::Scene;
+
+    // Two triangles in one mesh across the path of the ray: the near one
+    // faces away from the ray, the far one faces towards it.
+    let facing = |y: f32, towards: bool| {
+        let (a, b) = (Vector3::new(-1.0, y, -1.0), Vector3::new(1.0, y, -1.0));
+        let c = Vector3::new(0.0, y, 1.0);
+        if towards { Triangle::new(a, b, c) } else { Triangle::new(a, c, b) }
+    };
+    let make_scene = |triangles: Vec<Triangle>, culling: bool, flip: bool| {
+        let grey = MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8)));
+        let mut mesh = Object::new(Box::new(Mesh::new(triangles)), grey);
+        mesh.backface_culling = culling;
+        mesh.flip_normals = flip;
+        Scene::new(vec![mesh], ::camera::test_camera)
+    };
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let hits_at = |scene: Scene, expected: f32| match scene.intersect(&ray) {
+        Some((isect, _)) => (isect.distance - expected).abs() < 1.0e-4,
+        None => false
+    };
+
+    // A lone back-facing triangle is only hit without culling.
+    assert!(hits_at(make_scene(vec![facing(4.0, false)], false, false), 4.0));
+    assert!(make_scene(vec![facing(4.0, false)], true, false).intersect(&ray).is_none());
+
+    // With culling, the ray passes through the back of the near triangle
+    // and hits the front of the far one, even within one mesh.
+    let pair = || vec![facing(4.0, false), facing(6.0, true)];
+    assert!(hits_at(make_scene(pair(), false, false), 4.0));
+    assert!(hits_at(make_scene(pair(), true, false), 6.0));
+
+    // Flipping the normals swaps which triangle is culled.
+    assert!(hits_at(make_scene(pair(), true, true), 4.0));
+}
+
+#[test]
+fn instances_share_one_bottom_level_hierarchy() {
+    use std::sync::Arc;
+    use geometry::Transformed;
+    use material::DiffuseGreyMaterial;
+    use object::{Instance, MaterialBox, Object};
+    use quaternion::Quaternion;
+    use scene::Scene;
+
+    let grey = || MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8)));
+    // The triangles of a cube around the origin, transformed by `f`.
+    let cube = |f: &Fn(Vector3) -> Vector3| {
+        let corner = |i: usize| f(Vector3::new(if i & 1 == 0 { -1.0 } else { 1.0 },
+                                               if i & 2 == 0 { -1.0 } else { 1.0 },
+                                               if i & 4 == 0 { -1.0 } else { 1.0 }));
+        let faces = [[0, 2, 3, 1], [4, 5, 7, 6], [0, 1, 5, 4],
+                     [1, 3, 7, 5], [3, 2, 6, 7], [2, 0, 4, 6]];
+        faces.iter().flat_map(|q| vec![Triangle::new(corner(q[0]), corner(q[1]), corner(q[2])),
+                                       Triangle::new(corner(q[0]), corner(q[2]), corner(q[3]))])
+                    .collect::<Vec<_>>()
+    };
+    let mesh = Arc::new(Mesh::new(cube(&|p| p)));
+
+    // A grid of cubes that are rotated and scaled differently, once as
+    // instances of the one mesh, and once with the triangles transformed
+    // into a mesh of their own.
+    let transforms: Vec<(f32, Quaternion, Vector3)> = (0 .. 9).map(|i| {
+        let scale = 0.5 + 0.1 * i as f32;
+        let q = Quaternion::rotation(0.0, 0.6, 0.8, 0.3 * i as f32);
+        let offset = Vector3::new((i % 3) as f32 * 5.0 - 5.0, 0.0, (i / 3) as f32 * 5.0 - 5.0);
+        (scale, q, offset)
+    }).collect();
+    let instanced: Vec<Object> = transforms.iter().map(|&(scale, q, offset)| {
+        let surface: Arc<Surface> = mesh.clone();
+        let instance: Instance = Transformed::scaled(surface, scale, q, offset);
+        Object::new(Box::new(instance), grey())
+    }).collect();
+    let flattened: Vec<Object> = transforms.iter().map(|&(scale, q, offset)| {
+        let moved = cube(&|p| (p * scale).rotate(q) + offset);
+        Object::new(Box::new(Mesh::new(moved)), grey())
+    }).collect();
+
+    // The bottom-level hierarchy belongs to the mesh, which is shared.
+    assert_eq!(Arc::strong_count(&mesh), 10);
+
+    let mut instanced = Scene::new(instanced, ::camera::test_camera);
+    let flattened = Scene::new(flattened, ::camera::test_camera);
+    instanced.build_bvh(2);
+
+    // Rays from in front of the grid hit the same cube at the same point.
+    ::monte_carlo::seed([1, 6, 1, 8]);
+    let mut hits = 0;
+    for _ in 0 .. 1000 {
+        let target = Vector3::new(::monte_carlo::get_bi_unit() * 8.0, 0.0,
+                                  ::monte_carlo::get_bi_unit() * 8.0);
+        let origin = Vector3::new(0.0, -20.0, 0.0);
+        let ray = Ray {
+            origin: origin,
+            direction: (target - origin).normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let hit = |scene: &Scene| scene.intersect(&ray).map(|(isect, obj)| {
+            let index = scene.objects.iter().position(|o| o as *const Object == obj).unwrap();
+            (index, isect)
+        });
+        match (hit(&instanced), hit(&flattened)) {
+            (Some((i, a)), Some((j, b))) => {
+                assert_eq!(i, j);
+                assert!((a.distance - b.distance).abs() < 1.0e-3);
+                assert!((a.normal - b.normal).magnitude() < 1.0e-3);
+                hits += 1;
+            },
+            (None, None) => { },
+            _ => panic!("instanced and flattened meshes disagree")
+        }
+    }
+    assert!(hits > 100);
+}
+
+#[test]
+fn mesh_samples_triangles_by_area() {
+    // The second triangle is three times as large as the first, and the
+    // degenerate one in between is never picked.
+    let triangles = vec![Triangle::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0),
+                                       Vector3::new(0.0, 1.0, 0.0)),
+                         Triangle::new(Vector3::new(1.5, 0.0, 0.0), Vector3::new(1.5, 0.0, 0.0),
+                                       Vector3::new(1.5, 1.0, 0.0)),
+                         Triangle::new(Vector3::new(2.0, 0.0, 0.0), Vector3::new(5.0, 0.0, 0.0),
+                                       Vector3::new(2.0, 1.0, 0.0))];
+    let mesh = Mesh::new(triangles);
+    assert!((mesh.area().unwrap() - 2.0).abs() < 1.0e-5);
+
+    ::monte_carlo::seed([3, 1, 4, 1]);
+    let n = 4000;
+    let large = (0 .. n).filter(|_| {
+        let (p, _) = mesh.sample_point().unwrap();
+        assert!(p.x < 1.0 || p.x >= 2.0);
+        p.x >= 2.0
+    }).count();
+    assert!((large as f32 / n as f32 - 0.75).abs() < 0.03);
+}
@@ -14,45 +14,412 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::cell::RefCell;
+use std::f32::EPSILON;
 use std::f32::consts::PI;
 use rand;
-use rand::Closed01;
+use rand::{Closed01, Open01, Rng, SeedableRng, XorShiftRng};
 use vector3::Vector3;
 
-// Note that it is safe to just use rand::random: it uses a task-local rng.
+/// A source of random samples, that owns a seedable generator. Given the
+/// same seed, two instances produce the same samples.
+pub struct MonteCarlo {
+    rng: XorShiftRng
+}
+
+impl MonteCarlo {
+    /// Creates a sampler with a random seed.
+    pub fn new() -> MonteCarlo {
+        MonteCarlo {
+            rng: rand::random()
+        }
+    }
+
+    /// Creates a sampler that produces the same samples for the same seed.
+    pub fn with_seed(seed: [u32; 4]) -> MonteCarlo {
+        // The constants ensure that the state is never entirely zero.
+        let state = [scramble(seed[0] ^ 0x193a6754),
+                     scramble(seed[1] ^ 0xa8a7d469),
+                     scramble(seed[2] ^ 0x97830e05),
+                     scramble(seed[3] ^ 0x113ba7bb) | 1];
+        MonteCarlo {
+            rng: SeedableRng::from_seed(state)
+        }
+    }
+
+    /// Returns a uniformly distributed number in the closed interval [0, 1].
+    pub fn get_unit(&mut self) -> f32 {
+        let Closed01(x) = self.rng.gen::<Closed01<f32>>();
+        x
+    }
+
+    /// Returns a uniformly distributed number in the closed interval [-1, 1].
+    /// It has mean 0 and variance 1/3.
+    pub fn get_bi_unit(&mut self) -> f32 {
+        self.get_unit() * 2.0 - 1.0
+    }
+
+    /// Returns a uniformly distributed number in the open interval (0, 1).
+    pub fn get_open_unit(&mut self) -> f32 {
+        let Open01(x) = self.rng.gen::<Open01<f32>>();
+        x
+    }
 
-/// Returns a random number in the range [0, 1].
+    /// Returns a uniformly distributed angle in the half-open interval
+    /// [0, 2pi).
+    pub fn get_longitude(&mut self) -> f32 {
+        self.rng.gen::<f32>() * PI * 2.0
+    }
+
+    /// Returns a wavelength in the `i`-th of `n` equally wide strata of the
+    /// visible range, in nanometers. When every stratum is sampled equally
+    /// often, the wavelengths are distributed uniformly.
+    pub fn get_stratified_wavelength(&mut self, i: usize, n: usize) -> f32 {
+        let u = self.rng.gen::<f32>();
+        (i as f32 + u) / n as f32 * 400.0 + 380.0
+    }
+
+    /// Returns the numbers 0 up to `n` in a random order.
+    pub fn get_permutation(&mut self, n: usize) -> Vec<usize> {
+        let mut permutation: Vec<usize> = (0 .. n).collect();
+        self.rng.shuffle(&mut permutation);
+        permutation
+    }
+
+    /// Returns a cosine-weighted unit vector in the hemisphere around the
+    /// positive z-axis.
+    pub fn get_hemisphere_vector(&mut self) -> Vector3 {
+        let phi = self.get_longitude();
+        let rq = self.get_unit();
+        let r = rq.sqrt();
+
+        // Calculate the direction based on polar coordinates.
+        Vector3 {
+            x: phi.cos() * r,
+            y: phi.sin() * r,
+            z: (1.0 - rq).sqrt()
+        }
+    }
+
+    fn gen_f64(&mut self) -> f64 {
+        self.rng.gen::<f64>()
+    }
+}
+
+// Every thread has its own sampler, so sampling does not need to be
+// synchronised, and a thread can be seeded without affecting others. The
+// free functions below sample from the sampler of the current thread.
+thread_local!(static MONTE_CARLO: RefCell<MonteCarlo> = RefCell::new(MonteCarlo::new()));
+
+/// Scrambles the bits of `x`, so that similar seeds result in very different
+/// generator states. (This is the finaliser of MurmurHash3.)
+fn scramble(x: u32) -> u32 {
+    let mut h = x;
+    h = (h ^ (h >> 16)).wrapping_mul(0x85ebca6b);
+    h = (h ^ (h >> 13)).wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+/// Replaces the sampler of the current thread, and returns the old one.
+/// A trace unit can install its own sampler before it renders, so its
+/// samples do not depend on the thread that it happens to run on.
+pub fn install(monte_carlo: MonteCarlo) -> MonteCarlo {
+    MONTE_CARLO.with(|mc| ::std::mem::replace(&mut *mc.borrow_mut(), monte_carlo))
+}
+
+/// Seeds the sampler of the current thread. After seeding with the same
+/// seed, the samples returned on this thread are the same.
+pub fn seed(seed: [u32; 4]) {
+    install(MonteCarlo::with_seed(seed));
+}
+
+/// Returns a uniformly distributed number in the closed interval [0, 1].
 pub fn get_unit() -> f32 {
-    let Closed01(x) = rand::random::<Closed01<f32>>();
-    x
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_unit())
 }
 
-/// Returns a random number in the range [-1, 1].
+/// Returns a uniformly distributed number in the closed interval [-1, 1].
+/// It has mean 0 and variance 1/3.
 pub fn get_bi_unit() -> f32 {
-    get_unit() * 2.0 - 1.0
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_bi_unit())
+}
+
+/// Returns a uniformly distributed number in the open interval (0, 1).
+pub fn get_open_unit() -> f32 {
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_open_unit())
 }
 
 /// Returns a random number in the range [0, 2pi).
 pub fn  get_longitude() -> f32 {
-    rand::random::<f32>() * PI * 2.0
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_longitude())
+}
+
+/// Returns a wavelength in the `i`-th of `n` equally wide strata of the
+/// visible range, in nanometers. When every stratum is sampled equally
+/// often, the wavelengths are distributed uniformly.
+pub fn get_stratified_wavelength(i: usize, n: usize) -> f32 {
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_stratified_wavelength(i, n))
 }
 
-/// Returns a random number in the range [380, 780].
-pub fn get_wavelength() -> f32 {
-    get_unit() * 400.0 + 380.0
+/// Returns the numbers 0 up to `n` in a random order.
+pub fn get_permutation(n: usize) -> Vec<usize> {
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_permutation(n))
 }
 
 /// Returns a random unit vector, pointing up along the z-axis, in the
 /// hemisphere bounded by the xy-plane, with a cosine-weighted probability.
 pub fn get_hemisphere_vector() -> Vector3 {
-    let phi = get_longitude();
-    let rq = get_unit();
-    let r = rq.sqrt();
+    MONTE_CARLO.with(|mc| mc.borrow_mut().get_hemisphere_vector())
+}
+
+/// The number of equally wide bins of a `WavelengthDistribution`, one per
+/// nanometre, so that the density follows narrow reflectance peaks closely.
+const WAVELENGTH_BINS: usize = 400;
+
+/// A distribution of wavelengths over the visible range, proportional to an
+/// importance function, such as the reflectance of a saturated material.
+/// A tenth of the samples is spread uniformly, so every wavelength can still
+/// be sampled, and dividing out the density keeps the estimate unbiased.
+pub struct WavelengthDistribution {
+    /// The cumulative distribution at the boundaries of the bins. It starts
+    /// at 0.0 and ends at 1.0.
+    cdf: Vec<f32>
+}
+
+impl WavelengthDistribution {
+    /// Tabulates the distribution for the `importance` function, which must
+    /// not be negative.
+    pub fn new<F: Fn(f32) -> f32>(importance: F) -> WavelengthDistribution {
+        let n = WAVELENGTH_BINS;
+        let weights: Vec<f32> = (0 .. n).map(|i| {
+            importance(380.0 + (i as f32 + 0.5) * 400.0 / n as f32).max(0.0)
+        }).collect();
+        let total = weights.iter().fold(0.0, |acc, &w| acc + w);
+
+        let mut cdf = Vec::with_capacity(n + 1);
+        let mut acc = 0.0;
+        cdf.push(acc);
+        for w in weights {
+            acc += if total > 0.0 { 0.9 * w / total + 0.1 / n as f32 } else { 1.0 / n as f32 };
+            cdf.push(acc);
+        }
+
+        // Rounding must not make the end of the range unreachable.
+        for c in cdf.iter_mut() { *c = *c / acc; }
 
-    // Calculate the direction based on polar coordinates.
-    Vector3 {
-        x: phi.cos() * r,
-        y: phi.sin() * r,
-        z: (1.0 - rq).sqrt()
+        WavelengthDistribution {
+            cdf: cdf
+        }
     }
+
+    /// Maps `u` in [0, 1] to a wavelength. Also returns the weight of the
+    /// sample: the uniform density divided by the density of the sample.
+    pub fn sample(&self, u: f32) -> (f32, f32) {
+        let n = WAVELENGTH_BINS;
+
+        // Find the first bin that ends beyond `u` with a binary search.
+        let (mut i, mut last) = (0, n - 1);
+        while i < last {
+            let mid = (i + last) / 2;
+            if u < self.cdf[mid + 1] { last = mid; } else { i = mid + 1; }
+        }
+        let p = self.cdf[i + 1] - self.cdf[i];
+        let t = ((u - self.cdf[i]) / p).max(0.0).min(1.0);
+        let wavelength = 380.0 + (i as f32 + t) * 400.0 / n as f32;
+        (wavelength, 1.0 / (p * n as f32))
+    }
+
+    /// Returns the weight of `wavelength`, as `sample` would return it.
+    pub fn weight(&self, wavelength: f32) -> f32 {
+        let n = WAVELENGTH_BINS;
+        let i = ((wavelength - 380.0) / 400.0 * n as f32).max(0.0) as usize;
+        let i = i.min(n - 1);
+        1.0 / ((self.cdf[i + 1] - self.cdf[i]) * n as f32)
+    }
+
+    /// Returns a wavelength in the `i`-th of `n` equally likely strata of
+    /// the distribution, with its weight (see `sample`).
+    pub fn get_stratified_wavelength(&self, i: usize, n: usize) -> (f32, f32) {
+        let u = MONTE_CARLO.with(|mc| mc.borrow_mut().gen_f64()) as f32;
+        self.sample(((i as f32 + u) / n as f32).min(1.0))
+    }
+}
+
+/// Returns the radical inverse of `i` in the specified base: the digits of
+/// `i` mirrored around the decimal point.
+fn radical_inverse(mut i: usize, base: usize) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut factor = inv_base;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f64 * factor;
+        i = i / base;
+        factor = factor * inv_base;
+    }
+    result
+}
+
+/// A four-dimensional Halton sequence with a random toroidal shift, for
+/// sampling the screen and the lens jointly. Unlike independent random
+/// samples, the points of the sequence are spread evenly over all four
+/// dimensions, so they do not clump together in any of them. The shift
+/// keeps every point uniformly distributed, so the sequence is unbiased.
+pub struct HaltonSequence {
+    shift: [f64; 4]
+}
+
+impl HaltonSequence {
+    /// Creates a sequence with a shift drawn from the generator of the
+    /// current thread, so a seeded thread produces the same sequence.
+    pub fn new() -> HaltonSequence {
+        let mut shift = [0.0; 4];
+        MONTE_CARLO.with(|mc| {
+            let mut mc = mc.borrow_mut();
+            for s in shift.iter_mut() {
+                *s = mc.gen_f64();
+            }
+        });
+        HaltonSequence {
+            shift: shift
+        }
+    }
+
+    /// Returns the `i`-th point of the sequence, with every coordinate in
+    /// the open interval (0, 1).
+    pub fn get(&self, i: usize) -> [f32; 4] {
+        const BASES: [usize; 4] = [2, 3, 5, 7];
+        let mut point = [0.0f32; 4];
+        for d in 0 .. 4 {
+            let u = (radical_inverse(i, BASES[d]) + self.shift[d]).fract() as f32;
+            // Rounding to single precision could reach the endpoints.
+            point[d] = u.max(EPSILON).min(1.0 - EPSILON);
+        }
+        point
+    }
+}
+
+#[test]
+fn bi_unit_is_uniform() {
+    seed([1, 2, 3, 4]);
+
+    let n = 100000;
+    let bins = 20;
+    let mut counts = vec![0u32; bins];
+    let mut sum = 0.0f64;
+    let mut sum_sq = 0.0f64;
+    for _ in 0 .. n {
+        let x = get_bi_unit();
+        assert!(x >= -1.0 && x <= 1.0);
+        let bin = ((x + 1.0) * 0.5 * bins as f32) as usize;
+        counts[bin.min(bins - 1)] += 1;
+        sum += x as f64;
+        sum_sq += (x * x) as f64;
+    }
+
+    let mean = sum / n as f64;
+    let variance = sum_sq / n as f64 - mean * mean;
+    assert!(mean.abs() < 0.01);
+    assert!((variance - 1.0 / 3.0).abs() < 0.01);
+
+    // The chi-squared statistic for 19 degrees of freedom exceeds 43.8 with
+    // a probability of only 0.1%.
+    let expected = n as f64 / bins as f64;
+    let chi_squared = counts.iter().fold(0.0, |acc, &c| {
+        let d = c as f64 - expected;
+        acc + d * d / expected
+    });
+    assert!(chi_squared < 43.8, "chi-squared is {}", chi_squared);
+}
+
+#[test]
+fn halton_sequence_is_spread_evenly() {
+    seed([5, 6, 7, 8]);
+
+    // Returns the chi-squared statistic of the counts of the points in a
+    // grid of `cells` equal cells along dimension `a`, and along `b` if it
+    // is given; lower means more even.
+    let n = 1024;
+    let chi_squared = |points: &Vec<[f32; 4]>, a: usize, b: Option<usize>, cells: usize| {
+        let columns = if b.is_some() { cells } else { 1 };
+        let mut counts = vec![0u32; cells * columns];
+        for p in points {
+            let i = (p[a] * cells as f32) as usize;
+            let j = b.map_or(0, |b| (p[b] * cells as f32) as usize);
+            counts[i * columns + j] += 1;
+        }
+        let expected = n as f32 / counts.len() as f32;
+        counts.iter().fold(0.0, |acc, &c| {
+            let d = c as f32 - expected;
+            acc + d * d / expected
+        })
+    };
+
+    let halton = HaltonSequence::new();
+    let points: Vec<[f32; 4]> = (0 .. n).map(|i| halton.get(i)).collect();
+    assert!(points.iter().all(|p| p.iter().all(|&x| x > 0.0 && x < 1.0)));
+
+    // For independent samples, the statistic is about the number of cells
+    // minus one, and it exceeds 46 with only 5% probability for 64 cells.
+    // The Halton points cover every dimension almost perfectly evenly, and
+    // every pair of dimensions, such as the screen or the lens, very evenly.
+    for a in 0 .. 4 {
+        let chi = chi_squared(&points, a, None, 64);
+        assert!(chi < 8.0, "dimension {}: {}", a, chi);
+        for b in a + 1 .. 4 {
+            let chi = chi_squared(&points, a, Some(b), 8);
+            assert!(chi < 32.0, "dimensions {} and {}: {}", a, b, chi);
+        }
+    }
+
+    // Open samples never reach the endpoints.
+    assert!((0 .. n).all(|_| {
+        let x = get_open_unit();
+        x > 0.0 && x < 1.0
+    }));
+}
+
+#[test]
+fn wavelength_distribution_follows_importance() {
+    let in_range = |w: f32| w > 600.0 && w < 700.0;
+    let distribution = WavelengthDistribution::new(|w| if in_range(w) { 1.0 } else { 0.0 });
+
+    // Nine tenths of the samples plus their share of the uniform samples
+    // fall in the important range.
+    let n = 10000;
+    let samples: Vec<(f32, f32)> = (0 .. n).map(|i| distribution.get_stratified_wavelength(i, n))
+                                           .collect();
+    let inside = samples.iter().filter(|&&(w, _)| in_range(w)).count();
+    assert!((inside as f32 / n as f32 - 0.925).abs() < 0.01);
+    assert!(samples.iter().all(|&(w, _)| w >= 380.0 && w <= 780.0));
+
+    // The weights undo the density, so they average to 1.
+    let mean_weight = samples.iter().fold(0.0, |acc, &(_, weight)| acc + weight) / n as f32;
+    assert!((mean_weight - 1.0).abs() < 0.01);
+
+    // The weight of a wavelength can be looked up afterwards, except right
+    // at the boundary of a bin, where rounding may pick the neighbour.
+    let n_differ = samples.iter().filter(|&&(w, weight)| distribution.weight(w) != weight).count();
+    assert!(n_differ < n / 100);
+}
+
+#[test]
+fn seeded_samplers_are_reproducible() {
+    let mut a = MonteCarlo::with_seed([1, 2, 3, 4]);
+    let mut b = MonteCarlo::with_seed([1, 2, 3, 4]);
+    for _ in 0 .. 1000 {
+        assert_eq!(a.get_unit(), b.get_unit());
+        assert_eq!(a.get_stratified_wavelength(7, 10), b.get_stratified_wavelength(7, 10));
+        let (u, v) = (a.get_hemisphere_vector(), b.get_hemisphere_vector());
+        assert!(u.x == v.x && u.y == v.y && u.z == v.z);
+    }
+
+    // A different seed gives different samples, also for nearby seeds.
+    let mut c = MonteCarlo::with_seed([1, 2, 3, 5]);
+    let n_same = (0 .. 1000).filter(|_| a.get_unit() == c.get_unit()).count();
+    assert!(n_same < 10);
+
+    // The free functions sample from an installed sampler.
+    install(MonteCarlo::with_seed([1, 2, 3, 4]));
+    let mut d = MonteCarlo::with_seed([1, 2, 3, 4]);
+    assert_eq!(get_bi_unit(), d.get_bi_unit());
 }
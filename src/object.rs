@@ -14,20 +14,61 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use geometry::Surface;
+use std::sync::Arc;
+use geometry::{Sphere, Surface, Transformed};
 use material::{Material, EmissiveMaterial};
+use quaternion::Quaternion;
+use vector3::Vector3;
 
 pub enum MaterialBox {
     Reflective(Box<Material + Sync + Send>),
     Emissive(Box<EmissiveMaterial + Sync + Send>)
 }
 
+/// Determines how hits on the back side of a surface (the side that the
+/// surface normal points away from) are shaded.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Sidedness {
+    /// The material receives the normal as is, and handles both sides itself.
+    /// This is required for refractive materials, which must know whether a
+    /// ray enters or leaves.
+    Unchanged,
+
+    /// The normal is flipped for back side hits, so the back is shaded like
+    /// the front. This is useful for thin surfaces like foliage.
+    TwoSided,
+
+    /// The back side absorbs all light.
+    OneSided
+}
+
 /// Represents a surface with a material.
 pub struct Object {
     /// The surface that defines the geometry of the object.
     pub surface: Box<Surface + Sync + Send>,
     /// Either an emissive or a reflective material.
-    pub material: MaterialBox
+    pub material: MaterialBox,
+
+    /// Whether a ray that bounced off this object should skip it in the next
+    /// intersection test. This avoids self-intersection regardless of scale,
+    /// but it is only correct for surfaces that a ray cannot hit twice in a
+    /// row, such as planes. It is off by default.
+    pub exclude_self_hits: bool,
+
+    /// How the back side of the surface is shaded.
+    pub sidedness: Sidedness,
+
+    /// Whether rays that hit the back side of the surface pass through it,
+    /// as if it were not there. For closed opaque objects the back side is
+    /// never visible, so this saves shading interior surfaces. The triangles
+    /// of a mesh are culled one by one, by their vertex order. It is wrong
+    /// for thin or two-sided surfaces, so it is off by default.
+    pub backface_culling: bool,
+
+    /// Whether the normals of the surface are reversed, for surfaces that
+    /// point inward by mistake, such as meshes with the wrong winding order.
+    /// Sidedness and culling apply to the flipped normal. It is off by default.
+    pub flip_normals: bool
 }
 
 impl Object {
@@ -37,7 +78,147 @@ impl Object {
                -> Object {
         Object {
             surface: surface,
-            material: material
+            material: material,
+            exclude_self_hits: false,
+            sidedness: Sidedness::Unchanged,
+            backface_culling: false,
+            flip_normals: false
         }
     }
 }
+
+impl Object {
+    /// Starts building an object with a sphere of the specified radius,
+    /// centred at the origin.
+    pub fn sphere(radius: f32) -> ObjectBuilder<Sphere> {
+        ObjectBuilder::new(Sphere::new(Vector3::zero(), radius))
+    }
+}
+
+/// Builds an object by transforming a surface, and then applying a
+/// material. Transforms are applied in the order in which they are added.
+pub struct ObjectBuilder<S> {
+    surface: S,
+    rotation: Quaternion,
+    translation: Vector3
+}
+
+impl<S> ObjectBuilder<S> where S: Surface + Sync + Send + 'static {
+    /// Starts building an object with the specified untransformed surface.
+    pub fn new(surface: S) -> ObjectBuilder<S> {
+        ObjectBuilder {
+            surface: surface,
+            rotation: Quaternion::new(0.0, 0.0, 0.0, 1.0),
+            translation: Vector3::zero()
+        }
+    }
+
+    /// Moves the object by the specified offset.
+    pub fn translate(mut self, x: f32, y: f32, z: f32) -> ObjectBuilder<S> {
+        self.translation = self.translation + Vector3::new(x, y, z);
+        self
+    }
+
+    /// Rotates the object (including previous translations) around the
+    /// origin by the unit quaternion `q`.
+    pub fn rotate(mut self, q: Quaternion) -> ObjectBuilder<S> {
+        self.rotation = q * self.rotation;
+        self.translation = self.translation.rotate(q);
+        self
+    }
+
+    /// Completes the object with the specified material.
+    pub fn with_material(self, material: MaterialBox) -> Object {
+        let surface = Transformed::new(self.surface, self.rotation, self.translation);
+        Object::new(Box::new(surface), material)
+    }
+}
+
+/// A copy of a shared surface, that is scaled uniformly, then rotated, and
+/// then translated. Unlike a `Transformed` surface that owns its surface,
+/// many instances of a large mesh can be placed without duplicating it.
+/// The hierarchy of the scene bounds the instances, and the hierarchy of a
+/// shared mesh is built once, in the space of the mesh. Create an instance
+/// with `Transformed::scaled`.
+pub type Instance = Transformed<Arc<Surface>>;
+
+#[test]
+fn builder_matches_transformed_sphere() {
+    use std::f32::consts::PI;
+    use material::DiffuseGreyMaterial;
+    use ray::Ray;
+
+    let grey = || MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8)));
+    let q = Quaternion::rotation(0.0, 0.0, 1.0, PI * 0.5);
+
+    // Translating along x and then rotating a quarter turn around z
+    // moves the sphere to (0, 3, 0).
+    let built = Object::sphere(1.0).translate(3.0, 0.0, 0.0).rotate(q).with_material(grey());
+    let sphere = Sphere::new(Vector3::zero(), 1.0);
+    let manual = Object::new(Box::new(Transformed::new(sphere, q, Vector3::new(0.0, 3.0, 0.0))),
+                             grey());
+
+    for &(ox, oz) in [(0.0f32, 0.0f32), (0.3, 0.2), (-0.5, 0.7), (2.0, 0.0)].iter() {
+        let ray = Ray {
+            origin: Vector3::new(ox, -5.0, oz),
+            direction: Vector3::new(0.0, 1.0, 0.0),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        match (built.surface.intersect(&ray), manual.surface.intersect(&ray)) {
+            (Some(a), Some(b)) => {
+                assert!((a.distance - b.distance).abs() < 1.0e-4);
+                assert!((a.position - b.position).magnitude() < 1.0e-4);
+                assert!((a.normal - b.normal).magnitude() < 1.0e-4);
+            },
+            (None, None) => assert!(ox.abs() > 1.0),
+            _ => panic!("builder and manual construction disagree")
+        }
+    }
+
+    // The ray through the centre hits the front of the sphere at y = 2.
+    let ray = Ray {
+        origin: Vector3::new(0.0, -5.0, 0.0),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let isect = built.surface.intersect(&ray).unwrap();
+    assert!((isect.distance - 7.0).abs() < 1.0e-4);
+}
+
+#[test]
+fn instance_is_translated_and_scaled() {
+    use ray::Ray;
+
+    let sphere: Arc<Surface> = Arc::new(Sphere::new(Vector3::zero(), 1.0));
+    let identity = Quaternion::new(0.0, 0.0, 0.0, 1.0);
+    let moved: Instance = Transformed::new(sphere.clone(), identity, Vector3::new(5.0, 0.0, 0.0));
+    let scaled: Instance = Transformed::scaled(sphere.clone(), 2.0, identity,
+                                               Vector3::new(-5.0, 0.0, 0.0));
+
+    let ray = |x: f32| Ray {
+        origin: Vector3::new(x, -10.0, 0.0),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // A ray aimed at x = 5 hits the front of the moved sphere at y = -1.
+    let isect = moved.intersect(&ray(5.0)).unwrap();
+    assert!((isect.distance - 9.0).abs() < 1.0e-4);
+    assert!((isect.position - Vector3::new(5.0, -1.0, 0.0)).magnitude() < 1.0e-4);
+    assert!((isect.normal - Vector3::new(0.0, -1.0, 0.0)).magnitude() < 1.0e-4);
+    assert!(moved.intersect(&ray(0.0)).is_none());
+
+    // The scaled sphere has radius 2, so its front is at y = -2.
+    let isect = scaled.intersect(&ray(-5.0)).unwrap();
+    assert!((isect.distance - 8.0).abs() < 1.0e-4);
+    assert!(scaled.intersect(&ray(-3.5)).is_some());
+    assert!(scaled.intersect(&ray(-2.5)).is_none());
+    assert!((scaled.area().unwrap() - 16.0 * ::std::f32::consts::PI).abs() < 1.0e-3);
+
+    let bounds = scaled.bounding_box().unwrap();
+    assert!((bounds.min - Vector3::new(-7.0, -2.0, -2.0)).magnitude() < 1.0e-4);
+    assert!((bounds.max - Vector3::new(-3.0, 2.0, 2.0)).magnitude() < 1.0e-4);
+}
@@ -0,0 +1,472 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use image;
+use image::png::PNGEncoder;
+use image::png::hash::Crc32;
+
+/// The colour space of the values in an image, so that viewers can
+/// interpret them correctly.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ColourSpace {
+    /// The sRGB primaries and the sRGB transfer function. This is what the
+    /// `TonemapUnit` produces.
+    Srgb,
+
+    /// The sRGB primaries, but linear values without any gamma encoding.
+    LinearSrgb
+}
+
+/// The channels of a pixel, in the order in which they are stored.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ChannelLayout {
+    /// A single channel with the luma of the pixel.
+    Grey,
+
+    /// Red, green and blue.
+    Rgb,
+
+    /// Red, green and blue, followed by an alpha channel that is
+    /// always fully opaque.
+    Rgba,
+
+    /// Blue, green and red, as some Windows tools expect it.
+    Bgr
+}
+
+impl ChannelLayout {
+    /// Returns the number of channels per pixel.
+    pub fn channels(self) -> usize {
+        match self {
+            ChannelLayout::Grey => 1,
+            ChannelLayout::Rgb | ChannelLayout::Bgr => 3,
+            ChannelLayout::Rgba => 4
+        }
+    }
+}
+
+/// How pixels are stored in an output buffer.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct PixelFormat {
+    /// The channels of every pixel.
+    pub layout: ChannelLayout,
+
+    /// The number of bits per channel; 8, 16 or 32. Channels wider than 8
+    /// bits are stored as big-endian unsigned integers.
+    pub bits_per_channel: u8
+}
+
+/// Eight bits per channel RGB, the format of the images that the
+/// `TonemapUnit` produces.
+pub const RGB8: PixelFormat = PixelFormat { layout: ChannelLayout::Rgb, bits_per_channel: 8 };
+
+impl PixelFormat {
+    /// Parses a format written as the layout followed by the number of bits
+    /// per channel, such as "rgb8", "rgba16" or "grey16".
+    pub fn parse(name: &str) -> Option<PixelFormat> {
+        let digits = name.find(|c: char| c.is_ascii_digit()).unwrap_or(name.len());
+        let layout = match &name[.. digits] {
+            "grey" => ChannelLayout::Grey,
+            "rgb" => ChannelLayout::Rgb,
+            "rgba" => ChannelLayout::Rgba,
+            "bgr" => ChannelLayout::Bgr,
+            _ => return None
+        };
+        name[digits ..].parse().ok().map(|bits| PixelFormat {
+            layout: layout,
+            bits_per_channel: bits
+        })
+    }
+}
+
+/// Returns an error that indicates that the format cannot be written.
+fn unsupported(format: PixelFormat, reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput,
+               format!("unsupported pixel format {:?}: {}", format, reason))
+}
+
+/// Converts a buffer of RGB values in the range [0, 1] into the specified
+/// format. Values outside of the range are clamped.
+pub fn encode_pixels(rgb: &[f32], format: PixelFormat) -> Result<Vec<u8>> {
+    let max = match format.bits_per_channel {
+        8 => 0xff as f64,
+        16 => 0xffff as f64,
+        32 => 0xffffffffu32 as f64,
+        _ => return Err(unsupported(format, "only 8, 16 and 32 bits are supported"))
+    };
+    let bytes_per_channel = format.bits_per_channel as usize / 8;
+    let len = rgb.len() / 3 * format.layout.channels() * bytes_per_channel;
+    let mut buffer = Vec::with_capacity(len);
+
+    for px in rgb.chunks(3) {
+        let (r, g, b) = (px[0], px[1], px[2]);
+        let channels = match format.layout {
+            // Use the Rec. 709 luma coefficients, which match the sRGB primaries.
+            ChannelLayout::Grey => vec![0.2126 * r + 0.7152 * g + 0.0722 * b],
+            ChannelLayout::Rgb => vec![r, g, b],
+            ChannelLayout::Rgba => vec![r, g, b, 1.0],
+            ChannelLayout::Bgr => vec![b, g, r]
+        };
+        for c in channels {
+            let clamped = if c > 1.0 { 1.0 } else if c > 0.0 { c as f64 } else { 0.0 };
+            let x = (clamped * max).round() as u32;
+            for i in (0 .. bytes_per_channel).rev() {
+                buffer.push((x >> (i * 8)) as u8);
+            }
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Length of the PNG signature plus the IHDR chunk, which must come first.
+const SIGNATURE_AND_IHDR_LEN: usize = 8 + 4 + 4 + 13 + 4;
+
+/// Writes a PNG chunk with the specified name and data.
+fn write_chunk<W: Write>(writer: &mut W, name: &[u8; 4], data: &[u8]) -> Result<()> {
+    let mut crc = Crc32::new();
+    crc.update(name);
+    crc.update(data);
+
+    let len = data.len() as u32;
+    try!(writer.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]));
+    try!(writer.write_all(name));
+    try!(writer.write_all(data));
+    let crc = crc.checksum();
+    writer.write_all(&[(crc >> 24) as u8, (crc >> 16) as u8, (crc >> 8) as u8, crc as u8])
+}
+
+/// Returns the big-endian bytes of the unsigned integers, as used in
+/// the cHRM and gAMA chunks.
+fn be_bytes(xs: &[u32]) -> Vec<u8> {
+    xs.iter().flat_map(|&x| {
+        vec![(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8].into_iter()
+    }).collect()
+}
+
+/// Writes the chunks that describe the colour space.
+fn write_colour_space<W: Write>(writer: &mut W, colour_space: ColourSpace) -> Result<()> {
+    // The chromaticities of the white point and the red, green,
+    // and blue sRGB primaries, times 100000.
+    let srgb_primaries = be_bytes(&[31270, 32900, 64000, 33000,
+                                    30000, 60000, 15000, 6000]);
+
+    match colour_space {
+        ColourSpace::Srgb => {
+            // The sRGB chunk with perceptual rendering intent. The gAMA and
+            // cHRM chunks are for decoders that do not understand sRGB.
+            try!(write_chunk(writer, b"sRGB", &[0]));
+            try!(write_chunk(writer, b"gAMA", &be_bytes(&[45455])));
+            write_chunk(writer, b"cHRM", &srgb_primaries)
+        }
+        ColourSpace::LinearSrgb => {
+            try!(write_chunk(writer, b"gAMA", &be_bytes(&[100000])));
+            write_chunk(writer, b"cHRM", &srgb_primaries)
+        }
+    }
+}
+
+/// Writes encoded pixel data as PNG, tagged with the specified colour space.
+fn write_encoded_png<W: Write>(writer: &mut W,
+                               data: &[u8],
+                               width: u32,
+                               height: u32,
+                               colour_type: image::ColorType,
+                               colour_space: ColourSpace)
+                               -> Result<()> {
+    let mut png = Vec::new();
+    try!(PNGEncoder::new(&mut png).encode(data, width, height, colour_type));
+
+    // The colour space chunks must come before the image data,
+    // so insert them directly after the header.
+    let (header, data) = png.split_at(SIGNATURE_AND_IHDR_LEN);
+    try!(writer.write_all(header));
+    try!(write_colour_space(writer, colour_space));
+    writer.write_all(data)
+}
+
+/// Writes an 8-bit RGB buffer as PNG, tagged with the specified colour space.
+pub fn write_png<W: Write>(writer: &mut W,
+                           rgb: &[u8],
+                           width: u32,
+                           height: u32,
+                           colour_space: ColourSpace)
+                           -> Result<()> {
+    write_encoded_png(writer, rgb, width, height, image::RGB(8), colour_space)
+}
+
+/// Writes a buffer of RGB values in the range [0, 1] as PNG in the specified
+/// format. PNG supports only 8 and 16 bits per channel, and no BGR layout.
+pub fn write_png_with_format<W: Write>(writer: &mut W,
+                                       rgb: &[f32],
+                                       width: u32,
+                                       height: u32,
+                                       format: PixelFormat,
+                                       colour_space: ColourSpace)
+                                       -> Result<()> {
+    let bits = format.bits_per_channel;
+    if bits != 8 && bits != 16 {
+        return Err(unsupported(format, "PNG supports only 8 and 16 bits"));
+    }
+    let colour_type = match format.layout {
+        ChannelLayout::Grey => image::Gray(bits),
+        ChannelLayout::Rgb => image::RGB(bits),
+        ChannelLayout::Rgba => image::RGBA(bits),
+        ChannelLayout::Bgr => return Err(unsupported(format, "PNG does not store BGR"))
+    };
+    let data = try!(encode_pixels(rgb, format));
+    write_encoded_png(writer, &data, width, height, colour_type, colour_space)
+}
+
+/// Writes an 8-bit RGB buffer to a PNG file, tagged with the specified
+/// colour space.
+pub fn save_png<P: AsRef<Path>>(path: P,
+                                rgb: &[u8],
+                                width: u32,
+                                height: u32,
+                                colour_space: ColourSpace)
+                                -> Result<()> {
+    let file = try!(File::create(path));
+    write_png(&mut BufWriter::new(file), rgb, width, height, colour_space)
+}
+
+/// Encodes a pixel as the shared exponent representation of the Radiance
+/// format. Negative components are clamped to zero.
+fn encode_rgbe(px: &[f32]) -> [u8; 4] {
+    let (a, b, c) = (px[0].max(0.0), px[1].max(0.0), px[2].max(0.0));
+    let v = a.max(b).max(c);
+    if !(v > 1.0e-32) || !v.is_finite() {
+        return [0, 0, 0, 0];
+    }
+
+    // Find the exponent such that v = m * 2^e with m in [0.5, 1).
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2.0f32.powi(e) >= 1.0 { e += 1; }
+    let scale = 256.0 / 2.0f32.powi(e);
+    [(a * scale) as u8, (b * scale) as u8, (c * scale) as u8, (e + 128) as u8]
+}
+
+/// Decodes a pixel in the shared exponent representation.
+fn decode_rgbe(rgbe: &[u8]) -> [f32; 3] {
+    if rgbe[3] == 0 {
+        return [0.0, 0.0, 0.0];
+    }
+    let f = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+    [(rgbe[0] as f32 + 0.5) * f, (rgbe[1] as f32 + 0.5) * f, (rgbe[2] as f32 + 0.5) * f]
+}
+
+/// Writes a buffer of CIE XYZ tristimulus values as a Radiance HDR file in
+/// the XYZE format, which preserves the full range of the values, and which
+/// does not clip colours outside of the sRGB gamut. The scanlines are not
+/// run-length encoded, which all readers support.
+pub fn write_hdr<W: Write>(writer: &mut W, xyz: &[f32], width: u32, height: u32) -> Result<()> {
+    if xyz.len() != (width * height * 3) as usize {
+        return Err(Error::new(ErrorKind::InvalidInput, "the buffer does not match the size"));
+    }
+    try!(write!(writer, "#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y {} +X {}\n", height, width));
+    for px in xyz.chunks(3) {
+        try!(writer.write_all(&encode_rgbe(px)));
+    }
+    Ok(())
+}
+
+/// Writes a buffer of CIE XYZ tristimulus values to a Radiance HDR file.
+/// See `write_hdr`.
+pub fn save_hdr<P: AsRef<Path>>(path: P, xyz: &[f32], width: u32, height: u32) -> Result<()> {
+    let file = try!(File::create(path));
+    let mut writer = BufWriter::new(file);
+    try!(write_hdr(&mut writer, xyz, width, height));
+    writer.flush()
+}
+
+/// An image read from a Radiance HDR file.
+pub struct HdrImage {
+    /// The width of the image in pixels.
+    pub width: u32,
+
+    /// The height of the image in pixels.
+    pub height: u32,
+
+    /// The CIE XYZ tristimulus values of the pixels, row by row from the top.
+    pub xyz: Vec<f32>
+}
+
+/// Reads a Radiance HDR file in the XYZE format, as written by `write_hdr`.
+/// Run-length encoded scanlines are not supported.
+pub fn read_hdr<R: BufRead>(reader: &mut R) -> Result<HdrImage> {
+    let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+
+    // The header consists of lines up to an empty line, which is followed
+    // by the resolution string.
+    let mut line = String::new();
+    let mut format = None;
+    loop {
+        line.clear();
+        if try!(reader.read_line(&mut line)) == 0 {
+            return Err(invalid("the header ended prematurely".to_string()));
+        }
+        let field = line.trim_end();
+        if field.is_empty() { break; }
+        if field.starts_with("FORMAT=") {
+            format = Some(field["FORMAT=".len() ..].to_string());
+        }
+    }
+    if format.as_ref().map(|f| &f[..]) != Some("32-bit_rle_xyze") {
+        return Err(invalid(format!("unsupported format {:?}, expected XYZE", format)));
+    }
+
+    line.clear();
+    try!(reader.read_line(&mut line));
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+        return Err(invalid(format!("unsupported resolution string '{}'", line.trim())));
+    }
+    let (width, height) = match (fields[3].parse::<u32>(), fields[1].parse::<u32>()) {
+        (Ok(w), Ok(h)) => (w, h),
+        _ => return Err(invalid(format!("invalid resolution '{}'", line.trim())))
+    };
+
+    let mut xyz = Vec::with_capacity((width * height * 3) as usize);
+    let mut rgbe = [0u8; 4];
+    for _ in 0 .. width * height {
+        try!(::read::read_into(reader, &mut rgbe));
+        xyz.extend(decode_rgbe(&rgbe).iter().cloned());
+    }
+
+    Ok(HdrImage {
+        width: width,
+        height: height,
+        xyz: xyz
+    })
+}
+
+/// Reads a Radiance HDR file in the XYZE format. See `read_hdr`.
+pub fn load_hdr<P: AsRef<Path>>(path: P) -> Result<HdrImage> {
+    let file = try!(File::open(path));
+    read_hdr(&mut BufReader::new(file))
+}
+
+#[test]
+fn write_png_srgb_has_srgb_chunk() {
+    let rgb = [255u8, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+    let mut png = Vec::new();
+    write_png(&mut png, &rgb, 2, 2, ColourSpace::Srgb).unwrap();
+
+    // The sRGB chunk directly follows the header.
+    let chunk = &png[SIGNATURE_AND_IHDR_LEN ..];
+    assert_eq!(&chunk[0 .. 4], &[0, 0, 0, 1]);
+    assert_eq!(&chunk[4 .. 8], b"sRGB");
+    assert_eq!(chunk[8], 0);
+
+    // And the file must still be a valid PNG with the same pixels.
+    let img = image::load_from_memory(&png).unwrap();
+    assert_eq!(&img.raw_pixels()[..], &rgb[..]);
+}
+
+#[test]
+fn write_png_with_format_round_trips() {
+    use std::io::Cursor;
+    use image::ImageDecoder;
+    use image::png::PNGDecoder;
+
+    let rgb = [1.0f32, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.5, 0.5, 2.0];
+
+    // Returns the raw bytes of the decoded scanlines, because the image
+    // crate does not convert 16-bit images.
+    let decode = |format: PixelFormat| {
+        let mut png = Vec::new();
+        write_png_with_format(&mut png, &rgb, 2, 2, format, ColourSpace::Srgb).unwrap();
+        let mut decoder = PNGDecoder::new(Cursor::new(png));
+        let row_len = decoder.row_len().unwrap();
+        let mut data = vec![0u8; row_len * 2];
+        for row in data.chunks_mut(row_len) {
+            decoder.read_scanline(row).unwrap();
+        }
+        data
+    };
+
+    let rgb8 = decode(PixelFormat { layout: ChannelLayout::Rgb, bits_per_channel: 8 });
+    assert_eq!(&rgb8[..], &[255, 0, 0, 0, 255, 0, 0, 0, 255, 128, 128, 255]);
+
+    // The 16-bit channels are big-endian, and alpha is opaque.
+    let rgba16 = decode(PixelFormat { layout: ChannelLayout::Rgba, bits_per_channel: 16 });
+    assert_eq!(&rgba16[..], &[255, 255, 0, 0, 0, 0, 255, 255,
+                              0, 0, 255, 255, 0, 0, 255, 255,
+                              0, 0, 0, 0, 255, 255, 255, 255,
+                              128, 0, 128, 0, 255, 255, 255, 255]);
+}
+
+#[test]
+fn invalid_pixel_formats_are_rejected() {
+    let rgb = [0.25f32, 0.5, 0.75];
+    let bgr = PixelFormat { layout: ChannelLayout::Bgr, bits_per_channel: 8 };
+    let rgb32 = PixelFormat { layout: ChannelLayout::Rgb, bits_per_channel: 32 };
+    let rgb12 = PixelFormat { layout: ChannelLayout::Rgb, bits_per_channel: 12 };
+
+    // BGR and 32 bits are fine for raw buffers, but not for PNG.
+    assert_eq!(encode_pixels(&rgb, bgr).unwrap(), vec![191, 128, 64]);
+    assert_eq!(encode_pixels(&rgb, rgb32).unwrap().len(), 12);
+    assert!(encode_pixels(&rgb, rgb12).is_err());
+
+    let mut png = Vec::new();
+    for &format in [bgr, rgb32, rgb12].iter() {
+        assert!(write_png_with_format(&mut png, &rgb, 1, 1, format, ColourSpace::Srgb).is_err());
+    }
+    assert!(png.is_empty());
+}
+
+#[test]
+fn pixel_formats_are_parsed() {
+    assert_eq!(PixelFormat::parse("rgb8"), Some(RGB8));
+    let grey16 = PixelFormat { layout: ChannelLayout::Grey, bits_per_channel: 16 };
+    assert_eq!(PixelFormat::parse("grey16"), Some(grey16));
+    let rgba16 = PixelFormat { layout: ChannelLayout::Rgba, bits_per_channel: 16 };
+    assert_eq!(PixelFormat::parse("rgba16"), Some(rgba16));
+
+    // Unsupported bit depths are parsed, but rejected when encoding.
+    assert!(PixelFormat::parse("rgb12").is_some());
+    for name in &["rgb", "16", "cmyk8", "rgb8x", ""] {
+        assert_eq!(PixelFormat::parse(name), None);
+    }
+}
+
+#[test]
+fn write_hdr_round_trips() {
+    // Values far beyond the range of an 8-bit image must survive.
+    let xyz = [0.0f32, 0.0, 0.0, 1.0, 2.0, 3.0, 1500.0, 0.25, 1.0e-3, 0.5, 0.5, 0.5,
+               7.0, 70.0, 0.7, 1.0e5, 1.0e5, 1.0e5];
+    let mut hdr = Vec::new();
+    write_hdr(&mut hdr, &xyz, 3, 2).unwrap();
+    assert!(hdr.starts_with(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 2 +X 3\n"));
+
+    let image = read_hdr(&mut &hdr[..]).unwrap();
+    assert_eq!((image.width, image.height), (3, 2));
+    assert_eq!(image.xyz.len(), xyz.len());
+    for (px, original) in image.xyz.chunks(3).zip(xyz.chunks(3)) {
+        // The components share the exponent of the largest one, so their
+        // precision is relative to the largest component.
+        let max = original.iter().cloned().fold(0.0f32, f32::max);
+        for (&a, &b) in px.iter().zip(original.iter()) {
+            assert!((a - b).abs() <= max * 0.01);
+        }
+    }
+
+    // Truncated files are rejected.
+    assert!(read_hdr(&mut &hdr[.. hdr.len() - 1]).is_err());
+}
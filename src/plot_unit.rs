@@ -19,6 +19,18 @@ use std::iter::repeat;
 use trace_unit::MappedPhoton;
 use vector3::Vector3;
 
+/// What a `PlotUnit` accumulates per pixel.
+#[derive(Copy, Clone, PartialEq)]
+pub enum PlotMode {
+    /// CIE 1931 XYZ tristimulus values, for a perceptual image.
+    Tristimulus,
+
+    /// The wavelength weighted by intensity in x, and the intensity in y,
+    /// so the mean wavelength of a pixel is x / y. This is for false colour
+    /// visualisation with `TonemapOperator::FalseColour`.
+    Wavelength
+}
+
 /// Handles plotting the result of a `TraceUnit`.
 pub struct PlotUnit {
     /// The width of the canvas (in pixels).
@@ -30,9 +42,19 @@ pub struct PlotUnit {
     /// Width of the canvas divided by its height.
     aspect_ratio: f32,
 
-    /// The buffer of tristimulus values.
+    /// The buffer of tristimulus values, in the CIE 1931 XYZ space that
+    /// `srgb` converts from.
     pub tristimulus_buffer: Vec<Vector3>,
 
+    /// The number of photons plotted per pixel. A photon counts towards the
+    /// four pixels around it, with the same coefficients as its value.
+    pub photon_counts: Vec<f32>,
+
+    /// What to accumulate, tristimulus values by default. With
+    /// `PlotMode::Wavelength`, the tristimulus buffer contains weighted
+    /// wavelengths instead.
+    pub mode: PlotMode,
+
     /// An ID for identifying this unit in the UI.
     pub id: usize
 }
@@ -45,12 +67,25 @@ impl PlotUnit {
         PlotUnit {
             image_width: width,
             image_height: height,
-            aspect_ratio: width as f32 / height as f32,
+            aspect_ratio: ::screen::aspect_ratio(width, height),
             tristimulus_buffer: repeat(Vector3::zero()).take(sz).collect(),
+            photon_counts: repeat(0.0).take(sz).collect(),
+            mode: PlotMode::Tristimulus,
             id: id
         }
     }
 
+    /// Returns the value to accumulate for a photon of unit intensity.
+    fn photon_value(&self, wavelength: f32) -> Vector3 {
+        match self.mode {
+            PlotMode::Tristimulus => {
+                let (x, y, z) = ::spectrum::cie_xyz(wavelength);
+                Vector3::new(x, y, z)
+            }
+            PlotMode::Wavelength => Vector3::new(wavelength, 1.0, 0.0)
+        }
+    }
+
     /// Plots a pixel, anti-aliased into the buffer
     /// (adding it to existing content).
     fn plot_pixel(&mut self, x: f32, y: f32, cie: Vector3) {
@@ -81,23 +116,32 @@ impl PlotUnit {
         buffer[py1 * w + px2] = buffer[py1 * w + px2] + cie * c21;
         buffer[py2 * w + px1] = buffer[py2 * w + px1] + cie * c12;
         buffer[py2 * w + px2] = buffer[py2 * w + px2] + cie * c22;
+
+        let counts = &mut self.photon_counts;
+        counts[py1 * w + px1] += c11;
+        counts[py1 * w + px2] += c21;
+        counts[py2 * w + px1] += c12;
+        counts[py2 * w + px2] += c22;
     }
 
     /// Plots the result of the specified TraceUnit onto the canvas.
     pub fn plot(&mut self, photons: &[MappedPhoton]) {
         for photon in photons {
             // Calculate the CIE tristimulus values, given the wavelength.
-            let cie = ::cie1931::get_tristimulus(photon.wavelength);
+            let cie = self.photon_value(photon.wavelength);
 
             // Then plot the pixel into the buffer.
             self.plot_pixel(photon.x, photon.y, cie * photon.probability);
         }
     }
 
-    /// Resets the tristimulus buffer to black.
+    /// Resets the tristimulus buffer to black, and the photon counts to 0.
     pub fn clear(&mut self) {
         for x in &mut self.tristimulus_buffer {
             *x = Vector3::zero();
         }
+        for n in &mut self.photon_counts {
+            *n = 0.0;
+        }
     }
 }
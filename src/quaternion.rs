@@ -43,6 +43,38 @@ impl Quaternion {
     pub fn conjugate(self) -> Quaternion {
         Quaternion::new(-self.x, -self.y, -self.z, self.w)
     }
+
+    pub fn dot(self, other: Quaternion) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w
+    }
+
+    pub fn magnitude(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn normalise(self) -> Quaternion {
+        self * (1.0 / self.magnitude())
+    }
+
+    /// Interpolates between the rotations `a` and `b` with constant angular
+    /// velocity, where `t` = 0 returns `a` and `t` = 1 returns `b`. Both must
+    /// be unit quaternions. The rotation takes the shortest path.
+    pub fn slerp(a: Quaternion, b: Quaternion, t: f32) -> Quaternion {
+        // The quaternions q and -q represent the same rotation, but only one
+        // of them is less than half a turn away from a.
+        let cos_theta = a.dot(b);
+        let (b, cos_theta) = if cos_theta < 0.0 { (-b, -cos_theta) } else { (b, cos_theta) };
+
+        // For nearly parallel quaternions, sin(theta) vanishes, but then
+        // linear interpolation is accurate enough.
+        if cos_theta > 0.9995 {
+            return (a * (1.0 - t) + b * t).normalise();
+        }
+
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+        a * (((1.0 - t) * theta).sin() / sin_theta) + b * ((t * theta).sin() / sin_theta)
+    }
 }
 
 impl Add for Quaternion {
@@ -109,3 +141,35 @@ impl Mul<Quaternion> for Quaternion {
         }
     }
 }
+
+#[test]
+fn slerp_interpolates_angle() {
+    use std::f32::consts::PI;
+
+    let a = Quaternion::rotation(0.0, 0.0, 1.0, 0.2);
+    let b = Quaternion::rotation(0.0, 0.0, 1.0, 1.4);
+    let close = |p: Quaternion, q: Quaternion| (p - q).magnitude() < 1.0e-5;
+
+    assert!(close(Quaternion::slerp(a, b, 0.0), a));
+    assert!(close(Quaternion::slerp(a, b, 1.0), b));
+
+    // The midpoint is a unit quaternion, halfway in angle.
+    let mid = Quaternion::slerp(a, b, 0.5);
+    assert!((mid.magnitude() - 1.0).abs() < 1.0e-5);
+    assert!(close(mid, Quaternion::rotation(0.0, 0.0, 1.0, 0.8)));
+
+    // With -b, which is the same rotation, it takes the same short path.
+    let mid = Quaternion::slerp(a, -b, 0.5);
+    assert!(close(mid, Quaternion::rotation(0.0, 0.0, 1.0, 0.8)));
+
+    // Nearly parallel rotations do not divide by zero.
+    let c = Quaternion::rotation(0.0, 0.0, 1.0, 0.2 + 1.0e-4);
+    let mid = Quaternion::slerp(a, c, 0.5);
+    assert!(mid.x.is_finite() && (mid.magnitude() - 1.0).abs() < 1.0e-5);
+
+    // Halfway between quarter turns in opposite directions is no rotation.
+    let half = Quaternion::rotation(1.0, 0.0, 0.0, PI * 0.5);
+    let back = Quaternion::rotation(1.0, 0.0, 0.0, -PI * 0.5);
+    let mid = Quaternion::slerp(half, back, 0.5);
+    assert!(close(mid, Quaternion::new(0.0, 0.0, 0.0, 1.0)));
+}
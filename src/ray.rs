@@ -31,3 +31,12 @@ pub struct Ray {
     /// generated is not uniform.
     pub probability: f32
 }
+
+impl Ray {
+    /// Returns whether the direction of the ray is unusable, because it
+    /// is (nearly) zero, or not finite.
+    pub fn is_degenerate(&self) -> bool {
+        let magnitude_squared = self.direction.magnitude_squared();
+        !(magnitude_squared > 1.0e-12 && magnitude_squared.is_finite())
+    }
+}
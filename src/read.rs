@@ -14,18 +14,18 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use std::io::{Read, Result};
+use std::io::{Error, ErrorKind, Read, Result};
 
 /// Read into the buffer until it is full, regardless of how many calls it takes.
+/// Fails if the reader ends before the buffer is full.
 pub fn read_into<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
     let mut n = 0;
-    loop {
+    while n < buf.len() {
         let progress = try!(reader.read(&mut buf[n ..]));
-        if progress > 0 {
-            n += progress;
-        } else {
-            break;
+        if progress == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "the data ended prematurely"));
         }
+        n += progress;
     }
 
     Ok(())
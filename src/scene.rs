@@ -14,10 +14,32 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use camera::Camera;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read, Result};
+use std::path::Path;
+use std::sync::Arc;
+use bvh::Bvh;
+use camera::{Camera, Projection};
+use environment::{BackgroundPlate, Environment};
+use geometry::{AxisAlignedBox, Plane, Quad, Sphere, Surface, Transformed, Triangle};
 use intersection::Intersection;
-use object::Object;
+use json::{self, Json};
+use material::{BlackBodyMaterial, CauchyGlass, DielectricMaterial, DiffuseColouredMaterial,
+               DiffuseGreyMaterial, EmissiveMaterial, GlossyMaterial, PlasticMaterial,
+               RefractiveIndex, SellmeierGlass, SpecularMaterial, SpectralDiffuseMaterial,
+               TabulatedEmissiveMaterial};
+use mesh::{self, Mesh};
+use object::{Instance, MaterialBox, Object, Sidedness};
+use object::MaterialBox::{Emissive, Reflective};
+use output;
+use quaternion::Quaternion;
 use ray::Ray;
+use sky::SkyModel;
+use spectrum::{self, SampledSpectrum};
+use srgb;
+use vector3::{cross, Vector3};
 
 /// A collection of objects.
 pub struct Scene {
@@ -31,26 +53,129 @@ pub struct Scene {
     // TODO: apparently there is no such thing as an immutable closure
     // any more, but I'd prefer to be able to use a pure function here,
     // which might be a closure.
-    pub get_camera_at_time: fn (f32) -> Camera
+    pub get_camera_at_time: fn (f32) -> Camera,
+
+    /// A camera that is used at all times instead of `get_camera_at_time`,
+    /// such as a camera loaded from a scene file.
+    pub camera: Option<Camera>,
+
+    /// The camera at the end of the frame, if the fixed camera moves. The
+    /// camera at a time in between is interpolated between the two.
+    pub camera_end: Option<Camera>,
+
+    /// What rays see when they do not hit any object.
+    pub environment: Environment,
+
+    /// Intersections further away than this distance are ignored, as if the
+    /// ray escaped. This is a far clipping plane, and it saves time on rays
+    /// that would otherwise hit infinite objects far away.
+    pub max_distance: f32,
+
+    /// A constant light that every reflective surface receives, regardless
+    /// of occlusion. It is not physically based, but it is handy for quick
+    /// previews and stylised renders. It should be `None` for physically
+    /// based renders.
+    pub ambient: Option<Box<EmissiveMaterial>>,
+
+    /// A hierarchy over the objects that speeds up intersection, built by
+    /// `build_bvh`. Without it, every object is intersected. It must be
+    /// rebuilt when the objects change.
+    pub bvh: Option<Bvh>,
+
+    /// The probabilities of sampling the lights directly, built by
+    /// `build_light_distribution`. Without it, all lights are sampled
+    /// equally often. It must be rebuilt when the objects change.
+    pub light_distribution: Option<LightDistribution>
+}
+
+/// The lights of a scene that can be sampled directly, with probabilities
+/// proportional to the power that they emit.
+pub struct LightDistribution {
+    /// The index of every light in the objects of the scene, its area, and
+    /// the probability of sampling it.
+    lights: Vec<(usize, f32, f32)>
 }
 
 impl Scene {
+    /// Creates a scene with the specified objects, seen through the camera
+    /// that `get_camera_at_time` returns. The scene has no environment,
+    /// ambient light, hierarchy, or light distribution, and no far clipping
+    /// plane to speak of. Those can be set afterwards.
+    pub fn new(objects: Vec<Object>, get_camera_at_time: fn (f32) -> Camera) -> Scene {
+        Scene {
+            objects: objects,
+            get_camera_at_time: get_camera_at_time,
+            camera: None,
+            camera_end: None,
+            environment: Environment::Void,
+            max_distance: 1.0e12,
+            ambient: None,
+            bvh: None,
+            light_distribution: None
+        }
+    }
+
+    /// Returns the camera at time `t`: the fixed camera if there is one,
+    /// moved towards `camera_end` if that is set, or the camera that
+    /// `get_camera_at_time` returns otherwise.
+    pub fn get_camera(&self, t: f32) -> Camera {
+        match (self.camera, self.camera_end) {
+            (Some(camera), Some(end)) => camera.interpolate(&end, t),
+            (Some(camera), None) => camera,
+            (None, _) => (self.get_camera_at_time)(t)
+        }
+    }
+
     /// Intersects the specified ray with the scene.
     pub fn intersect(&self, ray: &Ray) -> Option<(Intersection, &Object)> {
+        self.intersect_excluding(ray, None)
+    }
+
+    /// Intersects the specified ray with the scene, but ignores the
+    /// `excluded` object, if any. (Objects are compared by identity.)
+    pub fn intersect_excluding<'a>(&'a self, ray: &Ray, excluded: Option<&Object>)
+                                   -> Option<(Intersection, &'a Object)> {
         // Assume Nothing is found, and that Nothing is Very Far Away (tm).
         let mut result = None;
-        let mut distance = 1.0e12f32;
-
-        // Then intersect all surfaces.
-        for obj in &self.objects {
-            match obj.surface.intersect(ray) {
-                None => { },
-                Some(isect) => {
-                    // If there is an intersection, and if it is nearer than a
-                    // previous one, use it.
-                    if isect.distance < distance {
+        let mut distance = self.max_distance;
+
+        {
+            let mut intersect_object = |obj: &'a Object| {
+                if let Some(ex) = excluded {
+                    if ::std::ptr::eq(ex, obj) { return distance; }
+                }
+
+                // With flipped normals, the front side is the one culled.
+                let hit = if obj.backface_culling {
+                    obj.surface.intersect_culled(ray, obj.flip_normals)
+                } else {
+                    obj.surface.intersect(ray)
+                };
+                let hit = hit.map(|mut isect| {
+                    if obj.flip_normals { isect.normal = -isect.normal; }
+                    isect
+                });
+                // If there is an intersection, and if it is nearer than a
+                // previous one, use it.
+                match hit {
+                    Some(isect) if isect.distance < distance => {
                         result = Some((isect, obj));
                         distance = isect.distance;
+                    },
+                    _ => { }
+                }
+                distance
+            };
+
+            // Then intersect all surfaces, or only those that the ray might
+            // hit, if there is a hierarchy.
+            match self.bvh {
+                Some(ref bvh) => {
+                    bvh.traverse(ray, self.max_distance, |i| intersect_object(&self.objects[i]));
+                },
+                None => {
+                    for obj in &self.objects {
+                        intersect_object(obj);
                     }
                 }
             }
@@ -58,4 +183,920 @@ impl Scene {
 
         result
     }
+
+    /// Returns all objects with an emissive material.
+    pub fn emissive_objects(&self) -> Vec<&Object> {
+        self.objects.iter().filter(|obj| match obj.material {
+            Emissive(_) => true,
+            _ => false
+        }).collect()
+    }
+
+    /// Returns the total power emitted by the emissive objects, as the area
+    /// of the light times pi times its intensity, integrated over the visible
+    /// spectrum. Flat lights are counted as emitting from one side only.
+    /// Lights without a known area, like planes, are ignored. The result is
+    /// only meaningful relative to other lights and scenes.
+    pub fn total_emitted_power(&self) -> f32 {
+        self.objects.iter().map(|obj| emitted_power(obj).unwrap_or(0.0)).sum()
+    }
+
+    /// Computes the probabilities of sampling every light directly, in
+    /// proportion to the power that it emits, so that bright lights are
+    /// sampled more often than dim ones.
+    pub fn build_light_distribution(&mut self) {
+        let powers: Vec<(usize, f32, f32)> = self.objects.iter().enumerate()
+            .filter_map(|(i, obj)| {
+                match (emitted_power(obj), obj.surface.area()) {
+                    (Some(power), Some(area)) => Some((i, area, power)),
+                    _ => None
+                }
+            }).collect();
+
+        // If no light emits anything, they might as well be sampled equally.
+        let total = self.total_emitted_power();
+        let n = powers.len() as f32;
+        let lights = powers.into_iter().map(|(i, area, power)| {
+            (i, area, if total > 0.0 { power / total } else { 1.0 / n })
+        }).collect();
+        self.light_distribution = Some(LightDistribution { lights: lights });
+    }
+
+    /// Returns the lights that can be sampled directly: the emissive objects
+    /// with a known area. Every light comes with its area and the probability
+    /// of sampling it, which is proportional to its power if the light
+    /// distribution was built, and uniform otherwise.
+    pub fn sampled_lights(&self) -> Vec<(&Object, f32, f32)> {
+        match self.light_distribution {
+            Some(ref distribution) => distribution.lights.iter().map(|&(i, area, p)| {
+                (&self.objects[i], area, p)
+            }).collect(),
+            None => {
+                let lights: Vec<(&Object, f32)> = self.emissive_objects().into_iter()
+                    .filter_map(|obj| obj.surface.area().map(|area| (obj, area)))
+                    .collect();
+                let p = 1.0 / lights.len() as f32;
+                lights.into_iter().map(|(obj, area)| (obj, area, p)).collect()
+            }
+        }
+    }
+
+    /// Builds a bounding volume hierarchy over the objects, that is used
+    /// for all further intersections. The build is spread over `threads`
+    /// threads; the hierarchy is the same for any number of threads.
+    pub fn build_bvh(&mut self, threads: usize) {
+        let bounds: Vec<_> = self.objects.iter().map(|obj| obj.surface.bounding_box()).collect();
+        self.bvh = Some(Bvh::new(&bounds, threads));
+    }
+}
+
+/// Returns the power emitted by an emissive object, as the area of the light
+/// times pi times its intensity, integrated over the visible spectrum, or
+/// `None` if the object is not emissive or its area is unknown.
+fn emitted_power(obj: &Object) -> Option<f32> {
+    match (&obj.material, obj.surface.area()) {
+        (&Emissive(ref mat), Some(area)) => {
+            // Integrate in steps of 1 nm. A Lambertian emitter with
+            // radiance L emits pi L per unit area.
+            let intensity = (380 .. 780).map(|w| mat.get_intensity(w as f32 + 0.5))
+                                        .sum::<f32>();
+            Some(PI * area * intensity)
+        },
+        _ => None
+    }
+}
+
+/// Picks one of the `lights`, as returned by `Scene::sampled_lights`, with
+/// its probability, using the uniform random number `u`, and returns its
+/// index. There must be at least one light.
+pub fn pick_light(lights: &[(&Object, f32, f32)], u: f32) -> usize {
+    let mut cumulative = 0.0;
+    for (i, light) in lights.iter().enumerate() {
+        cumulative += light.2;
+        if u < cumulative { return i; }
+    }
+
+    // Rounding errors can leave the total slightly below 1.
+    lights.len() - 1
+}
+
+/// Fails to compile unless `T` can be shared between render threads.
+pub fn assert_shareable<T: Send + Sync>() { }
+
+/// Returns an error for a scene file that does not describe a valid scene.
+fn invalid(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Returns the JSON object `json`, which describes the `context`.
+fn as_object<'a>(json: &'a Json, context: &str) -> Result<&'a json::Object> {
+    json.as_object().ok_or_else(|| invalid(format!("{} must be an object", context)))
+}
+
+/// Returns the number `key` of the `context`, or `default` if the key is
+/// absent and there is a default.
+fn get_f32(obj: &json::Object, key: &str, default: Option<f32>, context: &str) -> Result<f32> {
+    match (obj.get(key), default) {
+        (Some(value), _) => value.as_f64().map(|x| x as f32).ok_or_else(|| {
+            invalid(format!("'{}' of {} must be a number", key, context))
+        }),
+        (None, Some(default)) => Ok(default),
+        (None, None) => Err(invalid(format!("{} has no '{}'", context, key)))
+    }
+}
+
+/// Returns the numbers in the array `key` of the `context`, which must
+/// have `n` elements.
+fn get_numbers(obj: &json::Object, key: &str, n: usize, context: &str) -> Result<Vec<f32>> {
+    let error = || invalid(format!("'{}' of {} must be an array of {} numbers", key, context, n));
+    let array = match obj.get(key) {
+        Some(value) => try!(value.as_array().ok_or_else(&error)),
+        None => return Err(invalid(format!("{} has no '{}'", context, key)))
+    };
+    let numbers: Vec<f32> = array.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect();
+    if array.len() != n || numbers.len() != n { return Err(error()); }
+    Ok(numbers)
+}
+
+/// Returns the vector `key` of the `context`, written as [x, y, z].
+fn get_vector(obj: &json::Object, key: &str, context: &str) -> Result<Vector3> {
+    let v = try!(get_numbers(obj, key, 3, context));
+    Ok(Vector3::new(v[0], v[1], v[2]))
+}
+
+/// Returns the rotation `key` of the `context`, written as a quaternion
+/// [x, y, z, w], which is normalised. Without the key, there is no rotation.
+fn get_rotation(obj: &json::Object, key: &str, context: &str) -> Result<Quaternion> {
+    if !obj.contains_key(key) { return Ok(Quaternion::new(0.0, 0.0, 0.0, 1.0)); }
+    let q = try!(get_numbers(obj, key, 4, context));
+    let q = Quaternion::new(q[0], q[1], q[2], q[3]);
+    if !(q.magnitude() > 0.0) || !q.magnitude().is_finite() {
+        return Err(invalid(format!("'{}' of {} must be a non-zero quaternion", key, context)));
+    }
+    Ok(q.normalise())
+}
+
+/// Returns the string `key` of the `context`.
+fn get_string<'a>(obj: &'a json::Object, key: &str, context: &str) -> Result<&'a str> {
+    match obj.get(key) {
+        Some(value) => value.as_string().ok_or_else(|| {
+            invalid(format!("'{}' of {} must be a string", key, context))
+        }),
+        None => Err(invalid(format!("{} has no '{}'", context, key)))
+    }
+}
+
+/// Returns the boolean `key` of the `context`, or false if the key is absent.
+fn get_flag(obj: &json::Object, key: &str, context: &str) -> Result<bool> {
+    match obj.get(key) {
+        Some(value) => value.as_bool().ok_or_else(|| {
+            invalid(format!("'{}' of {} must be true or false", key, context))
+        }),
+        None => Ok(false)
+    }
+}
+
+/// Returns the spectrum `key` of the `context`, which is either the path of
+/// a CSV file (see `spectrum::read_csv`), or an array of [wavelength, value]
+/// pairs.
+fn get_spectrum(obj: &json::Object, key: &str, context: &str) -> Result<SampledSpectrum> {
+    let error = |err: Error| {
+        invalid(format!("'{}' of {} is not a spectrum: {}", key, context, err))
+    };
+    match obj.get(key) {
+        Some(&Json::String(ref path)) => spectrum::load_csv(path).map_err(error),
+        Some(&Json::Array(ref pairs)) => {
+            let samples: Vec<(f32, f32)> = pairs.iter()
+                .filter_map(|pair| pair.as_array())
+                .filter_map(|pair| match (pair.len(), pair[0].as_f64(), pair[1].as_f64()) {
+                    (2, Some(wavelength), Some(value)) => Some((wavelength as f32, value as f32)),
+                    _ => None
+                }).collect();
+            if samples.len() != pairs.len() {
+                return Err(invalid(format!("'{}' of {} must consist of [wavelength, value] pairs",
+                                           key, context)));
+            }
+            SampledSpectrum::new(&samples).map_err(error)
+        },
+        Some(_) => Err(invalid(format!("'{}' of {} must be a path or an array", key, context))),
+        None => Err(invalid(format!("{} has no '{}'", context, key)))
+    }
+}
+
+/// Returns the "type" of the `context`.
+fn get_type<'a>(obj: &'a json::Object, context: &str) -> Result<&'a str> {
+    obj.get("type").and_then(|t| t.as_string())
+       .ok_or_else(|| invalid(format!("{} has no 'type'", context)))
+}
+
+fn parse_camera(json: &Json, context: &str) -> Result<Camera> {
+    let obj = try!(as_object(json, context));
+    let projection = if obj.contains_key("orthographic_scale") {
+        Projection::Orthographic(try!(get_f32(obj, "orthographic_scale", None, context)))
+    } else {
+        Projection::Perspective
+    };
+    Ok(Camera {
+        position: try!(get_vector(obj, "position", context)),
+        field_of_view: try!(get_f32(obj, "field_of_view", None, context)),
+        focal_distance: try!(get_f32(obj, "focal_distance", Some(1.0), context)),
+        depth_of_field: try!(get_f32(obj, "depth_of_field", Some(1.0e6), context)),
+        chromatic_abberation: try!(get_f32(obj, "chromatic_abberation", Some(0.0), context)),
+        horizontal_shift: try!(get_f32(obj, "horizontal_shift", Some(0.0), context)),
+        vertical_shift: try!(get_f32(obj, "vertical_shift", Some(0.0), context)),
+        aperture_blades: try!(get_f32(obj, "aperture_blades", Some(0.0), context)) as u32,
+        projection: projection,
+        orientation: try!(get_rotation(obj, "orientation", context))
+    })
+}
+
+/// The meshes that a scene file refers to, by path, so that every file is
+/// loaded once, however many objects use it.
+type MeshCache = HashMap<String, Arc<Surface>>;
+
+fn parse_surface(json: &Json, context: &str, meshes: &mut MeshCache)
+                 -> Result<Box<Surface + Sync + Send>> {
+    let obj = try!(as_object(json, context));
+    let surface: Box<Surface + Sync + Send> = match try!(get_type(obj, context)) {
+        "plane" => {
+            let normal = try!(get_vector(obj, "normal", context));
+            let position = try!(get_vector(obj, "position", context));
+            if !(normal.magnitude() > 0.0) || !normal.magnitude().is_finite() {
+                return Err(invalid(format!("the normal of {} must be a non-zero vector", context)));
+            }
+            Box::new(Plane::new(normal.normalise(), position))
+        },
+        "sphere" => {
+            let position = try!(get_vector(obj, "position", context));
+            let radius = try!(get_f32(obj, "radius", None, context));
+            if !(radius > 0.0) {
+                return Err(invalid(format!("the radius of {} must be positive", context)));
+            }
+            Box::new(Sphere::new(position, radius))
+        },
+        "quad" => {
+            let origin = try!(get_vector(obj, "origin", context));
+            let edge_u = try!(get_vector(obj, "edge_u", context));
+            let edge_v = try!(get_vector(obj, "edge_v", context));
+            if !(cross(edge_u, edge_v).magnitude() > 0.0) {
+                return Err(invalid(format!("the edges of {} must not be parallel", context)));
+            }
+            Box::new(Quad::new(origin, edge_u, edge_v))
+        },
+        "triangle" => {
+            let v0 = try!(get_vector(obj, "v0", context));
+            let v1 = try!(get_vector(obj, "v1", context));
+            let v2 = try!(get_vector(obj, "v2", context));
+            if !(cross(v1 - v0, v2 - v0).magnitude() > 0.0) {
+                return Err(invalid(format!("the vertices of {} must not be collinear", context)));
+            }
+            Box::new(Triangle::new(v0, v1, v2))
+        },
+        "box" => {
+            let min = try!(get_vector(obj, "min", context));
+            let max = try!(get_vector(obj, "max", context));
+            if !(min.x < max.x && min.y < max.y && min.z < max.z) {
+                let msg = format!("the 'min' of {} must be less than its 'max'", context);
+                return Err(invalid(msg));
+            }
+            Box::new(AxisAlignedBox::new(min, max))
+        },
+        "mesh" => {
+            let path = try!(get_string(obj, "path", context));
+            if !meshes.contains_key(path) {
+                let triangles = try!(mesh::load_obj(path).map_err(|err| {
+                    invalid(format!("failed to load the mesh of {}: {}", context, err))
+                }));
+                meshes.insert(path.to_string(), Arc::new(Mesh::new(triangles)));
+            }
+            Box::new(meshes[path].clone())
+        },
+        other => return Err(invalid(format!("{} has unknown type '{}'", context, other)))
+    };
+
+    // With a transform, the surface becomes an instance of itself.
+    if !["scale", "rotation", "translation"].iter().any(|&key| obj.contains_key(key)) {
+        return Ok(surface);
+    }
+    let scale = try!(get_f32(obj, "scale", Some(1.0), context));
+    if !(scale > 0.0) {
+        return Err(invalid(format!("the scale of {} must be positive", context)));
+    }
+    let rotation = try!(get_rotation(obj, "rotation", context));
+    let translation = if obj.contains_key("translation") {
+        try!(get_vector(obj, "translation", context))
+    } else {
+        Vector3::zero()
+    };
+    let shared: Arc<Surface + Sync + Send> = Arc::from(surface);
+    let instance: Instance = Transformed::scaled(shared, scale, rotation, translation);
+    Ok(Box::new(instance))
+}
+
+/// Returns the index of refraction of the dielectric `context`: a named
+/// glass, Sellmeier coefficients "b" and "c", or an "ior" and an Abbe number.
+fn parse_refractive_index(obj: &json::Object, context: &str) -> Result<Box<RefractiveIndex>> {
+    if obj.contains_key("glass") {
+        return match try!(get_string(obj, "glass", context)) {
+            "bk7" => Ok(Box::new(SellmeierGlass::bk7())),
+            other => Err(invalid(format!("{} has unknown glass '{}'", context, other)))
+        };
+    }
+    if obj.contains_key("b") {
+        let b = try!(get_numbers(obj, "b", 3, context));
+        let c = try!(get_numbers(obj, "c", 3, context));
+        let b = [b[0] as f64, b[1] as f64, b[2] as f64];
+        let c = [c[0] as f64, c[1] as f64, c[2] as f64];
+        return Ok(Box::new(SellmeierGlass::new(b, c)));
+    }
+    let ior = try!(get_f32(obj, "ior", None, context));
+    let abbe_number = try!(get_f32(obj, "abbe_number", None, context));
+    if !(ior > 1.0) || !(abbe_number > 0.0) {
+        return Err(invalid(format!("{} must have an 'ior' above 1 and a positive 'abbe_number'",
+                                   context)));
+    }
+    Ok(Box::new(CauchyGlass::new(ior, abbe_number)))
+}
+
+fn parse_material(json: &Json, context: &str) -> Result<MaterialBox> {
+    let obj = try!(as_object(json, context));
+    match try!(get_type(obj, context)) {
+        "diffuse" if obj.contains_key("spectrum") => {
+            let reflectance = try!(get_spectrum(obj, "spectrum", context));
+            Ok(Reflective(Box::new(SpectralDiffuseMaterial::new(reflectance))))
+        },
+        "diffuse" => {
+            let reflectance = try!(get_f32(obj, "reflectance", None, context));
+            // Without a wavelength, the material is grey.
+            if obj.contains_key("wavelength") {
+                let wavelength = try!(get_f32(obj, "wavelength", None, context));
+                let width = try!(get_f32(obj, "width", None, context));
+                let mat = DiffuseColouredMaterial::new(reflectance, wavelength, width);
+                Ok(Reflective(Box::new(mat)))
+            } else {
+                Ok(Reflective(Box::new(DiffuseGreyMaterial::new(reflectance))))
+            }
+        },
+        "specular" => {
+            let reflectance = try!(get_f32(obj, "reflectance", Some(1.0), context));
+            Ok(Reflective(Box::new(SpecularMaterial::new(reflectance))))
+        },
+        "glossy" => {
+            let roughness = try!(get_f32(obj, "roughness", None, context));
+            Ok(Reflective(Box::new(GlossyMaterial::new(roughness))))
+        },
+        "plastic" => {
+            let reflectance = try!(get_f32(obj, "reflectance", None, context));
+            let ior = try!(get_f32(obj, "ior", Some(1.5), context));
+            Ok(Reflective(Box::new(PlasticMaterial::new(reflectance, ior))))
+        },
+        "dielectric" => {
+            let ior = try!(parse_refractive_index(obj, context));
+            Ok(Reflective(Box::new(DielectricMaterial::new(ior))))
+        },
+        "black_body" => {
+            let temperature = try!(get_f32(obj, "temperature", None, context));
+            let intensity = try!(get_f32(obj, "intensity", None, context));
+            Ok(Emissive(Box::new(BlackBodyMaterial::new(temperature, intensity))))
+        },
+        "tabulated" => {
+            let spectrum = try!(get_spectrum(obj, "spectrum", context));
+            Ok(Emissive(Box::new(TabulatedEmissiveMaterial::new(spectrum))))
+        },
+        other => Err(invalid(format!("{} has unknown type '{}'", context, other)))
+    }
+}
+
+fn parse_sidedness(obj: &json::Object, context: &str) -> Result<Sidedness> {
+    if !obj.contains_key("sidedness") { return Ok(Sidedness::Unchanged); }
+    match try!(get_string(obj, "sidedness", context)) {
+        "unchanged" => Ok(Sidedness::Unchanged),
+        "two_sided" => Ok(Sidedness::TwoSided),
+        "one_sided" => Ok(Sidedness::OneSided),
+        other => Err(invalid(format!("{} has unknown sidedness '{}'", context, other)))
+    }
+}
+
+fn parse_environment(json: &Json) -> Result<Environment> {
+    let context = "the environment";
+    let obj = try!(as_object(json, context));
+    match try!(get_type(obj, context)) {
+        "sky" => {
+            let sun_direction = try!(get_vector(obj, "sun_direction", context));
+            let turbidity = try!(get_f32(obj, "turbidity", None, context));
+            let intensity = try!(get_f32(obj, "intensity", Some(1.0), context));
+            if !(sun_direction.magnitude() > 0.0) {
+                return Err(invalid(format!("the sun direction of {} must not be zero", context)));
+            }
+            Ok(Environment::Sky(SkyModel::new(sun_direction, turbidity, intensity)))
+        },
+        "uniform" => {
+            let intensity = try!(get_f32(obj, "intensity", None, context));
+            Ok(Environment::Background(Box::new(move |_: Vector3, _: f32| intensity)))
+        },
+        "plate" => {
+            let path = try!(get_string(obj, "path", context));
+            let image = try!(output::load_hdr(path).map_err(|err| {
+                invalid(format!("failed to load the image of {}: {}", context, err))
+            }));
+            let pixels = image.xyz.chunks(3).map(|xyz| {
+                srgb::transform_linear(Vector3::new(xyz[0], xyz[1], xyz[2]))
+            }).collect();
+            Ok(Environment::Plate(BackgroundPlate::new(image.width, image.height, pixels)))
+        },
+        other => Err(invalid(format!("{} has unknown type '{}'", context, other)))
+    }
+}
+
+/// Reads a scene from a JSON description, of the form
+///
+/// ```json
+/// {
+///   "camera": { "position": [0, -30, 5], "field_of_view": 1.1 },
+///   "objects": [
+///     { "geometry": { "type": "sphere", "position": [0, 0, 5], "radius": 5 },
+///       "material": { "type": "black_body", "temperature": 6504, "intensity": 1 } },
+///     { "geometry": { "type": "plane", "normal": [0, 0, 1], "position": [0, 0, 0] },
+///       "material": { "type": "diffuse", "reflectance": 0.9,
+///                     "wavelength": 550, "width": 40 } }
+///   ]
+/// }
+/// ```
+///
+/// The camera may also have a "focal_distance", "depth_of_field",
+/// "chromatic_abberation", shifts, and an "orientation" quaternion [x, y, z,
+/// w]. With an "orthographic_scale", the projection is orthographic instead
+/// of perspective. With a "camera_end", the camera moves from "camera" to
+/// "camera_end" over the frame.
+///
+/// Besides planes and spheres, the geometry can be a "quad" ("origin",
+/// "edge_u", "edge_v"), a "triangle" ("v0", "v1", "v2"), a "box" ("min",
+/// "max"), or a "mesh" loaded from the OBJ file at "path". Any geometry can
+/// have a "scale", a "rotation" quaternion and a "translation".
+///
+/// A diffuse material without a wavelength is grey, and with a "spectrum"
+/// its reflectance is measured: a spectrum is the path of a CSV file, or an
+/// array of [wavelength, value] pairs. The other materials are "specular"
+/// ("reflectance"), "glossy" ("roughness"), "plastic" ("reflectance", "ior"),
+/// "dielectric" (a "glass" like "bk7", Sellmeier coefficients "b" and "c", or
+/// an "ior" and "abbe_number"), and the emissive "tabulated" ("spectrum").
+/// Objects can set "sidedness" ("two_sided" or "one_sided"), and the flags
+/// "backface_culling", "flip_normals" and "exclude_self_hits".
+///
+/// The optional "environment" is a "sky" ("sun_direction", "turbidity",
+/// "intensity"), a "uniform" radiance ("intensity"), or a background "plate"
+/// from the HDR image at "path". Paths are relative to the working directory.
+pub fn read_json<R: Read>(reader: &mut R) -> Result<Scene> {
+    let json = try!(Json::from_reader(reader).map_err(|err| {
+        invalid(format!("scene is not valid JSON: {}", err))
+    }));
+    let root = try!(as_object(&json, "the scene"));
+
+    let camera = match root.get("camera") {
+        Some(camera) => try!(parse_camera(camera, "the camera")),
+        None => return Err(invalid("the scene has no 'camera'".to_string()))
+    };
+    let camera_end = match root.get("camera_end") {
+        Some(camera) => Some(try!(parse_camera(camera, "the end camera"))),
+        None => None
+    };
+    let environment = match root.get("environment") {
+        Some(environment) => try!(parse_environment(environment)),
+        None => Environment::Void
+    };
+
+    let objects_json = match root.get("objects").and_then(|objects| objects.as_array()) {
+        Some(objects) => objects,
+        None => return Err(invalid("the scene must have an array of 'objects'".to_string()))
+    };
+    let mut meshes = MeshCache::new();
+    let mut objects = Vec::with_capacity(objects_json.len());
+    for (i, object_json) in objects_json.iter().enumerate() {
+        let context = format!("object {}", i);
+        let obj = try!(as_object(object_json, &context));
+        let geometry = match obj.get("geometry") {
+            Some(json) => {
+                try!(parse_surface(json, &format!("the geometry of {}", context), &mut meshes))
+            },
+            None => return Err(invalid(format!("{} has no 'geometry'", context)))
+        };
+        let material = match obj.get("material") {
+            Some(json) => try!(parse_material(json, &format!("the material of {}", context))),
+            None => return Err(invalid(format!("{} has no 'material'", context)))
+        };
+        let mut object = Object::new(geometry, material);
+        object.sidedness = try!(parse_sidedness(obj, &context));
+        object.backface_culling = try!(get_flag(obj, "backface_culling", &context));
+        object.flip_normals = try!(get_flag(obj, "flip_normals", &context));
+        object.exclude_self_hits = try!(get_flag(obj, "exclude_self_hits", &context));
+        objects.push(object);
+    }
+
+    Ok(Scene {
+        camera: Some(camera),
+        camera_end: camera_end,
+        environment: environment,
+        ..Scene::new(objects, fixed_camera)
+    })
+}
+
+/// Stands in for `get_camera_at_time` in scenes with a fixed camera.
+fn fixed_camera(_: f32) -> Camera {
+    unreachable!("the scene has a fixed camera")
+}
+
+/// Loads a scene from a JSON file. See `read_json` for the format.
+pub fn load_json<P: AsRef<Path>>(path: P) -> Result<Scene> {
+    let file = try!(File::open(path));
+    read_json(&mut BufReader::new(file))
+}
+
+#[test]
+fn intersect_excluding_skips_self_hit() {
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let mut plane = Object::new(Box::new(Plane::new(up, Vector3::zero())),
+                                Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    plane.exclude_self_hits = true;
+    let sphere = Object::new(Box::new(Sphere::new(up * 5.0, 1.0)),
+                             Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let scene = Scene::new(vec![plane, sphere], ::camera::test_camera);
+
+    // A ray that left the plane, but due to rounding errors larger than the
+    // origin nudge, starts slightly behind it.
+    let ray = Ray {
+        origin: Vector3::new(0.0, 0.0, -1.0e-3),
+        direction: up,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // Without exclusion, it hits the plane again immediately.
+    let (isect, _) = scene.intersect(&ray).unwrap();
+    assert!(isect.distance < 0.01);
+
+    // With exclusion, the plane is skipped, and the sphere is hit.
+    let (isect, _) = scene.intersect_excluding(&ray, Some(&scene.objects[0])).unwrap();
+    assert!((isect.distance - 4.001).abs() < 1.0e-3);
+}
+
+#[test]
+fn intersect_returns_nearest_regardless_of_order() {
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let make_light = |y: f32| {
+        Object::new(Box::new(Sphere::new(forward * y, 1.0)),
+                    Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))))
+    };
+    let make_grey = |y: f32| {
+        Object::new(Box::new(Sphere::new(forward * y, 1.0)),
+                    Reflective(Box::new(DiffuseGreyMaterial::new(0.8))))
+    };
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: forward,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    // Put a light in front of a reflector and vice versa, in both list orders.
+    let scenes = vec![
+        (vec![make_light(5.0), make_grey(10.0)], true),
+        (vec![make_grey(10.0), make_light(5.0)], true),
+        (vec![make_grey(5.0), make_light(10.0)], false),
+        (vec![make_light(10.0), make_grey(5.0)], false)
+    ];
+
+    for (objects, expect_emissive) in scenes {
+        let scene = Scene::new(objects, ::camera::test_camera);
+        let (isect, obj) = scene.intersect(&ray).unwrap();
+        assert!((isect.distance - 4.0).abs() < 1.0e-3);
+        let is_emissive = match obj.material {
+            Emissive(_) => true,
+            Reflective(_) => false
+        };
+        assert_eq!(is_emissive, expect_emissive);
+    }
+}
+
+#[test]
+fn intersect_ignores_hits_beyond_max_distance() {
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let make_scene = |max_distance: f32| {
+        let near = Object::new(Box::new(Plane::new(forward, forward * 5.0)),
+                               Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+        let far = Object::new(Box::new(Plane::new(forward, forward * -50.0)),
+                              Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+        Scene {
+            max_distance: max_distance,
+            ..Scene::new(vec![near, far], ::camera::test_camera)
+        }
+    };
+    let ray = |direction: Vector3| Ray {
+        origin: Vector3::zero(),
+        direction: direction,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    let scene = make_scene(20.0);
+
+    // The near plane lies within the maximum distance.
+    let (isect, _) = scene.intersect(&ray(forward)).unwrap();
+    assert!((isect.distance - 5.0).abs() < 1.0e-4);
+
+    // Looking backwards, only the far plane could be hit, but it is culled.
+    // Without the limit, it is hit.
+    assert!(scene.intersect(&ray(-forward)).is_none());
+    let scene = make_scene(1.0e12);
+    let (isect, _) = scene.intersect(&ray(-forward)).unwrap();
+    assert!((isect.distance - 50.0).abs() < 1.0e-3);
+}
+
+#[test]
+fn backface_culling_ignores_back_side_hits() {
+    use geometry::SpacePartitioning;
+
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let make_scene = |culling: bool| {
+        // The near surface faces away from the ray, the far one towards it.
+        let mut near = Object::new(Box::new(SpacePartitioning::new(forward, forward * 5.0)),
+                                   Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+        near.backface_culling = culling;
+        let far = Object::new(Box::new(SpacePartitioning::new(-forward, forward * 10.0)),
+                              Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+        Scene::new(vec![near, far], ::camera::test_camera)
+    };
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: forward,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    let (isect, _) = make_scene(false).intersect(&ray).unwrap();
+    assert!((isect.distance - 5.0).abs() < 1.0e-4);
+
+    let (isect, _) = make_scene(true).intersect(&ray).unwrap();
+    assert!((isect.distance - 10.0).abs() < 1.0e-4);
+}
+
+#[test]
+fn bvh_finds_the_same_nearest_hit() {
+    // A few hundred spheres scattered through a cube, and a floor plane
+    // which has no bounds.
+    ::monte_carlo::seed([3, 1, 4, 1]);
+    let random_point = || Vector3::new(::monte_carlo::get_bi_unit(),
+                                       ::monte_carlo::get_bi_unit(),
+                                       ::monte_carlo::get_bi_unit()) * 20.0;
+    let mut objects: Vec<Object> = (0 .. 300).map(|_| {
+        let radius = ::monte_carlo::get_unit() + 0.2;
+        Object::new(Box::new(Sphere::new(random_point(), radius)),
+                    Reflective(Box::new(DiffuseGreyMaterial::new(0.8))))
+    }).collect();
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    objects.push(Object::new(Box::new(Plane::new(up, up * -25.0)),
+                            Reflective(Box::new(DiffuseGreyMaterial::new(0.8)))));
+
+    let mut scene = Scene::new(objects, ::camera::test_camera);
+    let rays: Vec<Ray> = (0 .. 2000).map(|_| Ray {
+        origin: random_point() * 1.5,
+        direction: random_point().normalise(),
+        wavelength: 550.0,
+        probability: 1.0
+    }).collect();
+
+    let hit = |scene: &Scene, ray: &Ray| {
+        scene.intersect(ray).map(|(isect, obj)| (isect.distance, obj as *const Object))
+    };
+    let brute_force: Vec<_> = rays.iter().map(|ray| hit(&scene, ray)).collect();
+    scene.build_bvh(1);
+    let accelerated: Vec<_> = rays.iter().map(|ray| hit(&scene, ray)).collect();
+
+    assert!(brute_force.iter().filter(|h| h.is_some()).count() > 1000);
+    assert!(brute_force == accelerated);
+}
+
+#[test]
+fn flip_normals_turns_sphere_inside_out() {
+    use geometry::SpacePartitioning;
+    use trace_unit::TraceUnit;
+    use vector3::dot;
+
+    // A one-sided sphere in front of the camera, lit from behind the camera.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let make_scene = |flip: bool| {
+        let mut sphere = Object::new(Box::new(Sphere::new(forward * 5.0, 1.0)),
+                                     Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+        sphere.sidedness = Sidedness::OneSided;
+        sphere.flip_normals = flip;
+        let light = Object::new(Box::new(SpacePartitioning::new(forward, forward * -10.0)),
+                                Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+        Scene::new(vec![sphere, light], ::camera::test_camera)
+    };
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: forward,
+        wavelength: 550.0,
+        probability: 1.0
+    };
+
+    let (isect, _) = make_scene(false).intersect(&ray).unwrap();
+    assert!(dot(isect.normal, forward) < -0.999);
+    assert!(TraceUnit::trace_single_ray(&make_scene(false), Ray { .. ray }).contribution > 0.0);
+
+    // With flipped normals, the outside is the back side, so it is black.
+    let (isect, _) = make_scene(true).intersect(&ray).unwrap();
+    assert!(dot(isect.normal, forward) > 0.999);
+    assert_eq!(TraceUnit::trace_single_ray(&make_scene(true), ray).contribution, 0.0);
+}
+
+#[test]
+fn brighter_lights_emit_more_power() {
+    use geometry::Circle;
+
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let make_scene = |intensity: f32| {
+        let light = Object::new(Box::new(Circle::new(-up, up * 10.0, 2.0)),
+                                Emissive(Box::new(BlackBodyMaterial::new(6504.0, intensity))));
+        // An infinite light has no area, so it is ignored.
+        let plane = Object::new(Box::new(Plane::new(up, up * -10.0)),
+                                Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+        Scene::new(vec![light, plane], ::camera::test_camera)
+    };
+
+    let dim = make_scene(1.0).total_emitted_power();
+    let bright = make_scene(3.0).total_emitted_power();
+    assert!(dim > 0.0 && dim.is_finite());
+    assert!((bright / dim - 3.0).abs() < 1.0e-3);
+}
+
+#[test]
+fn read_json_scene() {
+    let json = br#"{
+        "camera": { "position": [0, -30, 5], "field_of_view": 1.1, "depth_of_field": 2 },
+        "objects": [
+            { "geometry": { "type": "sphere", "position": [0, 0, 5], "radius": 5 },
+              "material": { "type": "black_body", "temperature": 6504, "intensity": 1 } },
+            { "geometry": { "type": "plane", "normal": [0, 0, 1], "position": [0, 0, 0] },
+              "material": { "type": "diffuse", "reflectance": 0.9,
+                            "wavelength": 550, "width": 40 } },
+            { "geometry": { "type": "sphere", "position": [8, 0, 2], "radius": 2 },
+              "material": { "type": "diffuse", "reflectance": 0.5 } }
+        ]
+    }"#;
+    let scene = read_json(&mut &json[..]).unwrap();
+    assert_eq!(scene.objects.len(), 3);
+    assert_eq!(scene.emissive_objects().len(), 1);
+    let camera = scene.get_camera(0.5);
+    assert!((camera.field_of_view - 1.1).abs() < 1.0e-6);
+    assert_eq!(camera.position.y, -30.0);
+    assert_eq!(camera.depth_of_field, 2.0);
+
+    // Unknown types are reported, rather than ignored.
+    let json = br#"{
+        "camera": { "position": [0, 0, 0], "field_of_view": 1.1 },
+        "objects": [
+            { "geometry": { "type": "torus", "position": [0, 0, 0] },
+              "material": { "type": "diffuse", "reflectance": 0.5 } }
+        ]
+    }"#;
+    let error = read_json(&mut &json[..]).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(format!("{}", error).contains("torus"));
+
+    // A plane without a direction is an error, rather than a NaN normal.
+    let json = br#"{
+        "camera": { "position": [0, 0, 0], "field_of_view": 1.1 },
+        "objects": [
+            { "geometry": { "type": "plane", "normal": [0, 0, 0], "position": [0, 0, 0] },
+              "material": { "type": "diffuse", "reflectance": 0.5 } }
+        ]
+    }"#;
+    let error = read_json(&mut &json[..]).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(format!("{}", error).contains("normal"));
+}
+
+#[test]
+fn read_json_scene_with_instances_and_materials() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    // A mesh that two objects share.
+    let name = format!("robigo-luculenta-scene-mesh-{}.obj", process::id());
+    let mesh_path = env::temp_dir().join(name);
+    {
+        let mut file = File::create(&mesh_path).unwrap();
+        file.write_all(b"v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+    }
+
+    let json = format!(r#"{{
+        "camera": {{ "position": [0, -30, 5], "field_of_view": 1.0 }},
+        "camera_end": {{ "position": [10, -30, 5], "field_of_view": 0.5,
+                         "orientation": [0, 0, 2, 0] }},
+        "environment": {{ "type": "sky", "sun_direction": [0, 1, 1], "turbidity": 3 }},
+        "objects": [
+            {{ "geometry": {{ "type": "quad", "origin": [0, 0, 0],
+                             "edge_u": [1, 0, 0], "edge_v": [0, 1, 0] }},
+               "material": {{ "type": "specular", "reflectance": 0.9 }},
+               "sidedness": "two_sided" }},
+            {{ "geometry": {{ "type": "triangle", "v0": [0, 0, 0], "v1": [1, 0, 0],
+                             "v2": [0, 1, 0] }},
+               "material": {{ "type": "glossy", "roughness": 0.3 }},
+               "exclude_self_hits": true }},
+            {{ "geometry": {{ "type": "box", "min": [-1, -1, -1], "max": [1, 1, 1],
+                             "scale": 2, "translation": [0, 0, 10] }},
+               "material": {{ "type": "plastic", "reflectance": 0.5 }},
+               "backface_culling": true }},
+            {{ "geometry": {{ "type": "mesh", "path": "{0}" }},
+               "material": {{ "type": "dielectric", "glass": "bk7" }},
+               "flip_normals": true }},
+            {{ "geometry": {{ "type": "mesh", "path": "{0}", "rotation": [0, 0, 1, 1] }},
+               "material": {{ "type": "dielectric", "ior": 1.6, "abbe_number": 40 }} }},
+            {{ "geometry": {{ "type": "sphere", "position": [0, 0, 0], "radius": 1 }},
+               "material": {{ "type": "dielectric", "b": [1, 0, 0], "c": [0.01, 0, 0] }} }},
+            {{ "geometry": {{ "type": "sphere", "position": [5, 0, 0], "radius": 1 }},
+               "material": {{ "type": "diffuse", "spectrum": [[400, 0.1], [700, 0.9]] }} }},
+            {{ "geometry": {{ "type": "sphere", "position": [-5, 0, 0], "radius": 1 }},
+               "material": {{ "type": "tabulated", "spectrum": [[380, 1], [780, 1]] }} }}
+        ]
+    }}"#, mesh_path.display());
+    let scene = read_json(&mut json.as_bytes()).unwrap();
+    fs::remove_file(&mesh_path).unwrap();
+
+    assert_eq!(scene.objects.len(), 8);
+    assert_eq!(scene.objects[0].sidedness, Sidedness::TwoSided);
+    assert!(scene.objects[1].exclude_self_hits);
+    assert!(scene.objects[2].backface_culling);
+    assert!(scene.objects[3].flip_normals);
+    assert_eq!(scene.emissive_objects().len(), 1);
+    match scene.environment {
+        Environment::Sky(_) => { },
+        _ => panic!("the environment should be a sky")
+    }
+
+    // The scaled box is an instance, twice as large.
+    let bounds = scene.objects[2].surface.bounding_box().unwrap();
+    assert!((bounds.min.z - 8.0).abs() < 1.0e-5 && (bounds.max.z - 12.0).abs() < 1.0e-5);
+    assert!((scene.objects[2].surface.area().unwrap() - 96.0).abs() < 1.0e-3);
+
+    // The camera moves from the start to the end over the frame.
+    assert_eq!(scene.get_camera(0.0).position.x, 0.0);
+    assert!((scene.get_camera(0.5).position.x - 5.0).abs() < 1.0e-5);
+    assert!((scene.get_camera(1.0).field_of_view - 0.5).abs() < 1.0e-5);
+
+    // A box that is inside out is an error, rather than a failed assertion.
+    let json = br#"{
+        "camera": { "position": [0, 0, 0], "field_of_view": 1.1 },
+        "objects": [
+            { "geometry": { "type": "box", "min": [1, 1, 1], "max": [0, 2, 2] },
+              "material": { "type": "diffuse", "reflectance": 0.5 },
+              "sidedness": "three_sided" }
+        ]
+    }"#;
+    let error = read_json(&mut &json[..]).err().unwrap();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(format!("{}", error).contains("'min'"));
+}
+
+#[test]
+fn lights_are_sampled_by_power() {
+    use geometry::Circle;
+
+    // Two lights of the same size, one ten times as bright as the other, and
+    // a light without an area that cannot be sampled.
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let bright = Object::new(Box::new(Circle::new(-up, up * 10.0, 1.0)),
+                             Emissive(Box::new(BlackBodyMaterial::new(6504.0, 10.0))));
+    let dim = Object::new(Box::new(Circle::new(-up, up * 10.0 + up, 1.0)),
+                          Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let wall = Object::new(Box::new(Sphere::new(Vector3::zero(), 1.0)),
+                           Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let mut scene = Scene::new(vec![wall, bright, dim], ::camera::test_camera);
+
+    // Without the distribution, the lights are sampled uniformly.
+    {
+        let lights = scene.sampled_lights();
+        let (_, area, p) = lights[pick_light(&lights, 0.7)];
+        assert!((area - PI).abs() < 1.0e-5);
+        assert_eq!(p, 0.5);
+    }
+
+    scene.build_light_distribution();
+    let lights = scene.sampled_lights();
+    let n = 11000;
+    let mut bright_count = 0i32;
+    for _ in 0 .. n {
+        let (light, _, p) = lights[pick_light(&lights, ::monte_carlo::get_unit())];
+        if ::std::ptr::eq(light, &scene.objects[1]) {
+            assert!((p - 10.0 / 11.0).abs() < 1.0e-5);
+            bright_count += 1;
+        } else {
+            assert!((p - 1.0 / 11.0).abs() < 1.0e-5);
+        }
+    }
+
+    // The expected count is 10000, with a standard deviation of about 30.
+    assert!((bright_count - 10000).abs() < 150);
 }
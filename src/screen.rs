@@ -0,0 +1,30 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+//! Screen coordinates are shared by the camera, the trace units and the
+//! plot units. The x-coordinate ranges from -1.0 at the left of the image to
+//! 1.0 at the right. The y-coordinate has the same units, so it ranges from
+//! -1.0 / aspect ratio at the top to 1.0 / aspect ratio at the bottom.
+//!
+//! Camera rays are sampled in the open interval, so no photon lands exactly
+//! on the boundary of the frame.
+
+/// Returns the aspect ratio (width divided by height) of a canvas of the
+/// specified size. All stages must use this, so they agree on the mapping
+/// between screen coordinates and pixels.
+pub fn aspect_ratio(width: u32, height: u32) -> f32 {
+    width as f32 / height as f32
+}
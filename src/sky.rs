@@ -0,0 +1,193 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::f32::consts::PI;
+use vector3::{Vector3, dot};
+
+/// The analytic daylight sky model of Preetham, Shirley and Smits, "A
+/// Practical Analytic Model for Daylight" (1999). It gives the luminance and
+/// chromaticity of the sky, which are turned into a spectrum with the CIE
+/// daylight basis functions. The sun itself is not included, only the sky.
+pub struct SkyModel {
+    /// Unit vector towards the sun. The z-axis points up.
+    sun_direction: Vector3,
+
+    /// The angle between the sun and the zenith.
+    sun_theta: f32,
+
+    /// Perez coefficients for the luminance Y and chromaticities x and y.
+    perez_y: [f32; 5],
+    perez_cx: [f32; 5],
+    perez_cy: [f32; 5],
+
+    /// Luminance and chromaticity at the zenith.
+    zenith_y: f32,
+    zenith_cx: f32,
+    zenith_cy: f32,
+
+    /// A factor applied to the radiance.
+    intensity: f32
+}
+
+/// The Perez function of the angle to the zenith `theta`, and the angle to
+/// the sun `gamma`.
+fn perez(c: &[f32; 5], theta: f32, gamma: f32) -> f32 {
+    (1.0 + c[0] * (c[1] / theta.cos()).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos() * gamma.cos())
+}
+
+impl SkyModel {
+    /// Creates a sky for the specified direction of the sun, and turbidity
+    /// (2 is a very clear sky, 10 is hazy). The sun must be above the horizon;
+    /// lower suns are clamped to the horizon. With an intensity of 1, the
+    /// radiance is in the order of the luminance in kcd/m².
+    pub fn new(sun_direction: Vector3, turbidity: f32, intensity: f32) -> SkyModel {
+        let t = turbidity;
+        let sun_direction = sun_direction.normalise();
+        let ts = sun_direction.z.max(0.0).min(1.0).acos();
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * ts);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+
+        let (ts2, ts3) = (ts * ts, ts * ts * ts);
+        let zenith_cx = t * t * (0.00166 * ts3 - 0.00375 * ts2 + 0.00209 * ts)
+            + t * (-0.02903 * ts3 + 0.06377 * ts2 - 0.03202 * ts + 0.00394)
+            + (0.11693 * ts3 - 0.21196 * ts2 + 0.06052 * ts + 0.25886);
+        let zenith_cy = t * t * (0.00275 * ts3 - 0.00610 * ts2 + 0.00317 * ts)
+            + t * (-0.04214 * ts3 + 0.08970 * ts2 - 0.04153 * ts + 0.00516)
+            + (0.15346 * ts3 - 0.26756 * ts2 + 0.06670 * ts + 0.26688);
+
+        SkyModel {
+            sun_direction: sun_direction,
+            sun_theta: ts,
+            perez_y: [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251,
+                      0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            perez_cx: [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125,
+                       -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            perez_cy: [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102,
+                       -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+            zenith_y: zenith_y,
+            zenith_cx: zenith_cx,
+            zenith_cy: zenith_cy,
+            intensity: intensity
+        }
+    }
+
+    /// Returns the radiance of the sky in the specified (unit) direction, at
+    /// the specified wavelength. Below the horizon, there is no sky.
+    pub fn get_intensity(&self, direction: Vector3, wavelength: f32) -> f32 {
+        if direction.z <= 0.0 { return 0.0; }
+
+        let theta = direction.z.min(1.0).acos();
+        let gamma = dot(direction, self.sun_direction).max(-1.0).min(1.0).acos();
+
+        // The Perez function relative to its value at the zenith.
+        let relative = |c: &[f32; 5]| perez(c, theta, gamma) / perez(c, 0.0, self.sun_theta);
+        let lum = self.zenith_y * relative(&self.perez_y);
+        let cx = self.zenith_cx * relative(&self.perez_cx);
+        let cy = self.zenith_cy * relative(&self.perez_cy);
+
+        // Get the daylight spectrum with that chromaticity, and normalise it
+        // to the luminance.
+        let m = 0.0241 + 0.2562 * cx - 0.7341 * cy;
+        let m1 = (-1.3515 - 1.7703 * cx + 5.9114 * cy) / m;
+        let m2 = (0.0300 - 31.4424 * cx + 30.0717 * cy) / m;
+        let spectrum = |w: f32| {
+            daylight_basis(&S0, w) + m1 * daylight_basis(&S1, w) + m2 * daylight_basis(&S2, w)
+        };
+
+        // The (relative) luminance of the spectrum, at 10 nm intervals.
+        let (mut spectrum_y, mut total_y) = (0.0, 0.0);
+        for i in 0 .. 41 {
+            let w = 380.0 + 10.0 * i as f32;
+            let y = ::cie1931::get_tristimulus(w).y;
+            spectrum_y += spectrum(w) * y;
+            total_y += y;
+        }
+
+        self.intensity * lum * spectrum(wavelength) * total_y / spectrum_y
+    }
+}
+
+/// Interpolates one of the daylight basis functions, which are tabulated at
+/// 10 nm intervals from 380 nm to 780 nm.
+fn daylight_basis(s: &[f32; 41], wavelength: f32) -> f32 {
+    let indexf = ((wavelength - 380.0) / 10.0).max(0.0).min(40.0);
+    let index = (indexf.floor() as usize).min(39);
+    let remainder = indexf - index as f32;
+    s[index] * (1.0 - remainder) + s[index + 1] * remainder
+}
+
+// CIE daylight components, from CIE publication 15 (Colorimetry).
+
+/// The mean daylight spectrum.
+const S0: [f32; 41] = [
+    63.4, 65.8, 94.8, 104.8, 105.9, 96.8, 113.9, 125.6, 125.5, 121.3,
+    121.3, 113.5, 113.1, 110.8, 106.5, 108.8, 105.3, 104.4, 100.0, 96.0,
+    95.1, 89.1, 90.5, 90.3, 88.4, 84.0, 85.1, 81.9, 82.6, 84.9,
+    81.3, 71.9, 74.3, 76.4, 63.3, 71.7, 77.0, 65.2, 47.7, 68.6,
+    65.0
+];
+
+/// The first characteristic vector, the yellow-blue variation.
+const S1: [f32; 41] = [
+    38.5, 35.0, 43.4, 46.3, 43.9, 37.1, 36.7, 35.9, 32.6, 27.9,
+    24.3, 20.1, 16.2, 13.2, 8.6, 6.1, 4.2, 1.9, 0.0, -1.6,
+    -3.5, -3.5, -5.8, -7.2, -8.6, -9.5, -10.9, -10.7, -12.0, -14.0,
+    -13.6, -12.0, -13.3, -12.9, -10.6, -11.6, -12.2, -10.2, -7.8, -11.2,
+    -10.4
+];
+
+/// The second characteristic vector, the pink-green variation.
+const S2: [f32; 41] = [
+    3.0, 1.2, -1.1, -0.5, -0.7, -1.2, -2.6, -2.9, -2.8, -2.6,
+    -2.6, -1.8, -1.5, -1.3, -1.2, -1.0, -0.5, -0.3, 0.0, 0.2,
+    0.5, 2.1, 3.2, 4.1, 4.7, 5.1, 6.7, 7.3, 8.6, 9.8,
+    10.2, 8.3, 9.6, 8.5, 7.0, 7.6, 8.0, 6.7, 5.2, 7.4,
+    6.8
+];
+
+#[test]
+fn sky_is_brighter_near_sun() {
+    // A sun at 30 degrees above the horizon, towards +x.
+    let elevation = PI / 6.0;
+    let sun = Vector3::new(elevation.cos(), 0.0, elevation.sin());
+    let sky = SkyModel::new(sun, 3.0, 1.0);
+
+    // Compare directions at the same elevation, towards and away from the sun.
+    let near = Vector3::new(0.8, 0.3, 0.5).normalise();
+    let far = Vector3::new(-0.8, 0.3, 0.5).normalise();
+    for &w in [450.0f32, 550.0, 650.0].iter() {
+        assert!(sky.get_intensity(near, w) > sky.get_intensity(far, w));
+    }
+
+    // There is no sky below the horizon.
+    assert_eq!(sky.get_intensity(Vector3::new(0.0, 0.6, -0.8), 550.0), 0.0);
+}
+
+#[test]
+fn zenith_colour_shifts_with_turbidity() {
+    let sun = Vector3::new(0.5, 0.0, 0.8).normalise();
+    let up = Vector3::new(0.0, 0.0, 1.0);
+
+    // A clear sky is deep blue, a hazy sky is whiter.
+    let blueness = |turbidity: f32| {
+        let sky = SkyModel::new(sun, turbidity, 1.0);
+        sky.get_intensity(up, 450.0) / sky.get_intensity(up, 650.0)
+    };
+    assert!(blueness(2.0) > blueness(6.0));
+    assert!(blueness(6.0) > blueness(10.0));
+}
@@ -0,0 +1,179 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Returns the CIE 1931 2° standard observer colour matching functions
+/// (x̄, ȳ, z̄) at the specified wavelength in nm. The tabulated values are
+/// linearly interpolated between 380 and 780 nm, and fall to zero outside.
+pub fn cie_xyz(wavelength: f32) -> (f32, f32, f32) {
+    let xyz = ::cie1931::get_tristimulus(wavelength);
+    (xyz.x, xyz.y, xyz.z)
+}
+
+/// A spectrum that is known at a number of wavelengths, and linearly
+/// interpolated in between.
+pub struct SampledSpectrum {
+    /// The wavelengths of the samples in nm, in strictly increasing order.
+    wavelengths: Vec<f32>,
+
+    /// The value of the spectrum at the corresponding wavelength.
+    values: Vec<f32>
+}
+
+impl SampledSpectrum {
+    /// Constructs a spectrum from (wavelength, value) pairs. The wavelengths
+    /// must be strictly increasing, and there must be at least one sample.
+    pub fn new(samples: &[(f32, f32)]) -> Result<SampledSpectrum> {
+        if samples.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "spectrum has no samples"));
+        }
+        for pair in samples.windows(2) {
+            if !(pair[0].0 < pair[1].0) {
+                let msg = format!("wavelength {} nm does not follow {} nm in increasing order",
+                                  pair[1].0, pair[0].0);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        }
+
+        Ok(SampledSpectrum {
+            wavelengths: samples.iter().map(|s| s.0).collect(),
+            values: samples.iter().map(|s| s.1).collect()
+        })
+    }
+
+    /// Returns the shortest and the longest sampled wavelength.
+    pub fn range(&self) -> (f32, f32) {
+        (self.wavelengths[0], self.wavelengths[self.wavelengths.len() - 1])
+    }
+
+    /// Returns the value at the specified wavelength. Outside of the sampled
+    /// range, the nearest sample is used.
+    pub fn evaluate(&self, wavelength: f32) -> f32 {
+        let n = self.wavelengths.len();
+        if wavelength <= self.wavelengths[0] { return self.values[0]; }
+        if wavelength >= self.wavelengths[n - 1] { return self.values[n - 1]; }
+
+        // Find the first sample beyond the wavelength; it is not the first
+        // sample, because of the checks above.
+        let i = self.wavelengths.iter().position(|&w| w > wavelength).unwrap();
+        let (w0, w1) = (self.wavelengths[i - 1], self.wavelengths[i]);
+        let (v0, v1) = (self.values[i - 1], self.values[i]);
+        let t = (wavelength - w0) / (w1 - w0);
+        v0 * (1.0 - t) + v1 * t
+    }
+}
+
+/// Parses one line of a CSV spectrum, returning `None` for lines that carry
+/// no sample: blank lines, comments starting with '#', and a header.
+fn parse_row(line: &str, line_number: usize) -> Result<Option<(f32, f32)>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+    let malformed = || {
+        let msg = format!("line {}: expected 'wavelength, value', found '{}'", line_number, line);
+        Error::new(ErrorKind::InvalidData, msg)
+    };
+    if fields.len() != 2 {
+        return Err(malformed());
+    }
+
+    match (fields[0].parse::<f32>(), fields[1].parse::<f32>()) {
+        (Ok(wavelength), Ok(value)) => Ok(Some((wavelength, value))),
+        // A non-numeric first line is taken to be a header.
+        _ if line_number == 1 => Ok(None),
+        _ => Err(malformed())
+    }
+}
+
+/// Reads a spectrum from CSV data with a wavelength (in nm) and a value
+/// per row.
+pub fn read_csv<R: BufRead>(reader: R) -> Result<SampledSpectrum> {
+    let mut samples = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = try!(line);
+        if let Some(sample) = try!(parse_row(&line, i + 1)) {
+            samples.push(sample);
+        }
+    }
+    SampledSpectrum::new(&samples)
+}
+
+/// Loads a measured spectrum, such as a reflectance curve, from a CSV file.
+pub fn load_csv<P: AsRef<Path>>(path: P) -> Result<SampledSpectrum> {
+    let file = try!(File::open(path));
+    read_csv(BufReader::new(file))
+}
+
+#[test]
+fn cie_xyz_peaks_at_known_wavelengths() {
+    // Find the wavelength of the maximum of each function at 1 nm steps.
+    let peak = |f: &Fn((f32, f32, f32)) -> f32| {
+        (380 .. 781).map(|w| w as f32)
+                    .fold((0.0, 0.0), |(w_max, v_max), w| {
+                        let v = f(cie_xyz(w));
+                        if v > v_max { (w, v) } else { (w_max, v_max) }
+                    }).0
+    };
+    assert!((peak(&|xyz| xyz.0) - 600.0).abs() <= 5.0);
+    assert!((peak(&|xyz| xyz.1) - 555.0).abs() <= 5.0);
+    assert!((peak(&|xyz| xyz.2) - 445.0).abs() <= 5.0);
+
+    // The luminous efficiency function is normalised to 1 at its peak.
+    assert!((cie_xyz(555.0).1 - 1.0).abs() < 1.0e-3);
+    assert_eq!(cie_xyz(300.0), (0.0, 0.0, 0.0));
+    assert_eq!(cie_xyz(900.0), (0.0, 0.0, 0.0));
+}
+
+#[test]
+fn load_csv_interpolates_between_samples() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::process;
+
+    let name = format!("robigo-luculenta-spectrum-test-{}.csv", process::id());
+    let path = env::temp_dir().join(name);
+    {
+        let mut file = File::create(&path).unwrap();
+        write!(file, "wavelength,reflectance\n400, 0.2\n500, 0.6\n\n600, 0.4\n").unwrap();
+    }
+    let spectrum = load_csv(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!((spectrum.evaluate(450.0) - 0.4).abs() < 1.0e-6);
+    assert!((spectrum.evaluate(575.0) - 0.45).abs() < 1.0e-6);
+    assert_eq!(spectrum.evaluate(500.0), 0.6);
+    assert_eq!(spectrum.evaluate(380.0), 0.2);
+    assert_eq!(spectrum.evaluate(700.0), 0.4);
+}
+
+#[test]
+fn read_csv_rejects_bad_input() {
+    let malformed = read_csv("400, 0.2\n500; 0.6\n".as_bytes());
+    assert_eq!(malformed.err().unwrap().kind(), ErrorKind::InvalidData);
+
+    let unordered = read_csv("400, 0.2\n600, 0.6\n500, 0.4\n".as_bytes());
+    assert_eq!(unordered.err().unwrap().kind(), ErrorKind::InvalidData);
+
+    let empty = read_csv("wavelength, value\n".as_bytes());
+    assert!(empty.is_err());
+}
@@ -16,6 +16,36 @@
 
 use vector3::Vector3;
 
+/// The transfer function applied to linear values for display.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Encoding {
+    /// The sRGB transfer function, which has a linear segment near black.
+    Srgb,
+
+    /// A plain power curve with the specified gamma (such as 2.2), which
+    /// differs from sRGB in the shadows.
+    Gamma(f32)
+}
+
+impl Encoding {
+    /// Encodes the linear component `f`.
+    pub fn encode(self, f: f32) -> f32 {
+        match self {
+            Encoding::Srgb => gamma_correct(f),
+            Encoding::Gamma(gamma) => f.max(0.0).powf(1.0 / gamma)
+        }
+    }
+
+    /// Encodes all components of the linear colour.
+    pub fn encode_rgb(self, rgb: Vector3) -> Vector3 {
+        Vector3 {
+            x: self.encode(rgb.x),
+            y: self.encode(rgb.y),
+            z: self.encode(rgb.z)
+        }
+    }
+}
+
 /// Applies the sRGB gamma correction to the component.
 fn gamma_correct(f: f32) -> f32 {
     if f <= 0.0031308 {
@@ -25,17 +55,37 @@ fn gamma_correct(f: f32) -> f32 {
     }
 }
 
-/// Converts a CIE XYZ tristimulus to an sRGB colour.
-pub fn transform(cie: Vector3) -> Vector3 {
-    // Apply the sRGB matrix.
-    let r =  3.2406 * cie.x - 1.5372 * cie.y - 0.4986 * cie.z;
-    let g = -0.9689 * cie.x + 1.8758 * cie.y + 0.0415 * cie.z;
-    let b =  0.0557 * cie.x - 0.2040 * cie.y + 1.0570 * cie.z;
+/// The matrix that converts CIE 1931 XYZ tristimuli, as produced by
+/// `cie1931::get_tristimulus`, to linear sRGB. This is the only place
+/// where the renderer defines its RGB primaries.
+pub const XYZ_TO_LINEAR_SRGB: [[f32; 3]; 3] = [
+    [ 3.2406, -1.5372, -0.4986],
+    [-0.9689,  1.8758,  0.0415],
+    [ 0.0557, -0.2040,  1.0570]
+];
 
-    // Then do gamma correction.
+/// Converts a CIE XYZ tristimulus to a linear (not gamma corrected)
+/// colour with the sRGB primaries.
+pub fn transform_linear(cie: Vector3) -> Vector3 {
+    // Apply the sRGB matrix.
+    let m = &XYZ_TO_LINEAR_SRGB;
     Vector3 {
-        x: gamma_correct(r),
-        y: gamma_correct(g),
-        z: gamma_correct(b)
+        x: m[0][0] * cie.x + m[0][1] * cie.y + m[0][2] * cie.z,
+        y: m[1][0] * cie.x + m[1][1] * cie.y + m[1][2] * cie.z,
+        z: m[2][0] * cie.x + m[2][1] * cie.y + m[2][2] * cie.z
     }
 }
+
+#[test]
+fn srgb_encoding_has_linear_segment() {
+    // Small values are scaled linearly, instead of following the power curve.
+    let f = 0.001;
+    assert!((Encoding::Srgb.encode(f) - 12.92 * f).abs() < 1.0e-6);
+    assert!((Encoding::Gamma(2.2).encode(f) - f.powf(1.0 / 2.2)).abs() < 1.0e-6);
+    assert!(Encoding::Srgb.encode(f) < Encoding::Gamma(2.2).encode(f) * 0.5);
+
+    // Both are close in the mid tones, and map white to white.
+    assert!((Encoding::Srgb.encode(0.5) - Encoding::Gamma(2.2).encode(0.5)).abs() < 0.02);
+    assert!((Encoding::Srgb.encode(1.0) - 1.0).abs() < 1.0e-6);
+    assert!((Encoding::Gamma(2.2).encode(1.0) - 1.0).abs() < 1.0e-6);
+}
@@ -14,10 +14,17 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+// Units are always boxed, so that they move between the queues of the
+// scheduler and the tasks of the workers without being copied, also when
+// a task holds several of them.
+#![allow(clippy::vec_box)]
+
 use std::cmp::max;
-use std::collections::vec_deque::VecDeque;
+use std::collections::vec_deque::{IterMut, VecDeque};
+use std::sync::mpsc::Sender;
 use time::{Duration, Timespec, get_time};
 use gather_unit::GatherUnit;
+use importance_map::ImportanceMap;
 use plot_unit::PlotUnit;
 use pop_iter::PopFrontIter;
 use tonemap_unit::TonemapUnit;
@@ -37,7 +44,46 @@ pub enum Task {
     Gather(Box<GatherUnit>, Vec<Box<PlotUnit>>),
 
     /// Convert the CIE XYZ values to sRGB and display the image.
-    Tonemap(Box<TonemapUnit>, Box<GatherUnit>)
+    Tonemap(Box<TonemapUnit>, Box<GatherUnit>),
+
+    /// Convert the CIE XYZ values to sRGB for the final image, and signal
+    /// that the render is done.
+    Finish(Box<TonemapUnit>, Box<GatherUnit>),
+
+    /// The render is done, the worker can stop.
+    Exit
+}
+
+/// Conditions under which a render stops, whichever is met first. They are
+/// checked after every gather. By default, a render never stops. It also
+/// stops when every pixel reached `GatherUnit::max_pixel_photons`.
+#[derive(Copy, Clone, Default)]
+pub struct StopCriteria {
+    /// Stop when the estimated relative error of the image (see
+    /// `GatherUnit::relative_error`) drops below this threshold.
+    pub max_relative_error: Option<f32>,
+
+    /// Stop when the render has been running for this long.
+    pub max_time: Option<Duration>,
+
+    /// Stop when at least this many batches have been traced.
+    pub max_batches: Option<u32>
+}
+
+/// A report on how far a render has progressed, sent after every gather.
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    /// The number of batches traced so far.
+    pub batches_traced: u32,
+
+    /// The number of photons traced so far.
+    pub photons_traced: u64,
+
+    /// The number of photons traced so far per pixel of the image.
+    pub photons_per_pixel: f32,
+
+    /// The time since rendering started.
+    pub elapsed: Duration
 }
 
 /// Tonemap every 30 seconds.
@@ -60,6 +106,11 @@ pub struct TaskScheduler {
     /// The trace units which are available for tracing rays.
     available_trace_units: VecDeque<Box<TraceUnit>>,
 
+    /// For every trace unit, the worker that traced it last. Workers are
+    /// given their own trace units when possible, because the buffers of
+    /// those units might still be in the cache of their core.
+    trace_unit_workers: Vec<Option<usize>>,
+
     /// The trace units which have mapped photons that must be plotted,
     /// before the trace unit can be used again.
     done_trace_units: VecDeque<Box<TraceUnit>>,
@@ -82,13 +133,67 @@ pub struct TaskScheduler {
 
     /// Whether a new gather task has been executed since the last
     /// tonemapping task was executed.
-    image_changed: bool
+    image_changed: bool,
+
+    /// When to stop rendering.
+    pub stop_criteria: StopCriteria,
+
+    /// The time at which rendering started.
+    start_time: Timespec,
+
+    /// The total number of completed trace batches.
+    batches_traced: u32,
+
+    /// The total number of photons in the completed trace batches.
+    photons_traced: u64,
+
+    /// The number of pixels of the image.
+    pixel_count: u32,
+
+    /// Where to report progress to, if anywhere.
+    pub progress_tx: Option<Sender<Progress>>,
+
+    /// The number of tiles horizontally and vertically to sample adaptively,
+    /// if photons should be concentrated on the noisy parts of the image.
+    /// The photons are spread evenly by default.
+    pub adaptive_tiles: Option<(u32, u32)>,
+
+    /// The distribution of photons over the image, updated after every
+    /// gather when sampling adaptively.
+    importance_map: Option<ImportanceMap>,
+
+    /// Whether a stop criterion was met. No more rays are traced then.
+    stopping: bool,
+
+    /// Whether the final image has been tonemapped.
+    finished: bool
 }
 
 impl TaskScheduler {
     /// Creates a new task scheduler, that will render `scene` to a
-    /// canvas of the specified size, using `concurrency` threads.
-    pub fn new(concurrency: usize, width: u32, height: u32) -> TaskScheduler {
+    /// canvas of the specified size, using `concurrency` threads. Every
+    /// trace unit traces `batch_size` photons per batch. If a `seed` is
+    /// given, it seeds the samplers of all trace units.
+    pub fn new(concurrency: usize, width: u32, height: u32, batch_size: usize,
+               seed: Option<u32>) -> TaskScheduler {
+        let gather_unit = GatherUnit::new(width, height);
+        TaskScheduler::with_gather_unit(concurrency, width, height, batch_size, seed,
+                                        gather_unit)
+    }
+
+    /// Creates a new task scheduler like `new`, that starts from an empty
+    /// canvas, and does not save its progress.
+    pub fn new_empty(concurrency: usize, width: u32, height: u32, batch_size: usize,
+                     seed: Option<u32>) -> TaskScheduler {
+        let gather_unit = GatherUnit::new_empty(width, height);
+        TaskScheduler::with_gather_unit(concurrency, width, height, batch_size, seed,
+                                        gather_unit)
+    }
+
+    /// Creates a new task scheduler like `new`, that accumulates the image
+    /// into `gather_unit`, which must have the specified size.
+    pub fn with_gather_unit(concurrency: usize, width: u32, height: u32, batch_size: usize,
+                            seed: Option<u32>, gather_unit: GatherUnit) -> TaskScheduler {
         // More trace units than threads seems sensible,
         // but less plot units is acceptable,
         // because one plot unit can handle multiple trace units.
@@ -97,7 +202,12 @@ impl TaskScheduler {
 
         // Build the trace units.
         let trace_units = (0 .. n_trace_units)
-        .map(|i| { Box::new(TraceUnit::new(i, width, height)) })
+        .map(|i| {
+            let mut unit = Box::new(TraceUnit::new(i, width, height, batch_size));
+            // Units still sample differently, because their IDs differ.
+            if let Some(s) = seed { unit.frame_seed = s; }
+            unit
+        })
         .collect::<VecDeque<Box<TraceUnit>>>();
 
         // Then build the plot units.
@@ -106,7 +216,7 @@ impl TaskScheduler {
         .collect::<VecDeque<Box<PlotUnit>>>();
 
         // There must be one gather unit and one tonemap unit.
-        let gather_unit = Some(Box::new(GatherUnit::new(width, height)));
+        let gather_unit = Some(Box::new(gather_unit));
         let tonemap_unit = Some(Box::new(TonemapUnit::new(width, height)));
 
         TaskScheduler {
@@ -114,20 +224,85 @@ impl TaskScheduler {
             performance: VecDeque::new(),
             number_of_trace_units: n_trace_units,
             available_trace_units: trace_units,
+            trace_unit_workers: vec![None; n_trace_units],
             done_trace_units: VecDeque::new(),
             available_plot_units: plot_units,
             done_plot_units: VecDeque::new(),
             gather_unit: gather_unit,
             tonemap_unit: tonemap_unit,
             last_tonemap_time: get_time(),
-            image_changed: false
+            image_changed: false,
+            stop_criteria: StopCriteria::default(),
+            start_time: get_time(),
+            batches_traced: 0,
+            photons_traced: 0,
+            pixel_count: width * height,
+            progress_tx: None,
+            adaptive_tiles: None,
+            importance_map: None,
+            stopping: false,
+            finished: false
+        }
+    }
+
+    /// Returns the gather unit, so that it can be configured before the
+    /// render starts. It panics if the unit is in use by a task.
+    pub fn gather_unit(&mut self) -> &mut GatherUnit {
+        self.gather_unit.as_mut().expect("the gather unit is in use")
+    }
+
+    /// Returns the tonemap unit, so that it can be configured before the
+    /// render starts. It panics if the unit is in use by a task.
+    pub fn tonemap_unit(&mut self) -> &mut TonemapUnit {
+        self.tonemap_unit.as_mut().expect("the tonemap unit is in use")
+    }
+
+    /// Returns the trace units that are not in use, so that they can be
+    /// configured before the render starts, when all of them are available.
+    pub fn trace_units<'a>(&'a mut self) -> IterMut<'a, Box<TraceUnit>> {
+        self.available_trace_units.iter_mut()
+    }
+
+    /// Returns the plot units that are not in use, so that they can be
+    /// configured before the render starts, when all of them are available.
+    pub fn plot_units<'a>(&'a mut self) -> IterMut<'a, Box<PlotUnit>> {
+        self.available_plot_units.iter_mut()
+    }
+
+    /// Takes the tonemap unit with the final image, and the gather unit with
+    /// everything that was gathered, once the render is done. Returns `None`
+    /// if the render is not done, or if the units were taken already.
+    pub fn take_final_units(&mut self) -> Option<(Box<TonemapUnit>, Box<GatherUnit>)> {
+        if !self.finished { return None; }
+        match (self.tonemap_unit.take(), self.gather_unit.take()) {
+            (Some(tonemap_unit), Some(gather_unit)) => Some((tonemap_unit, gather_unit)),
+            (tonemap_unit, gather_unit) => {
+                self.tonemap_unit = tonemap_unit;
+                self.gather_unit = gather_unit;
+                None
+            }
         }
     }
 
-    pub fn get_new_task(&mut self, completed_task: Task) -> Task {
+    /// Completes the task that `worker` finished, and returns the next task
+    /// for that worker.
+    pub fn get_new_task(&mut self, worker: usize, completed_task: Task) -> Task {
         // Make the units that were used by the completed task available again.
         self.complete_task(completed_task);
 
+        // Once a stop criterion is met, only the final image remains to be
+        // tonemapped. Batches that were not gathered yet are discarded.
+        // After that, there is nothing left to do.
+        if self.finished {
+            return Task::Exit;
+        }
+        if self.stopping {
+            if self.gather_unit.is_some() && self.tonemap_unit.is_some() {
+                return self.create_finish_task();
+            }
+            return Task::Sleep;
+        }
+
         // If the last tonemapping time was more than x seconds ago,
         // an update should be done.
         let now = get_time();
@@ -159,7 +334,7 @@ impl TaskScheduler {
 
         // Then, if there are enough trace units available, go trace some rays!
         if !self.available_trace_units.is_empty() {
-            return self.create_trace_task();
+            return self.create_trace_task(worker);
         }
 
         // Otherwise, some trace units need to be plotted to make them
@@ -181,11 +356,18 @@ impl TaskScheduler {
         Task::Sleep
     }
 
-    fn create_trace_task(&mut self) -> Task {
-        // Pick the first available trace unit, and use it for the task.
+    fn create_trace_task(&mut self, worker: usize) -> Task {
+        // Prefer a trace unit that the worker traced before. If there is
+        // none, take the first available unit, rather than staying idle.
         // We know a unit is available, because this method would not
         // have been called otherwise.
-        let trace_unit = self.available_trace_units.pop_front().unwrap();
+        let workers = &self.trace_unit_workers;
+        let own = self.available_trace_units.iter()
+            .position(|unit| workers[unit.id] == Some(worker))
+            .unwrap_or(0);
+        let mut trace_unit = self.available_trace_units.remove(own).unwrap();
+        self.trace_unit_workers[trace_unit.id] = Some(worker);
+        trace_unit.importance_map = self.importance_map.clone();
         Task::Trace(trace_unit)
     }
 
@@ -227,26 +409,129 @@ impl TaskScheduler {
         Task::Tonemap(tonemap_unit, gather_unit)
     }
 
+    fn create_finish_task(&mut self) -> Task {
+        // We know the units are available, because this method would
+        // not have been called otherwise.
+        let gather_unit = self.gather_unit.take().unwrap();
+        let tonemap_unit = self.tonemap_unit.take().unwrap();
+        self.finished = true;
+
+        Task::Finish(tonemap_unit, gather_unit)
+    }
+
+    /// Takes back the units of a task that was not completed, because the
+    /// worker that executed it panicked. Whatever the task did not finish is
+    /// discarded, but the units can be used again, so that the render can
+    /// continue with another worker.
+    pub fn recover_task(&mut self, task: Task) {
+        match task {
+            Task::Sleep | Task::Exit => { },
+            Task::Trace(trace_unit) => {
+                // The unit will trace a full batch again when it is used.
+                self.available_trace_units.push_back(trace_unit);
+            },
+            Task::Plot(mut plot_unit, trace_units) => {
+                plot_unit.clear();
+                self.available_plot_units.push_back(plot_unit);
+                self.available_trace_units.extend(trace_units);
+            },
+            Task::Gather(gather_unit, plot_units) => {
+                // Plot units that were gathered already are clear.
+                for mut plot_unit in plot_units {
+                    plot_unit.clear();
+                    self.available_plot_units.push_back(plot_unit);
+                }
+                self.gather_unit = Some(gather_unit);
+            },
+            Task::Tonemap(tonemap_unit, gather_unit) => {
+                self.tonemap_unit = Some(tonemap_unit);
+                self.gather_unit = Some(gather_unit);
+            },
+            Task::Finish(tonemap_unit, gather_unit) => {
+                // The final image was not sent, so finish again.
+                self.tonemap_unit = Some(tonemap_unit);
+                self.gather_unit = Some(gather_unit);
+                self.finished = false;
+            }
+        }
+    }
+
+    /// Sends the current progress, if anybody is interested.
+    fn report_progress(&mut self) {
+        let progress = Progress {
+            batches_traced: self.batches_traced,
+            photons_traced: self.photons_traced,
+            photons_per_pixel: self.photons_traced as f32 / self.pixel_count as f32,
+            elapsed: get_time() - self.start_time
+        };
+        let disconnected = match self.progress_tx {
+            Some(ref tx) => tx.send(progress).is_err(),
+            None => false
+        };
+
+        // If the receiver is gone, stop reporting.
+        if disconnected { self.progress_tx = None; }
+    }
+
+    /// Returns whether one of the stop criteria is met.
+    fn should_stop(&self, gather_unit: &GatherUnit) -> bool {
+        let criteria = &self.stop_criteria;
+        let converged = match (criteria.max_relative_error, gather_unit.relative_error()) {
+            (Some(threshold), Some(error)) => error < threshold,
+            _ => false
+        };
+        let timed_out = match criteria.max_time {
+            Some(max_time) => get_time() - self.start_time >= max_time,
+            None => false
+        };
+        let sampled = match criteria.max_batches {
+            Some(max_batches) => self.batches_traced >= max_batches,
+            None => false
+        };
+        converged || timed_out || sampled || gather_unit.all_pixels_capped()
+    }
+
     /// Makes resources used by the task available again.
     fn complete_task(&mut self, task: Task) {
         match task {
-            Task::Sleep => { },
+            Task::Sleep | Task::Exit => { },
             Task::Trace(unit) => self.complete_trace_task(unit),
             Task::Plot(unit, units) => self.complete_plot_task(unit, units),
             Task::Gather(unit, units) => self.complete_gather_task(unit, units),
-            Task::Tonemap(t_unt, g_unt) => self.complete_tonemap_task(t_unt, g_unt)
+            Task::Tonemap(t_unt, g_unt) => self.complete_tonemap_task(t_unt, g_unt),
+            Task::Finish(t_unt, g_unt) => {
+                println!("done rendering");
+                self.gather_unit = Some(g_unt);
+                self.tonemap_unit = Some(t_unt);
+            }
         }
     }
 
     fn complete_trace_task(&mut self, trace_unit: Box<TraceUnit>) {
         println!("done tracing with unit {}", trace_unit.id);
 
-        // The trace unit used for the task, now needs plotting before
-        // it is available again.
-        self.done_trace_units.push_back(trace_unit);
+        let stats = trace_unit.statistics;
+        if stats.degenerate_rays > 0 {
+            println!("unit {} terminated {} paths with degenerate rays so far",
+                     trace_unit.id, stats.degenerate_rays);
+        }
+        if stats.clamped_probabilities > 0 {
+            println!("unit {} clamped {} out of range material probabilities so far",
+                     trace_unit.id, stats.clamped_probabilities);
+        }
+        if stats.cached_occlusions > 0 {
+            println!("unit {} skipped {} full shadow ray tests so far",
+                     trace_unit.id, stats.cached_occlusions);
+        }
 
         // Keep statatistics about performance.
         self.traces_completed += 1;
+        self.batches_traced += 1;
+        self.photons_traced += trace_unit.mapped_photons.len() as u64;
+
+        // The trace unit used for the task, now needs plotting before
+        // it is available again.
+        self.done_trace_units.push_back(trace_unit);
     }
 
     fn complete_plot_task(&mut self,
@@ -261,7 +546,7 @@ impl TaskScheduler {
             self.available_trace_units.push_back(trace_unit);
         }
 
-        println!("");
+        println!();
 
         // And the plot unit that was used, needs to be gathered before
         // it can be used again.
@@ -280,7 +565,30 @@ impl TaskScheduler {
             self.available_plot_units.push_back(plot_unit);
         }
 
-        println!("");
+        println!();
+
+        if let Some(error) = gather_unit.relative_error() {
+            println!("estimated relative error: {:.4}", error);
+        }
+        if let Some(noise) = gather_unit.rms_noise() {
+            println!("rms noise per batch: {:.6}", noise);
+        }
+        if let Some(difference) = gather_unit.half_difference() {
+            println!("relative difference between the half images: {:.4}", difference);
+        }
+
+        self.report_progress();
+
+        // Direct the next batches at the parts of the image that are noisiest
+        // now.
+        if let Some((tiles_x, tiles_y)) = self.adaptive_tiles {
+            self.importance_map = Some(gather_unit.importance_map(tiles_x, tiles_y));
+        }
+
+        if self.should_stop(&gather_unit) {
+            println!("stop criterion met, finishing the render");
+            self.stopping = true;
+        }
 
         // The gather unit can now be used again as well.
         self.gather_unit = Some(gather_unit);
@@ -325,3 +633,57 @@ impl TaskScheduler {
         println!("performance: {} +- {} batches/sec", mean, variance.sqrt());
     }
 }
+
+#[test]
+fn workers_keep_their_trace_units() {
+    // Two workers take turns, and complete their tasks instantly.
+    let mut ts = TaskScheduler::new_empty(2, 8, 8, 1024, None);
+    let mut tasks = [Task::Sleep, Task::Sleep];
+    let mut traced: Vec<Vec<usize>> = vec![Vec::new(), Vec::new()];
+    for step in 0 .. 200 {
+        let worker = step % 2;
+        let completed = ::std::mem::replace(&mut tasks[worker], Task::Sleep);
+        tasks[worker] = ts.get_new_task(worker, completed);
+        if let Task::Trace(ref unit) = tasks[worker] {
+            traced[worker].push(unit.id);
+        }
+    }
+
+    // Once every worker has units of its own, the units stay with it, so
+    // no unit is shared between the workers.
+    let steady: Vec<&[usize]> = traced.iter().map(|units| &units[units.len() / 2 ..]).collect();
+    for (units, steady_units) in traced.iter().zip(steady.iter()) {
+        assert!(steady_units.len() > 10);
+        assert!(steady_units.iter().all(|id| units[.. units.len() / 2].contains(id)));
+    }
+    assert!(steady[0].iter().all(|id| !steady[1].contains(id)));
+}
+
+#[test]
+fn recovered_units_are_used_again() {
+    // A worker that panics during any task returns its units. Without them,
+    // the render could never gather or tonemap again.
+    let mut ts = TaskScheduler::new_empty(1, 8, 8, 1024, None);
+    ts.stop_criteria.max_batches = Some(12);
+    let mut recovered = Vec::new();
+    let mut task = Task::Sleep;
+    for step in 0 .. {
+        assert!(step < 1000, "the render did not finish after recovering units");
+        task = ts.get_new_task(0, task);
+        let kind = match task {
+            Task::Sleep => continue,
+            Task::Trace(_) => "trace",
+            Task::Plot(..) => "plot",
+            Task::Gather(..) => "gather",
+            Task::Tonemap(..) => "tonemap",
+            Task::Finish(..) => "finish",
+            Task::Exit => break
+        };
+        if !recovered.contains(&kind) {
+            recovered.push(kind);
+            ts.recover_task(task);
+            task = Task::Sleep;
+        }
+    }
+    assert_eq!(recovered, ["trace", "plot", "gather", "finish"]);
+}
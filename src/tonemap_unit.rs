@@ -15,9 +15,156 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use std::cmp::PartialOrd;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::iter::repeat;
+use std::path::Path;
+use chroma_filter::ChromaFilter;
+use colour_map::ColourMap;
+use output::{ColourSpace, PixelFormat};
+use srgb::Encoding;
 use vector3::Vector3;
 
+/// A one-dimensional tone curve, sampled at evenly spaced inputs.
+/// Values in between are interpolated linearly.
+pub struct ToneCurve {
+    /// The linear input value that maps to the first entry.
+    domain_min: f32,
+
+    /// The linear input value that maps to the last entry.
+    domain_max: f32,
+
+    /// The output values of the curve.
+    entries: Vec<f32>
+}
+
+impl ToneCurve {
+    /// Creates a tone curve that maps the linear input range
+    /// [`domain_min`, `domain_max`] onto `entries`.
+    pub fn new(domain_min: f32, domain_max: f32, entries: Vec<f32>) -> ToneCurve {
+        assert!(entries.len() >= 2, "a tone curve needs at least two entries");
+        assert!(domain_min < domain_max, "the tone curve domain must not be empty");
+        ToneCurve {
+            domain_min: domain_min,
+            domain_max: domain_max,
+            entries: entries
+        }
+    }
+
+    /// Maps a linear value through the curve. Values outside of the domain
+    /// are clamped to it.
+    pub fn apply(&self, x: f32) -> f32 {
+        let last = self.entries.len() - 1;
+        let t = (x - self.domain_min) / (self.domain_max - self.domain_min);
+        let indexf = clamp(t) * last as f32;
+        let index = (indexf.floor() as usize).min(last - 1);
+        let remainder = indexf - index as f32;
+        self.entries[index] * (1.0 - remainder) + self.entries[index + 1] * remainder
+    }
+}
+
+/// A filmic tone curve after the one used in Uncharted 2, with a toe that
+/// lifts the shadows, a linear section, and a shoulder that rolls off the
+/// highlights. The defaults are the published parameters.
+pub struct FilmicCurve {
+    /// How strongly the highlights are compressed.
+    pub shoulder_strength: f32,
+
+    /// The slope of the linear section.
+    pub linear_strength: f32,
+
+    /// How abruptly the linear section transitions into the shoulder.
+    pub linear_angle: f32,
+
+    /// How strongly the shadows are lifted.
+    pub toe_strength: f32,
+
+    /// Together with the denominator, determines the angle of the toe.
+    pub toe_numerator: f32,
+
+    /// Together with the numerator, determines the angle of the toe.
+    pub toe_denominator: f32,
+
+    /// The input value that maps to white.
+    pub linear_white: f32,
+
+    /// The scale applied to exposure-corrected values before the curve.
+    pub exposure_bias: f32
+}
+
+impl FilmicCurve {
+    /// Creates a filmic curve with neutral default parameters.
+    pub fn new() -> FilmicCurve {
+        FilmicCurve {
+            shoulder_strength: 0.15,
+            linear_strength: 0.50,
+            linear_angle: 0.10,
+            toe_strength: 0.20,
+            toe_numerator: 0.02,
+            toe_denominator: 0.30,
+            linear_white: 11.2,
+            exposure_bias: 2.0
+        }
+    }
+
+    /// Evaluates the curve before normalisation to the white point.
+    fn evaluate(&self, x: f32) -> f32 {
+        let a = self.shoulder_strength;
+        let b = self.linear_strength;
+        let c = self.linear_angle;
+        let d = self.toe_strength;
+        let e = self.toe_numerator;
+        let f = self.toe_denominator;
+        (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+    }
+
+    /// Maps an exposure-corrected linear value to a linear display value,
+    /// where `linear_white` maps to 1.0.
+    pub fn apply(&self, x: f32) -> f32 {
+        let x = if x > 0.0 { x } else { 0.0 };
+        self.evaluate(x * self.exposure_bias) / self.evaluate(self.linear_white)
+    }
+}
+
+/// The fit of the ACES filmic curve by Krzysztof Narkowicz, which maps a
+/// linear value to a linear display value in [0, 1].
+pub fn aces_filmic(x: f32) -> f32 {
+    let x = if x > 0.0 { x } else { 0.0 };
+    clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14))
+}
+
+/// The mapping from scene intensities to display values.
+pub enum TonemapOperator {
+    /// A logarithmic curve, followed by the encoding of the tonemap unit.
+    Logarithmic,
+
+    /// A filmic curve applied per channel to exposure-corrected linear sRGB
+    /// values, followed by the encoding of the tonemap unit.
+    Filmic(FilmicCurve),
+
+    /// A custom curve that maps exposure-corrected linear sRGB values to
+    /// display values, applied per channel. No gamma correction is applied
+    /// afterwards; the curve should include it.
+    Curve(ToneCurve),
+
+    /// The global operator of Reinhard et al., which compresses the
+    /// exposure-corrected luminance L to L / (1 + L), so highlights roll off
+    /// softly instead of clipping. With a white point, luminances at or
+    /// above it map to white. It is followed by the encoding of the
+    /// tonemap unit.
+    Reinhard(Option<f32>),
+
+    /// The ACES filmic curve (see `aces_filmic`), applied per channel to
+    /// exposure-corrected linear sRGB values, followed by the encoding of
+    /// the tonemap unit.
+    Aces,
+
+    /// Maps the mean wavelength of every pixel through a colour map, for
+    /// scientific visualisation. This requires a buffer that was plotted
+    /// with `PlotMode::Wavelength`. Pixels without light remain black.
+    FalseColour(ColourMap)
+}
+
 /// Converts the result of a `GatherUnit` into an sRGB image.
 pub struct TonemapUnit {
     /// The width of the canvas (in pixels).
@@ -26,8 +173,33 @@ pub struct TonemapUnit {
     /// The height of the canvas (in pixels).
     image_height: u32,
 
+    /// The tonemapping operator to use.
+    pub operator: TonemapOperator,
+
+    /// A factor for the automatically determined exposure, 1.0 by default.
+    /// Every doubling makes the image one stop brighter.
+    pub exposure: f32,
+
+    /// The transfer function for the display, sRGB by default. For a plain
+    /// gamma curve, use `Encoding::Gamma`. It is not applied to custom
+    /// curves, which include their own encoding.
+    pub encoding: Encoding,
+
+    /// An optional filter that reduces colour noise before tonemapping.
+    pub chroma_filter: Option<ChromaFilter>,
+
+    /// The colour temperature in Kelvin of the light that should appear
+    /// white, if any. The image is adapted from daylight of that temperature
+    /// to the D65 white point of the display, so a render under a warm light
+    /// does not look tinted. 6504 K changes nothing. It does not apply to
+    /// false colour images.
+    pub white_balance: Option<f32>,
+
     /// The buffer of sRGB values.
-    pub rgb_buffer: Vec<u8>
+    pub rgb_buffer: Vec<u8>,
+
+    /// The display values of `rgb_buffer` before quantisation, in [0, 1].
+    rgb_values: Vec<f32>
 }
 
 /// Clamps `x` to the interval [0, 1].
@@ -45,8 +217,35 @@ impl TonemapUnit {
         TonemapUnit {
             image_width: width,
             image_height: height,
-            rgb_buffer: repeat(0).take(sz * 3).collect()
+            operator: TonemapOperator::Logarithmic,
+            exposure: 1.0,
+            encoding: Encoding::Srgb,
+            chroma_filter: None,
+            white_balance: None,
+            rgb_buffer: repeat(0).take(sz * 3).collect(),
+            rgb_values: repeat(0.0).take(sz * 3).collect()
+        }
+    }
+
+    /// Writes the most recently tonemapped image to a PNG file in the
+    /// specified format. An 8-bit RGB image is written exactly as it was
+    /// displayed; other formats are encoded from the unquantised values.
+    /// With a gamma of 1.0, the image is tagged as linear.
+    pub fn write_png<P: AsRef<Path>>(&self, path: P, format: PixelFormat) -> io::Result<()> {
+        let colour_space = if self.encoding == Encoding::Gamma(1.0) {
+            ColourSpace::LinearSrgb
+        } else {
+            ColourSpace::Srgb
+        };
+        if format == ::output::RGB8 {
+            return ::output::save_png(path, &self.rgb_buffer, self.image_width,
+                                      self.image_height, colour_space);
         }
+        let file = try!(File::create(path));
+        let mut writer = BufWriter::new(file);
+        try!(::output::write_png_with_format(&mut writer, &self.rgb_values, self.image_width,
+                                             self.image_height, format, colour_space));
+        writer.flush()
     }
 
     /// Returns an exposure estimate based on the average cieY value.
@@ -71,27 +270,106 @@ impl TonemapUnit {
     /// Converts the unweighted CIE XYZ values in the buffer
     /// to tonemapped sRGB values.
     pub fn tonemap(&mut self, tristimuli: &[Vector3]) {
-        let max_intensity = self.find_exposure(tristimuli);
-        let buffer = (&mut self.rgb_buffer).chunks_mut(3);
+        // Filter a copy of the buffer, the gathered values must not change.
+        let filtered;
+        let tristimuli = match self.chroma_filter {
+            Some(ref filter) => {
+                filtered = filter.apply(tristimuli, self.image_width, self.image_height);
+                &filtered[..]
+            },
+            None => tristimuli
+        };
+
+        // The false colour operator needs the values as they were plotted.
+        let adaptation = match self.operator {
+            TonemapOperator::FalseColour(_) => None,
+            _ => self.white_balance.map(::white_balance::white_balance)
+        };
+        let adapted: Vec<Vector3>;
+        let tristimuli = match adaptation {
+            Some(ref m) => {
+                adapted = tristimuli.iter()
+                                    .map(|&cie| ::white_balance::transform(m, cie))
+                                    .collect();
+                &adapted[..]
+            },
+            None => tristimuli
+        };
+
+        let max_intensity = self.find_exposure(tristimuli) / self.exposure;
+        let buffer = self.rgb_buffer.chunks_mut(3).zip(self.rgb_values.chunks_mut(3));
         let ln_4 = 4.0f32.ln();
 
         // Loop through all pixels.
-        for (px, cie) in buffer.zip(tristimuli.iter()) {
-            // Apply exposure correction.
-            let cie = Vector3 {
-                x: (cie.x / max_intensity + 1.0).ln() / ln_4,
-                y: (cie.y / max_intensity + 1.0).ln() / ln_4,
-                z: (cie.z / max_intensity + 1.0).ln() / ln_4
-            };
+        for ((px, values), cie) in buffer.zip(tristimuli.iter()) {
+            let rgb = match self.operator {
+                TonemapOperator::Logarithmic => {
+                    // Apply exposure correction.
+                    let cie = Vector3 {
+                        x: (cie.x / max_intensity + 1.0).ln() / ln_4,
+                        y: (cie.y / max_intensity + 1.0).ln() / ln_4,
+                        z: (cie.z / max_intensity + 1.0).ln() / ln_4
+                    };
 
-            // Then convert to sRGB.
-            let rgb = ::srgb::transform(cie);
+                    // Then convert to sRGB.
+                    self.encoding.encode_rgb(::srgb::transform_linear(cie))
+                },
+                TonemapOperator::Filmic(ref curve) => {
+                    let rgb = ::srgb::transform_linear(*cie * (1.0 / max_intensity));
+                    self.encoding.encode_rgb(Vector3 {
+                        x: curve.apply(rgb.x),
+                        y: curve.apply(rgb.y),
+                        z: curve.apply(rgb.z)
+                    })
+                },
+                TonemapOperator::Curve(ref curve) => {
+                    // Apply exposure correction and convert to linear sRGB,
+                    // then map every channel through the curve.
+                    let rgb = ::srgb::transform_linear(*cie * (1.0 / max_intensity));
+                    Vector3 {
+                        x: curve.apply(rgb.x),
+                        y: curve.apply(rgb.y),
+                        z: curve.apply(rgb.z)
+                    }
+                },
+                TonemapOperator::Reinhard(white) => {
+                    // Scale the colour, so that only the luminance changes.
+                    let l = cie.y / max_intensity;
+                    let l_out = match white {
+                        Some(w) => l * (1.0 + l / (w * w)) / (1.0 + l),
+                        None => l / (1.0 + l)
+                    };
+                    let scale = if l > 0.0 { l_out / l } else { 0.0 };
+                    let rgb = ::srgb::transform_linear(*cie * (scale / max_intensity));
+                    self.encoding.encode_rgb(rgb)
+                },
+                TonemapOperator::Aces => {
+                    let rgb = ::srgb::transform_linear(*cie * (1.0 / max_intensity));
+                    self.encoding.encode_rgb(Vector3 {
+                        x: aces_filmic(rgb.x),
+                        y: aces_filmic(rgb.y),
+                        z: aces_filmic(rgb.z)
+                    })
+                },
+                TonemapOperator::FalseColour(colour_map) => {
+                    // The colour map is already encoded for display.
+                    if cie.y > 0.0 {
+                        colour_map.map_wavelength(cie.x / cie.y)
+                    } else {
+                        Vector3::zero()
+                    }
+                }
+            };
 
             // Clamp colours to saturate.
             let r = clamp(rgb.x);
             let g = clamp(rgb.y);
             let b = clamp(rgb.z);
 
+            values[0] = r;
+            values[1] = g;
+            values[2] = b;
+
             // Then convert to integers.
             px[0] = (r * 255.0) as u8;
             px[1] = (g * 255.0) as u8;
@@ -99,3 +377,237 @@ impl TonemapUnit {
         }
     }
 }
+
+#[test]
+fn tone_curve_identity() {
+    let curve = ToneCurve::new(0.0, 1.0, vec![0.0, 0.25, 0.5, 0.75, 1.0]);
+    for i in 0 .. 101 {
+        let x = i as f32 / 100.0;
+        assert!((curve.apply(x) - x).abs() < 1.0e-6);
+    }
+}
+
+#[test]
+fn tone_curve_gamma() {
+    let n = 256;
+    let entries = (0 .. n).map(|i| (i as f32 / (n - 1) as f32).powf(1.0 / 2.2)).collect();
+    let curve = ToneCurve::new(0.0, 1.0, entries);
+    for i in 0 .. 100 {
+        // Start a bit above zero, where the curve is steep.
+        let x = 0.02 + i as f32 / 102.0;
+        assert!((curve.apply(x) - x.powf(1.0 / 2.2)).abs() < 1.0e-3);
+    }
+}
+
+#[test]
+fn filmic_shoulder_compresses_highlights() {
+    let mut curve = FilmicCurve::new();
+    let mut last_highlight_contrast = 1.0e6;
+    for &strength in [0.15f32, 0.3, 0.6].iter() {
+        curve.shoulder_strength = strength;
+
+        // Midtones stay close to linear: one stop brighter is about twice
+        // the output.
+        let midtone_contrast = curve.apply(0.18) / curve.apply(0.09);
+        assert!((midtone_contrast - 2.0).abs() < 0.2);
+
+        // The two stops from 1 to 4 get closer together as the shoulder
+        // strengthens.
+        let highlight_contrast = curve.apply(4.0) / curve.apply(1.0);
+        assert!(highlight_contrast < last_highlight_contrast);
+        last_highlight_contrast = highlight_contrast;
+    }
+
+    assert!((curve.apply(curve.linear_white / curve.exposure_bias) - 1.0).abs() < 1.0e-5);
+}
+
+#[test]
+fn monochromatic_lines_round_trip() {
+    use plot_unit::PlotUnit;
+    use trace_unit::MappedPhoton;
+
+    // Linear sRGB of monochromatic lines, normalised to a luminance of 1,
+    // computed with the reference CIE 1931 colour matching functions and
+    // the IEC 61966-2-1 matrix.
+    let lines = [(550.0f32, [-0.130f32, 1.454, -0.170]),
+                 (610.0, [4.922, -0.056, -0.092]),
+                 (500.0, [-1.908, 1.896, 0.687])];
+
+    for &(wavelength, expected) in lines.iter() {
+        let mut plot_unit = PlotUnit::new(0, 1, 1);
+        plot_unit.plot(&[MappedPhoton {
+            x: 0.0,
+            y: 0.0,
+            probability: 1.0,
+            wavelength: wavelength
+        }]);
+
+        // For a single pixel, the exposure normalises the luminance to 1.
+        // Map [-6, 6] linearly onto the output range, so that out of gamut
+        // values are not clipped.
+        let mut tonemap_unit = TonemapUnit::new(1, 1);
+        tonemap_unit.operator = TonemapOperator::Curve(ToneCurve::new(-6.0, 6.0, vec![0.0, 1.0]));
+        tonemap_unit.tonemap(&plot_unit.tristimulus_buffer);
+
+        for (c, &expected) in expected.iter().enumerate() {
+            let linear = tonemap_unit.rgb_buffer[c] as f32 / 255.0 * 12.0 - 6.0;
+            assert!((linear - expected).abs() < 0.1,
+                    "{} nm, channel {}: {} instead of {}", wavelength, c, linear, expected);
+        }
+    }
+}
+
+#[test]
+fn false_colour_maps_mean_wavelength() {
+    use plot_unit::{PlotMode, PlotUnit};
+    use trace_unit::MappedPhoton;
+
+    // A pixel dominated by 580 nm, with a little light on either side.
+    let photon = |wavelength: f32, probability: f32| MappedPhoton {
+        x: 0.0,
+        y: 0.0,
+        probability: probability,
+        wavelength: wavelength
+    };
+    let mut plot_unit = PlotUnit::new(0, 1, 1);
+    plot_unit.mode = PlotMode::Wavelength;
+    plot_unit.plot(&[photon(580.0, 5.0), photon(560.0, 0.5), photon(600.0, 0.5)]);
+
+    for &colour_map in [ColourMap::Viridis, ColourMap::Turbo].iter() {
+        let mut tonemap_unit = TonemapUnit::new(1, 1);
+        tonemap_unit.operator = TonemapOperator::FalseColour(colour_map);
+        tonemap_unit.tonemap(&plot_unit.tristimulus_buffer);
+
+        // 580 nm is halfway the visible range.
+        let expected = colour_map.map(0.5);
+        let expected = [expected.x, expected.y, expected.z];
+        for (c, &expected) in expected.iter().enumerate() {
+            let actual = tonemap_unit.rgb_buffer[c] as f32 / 255.0;
+            assert!((actual - expected).abs() < 2.0 / 255.0);
+        }
+    }
+}
+
+#[test]
+fn reinhard_compresses_highlights() {
+    // One bright pixel of D65 white among dimmer ones.
+    let white = Vector3::new(0.9505, 1.0, 1.0890);
+    let mut tristimuli = vec![white; 16];
+    tristimuli[5] = white * 100.0;
+
+    let bright_pixel = |operator: TonemapOperator| {
+        let mut tonemap_unit = TonemapUnit::new(4, 4);
+        tonemap_unit.operator = operator;
+        tonemap_unit.tonemap(&tristimuli);
+        [tonemap_unit.rgb_buffer[15], tonemap_unit.rgb_buffer[16], tonemap_unit.rgb_buffer[17]]
+    };
+
+    // The default operator clips the highlight, Reinhard does not.
+    assert_eq!(bright_pixel(TonemapOperator::Logarithmic)[0], 255);
+    let reinhard = bright_pixel(TonemapOperator::Reinhard(None));
+    assert!(reinhard.iter().all(|&c| c > 128 && c < 250), "{:?}", reinhard);
+
+    // With a white point below the luminance, it does clip.
+    assert_eq!(bright_pixel(TonemapOperator::Reinhard(Some(1.0))), [255, 255, 255]);
+}
+
+#[test]
+fn aces_filmic_matches_reference() {
+    // Mid grey maps to about 0.267, and the curve saturates to 1.
+    assert!((aces_filmic(0.18) - 0.2669).abs() < 1.0e-3);
+    assert!((aces_filmic(1.0) - 0.8038).abs() < 1.0e-3);
+    assert_eq!(aces_filmic(0.0), 0.0);
+    assert_eq!(aces_filmic(100.0), 1.0);
+}
+
+#[test]
+fn exposure_scales_linearly() {
+    // Half of the pixels are white, and half are at 20% of it, so the
+    // automatic exposure maps white to 1.0.
+    let white = Vector3::new(0.9505, 1.0, 1.0890);
+    let tristimuli: Vec<Vector3> = (0 .. 16).map(|i| {
+        if i % 2 == 0 { white } else { white * 0.2 }
+    }).collect();
+
+    let mid_tone = |exposure: f32| {
+        let mut tonemap_unit = TonemapUnit::new(4, 4);
+        tonemap_unit.operator = TonemapOperator::Curve(ToneCurve::new(0.0, 1.0, vec![0.0, 1.0]));
+        tonemap_unit.exposure = exposure;
+        tonemap_unit.tonemap(&tristimuli);
+        tonemap_unit.rgb_buffer[4] as f32
+    };
+
+    let ratio = mid_tone(2.0) / mid_tone(1.0);
+    assert!((ratio - 2.0).abs() < 0.05, "ratio is {}", ratio);
+}
+
+#[test]
+fn gamma_one_is_linear() {
+    // A single pixel is normalised to a luminance of 1, and Reinhard maps
+    // that to 0.5.
+    let white = Vector3::new(0.9505, 1.0, 1.0890);
+    let encoded = |gamma: f32| {
+        let mut tonemap_unit = TonemapUnit::new(1, 1);
+        tonemap_unit.operator = TonemapOperator::Reinhard(None);
+        tonemap_unit.encoding = Encoding::Gamma(gamma);
+        tonemap_unit.tonemap(&[white]);
+        tonemap_unit.rgb_buffer[1] as f32
+    };
+
+    assert!((encoded(1.0) - 127.5).abs() <= 1.0);
+    assert!((encoded(2.2) - 0.5f32.powf(1.0 / 2.2) * 255.0).abs() <= 1.0);
+}
+
+#[test]
+fn write_png_round_trips() {
+    use std::env;
+    use std::fs;
+    use image;
+
+    // Use the default size, with a pattern that differs per row and column,
+    // so that a wrong row stride would show.
+    let (width, height) = (1280, 720);
+    let mut tonemap_unit = TonemapUnit::new(width, height);
+    for (i, px) in tonemap_unit.rgb_buffer.chunks_mut(3).enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        px[0] = x as u8;
+        px[1] = y as u8;
+        px[2] = (x / 256 + y / 256 * 8) as u8;
+    }
+
+    let path = env::temp_dir().join(format!("robigo-tonemap-{}.png", ::rand::random::<u32>()));
+    tonemap_unit.write_png(&path, ::output::RGB8).unwrap();
+    let img = image::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(img.raw_pixels() == tonemap_unit.rgb_buffer);
+}
+
+#[test]
+fn white_balance_neutralises_warm_light() {
+    use white_balance::{D65_TEMPERATURE, daylight_white};
+
+    let tonemap = |cie: Vector3, white_balance: Option<f32>| {
+        let mut tonemap_unit = TonemapUnit::new(1, 1);
+        tonemap_unit.operator = TonemapOperator::Curve(ToneCurve::new(0.0, 2.0, vec![0.0, 1.0]));
+        tonemap_unit.white_balance = white_balance;
+        tonemap_unit.tonemap(&[cie]);
+        tonemap_unit.rgb_buffer.clone()
+    };
+
+    // A neutral surface under warm light renders orange, unless the white
+    // balance matches the light.
+    let warm = daylight_white(5000.0);
+    let unbalanced = tonemap(warm, None);
+    assert!(unbalanced[0] > unbalanced[2] + 10);
+    let balanced = tonemap(warm, Some(5000.0));
+    assert!((balanced[0] as i32 - balanced[2] as i32).abs() <= 1, "{:?}", balanced);
+
+    // Balancing for D65 is a no-op.
+    let d65 = daylight_white(D65_TEMPERATURE);
+    let unchanged = |a: Vec<u8>, b: Vec<u8>| {
+        a.iter().zip(b.iter()).all(|(&p, &q)| (p as i32 - q as i32).abs() <= 1)
+    };
+    assert!(unchanged(tonemap(warm, Some(D65_TEMPERATURE)), unbalanced));
+    assert!(unchanged(tonemap(d65, Some(D65_TEMPERATURE)), tonemap(d65, None)));
+}
@@ -14,10 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use std::f32::EPSILON;
+use std::f32::consts::PI;
 use std::iter::repeat;
+use rand;
+use environment::Environment;
+use importance_map::ImportanceMap;
+use intersection::Intersection;
+use material::{Material, Shading};
+use monte_carlo::{MonteCarlo, WavelengthDistribution};
 use object::MaterialBox::{Emissive, Reflective};
+use object::{Object, Sidedness};
 use ray::Ray;
 use scene::Scene;
+use vector3::{Vector3, dot};
 
 /// Represents a photon that has been traced.
 #[derive(Copy, Clone)]
@@ -47,38 +57,473 @@ impl MappedPhoton {
     }
 }
 
+/// The result of tracing a single path at one wavelength.
+pub struct SpectralSample {
+    /// The wavelength of the path (in nm).
+    pub wavelength: f32,
+
+    /// The contribution of the path at this wavelength.
+    pub contribution: f32,
+
+    /// The direction of the last segment of the path, towards the light
+    /// that ended it, or into the void.
+    pub final_direction: Vector3
+}
+
+/// An interaction of a path with the scene.
+#[derive(Copy, Clone)]
+pub struct PathVertex {
+    /// The position of the intersection.
+    pub position: Vector3,
+
+    /// The surface normal at the intersection, as seen by the material.
+    pub normal: Vector3,
+
+    /// The index of the object that was hit in the objects of the scene.
+    pub object: usize,
+
+    /// Whether the object is emissive. If so, this is the last vertex.
+    pub emissive: bool,
+
+    /// The direction in which the path continues, for reflective objects
+    /// that did not absorb the path.
+    pub outgoing_direction: Option<Vector3>
+}
+
+/// The full history of a single camera path, for debugging.
+pub struct DebugPath {
+    /// The contribution of the path.
+    pub contribution: f32,
+
+    /// The direction of the camera ray that started the path.
+    pub camera_direction: Vector3,
+
+    /// All interactions of the path with the scene, in order.
+    pub vertices: Vec<PathVertex>
+}
+
+/// Counts unusual events during tracing, for diagnostics.
+#[derive(Copy, Clone, Default)]
+pub struct TraceStatistics {
+    /// The number of paths that were terminated because a camera or
+    /// material produced a ray with a degenerate direction.
+    pub degenerate_rays: u32,
+
+    /// The number of times that a material returned a probability outside
+    /// of the range [0, 1], which was then clamped.
+    pub clamped_probabilities: u32,
+
+    /// The number of shadow rays that were found to be blocked by the cached
+    /// occluder of the light, without testing the full scene.
+    pub cached_occlusions: u32
+}
+
+/// The number of bounces that a path of the `Path` integrator makes before
+/// Russian roulette can end it.
+const ROULETTE_DEPTH: u32 = 3;
+
+/// How a trace unit estimates the light that arrives along a camera ray.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Integrator {
+    /// Full path tracing. Russian roulette may end a path once it made
+    /// `ROULETTE_DEPTH` bounces. This is the default.
+    Path,
+
+    /// Only the light that reaches the first surface directly from a light
+    /// source, and light sources that are seen directly. Paths make a single
+    /// bounce, so there is no need for Russian roulette.
+    Direct,
+
+    /// Ambient occlusion: the fraction of the cosine-weighted hemisphere
+    /// above the first surface that is not occluded within the distance.
+    /// Only one ray is cast after the camera ray, so it never roulettes.
+    AmbientOcclusion(f32)
+}
+
+impl Integrator {
+    /// Returns the number of bounces after which Russian roulette may end a
+    /// path, or `None` if the integrator never ends paths by chance.
+    pub fn roulette_depth(&self) -> Option<u32> {
+        match *self {
+            Integrator::Path => Some(ROULETTE_DEPTH),
+            Integrator::Direct | Integrator::AmbientOcclusion(_) => None
+        }
+    }
+
+    /// Returns the settings for the paths of the integrator, given the
+    /// settings of the trace unit. The direct integrator always samples the
+    /// lights, and stops after the first bounce.
+    fn path_settings(self, direct_light: bool, max_depth: Option<u32>) -> PathSettings {
+        let (direct_light, max_depth) = match self {
+            Integrator::Direct => (true, Some(1)),
+            _ => (direct_light, max_depth)
+        };
+        PathSettings {
+            direct_light: direct_light,
+            max_depth: max_depth,
+            integrator: self
+        }
+    }
+}
+
+/// Settings that determine how a path is traced.
+#[derive(Copy, Clone)]
+struct PathSettings {
+    /// Whether lights are sampled directly at diffuse bounces.
+    direct_light: bool,
+
+    /// The maximum number of bounces of a path, if any.
+    max_depth: Option<u32>,
+
+    /// The integrator that the settings are for, which determines when
+    /// Russian roulette may end the path.
+    integrator: Integrator
+}
+
+impl PathSettings {
+    /// Returns the default settings of the path integrator: with direct
+    /// light sampling, and without a maximum depth.
+    fn new() -> PathSettings {
+        Integrator::Path.path_settings(true, None)
+    }
+}
+
+/// Samples the lights of a scene directly at diffuse bounces. Shadow rays
+/// towards a light from nearby points are often blocked by the same object,
+/// so the object that blocked the last shadow ray towards every light is
+/// tested first, before the full scene.
+struct LightSampler<'a> {
+    /// The lights that can be sampled directly, with their area and the
+    /// probability of sampling them, as returned by `Scene::sampled_lights`.
+    lights: Vec<(&'a Object, f32, f32)>,
+
+    /// The object that blocked the last shadow ray towards every light.
+    occluders: Vec<Option<&'a Object>>
+}
+
+impl<'a> LightSampler<'a> {
+    /// Creates a sampler for the lights of `scene`, or one without lights
+    /// if lights should not be sampled directly.
+    fn new(scene: &'a Scene, enabled: bool) -> LightSampler<'a> {
+        let lights = if enabled { scene.sampled_lights() } else { Vec::new() };
+        LightSampler {
+            occluders: lights.iter().map(|_| None).collect(),
+            lights: lights
+        }
+    }
+
+    /// Returns whether `object` is one of the lights that are sampled.
+    fn contains(&self, object: &Object) -> bool {
+        self.lights.iter().any(|&(light, _, _)| ::std::ptr::eq(light, object))
+    }
+
+    /// Returns whether the `light` is visible along the shadow ray, up to
+    /// the sampled point at `distance` (not at the near side of the light).
+    fn is_visible(&mut self, scene: &'a Scene, light: usize, shadow_ray: &Ray,
+                  distance: f32, stats: &mut TraceStatistics) -> bool {
+        let blocks = |isect: &Intersection| isect.distance <= distance * 0.999 - 0.0001;
+
+        // The cached occluder can only prove that the light is blocked. If it
+        // does not block the ray, something else still might.
+        if let Some(occluder) = self.occluders[light] {
+            if occluder.surface.intersect(shadow_ray).map_or(false, |isect| blocks(&isect)) {
+                stats.cached_occlusions += 1;
+                return false;
+            }
+        }
+
+        let light_object = self.lights[light].0;
+        match scene.intersect(shadow_ray) {
+            Some((isect, obj)) => {
+                if ::std::ptr::eq(obj, light_object) {
+                    !blocks(&isect)
+                } else {
+                    self.occluders[light] = Some(obj);
+                    false
+                }
+            },
+            None => false
+        }
+    }
+
+    /// Samples a point on one of the lights, and returns the light that
+    /// arrives from there at the diffuse intersection, times the cosine at
+    /// the intersection, divided by pi and by the probability of sampling
+    /// that point. Multiplied by the reflectance, this is the reflected light.
+    fn sample(&mut self, scene: &'a Scene, ray: &Ray, intersection: &Intersection,
+              stats: &mut TraceStatistics) -> f32 {
+        // Pick a light with its probability, and a point uniformly on its
+        // surface.
+        let index = ::scene::pick_light(&self.lights, ::monte_carlo::get_unit());
+        let (light, area, probability) = self.lights[index];
+        let (point, light_normal) = match light.surface.sample_point() {
+            Some(x) => x,
+            None => return 0.0
+        };
+
+        // The light must be on the side of the surface where the ray came from.
+        let normal = if dot(ray.direction, intersection.normal) < 0.0 {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+        let to_light = point - intersection.position;
+        let distance_sqr = to_light.magnitude_squared();
+        let distance = distance_sqr.sqrt();
+        let direction = to_light * (1.0 / distance);
+        let cos_surface = dot(direction, normal);
+        let cos_light = dot(direction, light_normal).abs();
+        if cos_surface <= 0.0 || distance == 0.0 { return 0.0; }
+
+        // The point is visible if the first thing that a shadow ray hits is
+        // the light, at the sampled point.
+        let shadow_ray = Ray {
+            origin: intersection.position + direction * 0.00001,
+            direction: direction,
+            wavelength: ray.wavelength,
+            probability: 1.0
+        };
+        if !self.is_visible(scene, index, &shadow_ray, distance, stats) { return 0.0; }
+
+        let intensity = match light.material {
+            Emissive(ref mat) => mat.get_intensity(ray.wavelength),
+            Reflective(_) => 0.0
+        };
+
+        // Convert the probability per area into one per solid angle.
+        let pdf = distance_sqr / (cos_light * area) * probability;
+        intensity * cos_surface / (PI * pdf)
+    }
+}
+
+/// How a trace unit distributes the photons of a batch over the screen.
+#[derive(Copy, Clone, PartialEq)]
+pub enum ScreenSampling {
+    /// The screen and lens coordinates are points of a four-dimensional
+    /// Halton sequence, so they are spread evenly over screen and lens
+    /// jointly. This is the default.
+    Halton,
+
+    /// The screen is divided into a grid with about as many cells as there
+    /// are photons in a batch, and every photon is placed at a random point
+    /// in its own cell. Photons that do not fit in the grid are placed
+    /// uniformly at random.
+    Jittered
+}
+
+/// Returns the number of columns and rows of a grid of at most `n` cells,
+/// with cells that are nearly square on a screen with the specified aspect
+/// ratio.
+fn jitter_grid(n: usize, aspect_ratio: f32) -> (usize, usize) {
+    let rows = ((n as f32 / aspect_ratio).sqrt().floor() as usize).max(1);
+    let columns = (n / rows).max(1);
+    (columns, rows)
+}
+
+/// Returns a point strictly inside the unit square, in the cell of the grid
+/// that belongs to photon `i`, or anywhere if the photon is outside of the
+/// grid.
+fn jittered_sample(i: usize, columns: usize, rows: usize) -> (f32, f32) {
+    let (u, v) = (::monte_carlo::get_open_unit(), ::monte_carlo::get_open_unit());
+    if i >= columns * rows { return (u, v); }
+    let (column, row) = (i % columns, i / columns);
+
+    // Rounding to single precision could reach the edge of the last cell.
+    let x = (column as f32 + u) / columns as f32;
+    let y = (row as f32 + v) / rows as f32;
+    (x.max(EPSILON).min(1.0 - EPSILON), y.max(EPSILON).min(1.0 - EPSILON))
+}
+
+/// Returns the `k`-th of `n` wavelengths that are spread evenly over the
+/// visible range, starting at the hero `wavelength` and wrapping around.
+/// When the hero is distributed uniformly, so is every other wavelength.
+fn rotate_wavelength(wavelength: f32, k: usize, n: usize) -> f32 {
+    let wavelength = wavelength + k as f32 * 400.0 / n as f32;
+    if wavelength > 780.0 { wavelength - 400.0 } else { wavelength }
+}
+
 /// Handles ray tracing.
 pub struct TraceUnit {
     /// The aspect ratio of the image that will be rendered.
     aspect_ratio: f32,
 
+    /// The aspect ratio that the camera is intended for. If set and different
+    /// from the aspect ratio of the image, the image is letterboxed (or
+    /// pillarboxed) with black bars, instead of showing more (or less) of the
+    /// scene than intended.
+    pub letterbox_aspect_ratio: Option<f32>,
+
+    /// The time at which the frame starts, 0.0 by default. The shutter times
+    /// are relative to it. When rendering an animation, every frame starts
+    /// at a later time.
+    pub frame_time: f32,
+
+    /// The time at which the shutter opens, in the range 0.0 - 1.0 of the
+    /// time passed to `get_camera_at_time`, after the start of the frame.
+    pub shutter_open: f32,
+
+    /// The time at which the shutter closes. A longer exposure results in
+    /// more motion blur; if it is equal to `shutter_open`, motion freezes.
+    pub shutter_close: f32,
+
+    /// Whether light sources are sampled directly at diffuse bounces, which
+    /// reduces noise for small lights a lot. It is on by default.
+    pub direct_light_sampling: bool,
+
+    /// The maximum number of bounces of a path. A path that hits another
+    /// reflective surface after that many bounces contributes nothing more.
+    /// Russian roulette may still end paths earlier. There is no maximum by
+    /// default.
+    pub max_depth: Option<u32>,
+
+    /// How the light along camera rays is estimated, with full path tracing
+    /// by default.
+    pub integrator: Integrator,
+
+    /// How the photons are distributed over the screen, with a Halton
+    /// sequence by default.
+    pub screen_sampling: ScreenSampling,
+
+    /// The distribution to sample wavelengths from, if they should not be
+    /// sampled uniformly. See `guide_wavelengths`.
+    pub wavelength_distribution: Option<WavelengthDistribution>,
+
+    /// The number of wavelengths traced per path. See `set_spectral_samples`.
+    spectral_samples: usize,
+
+    /// The distribution of photons over the screen, if they should not be
+    /// spread evenly. With a map built from the noise in the image gathered
+    /// so far, noisy regions receive more photons than converged ones.
+    pub importance_map: Option<ImportanceMap>,
+
     /// The photons that were rendered.
     pub mapped_photons: Vec<MappedPhoton>,
 
+    /// The seed for the frame that is being rendered. When rendering an
+    /// animation, every frame should have a different seed, otherwise the
+    /// noise would stay in place. For a still image it stays fixed.
+    pub frame_seed: u32,
+
+    /// The number of batches rendered for the frame so far, so that every
+    /// batch samples different paths.
+    batches_rendered: u32,
+
+    /// Statistics about the paths traced by this unit.
+    pub statistics: TraceStatistics,
+
     /// An ID for identifying this unit in the UI.
     pub id: usize
 }
 
 impl TraceUnit {
-    /// Creates a new trace unit that renders the given scene.
-    pub fn new(id: usize, width: u32, height: u32) -> TraceUnit {
-        // The number of photons to trace in one batch.
-        #[cfg(not(test))]
-        const NUMBER_OF_PHOTONS: usize = 1024 * 512;
-
-        #[cfg(test)]
-        const NUMBER_OF_PHOTONS: usize = 1024;
-
+    /// Creates a new trace unit for an image of the specified size, that
+    /// traces `batch_size` photons per batch.
+    pub fn new(id: usize, width: u32, height: u32, batch_size: usize) -> TraceUnit {
         TraceUnit {
-            aspect_ratio: width as f32 / height as f32,
-            mapped_photons: repeat(MappedPhoton::new()).take(NUMBER_OF_PHOTONS).collect(),
+            aspect_ratio: ::screen::aspect_ratio(width, height),
+            letterbox_aspect_ratio: None,
+            frame_time: 0.0,
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            direct_light_sampling: true,
+            max_depth: None,
+            integrator: Integrator::Path,
+            screen_sampling: ScreenSampling::Halton,
+            wavelength_distribution: None,
+            spectral_samples: 1,
+            importance_map: None,
+            mapped_photons: repeat(MappedPhoton::new()).take(batch_size).collect(),
+            // A random seed by default, so a resumed render does not repeat
+            // the samples of the previous run.
+            frame_seed: rand::random(),
+            batches_rendered: 0,
+            statistics: TraceStatistics::default(),
             id: id
         }
     }
 
+    /// Samples wavelengths in proportion to the importance of `material`,
+    /// for scenes that are dominated by a saturated material. This reduces
+    /// colour noise on that material. If the material has no importance
+    /// function, wavelengths are sampled uniformly.
+    pub fn guide_wavelengths(&mut self, material: &Material) {
+        self.wavelength_distribution = material.get_wavelength_importance(550.0).map(|_| {
+            WavelengthDistribution::new(|w| material.get_wavelength_importance(w).unwrap_or(0.0))
+        });
+    }
+
+    /// Traces `n` wavelengths for every path, instead of one. The first is
+    /// sampled as usual, and the others are spread evenly over the rest of
+    /// the spectrum, so every batch of `n` photons covers all colours. This
+    /// reduces colour noise at the cost of tracing `n` photons per path.
+    /// The number of paths per batch stays the same, so a batch has `n`
+    /// times as many photons. There must be at least one wavelength.
+    pub fn set_spectral_samples(&mut self, n: usize) {
+        assert!(n > 0, "a path must have at least one wavelength");
+        let paths = self.mapped_photons.len() / self.spectral_samples;
+        self.mapped_photons = repeat(MappedPhoton::new()).take(paths * n).collect();
+        self.spectral_samples = n;
+    }
+
     /// Return the contribution of a photon travelling backwards
-    /// the specified ray.
-    fn render_ray(scene: &Scene, initial_ray: Ray) -> f32 {
+    /// the specified ray, and count its events in `stats`.
+    #[cfg(test)]
+    fn render_ray(scene: &Scene, initial_ray: Ray, stats: &mut TraceStatistics) -> f32 {
+        let mut lights = LightSampler::new(scene, true);
+        let (contribution, _) = TraceUnit::trace_path(scene, initial_ray, None, None,
+                                                      PathSettings::new(), &mut lights, stats);
+        contribution
+    }
+
+    /// Traces a single ray, and returns its contribution together with its
+    /// wavelength and the direction in which the path ended. This is meant
+    /// for verifying the spectral behaviour of specific paths.
+    pub fn trace_single_ray(scene: &Scene, ray: Ray) -> SpectralSample {
+        let wavelength = ray.wavelength;
+        let mut stats = TraceStatistics::default();
+        let mut lights = LightSampler::new(scene, true);
+        let (contribution, last_ray) = TraceUnit::trace_path(scene, ray, None, None,
+                                                             PathSettings::new(), &mut lights,
+                                                             &mut stats);
+        SpectralSample {
+            wavelength: wavelength,
+            contribution: contribution,
+            final_direction: last_ray.direction
+        }
+    }
+
+    /// Traces a single camera ray through the specified screen coordinate at
+    /// time 0.0, and records every interaction along the path.
+    pub fn trace_debug_ray(scene: &Scene, x: f32, y: f32, wavelength: f32) -> DebugPath {
+        let camera = scene.get_camera(0.0);
+        let ray = camera.get_ray(x, y, wavelength);
+        let camera_direction = ray.direction;
+        let mut stats = TraceStatistics::default();
+        let mut vertices = Vec::new();
+        let mut lights = LightSampler::new(scene, true);
+        let (contribution, _) = TraceUnit::trace_path(scene, ray, Some((x, y)),
+                                                      Some(&mut vertices), PathSettings::new(),
+                                                      &mut lights, &mut stats);
+        DebugPath {
+            contribution: contribution,
+            camera_direction: camera_direction,
+            vertices: vertices
+        }
+    }
+
+    /// Return the contribution of a photon travelling backwards the
+    /// specified ray, and the last ray of the path. For camera rays,
+    /// `screen_position` is the screen coordinate that the ray goes through.
+    /// If `path` is provided, all interactions are appended to it. If
+    /// `settings` enable direct light sampling, `lights` samples the lights.
+    fn trace_path<'a>(scene: &'a Scene,
+                      initial_ray: Ray,
+                      screen_position: Option<(f32, f32)>,
+                      mut path: Option<&mut Vec<PathVertex>>,
+                      settings: PathSettings,
+                      lights: &mut LightSampler<'a>,
+                      stats: &mut TraceStatistics)
+                      -> (f32, Ray) {
         // The path starts with the ray, and there is a chance it continues.
         let mut ray = initial_ray;
         let mut continue_chance = 1.0f32;
@@ -87,23 +532,160 @@ impl TraceUnit {
         // bounces, light intensity is affected by interaction probabilities.
         let mut intensity = 1.0f32;
 
+        // The object that the ray bounced off last, if it should not be
+        // intersected again in the next bounce.
+        let mut excluded = None;
+
+        // Only the camera ray can see the background plate.
+        let mut screen_position = screen_position;
+
+        // The ambient light reflected along the path so far, if the scene
+        // has ambient light. It is added to whatever the path finds.
+        let mut ambient = 0.0f32;
+
+        // The light that was sampled directly along the path so far.
+        let sample_lights = settings.direct_light && !lights.lights.is_empty();
+        let mut direct = 0.0f32;
+
+        // Whether the lights were sampled directly at the previous bounce.
+        // If so, hitting one of them must not count again.
+        let mut lights_sampled = false;
+
+        // The number of times the path bounced off a reflective surface.
+        let mut depth = 0u32;
+
         loop {
-            match scene.intersect(&ray) {
-                // If nothing was intersected, the path ends,
-                // and the only thing left is the utter darkness of The Void.
-                None => return 0.0,
-                Some((intersection, object)) => {
+            // A ray without a proper direction would produce NaNs, which
+            // would blacken the pixel. Terminate the path instead.
+            if ray.is_degenerate() {
+                stats.degenerate_rays += 1;
+                return (ambient + direct, ray);
+            }
+            ray.direction = ray.direction.normalise();
+
+            match scene.intersect_excluding(&ray, excluded) {
+                // If nothing was intersected, the path ends, and the only
+                // thing left is the environment.
+                None => {
+                    let contribution = match (&scene.environment, screen_position) {
+                        (&Environment::Plate(ref plate), Some((x, y))) => {
+                            intensity * plate.get_intensity(x, y, ray.wavelength)
+                        },
+                        (&Environment::Sky(ref sky), _) => {
+                            intensity * sky.get_intensity(ray.direction, ray.wavelength)
+                        },
+                        (&Environment::Background(ref background), _) => {
+                            intensity * background(ray.direction, ray.wavelength)
+                        },
+                        // Outside of the plate, there is the utter darkness
+                        // of The Void.
+                        _ => 0.0
+                    };
+                    return (contribution + ambient + direct, ray);
+                },
+                Some((mut intersection, object)) => {
+                    // Only look up the object index when debugging.
+                    let mut vertex = path.as_ref().map(|_| {
+                        let index = scene.objects.iter()
+                            .position(|obj| ::std::ptr::eq(obj, object))
+                            .unwrap();
+                        let emissive = match object.material {
+                            Emissive(_) => true,
+                            Reflective(_) => false
+                        };
+                        PathVertex {
+                            position: intersection.position,
+                            normal: intersection.normal,
+                            object: index,
+                            emissive: emissive,
+                            outgoing_direction: None
+                        }
+                    });
+
                     match object.material {
                         // If a light was hit, the path ends, and the intensity
                         // of the light determines the intensity of the path.
                         Emissive(ref mat) => {
-                            return intensity * mat.get_intensity(ray.wavelength);
+                            if let (Some(path), Some(vertex)) = (path, vertex) {
+                                path.push(vertex);
+                            }
+                            let sampled = lights_sampled && lights.contains(object);
+                            let contribution = if sampled {
+                                0.0
+                            } else {
+                                intensity * mat.get_intensity(ray.wavelength)
+                            };
+                            return (contribution + ambient + direct, ray);
                         },
                         // Otherwise, the ray must have hit a non-emissive surface,
                         // and so the journey continues ...
                         Reflective(ref mat) => {
-                            ray = mat.get_new_ray(&ray, &intersection);
+                            // ... unless the path is as long as allowed.
+                            if Some(depth) == settings.max_depth {
+                                if let (Some(path), Some(vertex)) = (path, vertex) {
+                                    path.push(vertex);
+                                }
+                                return (ambient + direct, ray);
+                            }
+                            depth += 1;
+
+                            if dot(ray.direction, intersection.normal) > 0.0 {
+                                match object.sidedness {
+                                    Sidedness::Unchanged => { },
+                                    Sidedness::TwoSided => {
+                                        intersection.normal = -intersection.normal;
+                                    },
+                                    Sidedness::OneSided => {
+                                        if let (Some(path), Some(vertex)) = (path, vertex) {
+                                            path.push(vertex);
+                                        }
+                                        return (ambient + direct, ray);
+                                    }
+                                }
+                            }
+
+                            // At diffuse bounces, sample the light directly,
+                            // and reflect the ambient light, if there is any.
+                            // The path continues with the same reflectance.
+                            let shading = Shading::new(&**mat, ray.wavelength);
+                            let reflectance = shading.diffuse_reflectance;
+                            if let (Some(light), Some(reflectance)) = (scene.ambient.as_ref(),
+                                                                      reflectance) {
+                                ambient += intensity * reflectance
+                                    * light.get_intensity(ray.wavelength);
+                            }
+                            lights_sampled = false;
+                            if let (true, Some(reflectance)) = (sample_lights, reflectance) {
+                                let light = lights.sample(scene, &ray, &intersection, stats);
+                                direct += intensity * reflectance * light;
+                                lights_sampled = true;
+                            }
+
+                            ray = shading.get_new_ray(&ray, &intersection);
+
+                            // A probability outside of [0, 1] would create or
+                            // destroy energy. It is a bug in the material, but
+                            // in a release build, clamp it, so that a custom
+                            // material cannot ruin the image.
+                            if !(ray.probability >= 0.0 && ray.probability <= 1.0) {
+                                debug_assert!(false, "material returned probability {}",
+                                              ray.probability);
+                                stats.clamped_probabilities += 1;
+                                ray.probability = if ray.probability > 1.0 { 1.0 } else { 0.0 };
+                            }
+
+                            if let (Some(path), Some(mut vertex)) = (path.as_mut(), vertex.take()) {
+                                vertex.normal = intersection.normal;
+                                vertex.outgoing_direction = Some(ray.direction);
+                                path.push(vertex);
+                            }
                             intensity = intensity * ray.probability;
+                            screen_position = None;
+                            excluded = if object.exclude_self_hits {
+                                Some(object)
+                            } else {
+                                None
+                            };
                         }
                     }
                 }
@@ -118,52 +700,1101 @@ impl TraceUnit {
 
             // Use a sharp falloff based on intensity, so an intensity of
             // 0.1 still has 86% chance of continuing, but an intensity of
-            // 0.01 has only 18% chance of continuing.
-            if ::monte_carlo::get_unit() * 0.85 > continue_chance
+            // 0.01 has only 18% chance of continuing. Short paths are not
+            // ended by chance.
+            let roulette = settings.integrator.roulette_depth().map_or(false, |d| depth >= d);
+            if roulette && ::monte_carlo::get_unit() * 0.85 > continue_chance
                 * (1.0 - (intensity * -20.0).exp()) {
                 break;
             }
         }
 
-        // If Russian roulette terminated the path, there is always
-        // an option of trying direct illumination, which could be
-        // implemented here, but is not.
+        // If Russian roulette terminated the path, the direct light of the
+        // last diffuse bounce was already sampled.
+        (ambient + direct, ray)
+    }
+
+    /// Returns a cheap estimate of the contribution of a camera ray, that
+    /// only includes light that reaches the camera directly, or after a
+    /// single bounce. The environment and ambient light are ignored.
+    fn trace_preview_path(scene: &Scene, camera_ray: Ray) -> f32 {
+        let mut ray = camera_ray;
+        let mut intensity = 1.0f32;
+
+        for bounce in 0 .. 2 {
+            if ray.is_degenerate() { return 0.0; }
+            ray.direction = ray.direction.normalise();
+
+            match scene.intersect(&ray) {
+                None => return 0.0,
+                Some((intersection, object)) => match object.material {
+                    Emissive(ref mat) => {
+                        return intensity * mat.get_intensity(ray.wavelength);
+                    },
+                    Reflective(ref mat) => {
+                        if bounce == 1 { return 0.0; }
+                        ray = mat.get_new_ray(&ray, &intersection);
+                        intensity = intensity * ray.probability.max(0.0).min(1.0);
+                        ray.origin = ray.origin + ray.direction * 0.00001;
+                    }
+                }
+            }
+        }
+
         0.0
     }
 
-    /// Returns the contribution of a ray
-    /// through the specified creen coordinate.
-    fn render_camera_ray(scene: &Scene, x: f32, y: f32, wavelength: f32) -> f32 {
-        // Get a random time to sample at.
-        let t = ::monte_carlo::get_unit();
+    /// Returns the ambient occlusion at the first surface that the camera ray
+    /// hits: 1.0 if a cosine-weighted ray from there does not hit anything
+    /// within `distance`, and 0.0 otherwise. Rays that hit nothing are not
+    /// occluded either.
+    fn trace_ambient_occlusion(scene: &Scene, camera_ray: Ray, distance: f32) -> f32 {
+        let mut ray = camera_ray;
+        if ray.is_degenerate() { return 0.0; }
+        ray.direction = ray.direction.normalise();
+        let intersection = match scene.intersect(&ray) {
+            Some((intersection, _)) => intersection,
+            None => return 1.0
+        };
 
-        // Get the camera at that time.
-        let camera = (scene.get_camera_at_time)(t);
+        // Cast the ray into the hemisphere on the side of the camera.
+        let normal = if dot(ray.direction, intersection.normal) < 0.0 {
+            intersection.normal
+        } else {
+            -intersection.normal
+        };
+        ray.direction = ::monte_carlo::get_hemisphere_vector().rotate_towards(normal);
+        ray.origin = intersection.position + ray.direction * 0.00001;
+        match scene.intersect(&ray) {
+            Some((hit, _)) if hit.distance < distance => 0.0,
+            _ => 1.0
+        }
+    }
 
-        // Create a camera ray for the specified pixel and wavelength.
-        let ray = camera.get_ray(x, y, wavelength);
+    /// Returns a random time at which the shutter is open.
+    fn get_shutter_time(shutter_open: f32, shutter_close: f32) -> f32 {
+        shutter_open + (shutter_close - shutter_open) * ::monte_carlo::get_unit()
+    }
 
-        // And render this camera ray.
-        TraceUnit::render_ray(scene, ray)
+    /// Returns the contribution of a camera ray, that goes through the
+    /// `screen` coordinate, as estimated by the integrator of the `settings`.
+    /// For a `preview`, only direct light and a single bounce are traced.
+    fn render_camera_ray<'a>(scene: &'a Scene, ray: Ray, screen: (f32, f32), preview: bool,
+                             settings: PathSettings, lights: &mut LightSampler<'a>,
+                             stats: &mut TraceStatistics) -> f32 {
+        if preview {
+            return TraceUnit::trace_preview_path(scene, ray);
+        }
+        if let Integrator::AmbientOcclusion(distance) = settings.integrator {
+            return TraceUnit::trace_ambient_occlusion(scene, ray, distance);
+        }
+        let (contribution, _) = TraceUnit::trace_path(scene, ray, Some(screen), None,
+                                                      settings, lights, stats);
+        contribution
     }
 
     /// Fills the buffer of mapped photons once.
     pub fn render(&mut self, scene: &Scene) {
-        for mapped_photon in &mut self.mapped_photons {
-            // Pick a wavelength for this photon.
-            let wavelength = ::monte_carlo::get_wavelength();
+        self.render_batch(scene, false);
+    }
 
-            // Pick a screen coordinate for the photon.
-            let x = ::monte_carlo::get_bi_unit();
-            let y = ::monte_carlo::get_bi_unit() / self.aspect_ratio;
+    /// Fills the buffer of mapped photons with a cheap and biased estimate,
+    /// that only includes direct light and a single bounce. It is meant for
+    /// a preview with `GatherUnit::seed_preview` before rendering starts.
+    pub fn render_preview(&mut self, scene: &Scene) {
+        self.render_batch(scene, true);
+    }
+
+    /// Fills the buffer of mapped photons once, with a preview estimate if
+    /// `preview` is true.
+    fn render_batch(&mut self, scene: &Scene, preview: bool) {
+        // Every batch has its own sampler, seeded such that every frame, unit
+        // and batch results in a different sequence of samples. It replaces
+        // the sampler of the thread for the duration of the batch, so the
+        // samples do not depend on the thread that the unit runs on.
+        let seed = [self.frame_seed, self.id as u32, self.batches_rendered, 0];
+        let thread_sampler = ::monte_carlo::install(MonteCarlo::with_seed(seed));
+        self.batches_rendered += 1;
+
+        // When letterboxing, the camera sees its own aspect ratio, and the
+        // frame is scaled down to fit inside the image. The part of the screen
+        // outside of the frame receives no photons, so it remains black.
+        let (camera_aspect_ratio, frame_scale) = match self.letterbox_aspect_ratio {
+            Some(aspect_ratio) => {
+                let scale = if aspect_ratio < self.aspect_ratio {
+                    aspect_ratio / self.aspect_ratio
+                } else {
+                    1.0
+                };
+                (aspect_ratio, scale)
+            },
+            None => (self.aspect_ratio, 1.0)
+        };
+
+        let (shutter_open, shutter_close) = (self.frame_time + self.shutter_open,
+                                             self.frame_time + self.shutter_close);
+        let settings = self.integrator.path_settings(self.direct_light_sampling, self.max_depth);
+
+        // Every path gets its own stratum of the spectrum, so a batch
+        // covers it evenly. The strata are assigned in a random order,
+        // because the screen position depends on the index of the path,
+        // and the wavelength must not depend on the screen position.
+        let m = self.spectral_samples;
+        let n = self.mapped_photons.len() / m;
+        let strata = ::monte_carlo::get_permutation(n);
+
+        // The screen and lens coordinates are sampled jointly, so that the
+        // rays of a batch are spread evenly over screen and lens alike, which
+        // reduces the noise in out-of-focus regions.
+        let halton = ::monte_carlo::HaltonSequence::new();
+        let screen_sampling = self.screen_sampling;
+        let (columns, rows) = jitter_grid(n, camera_aspect_ratio);
+
+        let distribution = self.wavelength_distribution.as_ref();
+        let importance_map = self.importance_map.as_ref();
+        let mut lights = LightSampler::new(scene, settings.direct_light);
+
+        for (i, mapped_photons) in self.mapped_photons.chunks_mut(m).enumerate() {
+            // Pick the hero wavelength for this path. The other wavelengths
+            // follow from it.
+            let hero = match distribution {
+                Some(distribution) => distribution.get_stratified_wavelength(strata[i], n).0,
+                None => ::monte_carlo::get_stratified_wavelength(strata[i], n)
+            };
+
+            // If the wavelengths are not sampled uniformly, the weight
+            // compensates for that. Any of the wavelengths could have been
+            // the hero, so the density is the mean of their densities.
+            let weight = match distribution {
+                Some(distribution) => {
+                    let inverse_weights = (0 .. m).map(|k| {
+                        1.0 / distribution.weight(rotate_wavelength(hero, k, m))
+                    });
+                    m as f32 / inverse_weights.fold(0.0, |acc, w| acc + w)
+                },
+                None => 1.0
+            };
+
+            // Pick a screen coordinate for the photon, strictly inside the
+            // frame, and a point on the lens.
+            let sample = halton.get(i);
+            let (sx, sy) = match screen_sampling {
+                ScreenSampling::Halton => (sample[0], sample[1]),
+                ScreenSampling::Jittered => jittered_sample(i, columns, rows)
+            };
 
-            // Store the coordinates already.
-            mapped_photon.wavelength = wavelength;
-            mapped_photon.x = x;
-            mapped_photon.y = y;
+            // Concentrate the photons on the important parts of the screen.
+            // The weight compensates for the higher density there.
+            let (sx, sy, screen_weight) = match importance_map {
+                Some(map) => map.sample(sx, sy),
+                None => (sx, sy, 1.0)
+            };
+            let x = sx * 2.0 - 1.0;
+            let y = (sy * 2.0 - 1.0) / camera_aspect_ratio;
 
-            // And then trace the scene at this wavelength.
-            mapped_photon.probability = TraceUnit::render_camera_ray(scene, x, y, wavelength);
+            // Get a random time to sample at, and the camera at that time.
+            let t = TraceUnit::get_shutter_time(shutter_open, shutter_close);
+            let camera = scene.get_camera(t);
+
+            for (k, mapped_photon) in mapped_photons.iter_mut().enumerate() {
+                // Store the coordinates already.
+                let wavelength = rotate_wavelength(hero, k, m);
+                mapped_photon.wavelength = wavelength;
+                mapped_photon.x = x * frame_scale;
+                mapped_photon.y = y * frame_scale;
+
+                // Create a camera ray for the pixel, the point on the lens,
+                // and the wavelength, and trace the scene along it. Every
+                // wavelength is refracted differently, so it has its own path.
+                let lens = (sample[2], sample[3]);
+                let ray = camera.get_ray_through_lens(x, y, wavelength, lens);
+                mapped_photon.probability = TraceUnit::render_camera_ray(scene, ray, (x, y),
+                                                                         preview, settings,
+                                                                         &mut lights,
+                                                                         &mut self.statistics);
+                mapped_photon.probability *= weight * screen_weight;
+            }
         }
+
+        ::monte_carlo::install(thread_sampler);
+    }
+}
+
+#[test]
+fn frame_seed_decorrelates_noise() {
+    let scene = ::app::App::set_up_scene();
+    let mut unit_a = TraceUnit::new(0, 64, 36, 1024);
+    let mut unit_b = TraceUnit::new(0, 64, 36, 1024);
+
+    // With the same seed, the same photons are traced.
+    unit_a.frame_seed = 1;
+    unit_b.frame_seed = 1;
+    unit_a.render(&scene);
+    unit_b.render(&scene);
+    assert!(unit_a.mapped_photons.iter().zip(unit_b.mapped_photons.iter())
+            .all(|(a, b)| a.x == b.x && a.y == b.y && a.wavelength == b.wavelength));
+
+    // But the next frame must have a different noise pattern.
+    let mut unit_c = TraceUnit::new(0, 64, 36, 1024);
+    unit_c.frame_seed = 2;
+    unit_c.render(&scene);
+    let n_same = unit_a.mapped_photons.iter().zip(unit_c.mapped_photons.iter())
+                 .filter(|&(a, c)| a.x == c.x && a.y == c.y).count();
+    assert_eq!(n_same, 0);
+}
+
+#[test]
+fn trace_single_ray_prism_disperses() {
+    use geometry::new_prism;
+    use material::Sf10GlassMaterial;
+    use object::{MaterialBox, Object};
+
+    let prism = new_prism(Vector3::new(0.0, 0.0, 1.0), Vector3::zero(), 4.0, 0.0, 2.0);
+    let glass = MaterialBox::Reflective(Box::new(Sf10GlassMaterial));
+    let scene = Scene::new(vec![Object::new(Box::new(prism), glass)], ::camera::test_camera);
+
+    let deflection = |wavelength: f32| {
+        let ray = Ray {
+            origin: Vector3::new(-10.0, 0.2, 1.0),
+            direction: Vector3::new(1.0, 0.0, 0.0),
+            wavelength: wavelength,
+            probability: 1.0
+        };
+        let sample = TraceUnit::trace_single_ray(&scene, ray);
+        assert_eq!(sample.wavelength, wavelength);
+        sample.final_direction.x.acos()
+    };
+
+    // Blue light is refracted more strongly than red light.
+    let d_450 = deflection(450.0);
+    let d_650 = deflection(650.0);
+    assert!(d_650 > 0.1);
+    assert!(d_450 > d_650);
+}
+
+#[test]
+fn degenerate_ray_terminates_path() {
+    use geometry::Plane;
+    use intersection::Intersection;
+    use material::Material;
+    use object::{MaterialBox, Object};
+
+    /// A broken material that does not produce a direction.
+    struct ZeroDirectionMaterial;
+
+    impl Material for ZeroDirectionMaterial {
+        fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+            Ray {
+                origin: intersection.position,
+                direction: Vector3::new(1.0e-20, 0.0, 0.0),
+                wavelength: incoming_ray.wavelength,
+                probability: 1.0
+            }
+        }
+    }
+
+    let plane = Plane::new(Vector3::new(0.0, 1.0, 0.0), Vector3::new(0.0, 5.0, 0.0));
+    let material = MaterialBox::Reflective(Box::new(ZeroDirectionMaterial));
+    let scene = Scene::new(vec![Object::new(Box::new(plane), material)], ::camera::test_camera);
+
+    let ray = Ray {
+        origin: Vector3::zero(),
+        direction: Vector3::new(0.0, 1.0, 0.0),
+        wavelength: 550.0,
+        probability: 1.0
+    };
+    let mut stats = TraceStatistics::default();
+    let contribution = TraceUnit::render_ray(&scene, ray, &mut stats);
+    assert_eq!(contribution, 0.0);
+    assert_eq!(stats.degenerate_rays, 1);
+}
+
+#[test]
+fn letterbox_adds_bars() {
+    use geometry::Plane;
+    use material::BlackBodyMaterial;
+    use object::{MaterialBox, Object};
+    use plot_unit::PlotUnit;
+
+    // Put a light in front of the camera, so every camera ray contributes.
+    let plane = Plane::new(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 10.0, 0.0));
+    let light = MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0)));
+    let scene = Scene::new(vec![Object::new(Box::new(plane), light)], ::camera::test_camera);
+
+    // Render a square image with a 16:9 camera.
+    let mut trace_unit = TraceUnit::new(0, 32, 32, 1024);
+    trace_unit.letterbox_aspect_ratio = Some(16.0 / 9.0);
+    trace_unit.render(&scene);
+    let mut plot_unit = PlotUnit::new(0, 32, 32);
+    plot_unit.plot(&trace_unit.mapped_photons);
+
+    fn row_energy(unit: &PlotUnit, row: usize) -> f32 {
+        unit.tristimulus_buffer[row * 32 .. (row + 1) * 32]
+            .iter().map(|cie| cie.y).sum::<f32>()
+    }
+
+    // The frame covers 32 * 9 / 16 = 18 rows in the middle, the 6 rows at
+    // the top and bottom must be black.
+    for row in (0 .. 6).chain(26 .. 32) {
+        assert_eq!(row_energy(&plot_unit, row), 0.0);
+    }
+    for row in 8 .. 24 {
+        assert!(row_energy(&plot_unit, row) > 0.0);
+    }
+
+    // And without letterboxing, all of the image is covered.
+    trace_unit.letterbox_aspect_ratio = None;
+    trace_unit.render(&scene);
+    plot_unit.clear();
+    plot_unit.plot(&trace_unit.mapped_photons);
+    assert!(row_energy(&plot_unit, 0) > 0.0 && row_energy(&plot_unit, 31) > 0.0);
+}
+
+#[test]
+fn sidedness_controls_back_face_shading() {
+    use geometry::{Plane, SpacePartitioning};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::{MaterialBox, Object};
+
+    let up = Vector3::new(0.0, 0.0, 1.0);
+
+    // Construct a one-sided surface facing upwards, between two lights.
+    let trace = |sidedness: Sidedness, from_front: bool| {
+        let surface = SpacePartitioning::new(up, Vector3::zero());
+        let grey = MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8)));
+        let mut object = Object::new(Box::new(surface), grey);
+        object.sidedness = sidedness;
+
+        let mut objects = vec![object];
+        for &z in [-10.0f32, 10.0].iter() {
+            let plane = Plane::new(up, up * z);
+            let light = MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0)));
+            objects.push(Object::new(Box::new(plane), light));
+        }
+
+        let scene = Scene::new(objects, ::camera::test_camera);
+
+        let side = if from_front { 1.0 } else { -1.0 };
+        let ray = Ray {
+            origin: up * (5.0 * side),
+            direction: Vector3::new(0.3, 0.0, -side).normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        TraceUnit::trace_single_ray(&scene, ray).contribution
+    };
+
+    let front = trace(Sidedness::TwoSided, true);
+    assert!(front > 0.0);
+    assert!((trace(Sidedness::TwoSided, false) - front).abs() < 1.0e-6);
+    assert!((trace(Sidedness::OneSided, true) - front).abs() < 1.0e-6);
+    assert_eq!(trace(Sidedness::OneSided, false), 0.0);
+}
+
+#[test]
+fn background_plate_is_seen_but_does_not_illuminate() {
+    use environment::BackgroundPlate;
+    use geometry::Plane;
+    use material::DiffuseGreyMaterial;
+    use object::{MaterialBox, Object};
+
+    // A 2x2 plate, with a different colour in every quadrant.
+    let pixels = vec![Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0),
+                      Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.5, 0.5, 0.5)];
+    let plate = BackgroundPlate::new(2, 2, pixels);
+
+    // A floor below the camera, that only the plate could light.
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let floor = Plane::new(up, up * -1.0);
+    let grey = MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8)));
+    let scene = Scene {
+        environment: Environment::Plate(plate),
+        ..Scene::new(vec![Object::new(Box::new(floor), grey)], ::camera::test_camera)
+    };
+
+    // Camera rays towards the top half escape and see the plate. The top
+    // left is red, the top right is green.
+    let mut stats = TraceStatistics::default();
+    let render = |x: f32, y: f32, wavelength: f32, stats: &mut TraceStatistics| {
+        let mut lights = LightSampler::new(&scene, true);
+        let ray = scene.get_camera(0.0).get_ray_through_lens(x, y, wavelength, (0.5, 0.5));
+        TraceUnit::render_camera_ray(&scene, ray, (x, y), false, PathSettings::new(),
+                                     &mut lights, stats)
+    };
+    assert_eq!(render(-0.5, -0.5, 650.0, &mut stats), 1.0);
+    assert_eq!(render(-0.5, -0.5, 450.0, &mut stats), 0.0);
+    assert_eq!(render(0.5, -0.5, 550.0, &mut stats), 1.0);
+
+    // Rays that hit the floor bounce, and their secondary rays escape to
+    // the void, the plate does not illuminate the floor.
+    for _ in 0 .. 100 {
+        assert_eq!(render(0.0, 0.5, 650.0, &mut stats), 0.0);
+    }
+}
+
+#[test]
+fn batch_covers_all_wavelength_strata() {
+    let scene = Scene::new(Vec::new(), ::camera::test_camera);
+    let mut unit = TraceUnit::new(0, 32, 32, 1024);
+    unit.render(&scene);
+
+    // Divide the spectrum into strata of 4 nm, and count the photons in them.
+    let strata = 100;
+    let mut counts = vec![0u32; strata];
+    let mut sum = 0.0;
+    for photon in &unit.mapped_photons {
+        let stratum = ((photon.wavelength - 380.0) / 400.0 * strata as f32) as usize;
+        counts[stratum.min(strata - 1)] += 1;
+        sum += photon.wavelength;
+    }
+
+    assert!(counts.iter().all(|&c| c > 0));
+
+    // The samples are still uniformly distributed, so the mean is centred.
+    let mean = sum / unit.mapped_photons.len() as f32;
+    assert!((mean - 580.0).abs() < 1.0);
+}
+
+#[test]
+fn shutter_interval_limits_motion() {
+    use camera::Camera;
+
+    // A camera that moves 10 units along the x-axis during the frame.
+    fn moving_camera(t: f32) -> Camera {
+        let mut camera = ::camera::test_camera(t);
+        camera.position = Vector3::new(10.0 * t, 0.0, 0.0);
+        camera
+    }
+
+    let spread = |shutter_open: f32, shutter_close: f32| {
+        let xs: Vec<f32> = (0 .. 1000).map(|_| {
+            let t = TraceUnit::get_shutter_time(shutter_open, shutter_close);
+            moving_camera(t).position.x
+        }).collect();
+        let min = xs.iter().fold(xs[0], |a, &x| a.min(x));
+        let max = xs.iter().fold(xs[0], |a, &x| a.max(x));
+        (min, max)
+    };
+
+    let (min, max) = spread(0.0, 1.0);
+    assert!(min < 0.5 && max > 9.5);
+
+    let (min, max) = spread(0.4, 0.6);
+    assert!(min >= 4.0 && max <= 6.0);
+
+    // When the shutter opens and closes at once, there is no motion at all.
+    let (min, max) = spread(0.5, 0.5);
+    assert_eq!(min, max);
+}
+
+#[test]
+fn debug_ray_records_first_hit() {
+    use app::App;
+
+    let scene = App::set_up_scene();
+    let path = TraceUnit::trace_debug_ray(&scene, 0.0, 0.0, 550.0);
+
+    // The camera looks at the sun sphere in the centre, so that is hit first.
+    let first = path.vertices[0];
+    assert_eq!(first.object, 0);
+    assert!(first.emissive);
+    assert!(first.outgoing_direction.is_none());
+    assert_eq!(path.vertices.len(), 1);
+    assert!(path.contribution > 0.0);
+
+    // The sphere has radius 5 and is centred at the origin, so the normal
+    // points radially outward, back towards the camera.
+    assert!((first.position.magnitude() - 5.0).abs() < 1.0e-3);
+    assert!(dot(first.normal, first.position * 0.2) > 0.999);
+    assert!(dot(first.normal, path.camera_direction) < -0.9);
+}
+
+#[test]
+fn plotting_agrees_with_trace_aspect_ratio() {
+    use plot_unit::PlotUnit;
+
+    for &(width, height) in [(32u32, 18u32), (18, 32)].iter() {
+        let scene = Scene::new(Vec::new(), ::camera::test_camera);
+        let mut unit = TraceUnit::new(0, width, height, 1024);
+        assert_eq!(unit.aspect_ratio, width as f32 / height as f32);
+        unit.render(&scene);
+
+        // The photons must cover exactly the screen of the canvas.
+        let max_y = unit.mapped_photons.iter().fold(0.0f32, |acc, p| acc.max(p.y.abs()));
+        assert!(max_y <= 1.0 / unit.aspect_ratio);
+        assert!(max_y > 0.95 / unit.aspect_ratio);
+
+        // And the corners of that screen must map onto the corner pixels.
+        let corner = |x: f32, y: f32| {
+            let mut plot_unit = PlotUnit::new(0, width, height);
+            plot_unit.plot(&[MappedPhoton { x: x, y: y, probability: 1.0, wavelength: 550.0 }]);
+            plot_unit.tristimulus_buffer.iter().position(|px| px.y > 0.0).unwrap()
+        };
+        let y_max = 1.0 / unit.aspect_ratio;
+        assert_eq!(corner(-1.0, -y_max), 0);
+        assert_eq!(corner(1.0, y_max), (width * height - 1) as usize);
+    }
+}
+
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "material returned probability"))]
+fn out_of_range_probability_is_clamped() {
+    use geometry::Plane;
+    use intersection::Intersection;
+    use material::{BlackBodyMaterial, Material};
+    use object::{MaterialBox, Object};
+
+    /// A mirror that claims to reflect with the specified probability.
+    struct AmplifyingMirror(f32);
+
+    impl Material for AmplifyingMirror {
+        fn get_new_ray(&self, incoming_ray: &Ray, intersection: &Intersection) -> Ray {
+            Ray {
+                origin: intersection.position,
+                direction: incoming_ray.direction.reflect(intersection.normal),
+                wavelength: incoming_ray.wavelength,
+                probability: self.0
+            }
+        }
+    }
+
+    // The ray bounces off the mirror in front, into the light behind.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let trace = |probability: f32, stats: &mut TraceStatistics| {
+        let mirror = Plane::new(forward, forward * 5.0);
+        let light = Plane::new(forward, forward * -10.0);
+        let objects = vec![
+            Object::new(Box::new(mirror),
+                        MaterialBox::Reflective(Box::new(AmplifyingMirror(probability)))),
+            Object::new(Box::new(light),
+                        MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))))
+        ];
+        let scene = Scene::new(objects, ::camera::test_camera);
+        let ray = Ray {
+            origin: Vector3::zero(),
+            direction: forward,
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        TraceUnit::render_ray(&scene, ray, stats)
+    };
+
+    let mut stats = TraceStatistics::default();
+    let reference = trace(1.0, &mut stats);
+    assert!(reference > 0.0);
+    assert_eq!(stats.clamped_probabilities, 0);
+
+    assert_eq!(trace(2.0, &mut stats), reference);
+    assert_eq!(stats.clamped_probabilities, 1);
+
+    assert_eq!(trace(-1.0, &mut stats), 0.0);
+    assert_eq!(stats.clamped_probabilities, 2);
+}
+
+#[test]
+fn camera_rays_stay_inside_the_frame() {
+    let scene = ::app::App::set_up_scene();
+    for &sampling in [ScreenSampling::Halton, ScreenSampling::Jittered].iter() {
+        let mut unit = TraceUnit::new(0, 16, 9, 1024);
+        unit.screen_sampling = sampling;
+        unit.render(&scene);
+
+        // Photons near every edge still reach the frame, but never the edge.
+        let y_max = 1.0 / unit.aspect_ratio;
+        for photon in &unit.mapped_photons {
+            assert!(photon.x > -1.0 && photon.x < 1.0);
+            assert!(photon.y > -y_max && photon.y < y_max);
+        }
+        let near_edge = |p: &MappedPhoton| p.x.abs() > 0.98 || p.y.abs() > y_max * 0.98;
+        assert!(unit.mapped_photons.iter().any(near_edge));
+    }
+}
+
+#[test]
+fn ambient_light_reaches_shadowed_surfaces() {
+    use geometry::SpacePartitioning;
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial, EmissiveMaterial, Material,
+                   SpecularMaterial};
+    use object::{MaterialBox, Object};
+
+    // A floor that faces away from the only light, which lies below it, so
+    // no path from the floor can reach the light.
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let trace = |material: Box<Material>, ambient: Option<Box<EmissiveMaterial>>| {
+        let floor = Object::new(Box::new(SpacePartitioning::new(up, Vector3::zero())),
+                                MaterialBox::Reflective(material));
+        let light = Object::new(Box::new(SpacePartitioning::new(up, up * -10.0)),
+                                MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+        let scene = Scene {
+            ambient: ambient,
+            ..Scene::new(vec![floor, light], ::camera::test_camera)
+        };
+        let ray = Ray {
+            origin: up * 5.0,
+            direction: -up,
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let mut stats = TraceStatistics::default();
+        (0 .. 100).map(|_| TraceUnit::render_ray(&scene, Ray { .. ray }, &mut stats))
+                  .collect::<Vec<f32>>()
+    };
+
+    let diffuse = || Box::new(DiffuseGreyMaterial::new(0.5));
+    let white = || Some(Box::new(BlackBodyMaterial::new(6504.0, 1.0)) as Box<EmissiveMaterial>);
+    assert!(trace(diffuse(), None).iter().all(|&c| c == 0.0));
+
+    // With ambient light, a diffuse floor reflects at least its reflectance
+    // times the ambient intensity at the first bounce.
+    let ambient = BlackBodyMaterial::new(6504.0, 1.0).get_intensity(550.0);
+    let lit = trace(diffuse(), white());
+    assert!(lit.iter().all(|&c| c >= 0.5 * ambient * 0.999));
+
+    // A mirror does not receive ambient light; it only reflects the sky.
+    let mirror = trace(Box::new(SpecularMaterial::new(1.0)), white());
+    assert!(mirror.iter().all(|&c| c == 0.0));
+}
+
+#[test]
+fn direct_light_sampling_reduces_variance() {
+    use geometry::{Sphere, SpacePartitioning};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::{MaterialBox, Object};
+
+    ::monte_carlo::seed([1, 1, 2, 3]);
+
+    // A diffuse floor, lit by a small spherical light above it.
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let floor = Object::new(Box::new(SpacePartitioning::new(up, Vector3::zero())),
+                            MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.8))));
+    let light = Object::new(Box::new(Sphere::new(up * 5.0, 0.5)),
+                            MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![floor, light], ::camera::test_camera);
+    assert_eq!(scene.emissive_objects().len(), 1);
+
+    // Returns the mean and the variance of paths that start at the floor.
+    let estimate = |direct_light: bool| {
+        let n = 40000;
+        let mut stats = TraceStatistics::default();
+        let mut lights = LightSampler::new(&scene, direct_light);
+        let (mut sum, mut sum_sq) = (0.0f64, 0.0f64);
+        for _ in 0 .. n {
+            let ray = Ray {
+                origin: Vector3::new(0.0, -1.0, 1.0),
+                direction: Vector3::new(0.0, 1.0, -1.0).normalise(),
+                wavelength: 550.0,
+                probability: 1.0
+            };
+            let settings = Integrator::Path.path_settings(direct_light, None);
+            let (c, _) = TraceUnit::trace_path(&scene, ray, None, None, settings, &mut lights,
+                                               &mut stats);
+            sum += c as f64;
+            sum_sq += (c * c) as f64;
+        }
+        let mean = sum / n as f64;
+        (mean, sum_sq / n as f64 - mean * mean)
+    };
+
+    let (mean_path, variance_path) = estimate(false);
+    let (mean_direct, variance_direct) = estimate(true);
+
+    // Both estimate the same light, but sampling the light directly is far
+    // less noisy, because only a few bounces hit the light by chance.
+    assert!(mean_path > 0.0);
+    assert!((mean_direct - mean_path).abs() < mean_path * 0.2,
+            "{} differs from {}", mean_direct, mean_path);
+    assert!(variance_direct * 10.0 < variance_path);
+}
+
+#[test]
+fn max_depth_terminates_mirror_corridor() {
+    use geometry::Plane;
+    use material::SpecularMaterial;
+    use object::{MaterialBox, Object};
+
+    // Two perfect mirrors facing each other, with nothing else around.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let mirror = || MaterialBox::Reflective(Box::new(SpecularMaterial::new(1.0)));
+    let objects = vec![Object::new(Box::new(Plane::new(-forward, forward * 1.0)), mirror()),
+                       Object::new(Box::new(Plane::new(forward, forward * -1.0)), mirror())];
+    let scene = Scene::new(objects, ::camera::test_camera);
+
+    // Returns the number of surfaces that the path hit.
+    let path_length = |max_depth: Option<u32>| {
+        let ray = Ray {
+            origin: Vector3::zero(),
+            direction: Vector3::new(0.01, 1.0, 0.0).normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        let settings = Integrator::Path.path_settings(true, max_depth);
+        let mut stats = TraceStatistics::default();
+        let mut vertices = Vec::new();
+        let mut lights = LightSampler::new(&scene, true);
+        TraceUnit::trace_path(&scene, ray, None, Some(&mut vertices), settings, &mut lights,
+                              &mut stats);
+        vertices.len()
+    };
+
+    // After three bounces, the fourth hit ends the path. Russian roulette
+    // does not end paths this short for a perfect mirror.
+    for _ in 0 .. 100 {
+        assert_eq!(path_length(Some(3)), 4);
+        assert_eq!(path_length(Some(0)), 1);
+    }
+
+    // Without a maximum, the paths are longer.
+    assert!((0 .. 100).map(|_| path_length(None)).any(|n| n > 4));
+}
+
+#[test]
+fn integrators_guarantee_their_minimum_depth() {
+    use geometry::Plane;
+    use material::SpecularMaterial;
+    use object::{MaterialBox, Object};
+
+    // Two dark mirrors facing each other, so that Russian roulette would end
+    // most paths after a few bounces.
+    ::monte_carlo::seed([2, 4, 0, 1]);
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let mirror = || MaterialBox::Reflective(Box::new(SpecularMaterial::new(0.1)));
+    let objects = vec![Object::new(Box::new(Plane::new(-forward, forward * 1.0)), mirror()),
+                       Object::new(Box::new(Plane::new(forward, forward * -1.0)), mirror())];
+    let scene = Scene::new(objects, ::camera::test_camera);
+
+    // Returns the shortest of a number of paths, in surfaces hit.
+    let min_path_length = |integrator: Integrator| {
+        (0 .. 200).map(|_| {
+            let ray = Ray {
+                origin: Vector3::zero(),
+                direction: Vector3::new(0.01, 1.0, 0.0).normalise(),
+                wavelength: 550.0,
+                probability: 1.0
+            };
+            let settings = integrator.path_settings(true, None);
+            let mut stats = TraceStatistics::default();
+            let mut vertices = Vec::new();
+            let mut lights = LightSampler::new(&scene, true);
+            TraceUnit::trace_path(&scene, ray, None, Some(&mut vertices), settings, &mut lights,
+                                  &mut stats);
+            vertices.len()
+        }).min().unwrap()
+    };
+
+    // Full paths make three bounces before roulette can end them, and the
+    // direct integrator always makes exactly one, followed by the surface
+    // where it ends.
+    assert_eq!(min_path_length(Integrator::Path), ROULETTE_DEPTH as usize);
+    assert_eq!(min_path_length(Integrator::Direct), 2);
+    assert_eq!(Integrator::AmbientOcclusion(1.0).roulette_depth(), None);
+
+    // Between the mirrors, every occlusion ray hits the other mirror, at a
+    // distance of at least two.
+    let occlusion = |distance: f32| {
+        let camera_ray = || scene.get_camera(0.0).get_ray(0.0, 0.0, 550.0);
+        (0 .. 100).map(|_| TraceUnit::trace_ambient_occlusion(&scene, camera_ray(), distance))
+                  .sum::<f32>() / 100.0
+    };
+    assert_eq!(occlusion(1.9), 1.0);
+    assert_eq!(occlusion(1.0e3), 0.0);
+}
+
+#[test]
+fn jittered_samples_cover_all_cells() {
+    let scene = Scene::new(Vec::new(), ::camera::test_camera);
+    let (width, height) = (64, 36);
+    let mut unit = TraceUnit::new(0, width, height, 1024);
+    unit.screen_sampling = ScreenSampling::Jittered;
+    unit.render(&scene);
+
+    // Map every photon back to the unit square; every cell must be hit.
+    let aspect_ratio = ::screen::aspect_ratio(width, height);
+    let (columns, rows) = jitter_grid(unit.mapped_photons.len(), aspect_ratio);
+    assert!(columns * rows > unit.mapped_photons.len() * 9 / 10);
+    let points: Vec<(f32, f32)> = unit.mapped_photons.iter().map(|photon| {
+        (photon.x * 0.5 + 0.5, photon.y * aspect_ratio * 0.5 + 0.5)
+    }).collect();
+
+    // Allow for rounding at the cell boundaries.
+    let eps = 1.0e-5;
+    let in_cell = |p: &(f32, f32), column: usize, row: usize| {
+        let (u, v) = (p.0 * columns as f32, p.1 * rows as f32);
+        u >= column as f32 - eps && u <= (column + 1) as f32 + eps &&
+        v >= row as f32 - eps && v <= (row + 1) as f32 + eps
+    };
+    for row in 0 .. rows {
+        for column in 0 .. columns {
+            assert!(points.iter().any(|p| in_cell(p, column, row)),
+                    "cell ({}, {}) is empty", column, row);
+        }
+    }
+}
+
+#[test]
+fn jittered_wavelengths_do_not_depend_on_the_row() {
+    let scene = Scene::new(Vec::new(), ::camera::test_camera);
+    let (width, height) = (64, 36);
+    let mut unit = TraceUnit::new(0, width, height, 4096);
+    unit.screen_sampling = ScreenSampling::Jittered;
+    unit.render(&scene);
+
+    // Divide the screen into horizontal bands. If the grid cells and the
+    // strata of the spectrum were tied together, the top of the screen
+    // would be blue and the bottom red.
+    let aspect_ratio = ::screen::aspect_ratio(width, height);
+    let bands = 4;
+    let mut sums = vec![0.0f32; bands];
+    let mut counts = vec![0u32; bands];
+    for photon in &unit.mapped_photons {
+        let v = photon.y * aspect_ratio * 0.5 + 0.5;
+        let band = ((v * bands as f32) as usize).min(bands - 1);
+        sums[band] += photon.wavelength;
+        counts[band] += 1;
+    }
+
+    // The standard error of the mean of a band is about 4 nm.
+    for (&sum, &count) in sums.iter().zip(counts.iter()) {
+        let mean = sum / count as f32;
+        assert!((mean - 580.0).abs() < 20.0, "a band has mean wavelength {}", mean);
+    }
+}
+
+#[test]
+fn wavelength_guiding_reduces_colour_noise() {
+    use geometry::Plane;
+    use material::{BlackBodyMaterial, DiffuseColouredMaterial};
+    use plot_unit::PlotUnit;
+
+    // A wall of saturated red paint, lit by white ambient light.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let paint = DiffuseColouredMaterial::new(0.9, 650.0, 10.0);
+    let mut guided = TraceUnit::new(0, 16, 16, 1024);
+    guided.guide_wavelengths(&paint);
+    let wall = Object::new(Box::new(Plane::new(forward, forward * 10.0)),
+                           Reflective(Box::new(paint)));
+    let scene = Scene {
+        ambient: Some(Box::new(BlackBodyMaterial::new(6504.0, 1.0))),
+        ..Scene::new(vec![wall], ::camera::test_camera)
+    };
+
+    // Returns the mean luminance and the variance of the chromaticity x over
+    // the pixels of the image. The wall is uniform, so all of the variance is
+    // colour noise. Every pixel receives only a few photons per batch, so a
+    // sampled wavelength often misses the narrow band that the paint reflects.
+    let measure = |unit: &mut TraceUnit| {
+        unit.frame_seed = 7;
+        let mut plot_unit = PlotUnit::new(0, 16, 16);
+        for _ in 0 .. 4 {
+            unit.render(&scene);
+            plot_unit.plot(&unit.mapped_photons);
+        }
+        let pixels = &plot_unit.tristimulus_buffer;
+        let n = pixels.len() as f32;
+        let chromaticities: Vec<f32> = pixels.iter()
+            .map(|c| c.x / (c.x + c.y + c.z).max(1.0e-20))
+            .collect();
+        let mean_x = chromaticities.iter().fold(0.0, |acc, &x| acc + x) / n;
+        let variance = chromaticities.iter().fold(0.0, |acc, &x| acc + (x - mean_x).powi(2)) / n;
+        let mean_y = pixels.iter().fold(0.0, |acc, c| acc + c.y) / n;
+        (mean_y, variance)
+    };
+
+    let mut uniform = TraceUnit::new(0, 16, 16, 1024);
+    let (uniform_y, uniform_variance) = measure(&mut uniform);
+    let (guided_y, guided_variance) = measure(&mut guided);
+
+    assert!(guided_variance < uniform_variance * 0.5,
+            "{} is not much less than {}", guided_variance, uniform_variance);
+    assert!((guided_y / uniform_y - 1.0).abs() < 0.1, "{} vs {}", guided_y, uniform_y);
+}
+
+#[test]
+fn spectral_samples_reduce_colour_noise() {
+    use geometry::Plane;
+    use material::{BlackBodyMaterial, DiffuseColouredMaterial};
+    use plot_unit::PlotUnit;
+
+    // A wall of saturated red paint, lit by white ambient light.
+    let forward = Vector3::new(0.0, 1.0, 0.0);
+    let paint = DiffuseColouredMaterial::new(0.9, 650.0, 10.0);
+    let wall = Object::new(Box::new(Plane::new(forward, forward * 10.0)),
+                           Reflective(Box::new(paint)));
+    let scene = Scene {
+        ambient: Some(Box::new(BlackBodyMaterial::new(6504.0, 1.0))),
+        ..Scene::new(vec![wall], ::camera::test_camera)
+    };
+
+    // Returns the mean luminance per photon, and the variance of the
+    // chromaticity x over the pixels of the image, for a fixed number of
+    // paths, and `n` wavelengths per path.
+    let measure = |n: usize, guided: bool| {
+        let mut unit = TraceUnit::new(0, 16, 16, 1024);
+        unit.frame_seed = 7;
+        unit.set_spectral_samples(n);
+        if guided {
+            unit.guide_wavelengths(&DiffuseColouredMaterial::new(0.9, 650.0, 10.0));
+        }
+        let mut plot_unit = PlotUnit::new(0, 16, 16);
+        for _ in 0 .. 4 {
+            unit.render(&scene);
+            plot_unit.plot(&unit.mapped_photons);
+        }
+        assert_eq!(unit.mapped_photons.len(), 1024 * n);
+        let pixels = &plot_unit.tristimulus_buffer;
+        let chromaticities: Vec<f32> = pixels.iter()
+            .map(|c| c.x / (c.x + c.y + c.z).max(1.0e-20))
+            .collect();
+        let m = pixels.len() as f32;
+        let mean_x = chromaticities.iter().fold(0.0, |acc, &x| acc + x) / m;
+        let variance = chromaticities.iter().fold(0.0, |acc, &x| acc + (x - mean_x).powi(2)) / m;
+        let photons = (4 * unit.mapped_photons.len()) as f32;
+        let y = pixels.iter().fold(0.0, |acc, c| acc + c.y) / photons;
+        (y, variance)
+    };
+
+    let (single_y, single_variance) = measure(1, false);
+    let (multi_y, multi_variance) = measure(4, false);
+    assert!(multi_variance < single_variance * 0.5,
+            "{} is not much less than {}", multi_variance, single_variance);
+    assert!((multi_y / single_y - 1.0).abs() < 0.1, "{} vs {}", multi_y, single_y);
+
+    // With guided wavelengths, the weights account for all wavelengths of a
+    // path. Weighting by the density of the first wavelength alone would be
+    // unbiased too, but a rare first wavelength would make the others bright
+    // outliers, so there would be more colour noise than with one wavelength.
+    let (guided_y, guided_variance) = measure(1, true);
+    let (multi_guided_y, multi_guided_variance) = measure(4, true);
+    assert!(multi_guided_variance < guided_variance,
+            "{} is not less than {}", multi_guided_variance, guided_variance);
+    assert!((multi_guided_y / guided_y - 1.0).abs() < 0.1, "{} vs {}", multi_guided_y, guided_y);
+}
+
+#[test]
+fn escaped_rays_see_background() {
+    use geometry::Plane;
+    use material::DiffuseGreyMaterial;
+
+    // Red light from above, and blue light from below the horizon.
+    let background = |direction: Vector3, wavelength: f32| {
+        match (direction.z > 0.0, wavelength > 600.0) {
+            (true, true) => 2.0,
+            (false, false) => 0.5,
+            _ => 0.0
+        }
+    };
+    let scene = Scene {
+        environment: Environment::Background(Box::new(background)),
+        ..Scene::new(Vec::new(), ::camera::test_camera)
+    };
+
+    let ray = |direction: Vector3, wavelength: f32| Ray {
+        origin: Vector3::zero(),
+        direction: direction,
+        wavelength: wavelength,
+        probability: 1.0
+    };
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    assert_eq!(TraceUnit::trace_single_ray(&scene, ray(up, 650.0)).contribution, 2.0);
+    assert_eq!(TraceUnit::trace_single_ray(&scene, ray(up, 450.0)).contribution, 0.0);
+    assert_eq!(TraceUnit::trace_single_ray(&scene, ray(-up, 450.0)).contribution, 0.5);
+
+    // The background illuminates objects too: a white floor reflects the
+    // light from above.
+    let floor = Object::new(Box::new(Plane::new(up, up * -1.0)),
+                            Reflective(Box::new(DiffuseGreyMaterial::new(1.0))));
+    let scene = Scene { objects: vec![floor], ..scene };
+    let down = Vector3::new(0.0, 0.0, -1.0);
+    let n = 100;
+    let total = (0 .. n).map(|_| TraceUnit::trace_single_ray(&scene, ray(down, 650.0)).contribution)
+                        .fold(0.0, |acc, c| acc + c);
+    assert!(total / n as f32 > 1.0);
+}
+
+#[test]
+fn importance_map_concentrates_photons_on_noise() {
+    use gather_unit::GatherUnit;
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    // The left half of the image is noisy, the right half is constant.
+    let mut rng = XorShiftRng::from_seed([43, 47, 53, 59]);
+    let mut gather_unit = GatherUnit::new_empty(8, 4);
+    for _ in 0 .. 16 {
+        let batch: Vec<Vector3> = (0 .. 32).map(|i| {
+            let y = if i % 8 < 4 { rng.gen::<f32>() } else { 0.5 };
+            Vector3::new(y, y, y)
+        }).collect();
+        gather_unit.accumulate(&batch);
+    }
+
+    let scene = Scene::new(Vec::new(), ::camera::test_camera);
+    let mut unit = TraceUnit::new(0, 8, 4, 1024);
+    unit.importance_map = Some(gather_unit.importance_map(2, 1));
+
+    // Without noise on the right, that half only gets its share of the
+    // uniform fraction of the photons.
+    let (mut left, mut right) = (0, 0);
+    for _ in 0 .. 4 {
+        unit.render(&scene);
+        let n = unit.mapped_photons.iter().filter(|p| p.x < 0.0).count();
+        left += n;
+        right += unit.mapped_photons.len() - n;
+    }
+    assert!(left > right * 5);
+}
+
+#[test]
+fn batch_size_determines_photons_per_render() {
+    let scene = ::app::App::set_up_scene();
+    let mut unit = TraceUnit::new(0, 16, 9, 37);
+    unit.render(&scene);
+
+    // Every photon of the batch is traced, and there are no more.
+    assert_eq!(unit.mapped_photons.len(), 37);
+    assert!(unit.mapped_photons.iter().all(|p| p.wavelength >= 380.0 && p.wavelength <= 780.0));
+}
+
+#[test]
+fn cached_occluder_blocks_shadow_rays_early() {
+    use geometry::{Circle, Plane};
+    use material::{BlackBodyMaterial, DiffuseGreyMaterial};
+    use object::MaterialBox;
+
+    // A light above the origin, and a small disk just below it that blocks
+    // rays from straight below, but not those that come in at an angle.
+    let up = Vector3::new(0.0, 0.0, 1.0);
+    let grey = || MaterialBox::Reflective(Box::new(DiffuseGreyMaterial::new(0.5)));
+    let floor = Object::new(Box::new(Plane::new(up, Vector3::zero())), grey());
+    let blocker = Object::new(Box::new(Circle::new(up, up * 4.0, 0.5)), grey());
+    let light = Object::new(Box::new(Circle::new(-up, up * 5.0, 3.0)),
+                            MaterialBox::Emissive(Box::new(BlackBodyMaterial::new(6504.0, 1.0))));
+    let scene = Scene::new(vec![floor, blocker, light], ::camera::test_camera);
+    let mut lights = LightSampler::new(&scene, true);
+    assert_eq!(lights.lights.len(), 1);
+
+    let shadow_ray = |from: Vector3, to: Vector3| {
+        let ray = Ray {
+            origin: from,
+            direction: (to - from).normalise(),
+            wavelength: 550.0,
+            probability: 1.0
+        };
+        (ray, (to - from).magnitude())
+    };
+
+    // The first blocked ray is tested against the scene, and the ones after
+    // it are blocked by the cached occluder right away.
+    let mut stats = TraceStatistics::default();
+    let (blocked, distance) = shadow_ray(Vector3::new(0.0, 0.0, 0.01), up * 5.0);
+    for _ in 0 .. 10 {
+        assert!(!lights.is_visible(&scene, 0, &blocked, distance, &mut stats));
+    }
+    assert_eq!(stats.cached_occlusions, 9);
+
+    // A ray that misses the cached occluder is still tested fully.
+    let (visible, distance) = shadow_ray(Vector3::new(2.0, 0.0, 0.01),
+                                         Vector3::new(2.5, 0.0, 5.0));
+    for _ in 0 .. 3 {
+        assert!(lights.is_visible(&scene, 0, &visible, distance, &mut stats));
     }
+    assert_eq!(stats.cached_occlusions, 9);
 }
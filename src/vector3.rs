@@ -91,6 +91,19 @@ impl Vector3 {
     pub fn reflect(self, normal: Vector3) -> Vector3 {
         self - normal * 2.0 * dot(normal, self)
     }
+
+    /// Refracts the direction at a surface with the unit `normal` on the side
+    /// of the incident direction, where `eta` is the ratio n1 / n2 of the
+    /// indices of refraction before and after the surface. Returns `None`
+    /// when there is total internal reflection instead.
+    pub fn refract(self, normal: Vector3, eta: f32) -> Option<Vector3> {
+        let cos_i = -dot(self, normal);
+        let sin_t_sqr = eta * eta * (1.0 - cos_i * cos_i);
+        if sin_t_sqr > 1.0 { return None; }
+
+        let cos_t = (1.0 - sin_t_sqr).sqrt();
+        Some(self * eta + normal * (eta * cos_i - cos_t))
+    }
 }
 
 impl Add for Vector3 {
@@ -140,3 +153,31 @@ impl Mul<f32> for Vector3 {
         }
     }
 }
+
+#[test]
+fn reflect_and_refract() {
+    let normal = Vector3::new(0.0, 0.0, 1.0);
+    let close = |a: Vector3, b: Vector3| (a - b).magnitude() < 1.0e-6;
+
+    // At 45 degrees, the reflection is perpendicular to the incident ray.
+    let d = Vector3::new(1.0, 0.0, -1.0).normalise();
+    assert!(close(d.reflect(normal), Vector3::new(1.0, 0.0, 1.0).normalise()));
+
+    // From air into glass with n = 1.5 at 45 degrees, Snell's law gives
+    // sin(theta_t) = sin(45) / 1.5 = 0.4714.
+    let sin_t = 0.5f32.sqrt() / 1.5;
+    let cos_t = (1.0 - sin_t * sin_t).sqrt();
+    let refracted = d.refract(normal, 1.0 / 1.5).unwrap();
+    assert!(close(refracted, Vector3::new(sin_t, 0.0, -cos_t)));
+
+    // Straight through, the direction does not change.
+    let down = Vector3::new(0.0, 0.0, -1.0);
+    assert!(close(down.refract(normal, 1.0 / 1.5).unwrap(), down));
+
+    // From glass into air, 45 degrees is beyond the critical angle of
+    // 41.8 degrees, but 40 degrees is not.
+    assert!(d.refract(normal, 1.5).is_none());
+    let angle = 40.0f32.to_radians();
+    let d = Vector3::new(angle.sin(), 0.0, -angle.cos());
+    assert!(d.refract(normal, 1.5).is_some());
+}
@@ -0,0 +1,116 @@
+// Robigo Luculenta -- Proof of concept spectral path tracer in Rust
+// Copyright (C) 2015 Ruud van Asseldonk
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use vector3::Vector3;
+
+/// The correlated colour temperature of CIE illuminant D65, the white point
+/// of sRGB, in Kelvin.
+pub const D65_TEMPERATURE: f32 = 6504.0;
+
+/// The Bradford matrix, which converts CIE XYZ tristimuli to the cone-like
+/// response in which the chromatic adaptation is applied.
+const BRADFORD: [[f32; 3]; 3] = [
+    [ 0.8951,  0.2664, -0.1614],
+    [-0.7502,  1.7135,  0.0367],
+    [ 0.0389, -0.0685,  1.0296]
+];
+
+/// The inverse of the Bradford matrix.
+const BRADFORD_INVERSE: [[f32; 3]; 3] = [
+    [ 0.9869929, -0.1470543,  0.1599627],
+    [ 0.4323053,  0.5183603,  0.0492912],
+    [-0.0085287,  0.0400428,  0.9684867]
+];
+
+/// Multiplies the vector by the matrix.
+pub fn transform(m: &[[f32; 3]; 3], v: Vector3) -> Vector3 {
+    Vector3 {
+        x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z
+    }
+}
+
+/// Returns the CIE XYZ tristimulus, with a Y of 1, of daylight with the
+/// specified correlated colour temperature, on the CIE daylight locus.
+/// The locus is defined from 4000 K to 25000 K; temperatures outside of
+/// that range are clamped to it.
+pub fn daylight_white(temperature: f32) -> Vector3 {
+    let t = temperature.max(4000.0).min(25000.0) as f64;
+    let (t2, t3) = (t * t, t * t * t);
+    let x = if t <= 7000.0 {
+        -4.6070e9 / t3 + 2.9678e6 / t2 + 0.09911e3 / t + 0.244063
+    } else {
+        -2.0064e9 / t3 + 1.9018e6 / t2 + 0.24748e3 / t + 0.237040
+    };
+    let y = -3.0 * x * x + 2.870 * x - 0.275;
+    Vector3::new((x / y) as f32, 1.0, ((1.0 - x - y) / y) as f32)
+}
+
+/// Returns the von Kries transform, in the Bradford cone space, that maps
+/// colours seen under a light with the `source` white to how they appear
+/// under a light with the `destination` white. Both whites are CIE XYZ.
+pub fn bradford_adaptation(source: Vector3, destination: Vector3) -> [[f32; 3]; 3] {
+    let s = transform(&BRADFORD, source);
+    let d = transform(&BRADFORD, destination);
+    let scale = [d.x / s.x, d.y / s.y, d.z / s.z];
+
+    // The product BRADFORD_INVERSE * diag(scale) * BRADFORD.
+    let mut m = [[0.0f32; 3]; 3];
+    for i in 0 .. 3 {
+        for j in 0 .. 3 {
+            m[i][j] = (0 .. 3).fold(0.0, |acc, k| {
+                acc + BRADFORD_INVERSE[i][k] * scale[k] * BRADFORD[k][j]
+            });
+        }
+    }
+    m
+}
+
+/// Returns the transform that white balances a render lit by daylight of
+/// the specified colour temperature for display on a D65 white point.
+pub fn white_balance(temperature: f32) -> [[f32; 3]; 3] {
+    bradford_adaptation(daylight_white(temperature), daylight_white(D65_TEMPERATURE))
+}
+
+#[test]
+fn adapting_warm_white_to_d65_cools_it() {
+    let chromaticity = |v: Vector3| {
+        let sum = v.x + v.y + v.z;
+        (v.x / sum, v.y / sum)
+    };
+
+    // The daylight locus passes through D65 at 6504 K.
+    let (x65, y65) = chromaticity(daylight_white(D65_TEMPERATURE));
+    assert!((x65 - 0.3127).abs() < 1.0e-3 && (y65 - 0.3290).abs() < 1.0e-3);
+
+    // A neutral surface under 5000 K light looks yellowish. After white
+    // balancing it is shifted towards blue, and it becomes the D65 white.
+    let neutral = daylight_white(5000.0);
+    let (x50, y50) = chromaticity(neutral);
+    let (x, y) = chromaticity(transform(&white_balance(5000.0), neutral));
+    assert!(x < x50 && y < y50);
+    assert!((x - x65).abs() < 1.0e-4 && (y - y65).abs() < 1.0e-4);
+
+    // At 6504 K, white balancing changes nothing.
+    let m = white_balance(D65_TEMPERATURE);
+    for (i, row) in m.iter().enumerate() {
+        for (j, &x) in row.iter().enumerate() {
+            let identity = if i == j { 1.0 } else { 0.0 };
+            assert!((x - identity).abs() < 1.0e-5);
+        }
+    }
+}